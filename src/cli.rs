@@ -1,7 +1,6 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright © 2022 Adrian <adrian.eddy at gmail>
 
-use argh::FromArgs;
 use cpp::*;
 use gyroflow_core::*;
 use std::sync::Arc;
@@ -11,6 +10,7 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use crate::rendering;
 use crate::rendering::render_queue::*;
+use gyroflow_headless::cli::{ self as headless_cli, Opts, setup_defaults };
 use indicatif::{ProgressBar, MultiProgress, ProgressState, ProgressStyle};
 
 cpp! {{
@@ -27,52 +27,6 @@ macro_rules! connect {
     };
 }
 
-/** Gyroflow v1.3.0
-Video stabilization using gyroscope data
-*/
-#[derive(FromArgs)]
-struct Opts {
-    /// input files: videos, project files, lens profiles, presets
-    #[argh(positional)]
-    input: Vec<String>,
-
-    /// overwrite if output file exists, default: false
-    #[argh(switch, short = 'f')]
-    overwrite: bool,
-
-    /// number of parallel renders, default: 1
-    #[argh(option, short = 'j', default = "1")]
-    parallel_renders: i32,
-
-    /// when done: 1 - shut down; 2 - reboot; 3 - sleep; 4 - hibernate; 5 - logout
-    #[argh(option, short = 'd', default = "0")]
-    when_done: i32,
-
-    /// output parameters, eg. "{{ 'codec': 'H.265/HEVC', 'bitrate': 150, 'use_gpu': true, 'audio': true }}"
-    #[argh(option, short = 'p')]
-    out_params: Option<String>,
-
-    /// export project file instead of rendering: 1 - default project, 2 - with gyro data, 3 - with processed gyro data
-    #[argh(option, default = "0")]
-    export_project: u32,
-
-    /// preset (file or content directly), eg. "{{ 'version': 2, 'stabilization': {{ 'fov': 1.5 }} }}"
-    #[argh(option)]
-    preset: Option<String>,
-
-    /// open file in the GUI (video or project)
-    #[argh(option)]
-    open: Option<String>,
-
-    /// watch folder for automated processing
-    #[argh(option)]
-    watch: Option<String>,
-
-    /// gyro file path
-    #[argh(option, short = 'g')]
-    gyro_file: Option<String>,
-}
-
 pub fn will_run_in_console() -> bool {
     if std::env::args().len() > 1 {
         let opts: Opts = argh::from_env();
@@ -90,27 +44,17 @@ pub fn run(open_file: &mut String) -> bool {
     if std::env::args().len() > 1 {
         let opts: Opts = argh::from_env();
 
-        let (videos, mut lens_profiles, mut presets) = detect_types(&opts.input);
-        if let Some(mut preset) = opts.preset {
-            if !preset.is_empty() {
-                if preset.starts_with('{') { preset = preset.replace('\'', "\""); }
-                presets.push(preset);
-            }
-        }
-
-        if let Some(open) = opts.open {
+        if let Some(open) = opts.open.as_ref() {
             if !open.is_empty() {
-                *open_file = open;
+                *open_file = open.clone();
                 return false;
             }
         }
 
-        for file in videos.iter().chain(lens_profiles.iter()) {
-            if !std::path::Path::new(&file).exists() {
-                log::error!("File {} doesn't exist.", file);
-                return true;
-            }
-        }
+        let (videos, mut lens_profiles, mut presets) = match headless_cli::process(&opts, false) {
+            Some(files) => files,
+            None => return true
+        };
         let mut watching = opts.watch.as_ref().map(|x| !x.is_empty()).unwrap_or_default();
 
         if !watching {
@@ -159,7 +103,11 @@ pub fn run(open_file: &mut String) -> bool {
         if let Some((name, _list_name)) = gyroflow_core::gpu::initialize_contexts() {
             rendering::set_gpu_type_from_name(&name);
         }
-        let mut additional_data = setup_defaults(stab, &mut queue);
+        let settings = get_saved_settings();
+        let mut additional_data = setup_defaults(&stab, &settings, opts.global_sync, opts.timestamp_sync);
+        if let Some(suffix) = settings.get("defaultSuffix") {
+            queue.default_suffix = QString::from(suffix.as_str());
+        }
 
         if let Some(mut outp) = opts.out_params {
             outp = outp.replace('\'', "\"");
@@ -365,32 +313,6 @@ pub fn run(open_file: &mut String) -> bool {
     false
 }
 
-fn detect_types(all_files: &[String]) -> (Vec<String>, Vec<String>, Vec<String>) { // -> Videos/projects, lens profiles, presets
-    let mut videos = Vec::new();
-    let mut lens_profiles = Vec::new();
-    let mut presets = Vec::new();
-    for file in all_files {
-        if file.ends_with(".json") { // Lens profile
-            lens_profiles.push(file.clone());
-        } else if file.ends_with(".gyroflow") {
-            let video_path = || -> Option<String> {
-                let data = std::fs::read(&file).ok()?;
-                let obj: serde_json::Value = serde_json::from_slice(&data).ok()?;
-                Some(obj.get("videofile")?.as_str()?.to_string())
-            }().unwrap_or_default();
-
-            if video_path.is_empty() { // It's a preset
-                presets.push(file.clone());
-            } else {
-                videos.push(file.clone());
-            }
-        } else {
-            videos.push(file.clone());
-        }
-    }
-    (videos, lens_profiles, presets)
-}
-
 fn get_saved_settings() -> HashMap<String, String> {
     let settings = cpp!(unsafe [] -> (QStringList, QStringList) as "std::pair<QStringList, QStringList>" {
         QSettings sett;
@@ -408,83 +330,6 @@ fn get_saved_settings() -> HashMap<String, String> {
     map
 }
 
-fn setup_defaults(stab: Arc<StabilizationManager<stabilization::RGBA8>>, queue: &mut RenderQueue) -> serde_json::Value {
-    let settings = get_saved_settings();
-    dbg!(&settings);
-
-    let codecs = [
-        "H.264/AVC",
-        "H.265/HEVC",
-        "ProRes",
-        "DNxHD",
-        "EXR Sequence",
-        "PNG Sequence",
-    ];
-
-    // Default settings - project file will override this
-
-    match settings.get("croppingMode").unwrap_or(&"1".into()).parse::<u32>() {
-        Ok(0) => stab.set_adaptive_zoom(0.0), // No zooming
-        Ok(1) => stab.set_adaptive_zoom(settings.get("adaptiveZoom").unwrap_or(&"4".into()).parse::<f64>().unwrap()),
-        Ok(2) => stab.set_adaptive_zoom(-1.0), // Static zoom
-        _ => { }
-    }
-    stab.set_lens_correction_amount(settings.get("correctionAmount").unwrap_or(&"1".into()).parse::<f64>().unwrap());
-    let smoothing_method = settings.get("smoothingMethod").unwrap_or(&"1".into()).parse::<usize>().unwrap();
-    let smoothing_method_prefix = format!("smoothing-{}-", smoothing_method);
-    stab.set_smoothing_method(smoothing_method);
-    for (k, v) in &settings {
-        if k.starts_with(&smoothing_method_prefix) {
-            stab.set_smoothing_param(k.strip_prefix(&smoothing_method_prefix).unwrap(), v.parse::<f64>().unwrap());
-        }
-    }
-
-    // TODO: set more params from `settings`
-
-    if let Some(gdec) = settings.get("gpudecode").and_then(|x| x.parse::<bool>().ok()) {
-        *rendering::GPU_DECODING.write() = gdec;
-    }
-    if let Some(suffix) = settings.get("defaultSuffix") {
-        queue.default_suffix = QString::from(suffix.as_str());
-    }
-
-    let codec = settings.get("defaultCodec").unwrap_or(&"0".into()).parse::<usize>().unwrap().min(codecs.len() - 1);
-    let codec_name = codecs[codec];
-
-    // Sync and export settings
-    serde_json::json!({
-        "output": {
-            "codec":          codec_name,
-            "codec_options":  "",
-            // "output_path":    "C:/test.mp4",
-            // "output_width":   3840,
-            // "output_height":  2160,
-            // "bitrate":        150,
-            "use_gpu":        settings.get(&format!("exportGpu-{}", codec)).unwrap_or(&"1".into()).parse::<u32>().unwrap() > 0,
-            "audio":          settings.get("exportAudio").unwrap_or(&"true".into()).parse::<bool>().unwrap(),
-            "pixel_format":   "",
-
-            // Advanced
-            "encoder_options":       settings.get(&format!("encoderOptions-{}", codec)).unwrap_or(&"".into()),
-            "keyframe_distance":     settings.get("keyframeDistance").unwrap_or(&"1".into()).parse::<u32>().unwrap(),
-            "preserve_other_tracks": settings.get("preserveOtherTracks").unwrap_or(&"false".into()).parse::<bool>().unwrap(),
-            "pad_with_black":        settings.get("padWithBlack").unwrap_or(&"false".into()).parse::<bool>().unwrap(),
-        },
-        "synchronization": {
-            "initial_offset":     0,
-            "initial_offset_inv": false,
-            "search_size":        5,
-            "calc_initial_fast":  false,
-            "max_sync_points":    5,
-            "every_nth_frame":    1,
-            "time_per_syncpoint": 1,
-            "of_method":          2,
-            "offset_method":      2,
-            "auto_sync_points":   true,
-        }
-    })
-}
-
 fn watch_folder<F: FnMut(String)>(path: String, cb: F) -> bool {
     if path.is_empty() { return false; }
     if !std::path::Path::new(&path).exists() { log::info!("{} doesn't exist.", path); return false; }
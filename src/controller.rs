@@ -67,7 +67,7 @@ pub struct Controller {
     estimate_bias: qt_method!(fn(&self, timestamp_fract: QString)),
     bias_estimated: qt_signal!(bx: f64, by: f64, bz: f64),
     orientation_guessed: qt_signal!(orientation: QString),
-    get_optimal_sync_points: qt_method!(fn(&mut self, target_sync_points: usize) -> QString),
+    get_optimal_sync_points: qt_method!(fn(&mut self, target_sync_points: usize, sync_params: String) -> QString),
 
     start_autocalibrate: qt_method!(fn(&self, max_points: usize, every_nth_frame: usize, iterations: usize, max_sharpness: f64, custom_timestamp_ms: f64, no_marker: bool)),
 
@@ -189,6 +189,7 @@ pub struct Controller {
     import_gyroflow_file: qt_method!(fn(&mut self, url: QUrl)),
     import_gyroflow_data: qt_method!(fn(&mut self, data: QString)),
     gyroflow_file_loaded: qt_signal!(obj: QJsonObject),
+    project_report_loaded: qt_signal!(report: QJsonObject),
     export_gyroflow_file: qt_method!(fn(&self, thin: bool, extended: bool, additional_data: QJsonObject, override_location: QString, overwrite: bool)),
     export_gyroflow_data: qt_method!(fn(&self, thin: bool, extended: bool, additional_data: QJsonObject) -> QString),
 
@@ -404,11 +405,12 @@ impl Controller {
                 };
             });
 
-            let ranges = sync.get_ranges();
-            let cancel_flag = self.cancel_flag.clone();
-
             let input_file = self.stabilizer.input_file.read().clone();
             let proc_height = self.processing_resolution;
+            sync.enable_feature_cache(&input_file.path, proc_height.max(0) as u32);
+
+            let ranges = sync.get_ranges();
+            let cancel_flag = self.cancel_flag.clone();
             core::run_threaded(move || {
                 let gpu_decoding = *rendering::GPU_DECODING.read();
 
@@ -453,8 +455,11 @@ impl Controller {
                             abs_frame_no += 1;
                             Ok(())
                         });
-                        if let Err(e) = proc.start_decoder_only(ranges, cancel_flag.clone()) {
-                            err(("An error occured: %1".to_string(), e.to_string()));
+                        // All ranges can be already in the feature cache
+                        if !ranges.is_empty() {
+                            if let Err(e) = proc.start_decoder_only(ranges, cancel_flag.clone()) {
+                                err(("An error occured: %1".to_string(), e.to_string()));
+                            }
                         }
                         sync.finished_feeding_frames();
                     }
@@ -488,16 +493,12 @@ impl Controller {
         }
     }
 
-    fn get_optimal_sync_points(&mut self, target_sync_points: usize) -> QString {
-        let dur_ms = self.stabilizer.params.read().get_scaled_duration_ms();
-        let trim_start = self.stabilizer.params.read().trim_start * dur_ms / 1000.0;
-        let trim_end = self.stabilizer.params.read().trim_end * dur_ms / 1000.0;
-        if let Some(mut optsync) = core::synchronization::optimsync::OptimSync::new(&self.stabilizer.gyro.read()) {
-            let s: String = optsync.run(target_sync_points, trim_start, trim_end).iter().map(|x| x / dur_ms).map(|x| x.to_string()).join(";").chars().collect();
-            QString::from(s)
-        } else {
-            QString::default()
-        }
+    fn get_optimal_sync_points(&mut self, target_sync_points: usize, sync_params: String) -> QString {
+        let mut sync_params: synchronization::SyncParams = serde_json::from_str(&sync_params).unwrap_or_default();
+        sync_params.initial_offset     *= 1000.0; // s to ms
+        sync_params.time_per_syncpoint *= 1000.0; // s to ms
+        let points = self.stabilizer.get_optimal_sync_points(target_sync_points, &sync_params);
+        QString::from(points.iter().map(|x| x.to_string()).join(";"))
     }
 
     fn update_chart(&mut self, chart: QJSValue, series: String) -> bool {
@@ -545,36 +546,7 @@ impl Controller {
                 let mut sample_ts = last_ts.min(raw_imu.last().unwrap().timestamp_ms) - (fft_size as f64) * dt_ms;
                 sample_ts = sample_ts.max(0.0);
 
-                let mut prev_ts = 0.0;
-                let mut prev_val = 0.0;
-
-                let mut samples: Vec<f64> = Vec::with_capacity(fft_size);
-                for x in raw_imu {
-                    let mut val = 0.0;
-                    if idx < 3 {
-                        if let Some(g) = x.gyro.as_ref() {
-                            val = g[idx % 3];
-                        }
-                    } else {
-                        if let Some(g) = x.accl.as_ref() {
-                            val = g[idx % 3];
-                        }
-                    }
-
-                    while x.timestamp_ms > sample_ts && samples.len() < fft_size {
-                        let frac = (sample_ts - prev_ts) / (x.timestamp_ms - prev_ts);
-                        let interpolated = prev_val + (val - prev_val) * frac.clamp(0.0, 1.0);
-                        samples.push(interpolated /*+ samples.last().unwrap_or(&0.0)*/);
-                        sample_ts += dt_ms;
-                    }
-
-                    if samples.len() >= fft_size {
-                        break;
-                    }
-
-                    prev_ts = x.timestamp_ms;
-                    prev_val = val;
-                }
+                let samples = gyroflow_core::spectral_analysis::sample_channel(raw_imu, idx, sample_ts, sr, fft_size);
 
                 if samples.len() == fft_size {
                     graph.setData(&samples, sr);
@@ -1164,7 +1136,7 @@ impl Controller {
             this.loading_gyro_progress(progress);
             this.loading_gyro_in_progress_changed();
         });
-        let finished = util::qt_queued_callback_mut(self, move |this, obj: std::io::Result<serde_json::Value>| {
+        let finished = util::qt_queued_callback_mut(self, move |this, obj: std::io::Result<(serde_json::Value, core::project::ProjectReport)>| {
             this.loading_gyro_in_progress = false;
             this.loading_gyro_progress(1.0);
             this.loading_gyro_in_progress_changed();
@@ -1192,7 +1164,7 @@ impl Controller {
             this.loading_gyro_progress(progress);
             this.loading_gyro_in_progress_changed();
         });
-        let finished = util::qt_queued_callback_mut(self, move |this, obj: std::io::Result<serde_json::Value>| {
+        let finished = util::qt_queued_callback_mut(self, move |this, obj: std::io::Result<(serde_json::Value, core::project::ProjectReport)>| {
             this.loading_gyro_in_progress = false;
             this.loading_gyro_progress(1.0);
             this.loading_gyro_in_progress_changed();
@@ -1214,9 +1186,14 @@ impl Controller {
             finished(stab.import_gyroflow_data(data.to_string().as_bytes(), false, None, progress, cancel_flag, &mut is_preset));
         });
     }
-    fn import_gyroflow_internal(&mut self, result: std::io::Result<serde_json::Value>) -> QJsonObject {
+    fn import_gyroflow_internal(&mut self, result: std::io::Result<(serde_json::Value, core::project::ProjectReport)>) -> QJsonObject {
         match result {
-            Ok(thin_obj) => {
+            Ok((thin_obj, report)) => {
+                if !report.is_empty() {
+                    if let Ok(report) = serde_json::to_value(&report) {
+                        self.project_report_loaded(util::serde_json_to_qt_object(&report));
+                    }
+                }
                 if thin_obj.as_object().unwrap().contains_key("calibration_data") {
                     self.lens_loaded = true;
                     self.lens_changed();
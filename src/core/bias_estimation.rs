@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Automatic gyro bias estimation from the stationary parts of the log.
+// The log is split into short windows, and a window is static when the gyro is steady (low standard deviation)
+// and the accelerometer magnitude is close to 1g. Consecutive static windows are merged into segments,
+// and the bias is the negated mean gyro reading of these segments (same sign convention as `GyroSource::find_bias`).
+
+use crate::gyro_source::TimeIMU;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BiasEstimationParams {
+    pub window_ms: f64,
+    /// Minimum duration of a static segment
+    pub min_duration_ms: f64,
+    /// Maximum standard deviation of each gyro axis in a static window, in deg/s
+    pub max_gyro_std: f64,
+    /// Maximum absolute mean of each gyro axis, in deg/s. Anything above that is a slow pan, not the bias
+    pub max_gyro_mean: f64,
+    /// Maximum deviation of the accelerometer magnitude from 1g, relative to 1g
+    pub max_accl_deviation: f64,
+}
+impl Default for BiasEstimationParams {
+    fn default() -> Self {
+        Self {
+            window_ms: 250.0,
+            min_duration_ms: 750.0,
+            max_gyro_std: 0.5,
+            max_gyro_mean: 5.0,
+            max_accl_deviation: 0.05,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct StaticSegment {
+    pub start_ms: f64,
+    pub end_ms: f64,
+    pub bias: [f64; 3],
+    pub std: [f64; 3],
+}
+impl StaticSegment {
+    pub fn center_ms(&self) -> f64 { (self.start_ms + self.end_ms) / 2.0 }
+    pub fn duration_ms(&self) -> f64 { self.end_ms - self.start_ms }
+}
+
+/// Mean and standard deviation of each gyro axis
+fn gyro_stats(samples: &[TimeIMU]) -> Option<([f64; 3], [f64; 3])> {
+    let gyro: Vec<[f64; 3]> = samples.iter().filter_map(|x| x.gyro).collect();
+    if gyro.len() < 2 { return None; }
+    let n = gyro.len() as f64;
+
+    let mut mean = [0.0; 3];
+    for g in &gyro { for i in 0..3 { mean[i] += g[i] / n; } }
+    let mut std = [0.0; 3];
+    for g in &gyro { for i in 0..3 { std[i] += (g[i] - mean[i]).powi(2) / (n - 1.0); } }
+    for s in std.iter_mut() { *s = s.sqrt(); }
+
+    Some((mean, std))
+}
+
+fn accl_magnitude(x: &TimeIMU) -> Option<f64> {
+    x.accl.map(|a| (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt())
+}
+
+/// Median accelerometer magnitude, used as 1g so it works regardless of the accelerometer units
+pub fn median_accl_magnitude(imu: &[TimeIMU]) -> Option<f64> {
+    let mut magnitudes: Vec<f64> = imu.iter().filter_map(accl_magnitude).collect();
+    if magnitudes.is_empty() { return None; }
+    magnitudes.sort_by(|a, b| a.total_cmp(b));
+    Some(magnitudes[magnitudes.len() / 2]).filter(|x| *x > 0.0)
+}
+
+/// Finds the stationary segments of the log
+pub fn find_static_segments(imu: &[TimeIMU], params: &BiasEstimationParams) -> Vec<StaticSegment> {
+    if imu.len() < 2 { return Vec::new(); }
+
+    let one_g = median_accl_magnitude(imu);
+
+    let is_static = |samples: &[TimeIMU]| -> bool {
+        let (mean, std) = match gyro_stats(samples) {
+            Some(x) => x,
+            None => return false
+        };
+        if std.iter().any(|x| *x > params.max_gyro_std) || mean.iter().any(|x| x.abs() > params.max_gyro_mean) {
+            return false;
+        }
+        if let Some(one_g) = one_g {
+            let magnitudes: Vec<f64> = samples.iter().filter_map(accl_magnitude).collect();
+            if !magnitudes.is_empty() {
+                let mean = magnitudes.iter().sum::<f64>() / magnitudes.len() as f64;
+                return ((mean - one_g) / one_g).abs() <= params.max_accl_deviation;
+            }
+        }
+        true
+    };
+
+    // Split to windows and merge the consecutive static ones, as sample index ranges
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    let mut start = 0;
+    while start < imu.len() {
+        let end_ts = imu[start].timestamp_ms + params.window_ms;
+        let end = start + imu[start..].iter().position(|x| x.timestamp_ms >= end_ts).unwrap_or(imu.len() - start).max(1);
+        if is_static(&imu[start..end]) {
+            match ranges.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => ranges.push((start, end))
+            }
+        }
+        start = end;
+    }
+
+    ranges.into_iter().filter_map(|(start, end)| {
+        let samples = &imu[start..end];
+        let segment_start = samples.first()?.timestamp_ms;
+        let segment_end = samples.last()?.timestamp_ms;
+        if segment_end - segment_start < params.min_duration_ms { return None; }
+
+        let (mean, std) = gyro_stats(samples)?;
+        Some(StaticSegment {
+            start_ms: segment_start,
+            end_ms: segment_end,
+            bias: [-mean[0], -mean[1], -mean[2]],
+            std
+        })
+    }).collect()
+}
+
+/// Constant bias, as an average of all segments weighted by their duration
+pub fn constant_bias(segments: &[StaticSegment]) -> Option<[f64; 3]> {
+    let total: f64 = segments.iter().map(|x| x.duration_ms()).sum();
+    if segments.is_empty() || total <= 0.0 { return None; }
+
+    let mut bias = [0.0; 3];
+    for s in segments {
+        for i in 0..3 { bias[i] += s.bias[i] * s.duration_ms() / total; }
+    }
+    Some(bias)
+}
+
+/// Time-varying bias at `timestamp_ms`, linearly interpolated between the segment centers and constant outside of them.
+/// `curve` is a list of (timestamp_ms, bias), sorted by timestamp
+pub fn bias_at_timestamp(curve: &[(f64, [f64; 3])], timestamp_ms: f64) -> [f64; 3] {
+    let first = match curve.first() { Some(x) => x, None => return [0.0; 3] };
+    let last = curve.last().unwrap();
+    if timestamp_ms <= first.0 { return first.1; }
+    if timestamp_ms >= last.0 { return last.1; }
+
+    let i = curve.partition_point(|x| x.0 <= timestamp_ms);
+    let (a, b) = (&curve[i - 1], &curve[i]);
+    let fract = if b.0 > a.0 { (timestamp_ms - a.0) / (b.0 - a.0) } else { 0.0 };
+    [
+        a.1[0] + (b.1[0] - a.1[0]) * fract,
+        a.1[1] + (b.1[1] - a.1[1]) * fract,
+        a.1[2] + (b.1[2] - a.1[2]) * fract,
+    ]
+}
@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Loads IMU data from arbitrary CSV/TSV logs, using a column mapping descriptor (JSON file).
+// The descriptor is found next to the log (`log.csv` -> `log.csv.mapping.json`), as `imu_mapping.json` in the same directory,
+// or it can be specified explicitly with `FileLoadOptions::csv_mapping`.
+//
+// Example:
+// {
+//     "delimiter": ",",
+//     "timestamp": { "column": "time", "unit": "ns" },
+//     "gyro": { "columns": ["gx", "gy", "gz"], "unit": "rad/s", "signs": [1, -1, 1] },
+//     "accl": { "columns": [4, 5, 6], "unit": "m/s2" },
+//     "imu_orientation": "XYZ"
+// }
+// Columns can be given by the header name or by the 0-based index.
+
+use std::io::{ BufRead, BufReader, Error, ErrorKind, Result };
+use std::path::{ Path, PathBuf };
+use serde::{ Serialize, Deserialize };
+use crate::gyro_source::{ FileMetadata, TimeIMU };
+use crate::synchronization::timestamp_sync::{ AbsoluteTime, TimeSource };
+
+const GRAVITY: f64 = 9.81;
+// Timestamps after 2000-01-01 in ms are considered as Unix epoch time
+const MIN_EPOCH_MS: f64 = 946684800000.0;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Column {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum TimeUnit {
+    #[serde(rename = "s")]  Seconds,
+    #[default]
+    #[serde(rename = "ms")] Milliseconds,
+    #[serde(rename = "us")] Microseconds,
+    #[serde(rename = "ns")] Nanoseconds,
+}
+impl TimeUnit {
+    fn to_ms(&self) -> f64 {
+        match self {
+            Self::Seconds      => 1000.0,
+            Self::Milliseconds => 1.0,
+            Self::Microseconds => 0.001,
+            Self::Nanoseconds  => 0.000001,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum GyroUnit {
+    #[serde(rename = "rad/s")] RadPerSecond,
+    #[default]
+    #[serde(rename = "deg/s")] DegPerSecond,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum AcclUnit {
+    #[serde(rename = "g")] G,
+    #[default]
+    #[serde(rename = "m/s2", alias = "m/s²")] MetersPerSecondSquared,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TimestampMapping {
+    pub column: Column,
+    #[serde(default)]
+    pub unit: TimeUnit,
+    /// Start the timestamps from 0. Useful for logs with absolute (epoch) time
+    #[serde(default = "default_true")]
+    pub relative: bool,
+    #[serde(default)]
+    pub offset_ms: f64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct SensorMapping<U: Default> {
+    pub columns: [Column; 3],
+    #[serde(default)]
+    pub unit: U,
+    /// Multiplier for each axis, eg. -1 to flip the axis
+    #[serde(default = "default_signs")]
+    pub signs: [f64; 3],
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct CsvImuDescriptor {
+    /// Auto detected from the first line if not set
+    #[serde(default)]
+    pub delimiter: Option<char>,
+    /// Number of lines to skip before the header
+    #[serde(default)]
+    pub skip_rows: usize,
+    #[serde(default = "default_true")]
+    pub has_header: bool,
+    pub timestamp: TimestampMapping,
+    pub gyro: SensorMapping<GyroUnit>,
+    #[serde(default)]
+    pub accl: Option<SensorMapping<AcclUnit>>,
+    #[serde(default)]
+    pub magn: Option<SensorMapping<()>>,
+    #[serde(default)]
+    pub imu_orientation: Option<String>,
+}
+
+fn default_true() -> bool { true }
+fn default_signs() -> [f64; 3] { [1.0, 1.0, 1.0] }
+
+fn invalid_data(msg: String) -> Error { Error::new(ErrorKind::InvalidData, msg) }
+
+impl CsvImuDescriptor {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        serde_json::from_slice(&std::fs::read(path)?).map_err(|e| invalid_data(format!("Invalid IMU mapping {:?}: {}", path, e)))
+    }
+
+    /// Finds the descriptor for the log file, if it's a CSV/TSV file
+    pub fn find_for(path: &str) -> Option<PathBuf> {
+        let path = Path::new(path);
+        let ext = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        if !["csv", "tsv", "txt"].contains(&ext.as_str()) { return None; }
+
+        let mut name = path.file_name()?.to_os_string();
+        name.push(".mapping.json");
+        [path.with_file_name(name), path.with_file_name("imu_mapping.json")].into_iter().find(|x| x.exists())
+    }
+
+    fn detect_delimiter(line: &str) -> char {
+        if line.contains('\t') { return '\t'; }
+        if line.matches(';').count() > line.matches(',').count() { ';' } else { ',' }
+    }
+
+    fn resolve(column: &Column, header: &[String]) -> Result<usize> {
+        match column {
+            Column::Index(i) => Ok(*i),
+            Column::Name(name) => header.iter().position(|x| x == name).ok_or_else(|| invalid_data(format!("Column {} not found in the header", name)))
+        }
+    }
+
+    /// Returns the samples and the absolute time of the timestamp 0, if the log has Unix epoch timestamps
+    pub fn parse<R: BufRead>(&self, reader: R) -> Result<(Vec<TimeIMU>, Option<AbsoluteTime>)> {
+        let mut lines = reader.lines().skip(self.skip_rows).peekable();
+
+        let first_line = match lines.peek() {
+            Some(Ok(line)) => line.clone(),
+            _ => String::new()
+        };
+        let delimiter = self.delimiter.unwrap_or_else(|| Self::detect_delimiter(&first_line));
+        let split = |line: &str| -> Vec<String> { line.split(delimiter).map(|x| x.trim().trim_matches('"').to_string()).collect() };
+
+        let header = if self.has_header { lines.next().transpose()?.map(|x| split(&x)).unwrap_or_default() } else { Vec::new() };
+
+        let ts_col = Self::resolve(&self.timestamp.column, &header)?;
+        let axes = |m: &[Column; 3]| -> Result<[usize; 3]> { Ok([Self::resolve(&m[0], &header)?, Self::resolve(&m[1], &header)?, Self::resolve(&m[2], &header)?]) };
+        let gyro_cols = axes(&self.gyro.columns)?;
+        let accl_cols = self.accl.as_ref().map(|x| axes(&x.columns)).transpose()?;
+        let magn_cols = self.magn.as_ref().map(|x| axes(&x.columns)).transpose()?;
+
+        let gyro_scale = match self.gyro.unit {
+            GyroUnit::RadPerSecond => 180.0 / std::f64::consts::PI,
+            GyroUnit::DegPerSecond => 1.0
+        };
+        let accl_scale = match self.accl.as_ref().map(|x| x.unit).unwrap_or_default() {
+            AcclUnit::G => GRAVITY,
+            AcclUnit::MetersPerSecondSquared => 1.0
+        };
+
+        let mut samples = Vec::new();
+        let mut invalid_lines = 0;
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') { continue; }
+            let values = split(&line);
+            let get = |i: usize| -> Option<f64> { values.get(i)?.parse::<f64>().ok() };
+            let get3 = |cols: &[usize; 3], signs: &[f64; 3], scale: f64| -> Option<[f64; 3]> {
+                Some([get(cols[0])? * signs[0] * scale, get(cols[1])? * signs[1] * scale, get(cols[2])? * signs[2] * scale])
+            };
+
+            let sample = (|| -> Option<TimeIMU> {
+                Some(TimeIMU {
+                    timestamp_ms: get(ts_col)? * self.timestamp.unit.to_ms(),
+                    gyro: Some(get3(&gyro_cols, &self.gyro.signs, gyro_scale)?),
+                    accl: match (&accl_cols, &self.accl) { (Some(c), Some(m)) => get3(c, &m.signs, accl_scale), _ => None },
+                    magn: match (&magn_cols, &self.magn) { (Some(c), Some(m)) => get3(c, &m.signs, 1.0), _ => None },
+                })
+            })();
+            match sample {
+                Some(s) => samples.push(s),
+                None => invalid_lines += 1
+            }
+        }
+        if invalid_lines > 0 {
+            ::log::warn!("Skipped {} invalid lines in the IMU log", invalid_lines);
+        }
+        if samples.is_empty() {
+            return Err(invalid_data("No valid IMU samples found".into()));
+        }
+
+        samples.sort_by(|a, b| a.timestamp_ms.total_cmp(&b.timestamp_ms));
+        let first_ts = if self.timestamp.relative { samples[0].timestamp_ms } else { 0.0 };
+        let start_time = (samples[0].timestamp_ms >= MIN_EPOCH_MS).then(|| AbsoluteTime {
+            start_ms: first_ts - self.timestamp.offset_ms,
+            source: TimeSource::CsvTimestamps,
+            resolution_ms: 0.0
+        });
+        for s in samples.iter_mut() {
+            s.timestamp_ms = s.timestamp_ms - first_ts + self.timestamp.offset_ms;
+        }
+        Ok((samples, start_time))
+    }
+}
+
+pub fn parse_file(path: &str, descriptor_path: &Path) -> Result<FileMetadata> {
+    let descriptor = CsvImuDescriptor::from_file(descriptor_path)?;
+    ::log::info!("Loading {} with IMU mapping {:?}", path, descriptor_path);
+
+    let (raw_imu, start_time) = descriptor.parse(BufReader::new(std::fs::File::open(path)?))?;
+
+    Ok(FileMetadata {
+        imu_orientation: Some(descriptor.imu_orientation.clone().unwrap_or_else(|| "XYZ".into())),
+        detected_source: Some("CSV (column mapping)".into()),
+        raw_imu: Some(raw_imu),
+        start_time,
+        ..Default::default()
+    })
+}
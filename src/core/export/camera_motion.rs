@@ -0,0 +1,273 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Exports the camera orientation from gyro data as animated camera for 3D and compositing apps.
+//
+// Gyro quaternions are camera-to-world rotations in the OpenGL convention (x right, y up, looking down -z),
+// which is what Nuke and Blender (after rotating to Z-up) use. After Effects uses y down and z forward.
+// Lens distortion is not part of the exported camera, use the undistorted plate or ST-maps with it.
+
+use std::io::{ Write, BufWriter };
+use nalgebra::{ Matrix3, Rotation3, Vector3 };
+use rayon::prelude::*;
+use crate::gyro_source::Quat64;
+use crate::stabilization::{ ComputeParams, FrameTransform };
+
+// Film back used to convert focal length in pixels to millimeters
+const SENSOR_WIDTH_MM: f64 = 36.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CameraMotionFormat {
+    NukeChan,
+    AfterEffects,
+    BlenderPython,
+    Json,
+    Csv,
+}
+impl CameraMotionFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "chan" | "nuke"            => Some(Self::NukeChan),
+            "ae"   | "aftereffects"    => Some(Self::AfterEffects),
+            "py"   | "blender"         => Some(Self::BlenderPython),
+            "json"                     => Some(Self::Json),
+            "csv"                      => Some(Self::Csv),
+            _ => None
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::NukeChan      => "chan",
+            Self::AfterEffects  => "txt",
+            Self::BlenderPython => "py",
+            Self::Json          => "json",
+            Self::Csv           => "csv",
+        }
+    }
+}
+
+/// Which orientation to write as the camera rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CameraRotation {
+    /// Real camera motion, for match-moving CG elements to the original footage
+    #[default]
+    Original,
+    /// Virtual camera of the stabilized footage
+    Smoothed,
+    /// Rotation from the original to the smoothed camera, in the original camera space. Can be used to apply counter-motion
+    Correction,
+}
+impl CameraRotation {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "original"   => Some(Self::Original),
+            "smoothed"   => Some(Self::Smoothed),
+            "correction" => Some(Self::Correction),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CameraSample {
+    pub frame: usize,
+    pub timestamp_ms: f64,
+    pub original: Quat64,
+    pub smoothed: Quat64,
+    pub original_focal_px: f64, // at processing size
+    pub output_focal_px: f64,   // at output size, includes zooming
+    pub focal_length_mm: Option<f64>, // from the lens metadata, if available
+}
+impl CameraSample {
+    pub fn correction(&self) -> Quat64 {
+        self.original.inverse() * self.smoothed
+    }
+    pub fn rotation(&self, r: CameraRotation) -> Quat64 {
+        match r {
+            CameraRotation::Original   => self.original,
+            CameraRotation::Smoothed   => self.smoothed,
+            CameraRotation::Correction => self.correction(),
+        }
+    }
+    pub fn focal_px(&self, r: CameraRotation) -> f64 {
+        match r {
+            CameraRotation::Original => self.original_focal_px,
+            _ => self.output_focal_px
+        }
+    }
+}
+
+fn image_size(params: &ComputeParams, r: CameraRotation) -> (f64, f64) {
+    match r {
+        CameraRotation::Original => (params.width as f64, params.height as f64),
+        _ => (params.output_width as f64, params.output_height as f64)
+    }
+}
+
+fn fov_deg(size: f64, focal_px: f64) -> f64 {
+    (2.0 * (size / 2.0 / focal_px.max(0.0001)).atan()).to_degrees()
+}
+
+pub fn compute_samples(params: &ComputeParams, range: std::ops::Range<usize>) -> Vec<CameraSample> {
+    // Only the main matrix is needed, skip the rolling shutter rows
+    let mut params = params.clone();
+    params.frame_readout_time = 0.0;
+
+    range.into_par_iter().map(|frame| {
+        let timestamp_ms = crate::timestamp_at_frame(frame as i32, params.scaled_fps);
+        let t = FrameTransform::at_timestamp(&params, timestamp_ms, frame);
+        let (f, fov) = (t.kernel_params.f, t.kernel_params.fov);
+        CameraSample {
+            frame,
+            timestamp_ms,
+            original: params.gyro.org_quat_at_timestamp(timestamp_ms),
+            smoothed: params.gyro.smoothed_quat_at_timestamp(timestamp_ms),
+            original_focal_px: f[0] as f64,
+            output_focal_px: (f[0] / fov.max(0.0001)) as f64,
+            focal_length_mm: t.focal_length,
+        }
+    }).collect()
+}
+
+// Euler angles (x, y, z) in radians for m = Ry * Rx * Rz (Nuke's default ZXY order)
+fn euler_zxy(m: &Matrix3<f64>) -> Vector3<f64> {
+    let x = (-m[(1, 2)]).clamp(-1.0, 1.0).asin();
+    if m[(1, 2)].abs() < 0.99999 {
+        Vector3::new(x, m[(0, 2)].atan2(m[(2, 2)]), m[(1, 0)].atan2(m[(1, 1)]))
+    } else {
+        Vector3::new(x, (-m[(2, 0)]).atan2(m[(0, 0)]), 0.0)
+    }
+}
+// Euler angles (x, y, z) in radians for m = Rz * Ry * Rx (Blender's XYZ order)
+fn euler_xyz(m: &Matrix3<f64>) -> Vector3<f64> {
+    let y = (-m[(2, 0)]).clamp(-1.0, 1.0).asin();
+    if m[(2, 0)].abs() < 0.99999 {
+        Vector3::new(m[(2, 1)].atan2(m[(2, 2)]), y, m[(1, 0)].atan2(m[(0, 0)]))
+    } else {
+        Vector3::new((-m[(1, 2)]).atan2(m[(1, 1)]), y, 0.0)
+    }
+}
+
+fn matrix(q: &Quat64) -> Matrix3<f64> {
+    *q.to_rotation_matrix().matrix()
+}
+// Y down, Z forward
+fn matrix_ae(q: &Quat64) -> Matrix3<f64> {
+    let s = Matrix3::from_diagonal(&Vector3::new(1.0, -1.0, -1.0));
+    s * matrix(q) * s
+}
+// Z up world, camera looking at +Y when not rotated
+fn matrix_blender(q: &Quat64) -> Matrix3<f64> {
+    *Rotation3::from_axis_angle(&Vector3::x_axis(), std::f64::consts::FRAC_PI_2).matrix() * matrix(q)
+}
+
+pub fn export<W: Write, F: Fn(f64)>(params: &ComputeParams, format: CameraMotionFormat, rotation: CameraRotation, trim_range_only: bool, writer: W, progress_cb: F) -> std::io::Result<()> {
+    let mut w = BufWriter::new(writer);
+    let range = super::frame_range(params.frame_count, params.trim_start, params.trim_end, trim_range_only);
+    let samples = compute_samples(params, range);
+    progress_cb(0.5);
+
+    let (width, height) = image_size(params, rotation);
+
+    match format {
+        CameraMotionFormat::NukeChan => {
+            // frame, translation xyz, rotation xyz (degrees, ZXY order), vertical field of view
+            for s in &samples {
+                let r = euler_zxy(&matrix(&s.rotation(rotation)));
+                writeln!(w, "{}\t0.0\t0.0\t0.0\t{:.6}\t{:.6}\t{:.6}\t{:.6}", s.frame, r.x.to_degrees(), r.y.to_degrees(), r.z.to_degrees(), fov_deg(height, s.focal_px(rotation)))?;
+            }
+        },
+        CameraMotionFormat::AfterEffects => {
+            writeln!(w, "Adobe After Effects 8.0 Keyframe Data\n")?;
+            writeln!(w, "\tUnits Per Second\t{:.3}", params.scaled_fps)?;
+            writeln!(w, "\tSource Width\t{}", width as usize)?;
+            writeln!(w, "\tSource Height\t{}", height as usize)?;
+            writeln!(w, "\tSource Pixel Aspect Ratio\t1")?;
+            writeln!(w, "\tComp Pixel Aspect Ratio\t1\n")?;
+
+            let rotations: Vec<Vector3<f64>> = samples.iter().map(|s| euler_xyz(&matrix_ae(&s.rotation(rotation)))).collect();
+            for (i, name) in ["X Rotation", "Y Rotation", "Z Rotation"].iter().enumerate() {
+                writeln!(w, "Transform\t{}\n\tFrame\tdegrees\t", name)?;
+                for (s, r) in samples.iter().zip(rotations.iter()) {
+                    writeln!(w, "\t{}\t{:.6}\t", s.frame, r[i].to_degrees())?;
+                }
+                writeln!(w)?;
+            }
+            writeln!(w, "Camera Options\tZoom\n\tFrame\tpixels\t")?;
+            for s in &samples {
+                writeln!(w, "\t{}\t{:.6}\t", s.frame, s.focal_px(rotation))?;
+            }
+            writeln!(w, "\nEnd of Keyframe Data")?;
+        },
+        CameraMotionFormat::BlenderPython => {
+            writeln!(w, "# Camera motion exported from Gyroflow. Run in Blender's Text Editor to create the animated camera")?;
+            writeln!(w, "import bpy\n")?;
+            writeln!(w, "fps = {}", params.scaled_fps)?;
+            writeln!(w, "resolution = ({}, {})", width as usize, height as usize)?;
+            writeln!(w, "sensor_width = {}", SENSOR_WIDTH_MM)?;
+            writeln!(w, "# frame, rotation_euler (XYZ, radians), lens (mm)")?;
+            writeln!(w, "data = [")?;
+            for s in &samples {
+                let r = euler_xyz(&matrix_blender(&s.rotation(rotation)));
+                writeln!(w, "    ({}, ({:.8}, {:.8}, {:.8}), {:.6}),", s.frame, r.x, r.y, r.z, s.focal_px(rotation) / width * SENSOR_WIDTH_MM)?;
+            }
+            writeln!(w, "]\n")?;
+            w.write_all(br#"scene = bpy.context.scene
+scene.render.fps = round(fps)
+scene.render.fps_base = round(fps) / fps
+scene.render.resolution_x, scene.render.resolution_y = resolution
+
+cam_data = bpy.data.cameras.new("Gyroflow camera")
+cam_data.sensor_fit = 'HORIZONTAL'
+cam_data.sensor_width = sensor_width
+cam = bpy.data.objects.new("Gyroflow camera", cam_data)
+cam.rotation_mode = 'XYZ'
+scene.collection.objects.link(cam)
+
+for frame, rotation, lens in data:
+    cam.rotation_euler = rotation
+    cam.keyframe_insert(data_path="rotation_euler", frame=scene.frame_start + frame)
+    cam_data.lens = lens
+    cam_data.keyframe_insert(data_path="lens", frame=scene.frame_start + frame)
+"#)?;
+        },
+        CameraMotionFormat::Json => {
+            let frames: Vec<serde_json::Value> = samples.iter().map(|s| {
+                let q = |q: Quat64| [q.w, q.i, q.j, q.k];
+                serde_json::json!({
+                    "frame":             s.frame,
+                    "timestamp_ms":      s.timestamp_ms,
+                    "original":          q(s.original),
+                    "smoothed":          q(s.smoothed),
+                    "correction":        q(s.correction()),
+                    "original_focal_px": s.original_focal_px,
+                    "output_focal_px":   s.output_focal_px,
+                    "focal_length_mm":   s.focal_length_mm,
+                })
+            }).collect();
+            serde_json::to_writer_pretty(&mut w, &serde_json::json!({
+                "version":      1,
+                "fps":          params.scaled_fps,
+                "size":         [params.width, params.height],
+                "output_size":  [params.output_width, params.output_height],
+                "quaternion_order": "wxyz",
+                "frames":       frames
+            }))?;
+        },
+        CameraMotionFormat::Csv => {
+            writeln!(w, "frame,timestamp_ms,rx,ry,rz,qw,qx,qy,qz,hfov,vfov,focal_px,focal_mm_36")?;
+            for s in &samples {
+                let q = s.rotation(rotation);
+                let r = euler_xyz(&matrix(&q));
+                let f = s.focal_px(rotation);
+                writeln!(w, "{},{:.3},{:.6},{:.6},{:.6},{:.8},{:.8},{:.8},{:.8},{:.6},{:.6},{:.6},{:.6}", s.frame, s.timestamp_ms,
+                    r.x.to_degrees(), r.y.to_degrees(), r.z.to_degrees(),
+                    q.w, q.i, q.j, q.k,
+                    fov_deg(width, f), fov_deg(height, f), f, f / width * SENSOR_WIDTH_MM)?;
+            }
+        }
+    }
+    w.flush()?;
+    progress_cb(1.0);
+    Ok(())
+}
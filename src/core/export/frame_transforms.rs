@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Exports per-frame transformation data used by the undistortion kernels,
+// so the exact warp can be re-applied in other applications.
+//
+// Each frame has `matrix_count` 3x3 matrices (row-major). If rolling shutter correction is enabled,
+// there's one matrix per input row (at processing resolution), otherwise only one.
+// The matrix maps an output pixel to an undistorted camera ray, which is then distorted with the lens model (f, c, k).
+
+use std::io::{ Write, BufWriter };
+use byteorder::{ LittleEndian, WriteBytesExt };
+use serde::ser::{ Serialize, Serializer, SerializeMap, SerializeSeq };
+use super::ExportFormat;
+use crate::stabilization::{ ComputeParams, FrameTransform };
+
+const BINARY_MAGIC: &[u8; 4] = b"GFFT";
+const BINARY_VERSION: u32 = 1;
+
+fn transform_for_frame(params: &ComputeParams, frame: usize) -> (f64, FrameTransform) {
+    let timestamp_ms = crate::timestamp_at_frame(frame as i32, params.scaled_fps);
+
+    let mut transform = FrameTransform::at_timestamp(params, timestamp_ms, frame);
+    transform.kernel_params.width         = params.width as i32;
+    transform.kernel_params.height        = params.height as i32;
+    transform.kernel_params.output_width  = params.output_width as i32;
+    transform.kernel_params.output_height = params.output_height as i32;
+    (timestamp_ms, transform)
+}
+
+fn frame_json(frame: usize, timestamp_ms: f64, t: &FrameTransform) -> serde_json::Value {
+    let kp = t.kernel_params;
+    let (f, c, k) = (kp.f, kp.c, kp.k);
+    let (translation2d, digital_lens_params) = (kp.translation2d, kp.digital_lens_params);
+    serde_json::json!({
+        "frame":        frame,
+        "timestamp_ms": timestamp_ms,
+        "fov":          t.fov,
+        "focal_length": t.focal_length,
+        "lens": {
+            "f": f,
+            "c": c,
+            "k": k,
+            "fov":                      kp.fov,
+            "r_limit":                  kp.r_limit,
+            "lens_correction_amount":   kp.lens_correction_amount,
+            "input_horizontal_stretch": kp.input_horizontal_stretch,
+            "input_vertical_stretch":   kp.input_vertical_stretch,
+            "translation2d":            translation2d,
+            "digital_lens_params":      digital_lens_params,
+        },
+        "matrices": t.matrices
+    })
+}
+
+fn header_json(params: &ComputeParams) -> serde_json::Value {
+    serde_json::json!({
+        "version":          1,
+        "fps":              params.scaled_fps,
+        "frame_count":      params.frame_count,
+        "size":             [params.width, params.height],
+        "output_size":      [params.output_width, params.output_height],
+        "video_size":       [params.video_width, params.video_height],
+        "frame_readout_time":   params.frame_readout_time,
+        "framebuffer_inverted": params.framebuffer_inverted,
+        "distortion_model": params.distortion_model.id(),
+        "digital_lens":     params.digital_lens.as_ref().map(|x| x.id()),
+    })
+}
+
+// Serializes the frames one by one, so the whole document isn't built in memory
+struct JsonFrames<'a, F: Fn(f64)> {
+    params: &'a ComputeParams,
+    range: std::ops::Range<usize>,
+    progress_cb: &'a F,
+}
+impl<'a, F: Fn(f64)> Serialize for JsonFrames<'a, F> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let total = self.range.len().max(1) as f64;
+        let mut seq = serializer.serialize_seq(Some(self.range.len()))?;
+        for (i, frame) in self.range.clone().enumerate() {
+            let (timestamp_ms, t) = transform_for_frame(self.params, frame);
+            seq.serialize_element(&frame_json(frame, timestamp_ms, &t))?;
+            (self.progress_cb)(i as f64 / total);
+        }
+        seq.end()
+    }
+}
+
+pub fn export<W: Write, F: Fn(f64)>(params: &ComputeParams, format: ExportFormat, trim_range_only: bool, writer: W, progress_cb: F) -> std::io::Result<()> {
+    let mut w = BufWriter::new(writer);
+    let range = super::frame_range(params.frame_count, params.trim_start, params.trim_end, trim_range_only);
+    let total = range.len().max(1) as f64;
+
+    match format {
+        ExportFormat::Json => {
+            // Header fields followed by the streamed "frames" array
+            let mut serializer = serde_json::Serializer::new(&mut w);
+            let mut map = serializer.serialize_map(None)?;
+            if let serde_json::Value::Object(header) = header_json(params) {
+                for (k, v) in &header {
+                    map.serialize_entry(k, v)?;
+                }
+            }
+            map.serialize_entry("frames", &JsonFrames { params, range, progress_cb: &progress_cb })?;
+            map.end()?;
+        },
+        ExportFormat::Csv => {
+            write!(w, "frame,timestamp_ms,row,fov,focal_length,fx,fy,cx,cy")?;
+            for i in 0..12 { write!(w, ",k{}", i)?; }
+            write!(w, ",r_limit,lens_correction_amount,input_horizontal_stretch,input_vertical_stretch,translation_x,translation_y")?;
+            for i in 0..9 { write!(w, ",m{}", i)?; }
+            writeln!(w)?;
+
+            for (i, frame) in range.enumerate() {
+                let (timestamp_ms, t) = transform_for_frame(params, frame);
+                let kp = t.kernel_params;
+                let (f, c, k, translation2d) = (kp.f, kp.c, kp.k, kp.translation2d);
+                let mut common = format!("{:.6},{:.6},{:.6},{:.6},{:.6},{:.6}", t.fov, t.focal_length.unwrap_or_default(), f[0], f[1], c[0], c[1]);
+                for v in k { common.push_str(&format!(",{}", v)); }
+                common.push_str(&format!(",{},{},{},{},{},{}", kp.r_limit, kp.lens_correction_amount, kp.input_horizontal_stretch, kp.input_vertical_stretch, translation2d[0], translation2d[1]));
+
+                for (row, m) in t.matrices.iter().enumerate() {
+                    write!(w, "{},{:.3},{},{}", frame, timestamp_ms, row, common)?;
+                    for v in m { write!(w, ",{}", v)?; }
+                    writeln!(w)?;
+                }
+                progress_cb(i as f64 / total);
+            }
+        },
+        ExportFormat::Binary => {
+            // Header: magic, version, header json length + json, frame count
+            // Frame: frame (u32), timestamp_ms (f64), fov (f64), focal_length (f64, NaN if unknown),
+            //        KernelParams struct (as used by the GPU kernels), matrix count (u32), matrices (f32 * 9 * count)
+            let header = serde_json::to_vec(&header_json(params))?;
+            w.write_all(BINARY_MAGIC)?;
+            w.write_u32::<LittleEndian>(BINARY_VERSION)?;
+            w.write_u32::<LittleEndian>(header.len() as u32)?;
+            w.write_all(&header)?;
+            w.write_u32::<LittleEndian>(range.len() as u32)?;
+
+            for (i, frame) in range.enumerate() {
+                let (timestamp_ms, t) = transform_for_frame(params, frame);
+                w.write_u32::<LittleEndian>(frame as u32)?;
+                w.write_f64::<LittleEndian>(timestamp_ms)?;
+                w.write_f64::<LittleEndian>(t.fov)?;
+                w.write_f64::<LittleEndian>(t.focal_length.unwrap_or(f64::NAN))?;
+                w.write_all(bytemuck::bytes_of(&t.kernel_params))?;
+                w.write_u32::<LittleEndian>(t.matrices.len() as u32)?;
+                for m in &t.matrices {
+                    for v in m { w.write_f32::<LittleEndian>(*v)?; }
+                }
+                progress_cb(i as f64 / total);
+            }
+        }
+    }
+    w.flush()?;
+    progress_cb(1.0);
+    Ok(())
+}
@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Exports the loaded and processed gyro data, so it can be analyzed or used in other tools without decoding the project file.
+//
+// GCSV contains the processed IMU samples (after rotation, low-pass filter and bias), with the orientation already applied,
+// so it can be loaded back to Gyroflow. CSV and binary contain all streams: original samples, processed samples,
+// integrated quaternions and smoothed quaternions. Quaternions are without the sync offsets, in the gyro time base.
+
+use std::io::{ Write, BufWriter };
+use byteorder::{ LittleEndian, WriteBytesExt };
+use crate::gyro_source::{ GyroSource, Quat64, TimeIMU, TimeQuat };
+
+const GRAVITY: f64 = 9.81;
+const BINARY_MAGIC: &[u8; 4] = b"GFGD";
+const BINARY_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GyroDataFormat {
+    Gcsv,
+    Csv,
+    Binary,
+}
+impl GyroDataFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "gcsv"           => Some(Self::Gcsv),
+            "csv"            => Some(Self::Csv),
+            "bin" | "binary" => Some(Self::Binary),
+            _ => None
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Gcsv   => "gcsv",
+            Self::Csv    => "csv",
+            Self::Binary => "bin",
+        }
+    }
+}
+
+/// Quaternion at `timestamp_us`, interpolated between the nearest samples
+fn quat_at(quats: &TimeQuat, timestamp_us: i64) -> Option<Quat64> {
+    let q1 = quats.range(..=timestamp_us).next_back();
+    let q2 = quats.range(timestamp_us..).next();
+    match (q1, q2) {
+        (Some(q1), Some(q2)) if q1.0 != q2.0 => Some(q1.1.slerp(q2.1, (timestamp_us - q1.0) as f64 / (q2.0 - q1.0) as f64)),
+        (Some(q), _) | (_, Some(q)) => Some(*q.1),
+        _ => None
+    }
+}
+
+/// Gyro and accelerometer sample at `timestamp_ms`, interpolated between the nearest samples. None outside of the data
+fn imu_at(imu: &[TimeIMU], timestamp_ms: f64) -> (Option<[f64; 3]>, Option<[f64; 3]>) {
+    let i = imu.partition_point(|x| x.timestamp_ms < timestamp_ms);
+    let (s1, s2) = match (i.checked_sub(1).and_then(|i| imu.get(i)), imu.get(i)) {
+        (_, Some(s2)) if s2.timestamp_ms == timestamp_ms => return (s2.gyro, s2.accl),
+        (Some(s1), Some(s2)) => (s1, s2),
+        _ => return (None, None)
+    };
+    let t = (timestamp_ms - s1.timestamp_ms) / (s2.timestamp_ms - s1.timestamp_ms);
+    let lerp = |a: Option<[f64; 3]>, b: Option<[f64; 3]>| -> Option<[f64; 3]> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some([0, 1, 2].map(|j| a[j] + (b[j] - a[j]) * t)),
+            _ => None
+        }
+    };
+    (lerp(s1.gyro, s2.gyro), lerp(s1.accl, s2.accl))
+}
+
+fn header_json(gyro: &GyroSource) -> serde_json::Value {
+    serde_json::json!({
+        "version":            1,
+        "file_path":          gyro.file_path,
+        "detected_source":    gyro.detected_source,
+        "sample_rate":        gyro.get_sample_rate(),
+        "imu_orientation":    gyro.imu_orientation,
+        "rotation":           gyro.imu_rotation_angles,
+        "acc_rotation":       gyro.acc_rotation_angles,
+        "lpf":                gyro.imu_lpf,
+        "gyro_bias":          gyro.gyro_bias,
+        "integration_method": gyro.integration_method,
+        "units": { "timestamp": "ms", "gyro": "deg/s", "accl": "m/s2" }
+    })
+}
+
+fn write_gcsv<W: Write, F: Fn(f64)>(gyro: &GyroSource, w: &mut W, progress_cb: F) -> std::io::Result<()> {
+    let has_accl = gyro.raw_imu.iter().any(|x| x.accl.is_some());
+    let has_magn = gyro.raw_imu.iter().any(|x| x.magn.is_some());
+
+    writeln!(w, "GYROFLOW IMU LOG")?;
+    writeln!(w, "version,1.3")?;
+    writeln!(w, "id,gyroflow_export")?;
+    // Processed samples are already in the Gyroflow coordinate system
+    writeln!(w, "orientation,XYZ")?;
+    writeln!(w, "note,{}", gyro.detected_source.as_deref().unwrap_or_default().replace(',', " "))?;
+    writeln!(w, "tscale,0.001")?;
+    writeln!(w, "gscale,{}", std::f64::consts::PI / 180.0)?;
+    writeln!(w, "ascale,{}", 1.0 / GRAVITY)?;
+    if has_magn { writeln!(w, "mscale,1")?; }
+    write!(w, "t,gx,gy,gz")?;
+    if has_accl { write!(w, ",ax,ay,az")?; }
+    if has_magn { write!(w, ",mx,my,mz")?; }
+    writeln!(w)?;
+
+    let total = gyro.raw_imu.len().max(1) as f64;
+    for (i, x) in gyro.raw_imu.iter().enumerate() {
+        let g = x.gyro.unwrap_or_default();
+        write!(w, "{:.3},{},{},{}", x.timestamp_ms, g[0], g[1], g[2])?;
+        if has_accl { let a = x.accl.unwrap_or_default(); write!(w, ",{},{},{}", a[0], a[1], a[2])?; }
+        if has_magn { let m = x.magn.unwrap_or_default(); write!(w, ",{},{},{}", m[0], m[1], m[2])?; }
+        writeln!(w)?;
+        if i % 1000 == 0 { progress_cb(i as f64 / total); }
+    }
+    Ok(())
+}
+
+fn write_csv<W: Write, F: Fn(f64)>(gyro: &GyroSource, w: &mut W, progress_cb: F) -> std::io::Result<()> {
+    // Rows follow the IMU samples, or the quaternions if the file has only quaternions
+    let timestamps: Vec<f64> = if !gyro.raw_imu.is_empty() {
+        gyro.raw_imu.iter().map(|x| x.timestamp_ms).collect()
+    } else {
+        gyro.quaternions.keys().map(|x| *x as f64 / 1000.0).collect()
+    };
+    let has_magn = gyro.raw_imu.iter().any(|x| x.magn.is_some());
+
+    write!(w, "timestamp_ms,org_gx,org_gy,org_gz,org_ax,org_ay,org_az,gx,gy,gz,ax,ay,az")?;
+    if has_magn { write!(w, ",mx,my,mz")?; }
+    writeln!(w, ",qw,qx,qy,qz,smoothed_qw,smoothed_qx,smoothed_qy,smoothed_qz")?;
+
+    let vec3 = |v: Option<[f64; 3]>| -> String { v.map(|v| format!("{},{},{}", v[0], v[1], v[2])).unwrap_or_else(|| ",,".into()) };
+    let quat = |q: Option<Quat64>| -> String { q.map(|q| format!("{},{},{},{}", q.w, q.i, q.j, q.k)).unwrap_or_else(|| ",,,".into()) };
+
+    let total = timestamps.len().max(1) as f64;
+    for (i, ts) in timestamps.iter().enumerate() {
+        // Original samples are matched by timestamp, because resampling changes the sample count and timing
+        let (org_gyro, org_accl) = imu_at(&gyro.org_raw_imu, *ts);
+        let imu = gyro.raw_imu.get(i);
+        let ts_us = (ts * 1000.0).round() as i64;
+
+        write!(w, "{:.3},{},{},{},{}", ts,
+            vec3(org_gyro), vec3(org_accl),
+            vec3(imu.and_then(|x| x.gyro)), vec3(imu.and_then(|x| x.accl))
+        )?;
+        if has_magn { write!(w, ",{}", vec3(imu.and_then(|x| x.magn)))?; }
+        writeln!(w, ",{},{}", quat(quat_at(&gyro.quaternions, ts_us)), quat(quat_at(&gyro.smoothed_quaternions, ts_us)))?;
+
+        if i % 1000 == 0 { progress_cb(i as f64 / total); }
+    }
+    Ok(())
+}
+
+fn write_binary<W: Write>(gyro: &GyroSource, w: &mut W) -> std::io::Result<()> {
+    // Header: magic, version, header json length + json
+    // Then 4 sections, each with sample count (u32) and samples:
+    //   original IMU, processed IMU: timestamp_ms (f64), gyro, accl, magn (3 * f64 each, NaN if missing)
+    //   integrated quaternions, smoothed quaternions: timestamp_us (i64), w, x, y, z (f64)
+    fn write_imu<W: Write>(w: &mut W, imu: &[TimeIMU]) -> std::io::Result<()> {
+        w.write_u32::<LittleEndian>(imu.len() as u32)?;
+        for x in imu {
+            w.write_f64::<LittleEndian>(x.timestamp_ms)?;
+            for v in [x.gyro, x.accl, x.magn] {
+                for v in v.unwrap_or([f64::NAN; 3]) { w.write_f64::<LittleEndian>(v)?; }
+            }
+        }
+        Ok(())
+    }
+    fn write_quats<W: Write>(w: &mut W, quats: &TimeQuat) -> std::io::Result<()> {
+        w.write_u32::<LittleEndian>(quats.len() as u32)?;
+        for (ts, q) in quats {
+            w.write_i64::<LittleEndian>(*ts)?;
+            for v in [q.w, q.i, q.j, q.k] { w.write_f64::<LittleEndian>(v)?; }
+        }
+        Ok(())
+    }
+
+    let header = serde_json::to_vec(&header_json(gyro))?;
+    w.write_all(BINARY_MAGIC)?;
+    w.write_u32::<LittleEndian>(BINARY_VERSION)?;
+    w.write_u32::<LittleEndian>(header.len() as u32)?;
+    w.write_all(&header)?;
+
+    write_imu(w, &gyro.org_raw_imu)?;
+    write_imu(w, &gyro.raw_imu)?;
+    write_quats(w, &gyro.quaternions)?;
+    write_quats(w, &gyro.smoothed_quaternions)
+}
+
+pub fn export<W: Write, F: Fn(f64)>(gyro: &GyroSource, format: GyroDataFormat, writer: W, progress_cb: F) -> std::io::Result<()> {
+    if gyro.raw_imu.is_empty() && gyro.quaternions.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No gyro data loaded"));
+    }
+    if format == GyroDataFormat::Gcsv && gyro.raw_imu.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "GCSV requires raw IMU samples, but the file contains only quaternions"));
+    }
+
+    let mut w = BufWriter::new(writer);
+    match format {
+        GyroDataFormat::Gcsv   => write_gcsv(gyro, &mut w, &progress_cb)?,
+        GyroDataFormat::Csv    => write_csv(gyro, &mut w, &progress_cb)?,
+        GyroDataFormat::Binary => write_binary(gyro, &mut w)?,
+    }
+    w.flush()?;
+    progress_cb(1.0);
+    Ok(())
+}
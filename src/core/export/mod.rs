@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+pub mod frame_transforms;
+pub mod stmap;
+pub mod camera_motion;
+pub mod gyro_data;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    Binary,
+}
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "json"                 => Some(Self::Json),
+            "csv"                  => Some(Self::Csv),
+            "bin" | "binary"       => Some(Self::Binary),
+            _ => None
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Json   => "json",
+            Self::Csv    => "csv",
+            Self::Binary => "bin",
+        }
+    }
+}
+
+/// Range of frames to export, taking trim range into account
+pub fn frame_range(frame_count: usize, trim_start: f64, trim_end: f64, trim_range_only: bool) -> std::ops::Range<usize> {
+    if trim_range_only {
+        let start = (trim_start * frame_count as f64).floor() as usize;
+        let end = (trim_end * frame_count as f64).ceil() as usize;
+        start.min(frame_count)..end.min(frame_count)
+    } else {
+        0..frame_count
+    }
+}
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Exports ST-maps (UV maps) as a 32-bit float EXR sequence, one file per frame.
+// Each output pixel stores the normalized source position it samples from, so the stabilization and lens correction
+// can be applied on the original plate with the STMap node in Nuke, Fusion or Resolve.
+//
+// Red = x / width, green = 1 - y / height (bottom-left origin, as expected by the STMap nodes),
+// alpha = 0 where the output pixel doesn't map to the source image.
+
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, atomic::{ AtomicBool, Ordering::SeqCst } };
+use rayon::prelude::*;
+use crate::stabilization::{ ComputeParams, FrameTransform, KernelParamsFlags, undistort_coord };
+
+/// File path for a single frame, eg. `clip.stmap` -> `clip.stmap.000123.exr`
+pub fn frame_path(base_path: &Path, frame: usize) -> PathBuf {
+    let mut name = base_path.file_name().map(|x| x.to_os_string()).unwrap_or_default();
+    name.push(format!(".{:06}.exr", frame));
+    base_path.with_file_name(name)
+}
+
+/// Computes the ST-map of a single frame as RGBA values, row by row
+pub fn compute_frame(params: &ComputeParams, frame: usize) -> Vec<[f32; 4]> {
+    let timestamp_ms = crate::timestamp_at_frame(frame as i32, params.scaled_fps);
+
+    let mut transform = FrameTransform::at_timestamp(params, timestamp_ms, frame);
+    transform.kernel_params.width         = params.width as i32;
+    transform.kernel_params.height        = params.height as i32;
+    transform.kernel_params.output_width  = params.output_width as i32;
+    transform.kernel_params.output_height = params.output_height as i32;
+    transform.kernel_params.source_rect   = [0, 0, params.width as i32, params.height as i32];
+    transform.kernel_params.output_rect   = [0, 0, params.output_width as i32, params.output_height as i32];
+    if params.digital_lens.is_some() {
+        transform.kernel_params.flags = KernelParamsFlags::HAS_DIGITAL_LENS.bits();
+    }
+
+    let kernel_params = transform.kernel_params;
+    let (width, height) = (params.width as f32, params.height as f32);
+
+    let mut map = vec![[0.0f32; 4]; params.output_width * params.output_height];
+    map.par_chunks_mut(params.output_width.max(1)).enumerate().for_each(|(y, row)| {
+        for (x, px) in row.iter_mut().enumerate() {
+            if let Some(uv) = undistort_coord((x as f32, y as f32), y, &kernel_params, &transform.matrices, &params.distortion_model, params.digital_lens.as_ref()) {
+                // Integer coordinates in the kernel are pixel centers, STMap expects them at +0.5
+                let inside = (0.0..width).contains(&uv.0) && (0.0..height).contains(&uv.1);
+                *px = [(uv.0 + 0.5) / width, 1.0 - (uv.1 + 0.5) / height, 0.0, if inside { 1.0 } else { 0.0 }];
+            }
+        }
+    });
+    map
+}
+
+/// Writes the EXR sequence and returns the number of written frames
+pub fn export<F: Fn(f64)>(params: &ComputeParams, base_path: &Path, trim_range_only: bool, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> std::io::Result<usize> {
+    let range = super::frame_range(params.frame_count, params.trim_start, params.trim_end, trim_range_only);
+    let total = range.len().max(1) as f64;
+    let width = params.output_width;
+
+    let mut written = 0;
+    for (i, frame) in range.enumerate() {
+        if cancel_flag.load(SeqCst) { break; }
+
+        let map = compute_frame(params, frame);
+        exr::prelude::write_rgba_file(frame_path(base_path, frame), params.output_width, params.output_height, |x, y| {
+            let px = map[y * width + x];
+            (px[0], px[1], px[2], px[3])
+        }).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to write EXR: {}", e)))?;
+
+        written += 1;
+        progress_cb(i as f64 / total);
+    }
+    progress_cb(1.0);
+    Ok(written)
+}
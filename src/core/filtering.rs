@@ -2,8 +2,11 @@
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
 use biquad::{Biquad, Coefficients, Type, DirectForm2Transposed, ToHertz};
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
 
 use super::gyro_source::TimeIMU;
+use super::spectral_analysis;
 
 pub struct Lowpass {
     filters: [DirectForm2Transposed<f64>; 6]
@@ -72,3 +75,216 @@ impl Lowpass {
         Ok(())
     }
 }
+
+// ----------- Notch filters -----------
+// Narrow-band vibration (eg. drone propellers) is usually well above the camera motion frequencies,
+// so removing just that band keeps the real motion, unlike a low-pass strong enough to remove it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct NotchFilter {
+    /// Center frequency in Hz
+    pub frequency: f64,
+    /// Quality factor, higher is narrower
+    pub q: f64,
+}
+
+/// Notch following the dominant frequency in the band over time
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct DynamicNotchSettings {
+    pub min_frequency: f64,
+    pub max_frequency: f64,
+    pub q: f64,
+    /// Length of the FFT analysis window in ms
+    pub window_ms: f64,
+    /// Minimum ratio of the peak to the median magnitude in the band to consider it vibration
+    pub min_prominence: f64,
+}
+impl Default for DynamicNotchSettings {
+    fn default() -> Self {
+        Self {
+            min_frequency: 60.0,
+            max_frequency: 400.0,
+            q: 3.0,
+            window_ms: 250.0,
+            min_prominence: 4.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct AxisNotchSettings {
+    pub notches: Vec<NotchFilter>,
+    pub dynamic: Option<DynamicNotchSettings>,
+}
+impl AxisNotchSettings {
+    pub fn is_empty(&self) -> bool {
+        self.notches.is_empty() && self.dynamic.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct NotchSettings {
+    /// Gyro X, Y and Z
+    pub axes: [AxisNotchSettings; 3],
+    /// Apply the same filters to the accelerometer axes
+    pub filter_accl: bool,
+}
+impl NotchSettings {
+    pub fn uniform(axis: AxisNotchSettings, filter_accl: bool) -> Self {
+        Self { axes: [axis.clone(), axis.clone(), axis], filter_accl }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.axes.iter().all(|x| x.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct NotchReport {
+    /// Dominant vibration frequency of each gyro axis as (timestamp_ms, frequency), `None` where there's no prominent peak
+    pub tracks: [Vec<(f64, Option<f64>)>; 3],
+}
+impl NotchReport {
+    pub fn log(&self) {
+        for (axis, track) in self.tracks.iter().enumerate() {
+            if track.is_empty() { continue; }
+            let mut freqs: Vec<f64> = track.iter().filter_map(|x| x.1).collect();
+            if freqs.is_empty() {
+                ::log::info!("Dynamic notch axis {}: no vibration detected", axis);
+                continue;
+            }
+            freqs.sort_by(|a, b| a.total_cmp(b));
+            ::log::info!("Dynamic notch axis {}: vibration in {:.1}% of the windows, median {:.1} Hz, range {:.1} - {:.1} Hz", axis,
+                freqs.len() as f64 * 100.0 / track.len() as f64, freqs[freqs.len() / 2], freqs[0], freqs[freqs.len() - 1]);
+        }
+    }
+}
+
+fn channel(x: &mut TimeIMU, ch: usize) -> Option<&mut f64> {
+    let v = if ch < 3 { x.gyro.as_mut() } else { x.accl.as_mut() };
+    v.map(|v| &mut v[ch % 3])
+}
+
+/// Zero-phase filtering of one channel with per-sample coefficients and the amount of the filtered signal in the output
+fn run_forward_backward(data: &mut [TimeIMU], ch: usize, coeffs: &[(Coefficients<f64>, f64)]) {
+    if coeffs.is_empty() { return; }
+    let mut filter = DirectForm2Transposed::<f64>::new(coeffs[0].0);
+    for (x, (c, mix)) in data.iter_mut().zip(coeffs) {
+        if let Some(v) = channel(x, ch) {
+            filter.update_coefficients(*c);
+            let y = filter.run(*v);
+            *v += (y - *v) * mix;
+        }
+    }
+    let mut filter = DirectForm2Transposed::<f64>::new(coeffs[coeffs.len() - 1].0);
+    for (x, (c, mix)) in data.iter_mut().zip(coeffs).rev() {
+        if let Some(v) = channel(x, ch) {
+            filter.update_coefficients(*c);
+            let y = filter.run(*v);
+            *v += (y - *v) * mix;
+        }
+    }
+}
+
+/// Dominant frequency in the `min_frequency..max_frequency` band for each analysis window, as (sample index of the window center, frequency).
+/// Uses the same spectrum, noise floor and peak detection as the spectral analysis. Assumes uniform sample rate
+pub fn track_dominant_frequency(data: &[TimeIMU], ch: usize, sample_rate: f64, settings: &DynamicNotchSettings) -> Vec<(usize, Option<f64>)> {
+    let size = ((settings.window_ms * sample_rate / 1000.0).round() as usize).max(16).next_power_of_two();
+    if data.len() < size || sample_rate <= 0.0 { return Vec::new(); }
+    let hop = size / 4;
+    let resolution = sample_rate / size as f64;
+    let max_frequency = settings.max_frequency.min(sample_rate / 2.0 * 0.95);
+    let min_bin = ((settings.min_frequency / resolution).ceil() as usize).max(1);
+    // Keep one bin above the band, the peak detection needs both neighbors
+    let max_bin = ((max_frequency / resolution).floor() as usize).min(size / 2 - 2);
+    if min_bin + 2 > max_bin { return Vec::new(); }
+
+    let values: Vec<f64> = data.iter().map(|x| {
+        let v = if ch < 3 { x.gyro } else { x.accl };
+        v.map(|v| v[ch % 3]).unwrap_or_default()
+    }).collect();
+
+    let mut ret = Vec::with_capacity(data.len() / hop + 1);
+    let mut start = 0;
+    while start + size <= values.len() {
+        let chunk = &values[start..start + size];
+        let mean = chunk.iter().sum::<f64>() / size as f64;
+        let centered: Vec<f64> = chunk.iter().map(|x| x - mean).collect();
+        let spec = spectral_analysis::spectrum(&centered);
+
+        // Noise floor and peaks only within the band
+        let floor = spectral_analysis::noise_floor(&spec[..=max_bin], min_bin);
+        let freq = spectral_analysis::find_peaks(&spec[..=max_bin + 1], resolution, min_bin, floor, settings.min_prominence, 1).first().map(|x| x.frequency);
+
+        ret.push((start + size / 2, freq));
+        start += hop;
+    }
+    ret
+}
+
+/// Per-sample frequency and amount of the filter from the tracked frequencies.
+/// Frequency is held outside of the detected parts so the filter state stays continuous, and the filter is faded in and out there
+fn dynamic_coefficients(track: &[(usize, Option<f64>)], len: usize, sample_rate: f64, q: f64) -> Option<Vec<(Coefficients<f64>, f64)>> {
+    let first = track.iter().find_map(|x| x.1)?;
+    let mut last = first;
+    let held: Vec<(usize, f64, f64)> = track.iter().map(|(i, f)| {
+        if let Some(f) = f { last = *f; }
+        (*i, last, if f.is_some() { 1.0 } else { 0.0 })
+    }).collect();
+
+    let mut ret = Vec::with_capacity(len);
+    let mut j = 0;
+    for i in 0..len {
+        while j + 1 < held.len() && held[j + 1].0 <= i { j += 1; }
+        let (freq, mix) = match (held.get(j), held.get(j + 1)) {
+            (Some(a), Some(b)) if i >= a.0 => {
+                let t = (i - a.0) as f64 / (b.0 - a.0) as f64;
+                (a.1 + (b.1 - a.1) * t, a.2 + (b.2 - a.2) * t)
+            },
+            (Some(a), _) => (a.1, a.2),
+            _ => return None
+        };
+        let coeffs = Coefficients::<f64>::from_params(Type::Notch, sample_rate.hz(), freq.hz(), q).ok()?;
+        ret.push((coeffs, mix));
+    }
+    Some(ret)
+}
+
+/// Applies the static and dynamic notch filters. Assumes uniform sample rate
+pub fn filter_notch(settings: &NotchSettings, sample_rate: f64, data: &mut [TimeIMU]) -> Result<NotchReport, biquad::Errors> {
+    let mut report = NotchReport::default();
+    if settings.is_empty() || data.is_empty() { return Ok(report); }
+
+    // Validate all filters before touching the data, so an invalid one doesn't leave some axes filtered and others not
+    let static_coeffs = settings.axes.iter().map(|axis_settings| {
+        axis_settings.notches.iter().map(|notch| {
+            if notch.q <= 0.0 { return Err(biquad::Errors::NegativeQ); }
+            if notch.frequency * 2.0 >= sample_rate { return Err(biquad::Errors::OutsideNyquist); }
+            Coefficients::<f64>::from_params(Type::Notch, sample_rate.hz(), notch.frequency.hz(), notch.q)
+        }).collect::<Result<Vec<_>, _>>()
+    }).collect::<Result<Vec<_>, _>>()?;
+    if settings.axes.iter().filter_map(|x| x.dynamic.as_ref()).any(|x| x.q <= 0.0) {
+        return Err(biquad::Errors::NegativeQ);
+    }
+
+    for (axis, (axis_settings, static_coeffs)) in settings.axes.iter().zip(static_coeffs).enumerate() {
+        let channels = if settings.filter_accl { vec![axis, axis + 3] } else { vec![axis] };
+        for ch in channels {
+            for coeffs in &static_coeffs {
+                run_forward_backward(data, ch, &vec![(*coeffs, 1.0); data.len()]);
+            }
+            if let Some(dynamic) = &axis_settings.dynamic {
+                let track = track_dominant_frequency(data, ch, sample_rate, dynamic);
+                if let Some(coeffs) = dynamic_coefficients(&track, data.len(), sample_rate, dynamic.q) {
+                    run_forward_backward(data, ch, &coeffs);
+                }
+                if ch < 3 {
+                    report.tracks[ch] = track.into_iter().map(|(i, f)| (data[i].timestamp_ms, f)).collect();
+                }
+            }
+        }
+    }
+    Ok(report)
+}
@@ -17,6 +17,11 @@ use super::imu_integration::*;
 use super::smoothing::SmoothingAlgorithm;
 use std::io::Result;
 use crate::StabilizationParams;
+use crate::synchronization::drift::{ self, DriftFit, DriftModelSettings };
+use crate::synchronization::timestamp_sync::{ self, AbsoluteTime };
+use crate::imu_resampling::{ ImuQualityReport, ResampleSettings };
+use crate::saturation::{ SaturationReport, SaturationSettings };
+use crate::filtering::{ NotchReport, NotchSettings };
 
 pub type Quat64 = UnitQuaternion<f64>;
 pub type TimeIMU = telemetry_parser::util::IMUData;
@@ -37,12 +42,14 @@ pub struct FileMetadata {
     pub camera_identifier: Option<CameraIdentifier>,
     pub lens_profile: Option<serde_json::Value>,
     pub lens_positions: Option<TimeFloat>,
+    pub start_time: Option<AbsoluteTime>,
     pub usable_logs: Vec<String>
 }
 
 #[derive(Default, Clone)]
 pub struct FileLoadOptions {
-    pub sample_index: Option<usize>
+    pub sample_index: Option<usize>,
+    pub csv_mapping: Option<String>, // path to the column mapping descriptor, see `csv_imu`
 }
 
 #[derive(Default, Clone)]
@@ -64,8 +71,18 @@ pub struct GyroSource {
     pub acc_rotation_angles: Option<[f64; 3]>,
     pub acc_rotation: Option<Rotation3<f64>>,
     pub imu_lpf: f64,
+    pub imu_notch: Option<NotchSettings>,
+    pub notch_report: NotchReport,
+
+    pub imu_resampling: Option<ResampleSettings>,
+    pub imu_quality: ImuQualityReport,
+
+    pub saturation: Option<SaturationSettings>,
+    pub saturation_report: SaturationReport,
+    pub saturation_reference: Option<Vec<TimeIMU>>, // gyro estimated from the video, in raw sensor axes and gyro time
 
     pub gyro_bias: Option<[f64; 3]>,
+    pub gyro_bias_curve: Option<Vec<(f64, [f64; 3])>>, // <timestamp_ms, bias> - time-varying bias, used instead of `gyro_bias` if set
 
     pub integration_method: usize,
 
@@ -82,6 +99,8 @@ pub struct GyroSource {
 
     pub lens_positions: Option<TimeFloat>,
 
+    pub start_time: Option<AbsoluteTime>, // absolute time of the log, used for the initial offset from timestamps
+
     pub max_angles: (f64, f64, f64), // (pitch, yaw, roll) in deg
 
     pub smoothing_status: serde_json::Value,
@@ -92,6 +111,9 @@ pub struct GyroSource {
     offsets_linear: BTreeMap<i64, f64>, // <microseconds timestamp, offset in milliseconds> - linear fit
     offsets_adjusted: BTreeMap<i64, f64>, // <timestamp + offset, offset>
 
+    pub drift_model: Option<DriftModelSettings>, // if set, offsets follow the fitted clock drift model instead of the raw sync points
+    drift_fit: Option<DriftFit>,
+
     pub file_path: String
 }
 
@@ -115,6 +137,12 @@ impl GyroSource {
         self.duration_ms = stabilization_params.get_scaled_duration_ms();
     }
     pub fn parse_telemetry_file<F: Fn(f64)>(path: &str, options: &FileLoadOptions, size: (usize, usize), fps: f64, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Result<FileMetadata> {
+        // Custom CSV logs which are not supported by telemetry-parser
+        let csv_mapping = options.csv_mapping.as_ref().map(std::path::PathBuf::from).or_else(|| crate::csv_imu::CsvImuDescriptor::find_for(path));
+        if let Some(csv_mapping) = csv_mapping {
+            return crate::csv_imu::parse_file(path, &csv_mapping);
+        }
+
         let mut stream = File::open(path)?;
         let filesize = stream.metadata()?.len() as usize;
 
@@ -133,6 +161,7 @@ impl GyroSource {
         let mut frame_rate = None;
         let mut lens_positions = None;
         let mut usable_logs = Vec::new();
+        let mut log_index = options.sample_index;
 
         if input.camera_type() == "BlackBox" {
             if let Some(ref mut samples) = input.samples {
@@ -144,6 +173,8 @@ impl GyroSource {
                 }
                 if let Some(requested_index) = options.sample_index {
                     samples.retain(|x| x.sample_index as usize == requested_index);
+                } else if usable_logs.len() == 1 {
+                    log_index = samples.iter().find(|x| x.tag_map.is_some() && x.duration_ms > 0.0).map(|x| x.sample_index as usize);
                 }
             }
         }
@@ -260,6 +291,13 @@ impl GyroSource {
 
         let raw_imu = util::normalized_imu_interpolated(&input, Some("XYZ".into())).ok();
 
+        // With multiple blackbox logs the absolute time is known only for the selected one
+        let start_time = if input.camera_type() != "BlackBox" || log_index.is_some() {
+            timestamp_sync::log_start_time(path, &input.camera_type(), log_index)
+        } else {
+            None
+        };
+
         Ok(FileMetadata {
             imu_orientation,
             detected_source: Some(detected_source),
@@ -272,6 +310,7 @@ impl GyroSource {
             frame_rate,
             lens_profile,
             camera_identifier,
+            start_time,
             usable_logs
         })
     }
@@ -314,10 +353,12 @@ impl GyroSource {
 
         self.gravity_vectors = telemetry.gravity_vectors.clone();
         self.lens_positions = telemetry.lens_positions.clone();
+        self.start_time = telemetry.start_time;
 
         if let Some(imu) = &telemetry.raw_imu {
             self.org_raw_imu = imu.clone();
             self.apply_transforms();
+            self.imu_quality.log();
         } else if self.quaternions.is_empty() {
             self.integrate();
         }
@@ -336,6 +377,9 @@ impl GyroSource {
             4 => self.quaternions = SimpleGyroAccelIntegrator::integrate(&self.raw_imu, self.duration_ms),
             5 => self.quaternions = MahonyIntegrator::integrate(&self.raw_imu, self.duration_ms),
             6 => self.quaternions = MadgwickIntegrator::integrate(&self.raw_imu, self.duration_ms),
+            7 => self.quaternions = ComplementaryMagIntegrator::integrate(&self.raw_imu, self.duration_ms),
+            8 => self.quaternions = VQFMagIntegrator::integrate(&self.raw_imu, self.duration_ms),
+            9 => self.quaternions = EskfIntegrator::integrate(&self.raw_imu, self.duration_ms),
             _ => log::error!("Unknown integrator")
         }
     }
@@ -376,6 +420,15 @@ impl GyroSource {
     pub fn clear_offsets(&mut self) {
         self.offsets.clear();
         self.offsets_adjusted.clear();
+        self.drift_fit = None;
+    }
+    pub fn set_drift_model(&mut self, model: Option<DriftModelSettings>) {
+        self.drift_model = model;
+        self.adjust_offsets();
+    }
+    /// Residuals and outliers of the clock drift model, if it's enabled and fitted
+    pub fn get_drift_fit(&self) -> Option<&DriftFit> {
+        self.drift_fit.as_ref()
     }
     pub fn get_offsets(&self) -> &BTreeMap<i64, f64> {
         &self.offsets
@@ -412,6 +465,26 @@ impl GyroSource {
 
     pub fn adjust_offsets(&mut self) {
         if self.prevent_recompute { return; }
+        self.drift_fit = None;
+        if let Some(settings) = self.drift_model {
+            if self.offsets.len() > 1 {
+                if let Some(fit) = drift::fit(&self.offsets, &settings) {
+                    self.offsets_linear = self.offsets.keys().map(|k| (*k, fit.model.evaluate(*k as f64 / 1000.0))).collect();
+
+                    // Use the model instead of the measured values, and extend it to the whole video so the drift is extrapolated
+                    let mut keys: Vec<i64> = self.offsets.keys().copied().collect();
+                    keys.push(0);
+                    keys.push((self.duration_ms * 1000.0).round() as i64);
+                    self.offsets_adjusted = keys.into_iter().map(|k| {
+                        let v = fit.model.evaluate(k as f64 / 1000.0);
+                        (k + (v * 1000.0).round() as i64, v)
+                    }).collect();
+
+                    self.drift_fit = Some(fit);
+                    return;
+                }
+            }
+        }
         // Calculate line fit
         if self.offsets.len() > 1 {
             let len = self.offsets.len();
@@ -489,14 +562,48 @@ impl GyroSource {
     }
 
     pub fn apply_transforms(&mut self) {
-        self.raw_imu = self.org_raw_imu.clone();
-        if self.imu_lpf > 0.0 && !self.org_raw_imu.is_empty() && self.duration_ms > 0.0 {
-            let sample_rate = self.org_raw_imu.len() as f64 / (self.duration_ms / 1000.0);
+        let (resampled, quality) = super::imu_resampling::process(&self.org_raw_imu, self.imu_resampling.as_ref());
+        self.raw_imu = resampled.unwrap_or_else(|| self.org_raw_imu.clone());
+        self.imu_quality = quality;
+
+        self.saturation_report = match &self.saturation {
+            Some(settings) => super::saturation::reconstruct(&mut self.raw_imu, settings, self.saturation_reference.as_deref()),
+            None => SaturationReport::default()
+        };
+
+        if self.imu_lpf > 0.0 && !self.raw_imu.is_empty() && self.duration_ms > 0.0 {
+            let sample_rate = self.raw_imu.len() as f64 / (self.duration_ms / 1000.0);
             if let Err(e) = super::filtering::Lowpass::filter_gyro_forward_backward(self.imu_lpf, sample_rate, &mut self.raw_imu) {
                 log::error!("Filter error {:?}", e);
             }
         }
-        if let Some(bias) = self.gyro_bias {
+        self.notch_report = NotchReport::default();
+        if let Some(notch) = self.imu_notch.as_ref().filter(|x| !x.is_empty()) {
+            // The notch frequencies are absolute, so use the actual rate of the samples, not the video duration
+            let span_ms = match (self.raw_imu.first(), self.raw_imu.last()) {
+                (Some(first), Some(last)) => last.timestamp_ms - first.timestamp_ms,
+                _ => 0.0
+            };
+            if self.raw_imu.len() > 1 && span_ms > 0.0 {
+                let sample_rate = (self.raw_imu.len() - 1) as f64 / (span_ms / 1000.0);
+                match super::filtering::filter_notch(notch, sample_rate, &mut self.raw_imu) {
+                    Ok(report) => self.notch_report = report,
+                    Err(e) => log::error!("Notch filter error {:?}", e)
+                }
+            }
+        }
+        if let Some(curve) = self.gyro_bias_curve.as_ref().filter(|x| !x.is_empty()) {
+            for x in &mut self.raw_imu {
+                if let Some(g) = x.gyro.as_mut() {
+                    let bias = super::bias_estimation::bias_at_timestamp(curve, x.timestamp_ms);
+                    *g = [
+                        g[0] + bias[0],
+                        g[1] + bias[1],
+                        g[2] + bias[2]
+                    ];
+                }
+            }
+        } else if let Some(bias) = self.gyro_bias {
             for x in &mut self.raw_imu {
                 if let Some(g) = x.gyro.as_mut() {
                     *g = [
@@ -675,4 +782,64 @@ impl GyroSource {
 
         (bias_vals[0], bias_vals[1], bias_vals[2])
     }
+
+    /// Sets the gyro estimated from the video (`PoseEstimator::estimated_gyro`) as the reference for the saturation reconstruction.
+    /// The estimated gyro is in the final camera axes and video time, so it's converted back to the raw sensor axes and gyro time
+    pub fn set_saturation_reference(&mut self, estimated_gyro: &BTreeMap<i64, TimeIMU>) {
+        let inv_rotation = self.imu_rotation.map(|x| x.inverse());
+        let orientation = self.imu_orientation.clone().unwrap_or_else(|| "XYZ".into());
+        let unorient = |v: [f64; 3]| -> [f64; 3] {
+            let mut ret = [0.0; 3];
+            for (i, o) in orientation.bytes().take(3).enumerate() {
+                let idx = (o.to_ascii_uppercase().saturating_sub(b'X') as usize).min(2);
+                ret[idx] = if o.is_ascii_uppercase() { v[i] } else { -v[i] };
+            }
+            ret
+        };
+        let reference: Vec<TimeIMU> = estimated_gyro.values().filter_map(|x| {
+            let mut g = Vector3::from(x.gyro?);
+            if let Some(rot) = inv_rotation { g = rot * g; }
+            Some(TimeIMU {
+                timestamp_ms: x.timestamp_ms - self.offset_at_video_timestamp(x.timestamp_ms),
+                gyro: Some(unorient([g[0], g[1], g[2]])),
+                accl: None,
+                magn: None
+            })
+        }).collect();
+        self.saturation_reference = if reference.is_empty() { None } else { Some(reference) };
+    }
+
+    /// Video frames affected by the gyro saturation
+    pub fn saturated_frames(&self) -> Vec<usize> {
+        if self.fps <= 0.0 { return Vec::new(); }
+        let mut frames: Vec<usize> = self.saturation_report.segments.iter().flat_map(|s| {
+            let start = s.start_ms + self.offset_at_gyro_timestamp(s.start_ms);
+            let end = s.end_ms + self.offset_at_gyro_timestamp(s.end_ms);
+            let first = (start * self.fps / 1000.0).floor().max(0.0) as usize;
+            let last = (end * self.fps / 1000.0).ceil().max(0.0) as usize;
+            first..=last
+        }).collect();
+        frames.sort_unstable();
+        frames.dedup();
+        frames
+    }
+
+    /// Estimates the bias from the static segments of the whole log and applies it.
+    /// With `time_varying`, the bias is interpolated between the segments, to compensate for the drift
+    pub fn estimate_bias_auto(&mut self, params: &super::bias_estimation::BiasEstimationParams, time_varying: bool) -> Vec<super::bias_estimation::StaticSegment> {
+        use super::bias_estimation::*;
+        let segments = find_static_segments(&self.org_raw_imu, params);
+        log::info!("Found {} static segments for the bias estimation", segments.len());
+
+        if let Some(bias) = constant_bias(&segments) {
+            self.gyro_bias = Some(bias);
+            self.gyro_bias_curve = if time_varying && segments.len() > 1 {
+                Some(segments.iter().map(|x| (x.center_ms(), x.bias)).collect())
+            } else {
+                None
+            };
+            self.apply_transforms();
+        }
+        segments
+    }
 }
@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Error-state Kalman filter with gyro bias estimation, followed by the Rauch-Tung-Striebel smoother.
+// Nominal state is the orientation (body to world, z up) and the gyro bias, the error state is
+// the small rotation and the bias error. The accelerometer direction corrects the tilt.
+// Since the whole log is available, the backward pass uses the future samples as well,
+// which removes the delay and the initial convergence of the forward filter.
+
+use nalgebra::{ Matrix3, Matrix6, Vector3, Matrix3x6, SMatrix, UnitQuaternion };
+use super::{ TimeIMU, TimeQuat, DEG2RAD };
+
+type Vector6 = SMatrix<f64, 6, 1>;
+
+// Gyro noise density in rad/s/sqrt(Hz)
+const GYRO_NOISE: f64 = 0.02 * DEG2RAD;
+// Gyro bias random walk in rad/s/sqrt(s)
+const BIAS_RANDOM_WALK: f64 = 0.002 * DEG2RAD;
+const INITIAL_BIAS_STD: f64 = 2.0 * DEG2RAD;
+// Noise of the normalized accelerometer direction
+const ACCL_NOISE: f64 = 0.05;
+// Accelerometer is ignored if its magnitude differs from 1g by more than this fraction
+const ACCL_MAX_DEVIATION: f64 = 0.2;
+
+fn skew(v: &Vector3<f64>) -> Matrix3<f64> {
+    Matrix3::new(
+         0.0, -v.z,  v.y,
+         v.z,  0.0, -v.x,
+        -v.y,  v.x,  0.0
+    )
+}
+
+struct Step {
+    timestamp_us: i64,
+    /// Filtered (a posteriori) state
+    q: UnitQuaternion<f64>,
+    bias: Vector3<f64>,
+    p: Matrix6<f64>,
+    /// Transition from the previous step and the predicted (a priori) state and covariance
+    f: Matrix6<f64>,
+    q_pred: UnitQuaternion<f64>,
+    bias_pred: Vector3<f64>,
+    p_pred: Matrix6<f64>,
+}
+
+pub fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
+    let mut quats = TimeQuat::new();
+    if imu_data.is_empty() { return quats; }
+
+    let sample_time_ms = duration_ms / imu_data.len() as f64;
+    let body = |v: [f64; 3]| Vector3::new(-v[1], v[0], v[2]);
+    let one_g = crate::bias_estimation::median_accl_magnitude(imu_data);
+
+    // Initial orientation from the first valid accelerometer reading
+    let first_acc = imu_data.iter().filter_map(|x| x.accl).map(body).find(|x| x.norm() > 0.0);
+    let mut q = first_acc.and_then(|a| UnitQuaternion::rotation_between(&a, &Vector3::z())).unwrap_or_else(UnitQuaternion::identity);
+    let mut bias = Vector3::zeros();
+    let mut p = Matrix6::identity() * 1e-4;
+    for i in 3..6 { p[(i, i)] = INITIAL_BIAS_STD.powi(2); }
+
+    let mut steps: Vec<Step> = Vec::with_capacity(imu_data.len());
+    let mut prev_time = imu_data[0].timestamp_ms - sample_time_ms;
+
+    for v in imu_data {
+        let g = match v.gyro { Some(g) => body(g) * DEG2RAD, None => continue };
+        let dt = ((v.timestamp_ms - prev_time) / 1000.0).max(0.0);
+        prev_time = v.timestamp_ms;
+
+        // ----------- Prediction -----------
+        let omega = g - bias;
+        let dq = UnitQuaternion::from_scaled_axis(omega * dt);
+        let q_pred = q * dq;
+        let bias_pred = bias;
+
+        let mut f = Matrix6::identity();
+        f.fixed_slice_mut::<3, 3>(0, 0).copy_from(&dq.to_rotation_matrix().matrix().transpose());
+        f.fixed_slice_mut::<3, 3>(0, 3).copy_from(&(-Matrix3::identity() * dt));
+
+        let mut noise = Matrix6::zeros();
+        for i in 0..3 {
+            noise[(i, i)] = GYRO_NOISE.powi(2) * dt;
+            noise[(i + 3, i + 3)] = BIAS_RANDOM_WALK.powi(2) * dt;
+        }
+        let p_pred = f * p * f.transpose() + noise;
+
+        q = q_pred;
+        bias = bias_pred;
+        p = p_pred;
+
+        // ----------- Correction -----------
+        if let (Some(a), Some(one_g)) = (v.accl.map(body), one_g) {
+            let norm = a.norm();
+            let deviation = (norm - one_g).abs() / one_g;
+            if norm > 0.0 && deviation < ACCL_MAX_DEVIATION {
+                let z = a / norm;
+                let h0 = q.inverse_transform_vector(&Vector3::z());
+                let mut h = Matrix3x6::zeros();
+                h.fixed_slice_mut::<3, 3>(0, 0).copy_from(&skew(&h0));
+
+                // Less trust in the accelerometer when it's further from 1g
+                let r = Matrix3::identity() * (ACCL_NOISE * (1.0 + 10.0 * deviation)).powi(2);
+                let s = h * p * h.transpose() + r;
+                if let Some(s_inv) = s.try_inverse() {
+                    let k = p * h.transpose() * s_inv;
+                    let dx: Vector6 = k * (z - h0);
+
+                    q *= UnitQuaternion::from_scaled_axis(dx.fixed_rows::<3>(0).into_owned());
+                    bias += dx.fixed_rows::<3>(3);
+                    let i_kh = Matrix6::identity() - k * h;
+                    // Joseph form keeps the covariance symmetric and positive definite
+                    p = i_kh * p * i_kh.transpose() + k * r * k.transpose();
+                }
+            }
+        }
+
+        steps.push(Step { timestamp_us: (v.timestamp_ms * 1000.0) as i64, q, bias, p, f, q_pred, bias_pred, p_pred });
+    }
+
+    // ----------- RTS smoother -----------
+    if let Some(last) = steps.last() {
+        let (mut q_s, mut bias_s, mut p_s) = (last.q, last.bias, last.p);
+        quats.insert(last.timestamp_us, q_s);
+
+        for k in (0..steps.len() - 1).rev() {
+            let (cur, next) = (&steps[k], &steps[k + 1]);
+            let c = match next.p_pred.try_inverse() {
+                Some(inv) => cur.p * next.f.transpose() * inv,
+                None => Matrix6::zeros()
+            };
+            let mut diff = Vector6::zeros();
+            diff.fixed_rows_mut::<3>(0).copy_from(&(next.q_pred.inverse() * q_s).scaled_axis());
+            diff.fixed_rows_mut::<3>(3).copy_from(&(bias_s - next.bias_pred));
+            let dx = c * diff;
+
+            q_s = cur.q * UnitQuaternion::from_scaled_axis(dx.fixed_rows::<3>(0).into_owned());
+            bias_s = cur.bias + dx.fixed_rows::<3>(3);
+            p_s = cur.p + c * (p_s - next.p_pred) * c.transpose();
+
+            quats.insert(cur.timestamp_us, q_s);
+        }
+        ::log::debug!("ESKF final bias estimate: {:?} deg/s", (bias_s / DEG2RAD).as_slice());
+    }
+
+    quats
+}
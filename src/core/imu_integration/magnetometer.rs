@@ -0,0 +1,137 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Magnetometer preprocessing for the integrators: hard/soft-iron calibration and magnetic disturbance detection.
+// Uncalibrated readings are dominated by the offset from the camera itself (hard-iron) and the distortion
+// from nearby metal (soft-iron), so the heading correction pulls the orientation in a wrong direction.
+
+use nalgebra::{ DMatrix, DVector, Matrix3, Vector3 };
+use crate::gyro_source::TimeIMU;
+
+// Minimum number of samples for the ellipsoid fit
+const MIN_SAMPLES: usize = 100;
+// Maximum deviation of the calibrated field strength from 1
+const MAX_NORM_DEVIATION: f64 = 0.15;
+// Maximum deviation of the angle between the magnetic field and the gravity from its median, in degrees
+const MAX_DIP_DEVIATION: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MagCalibration {
+    /// Hard-iron offset
+    pub offset: Vector3<f64>,
+    /// Soft-iron correction, maps the offset-corrected reading to the unit sphere
+    pub transform: Matrix3<f64>,
+}
+impl MagCalibration {
+    pub fn apply(&self, m: &[f64; 3]) -> Vector3<f64> {
+        self.transform * (Vector3::from(*m) - self.offset)
+    }
+
+    /// Fits an ellipsoid `x^T A x + b^T x = 1` to the readings
+    pub fn fit(samples: &[Vector3<f64>]) -> Option<Self> {
+        if samples.len() < MIN_SAMPLES { return None; }
+
+        // Scale the data to avoid numerical issues with large raw values
+        let scale = samples.iter().map(|x| x.amax()).fold(0.0, f64::max);
+        if scale <= 0.0 { return None; }
+
+        let a = DMatrix::from_fn(samples.len(), 9, |r, c| {
+            let v = samples[r] / scale;
+            match c {
+                0 => v.x * v.x,
+                1 => v.y * v.y,
+                2 => v.z * v.z,
+                3 => 2.0 * v.x * v.y,
+                4 => 2.0 * v.x * v.z,
+                5 => 2.0 * v.y * v.z,
+                6 => v.x,
+                7 => v.y,
+                _ => v.z,
+            }
+        });
+        let b = DVector::from_element(samples.len(), 1.0);
+        let p = a.svd(true, true).solve(&b, 1e-12).ok()?;
+
+        let quad = Matrix3::new(
+            p[0], p[3], p[4],
+            p[3], p[1], p[5],
+            p[4], p[5], p[2]
+        );
+        let lin = Vector3::new(p[6], p[7], p[8]);
+        let center = -0.5 * quad.try_inverse()? * lin;
+        // (x - c)^T A (x - c) = 1 + c^T A c
+        let k = 1.0 + (center.transpose() * quad * center)[0];
+        if k <= 0.0 { return None; }
+
+        let eigen = (quad / k).symmetric_eigen();
+        if eigen.eigenvalues.iter().any(|x| *x <= 0.0) {
+            // Not an ellipsoid, usually not enough rotation in the data
+            return None;
+        }
+        let sqrt = Matrix3::from_diagonal(&eigen.eigenvalues.map(f64::sqrt));
+        let transform = eigen.eigenvectors * sqrt * eigen.eigenvectors.transpose();
+
+        Some(Self {
+            offset: center * scale,
+            transform: transform / scale,
+        })
+    }
+
+    /// Hard-iron only, from the range of each axis
+    pub fn from_min_max(samples: &[Vector3<f64>]) -> Option<Self> {
+        if samples.len() < MIN_SAMPLES { return None; }
+        let min = samples.iter().fold(Vector3::repeat(f64::MAX), |a, b| a.inf(b));
+        let max = samples.iter().fold(Vector3::repeat(f64::MIN), |a, b| a.sup(b));
+        let radius = (max - min) / 2.0;
+        if radius.iter().any(|x| *x <= 0.0) { return None; }
+        Some(Self {
+            offset: (max + min) / 2.0,
+            transform: Matrix3::from_diagonal(&radius.map(|x| 1.0 / x)),
+        })
+    }
+}
+
+/// Calibrated magnetometer readings for each sample, `None` where there's no reading or the field is disturbed
+pub fn prepare(imu_data: &[TimeIMU]) -> Vec<Option<[f64; 3]>> {
+    let raw: Vec<Vector3<f64>> = imu_data.iter().filter_map(|x| x.magn.map(Vector3::from)).collect();
+    let calibration = match MagCalibration::fit(&raw).or_else(|| MagCalibration::from_min_max(&raw)) {
+        Some(x) => x,
+        None => {
+            if !raw.is_empty() { ::log::warn!("Not enough magnetometer data for the calibration"); }
+            return vec![None; imu_data.len()];
+        }
+    };
+    ::log::info!("Magnetometer calibration: offset {:?}, soft-iron {:?}", calibration.offset.as_slice(), calibration.transform.as_slice());
+
+    let dip = |m: &Vector3<f64>, a: Option<[f64; 3]>| -> Option<f64> {
+        let a = Vector3::from(a?);
+        if a.norm() < 1e-6 { return None; }
+        Some(m.angle(&a).to_degrees())
+    };
+    let calibrated: Vec<Option<Vector3<f64>>> = imu_data.iter().map(|x| x.magn.map(|m| calibration.apply(&m))).collect();
+
+    let mut dips: Vec<f64> = calibrated.iter().zip(imu_data).filter_map(|(m, x)| dip(m.as_ref()?, x.accl)).collect();
+    dips.sort_by(|a, b| a.total_cmp(b));
+    let median_dip = dips.get(dips.len() / 2).copied();
+
+    let mut disturbed = 0;
+    let ret: Vec<Option<[f64; 3]>> = calibrated.iter().zip(imu_data).map(|(m, x)| {
+        let m = (*m)?;
+        let norm_ok = (m.norm() - 1.0).abs() <= MAX_NORM_DEVIATION;
+        let dip_ok = match (median_dip, dip(&m, x.accl)) {
+            (Some(median), Some(d)) => (d - median).abs() <= MAX_DIP_DEVIATION,
+            _ => true
+        };
+        if norm_ok && dip_ok {
+            Some([m.x, m.y, m.z])
+        } else {
+            disturbed += 1;
+            None
+        }
+    }).collect();
+
+    if disturbed > 0 {
+        ::log::info!("Magnetic disturbance detected in {} of {} samples", disturbed, raw.len());
+    }
+    ret
+}
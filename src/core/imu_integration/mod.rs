@@ -4,13 +4,16 @@
 mod complementary_v2;
 mod complementary;
 mod vqf;
+mod eskf;
+pub mod magnetometer;
 
 use std::collections::BTreeMap;
 use nalgebra::*;
 use super::gyro_source::{TimeIMU, Quat64, TimeQuat};
 use ahrs::{Ahrs, Madgwick, Mahony};
 
-// TODO: Magnetometer calculations are disabled in Complementary and VQF. Figure out what's wrong and enable them
+// Magnetometer is used only by the `*MagIntegrator` variants, after the hard/soft-iron calibration.
+// Raw readings are too distorted, so the plain Complementary and VQF integrators ignore it.
 
 pub trait GyroIntegrator {
     fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat;
@@ -18,11 +21,14 @@ pub trait GyroIntegrator {
 
 pub struct QuaternionConverter { }
 pub struct ComplementaryIntegrator { }
+pub struct ComplementaryMagIntegrator { }
 pub struct VQFIntegrator { }
+pub struct VQFMagIntegrator { }
 pub struct SimpleGyroIntegrator { }
 pub struct SimpleGyroAccelIntegrator { }
 pub struct MahonyIntegrator { }
 pub struct MadgwickIntegrator { }
+pub struct EskfIntegrator { }
 
 // const RAD2DEG: f64 = 180.0 / std::f64::consts::PI;
 const DEG2RAD: f64 = std::f64::consts::PI / 180.0;
@@ -52,48 +58,57 @@ use complementary_v2::ComplementaryFilterV2;
 
 impl GyroIntegrator for ComplementaryIntegrator {
     fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
-        if imu_data.is_empty() { return BTreeMap::new(); }
-        let mut quats = BTreeMap::new();
-        let sample_time_ms = duration_ms / imu_data.len() as f64;
+        integrate_complementary(imu_data, duration_ms, None)
+    }
+}
+impl GyroIntegrator for ComplementaryMagIntegrator {
+    fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
+        let magn = magnetometer::prepare(imu_data);
+        integrate_complementary(imu_data, duration_ms, Some(&magn))
+    }
+}
 
-        let mut f = ComplementaryFilterV2::default();
-        // Limit initial settle time for short videos
-        f.set_initial_settle_time((duration_ms / 1000.0 * 0.05).min(2.0));
-        //f.set_orientation(init_pos_q.scalar(), -init_pos_q.vector()[0], -init_pos_q.vector()[1], -init_pos_q.vector()[2]);
-        let mut counter = 0;
-        let mut prev_time = imu_data[0].timestamp_ms - sample_time_ms;
-        for v in imu_data {
-            if let Some(g) = v.gyro.as_ref() {
-                let mut a = v.accl.unwrap_or_default();
-                if a[0].abs() == 0.0 && a[1].abs() == 0.0 && a[2].abs() == 0.0 { a[0] += 0.0000001; }
-                let acc = Vector3::new(-a[1], a[0], a[2]);
-                // log::info!("acc norm: {}", acc.norm());
-
-                /*if let Some(m) = v.magn.as_ref() {
-                    if let Some(magn) = Vector3::new(-m[1], m[0], m[2]).try_normalize(0.0) {
-                        f.update_mag(acc[0], acc[1], acc[2],
-                            -g[1] * DEG2RAD, g[0] * DEG2RAD, g[2] * DEG2RAD,
-                            magn[0], magn[1], magn[2],
-                            (v.timestamp_ms - prev_time) / 1000.0);
-                    }
-                } else */{
-                    counter += 1;
-                    if counter % 20 == 0 {
-                        //println!("{:?}, {:?}, {:?}, {:?}, {:?}, {:?}, {:?}", v.timestamp_ms, acc[0], acc[1], acc[2], -g[1] * DEG2RAD, g[0] * DEG2RAD, g[2] * DEG2RAD);
-                    }
-                    f.update(acc[0], acc[1], acc[2],
-                        -g[1] * DEG2RAD, g[0] * DEG2RAD, g[2] * DEG2RAD,
-                        (v.timestamp_ms - prev_time) / 1000.0);
+fn integrate_complementary(imu_data: &[TimeIMU], duration_ms: f64, magn: Option<&[Option<[f64; 3]>]>) -> TimeQuat {
+    if imu_data.is_empty() { return BTreeMap::new(); }
+    let mut quats = BTreeMap::new();
+    let sample_time_ms = duration_ms / imu_data.len() as f64;
+
+    let mut f = ComplementaryFilterV2::default();
+    // Limit initial settle time for short videos
+    f.set_initial_settle_time((duration_ms / 1000.0 * 0.05).min(2.0));
+    //f.set_orientation(init_pos_q.scalar(), -init_pos_q.vector()[0], -init_pos_q.vector()[1], -init_pos_q.vector()[2]);
+    let mut counter = 0;
+    let mut prev_time = imu_data[0].timestamp_ms - sample_time_ms;
+    for (i, v) in imu_data.iter().enumerate() {
+        if let Some(g) = v.gyro.as_ref() {
+            let mut a = v.accl.unwrap_or_default();
+            if a[0].abs() == 0.0 && a[1].abs() == 0.0 && a[2].abs() == 0.0 { a[0] += 0.0000001; }
+            let acc = Vector3::new(-a[1], a[0], a[2]);
+            // log::info!("acc norm: {}", acc.norm());
+
+            let m = magn.and_then(|x| x.get(i).copied().flatten()).and_then(|m| Vector3::new(-m[1], m[0], m[2]).try_normalize(0.0));
+            if let Some(magn) = m {
+                f.update_mag(acc[0], acc[1], acc[2],
+                    -g[1] * DEG2RAD, g[0] * DEG2RAD, g[2] * DEG2RAD,
+                    magn[0], magn[1], magn[2],
+                    (v.timestamp_ms - prev_time) / 1000.0);
+            } else {
+                counter += 1;
+                if counter % 20 == 0 {
+                    //println!("{:?}, {:?}, {:?}, {:?}, {:?}, {:?}, {:?}", v.timestamp_ms, acc[0], acc[1], acc[2], -g[1] * DEG2RAD, g[0] * DEG2RAD, g[2] * DEG2RAD);
                 }
-                let x = f.get_orientation();
-                quats.insert((v.timestamp_ms * 1000.0) as i64, Quat64::from_quaternion(Quaternion::from_parts(x.0, Vector3::new(x.1, x.2, x.3))));
-
-                prev_time = v.timestamp_ms;
+                f.update(acc[0], acc[1], acc[2],
+                    -g[1] * DEG2RAD, g[0] * DEG2RAD, g[2] * DEG2RAD,
+                    (v.timestamp_ms - prev_time) / 1000.0);
             }
-        }
+            let x = f.get_orientation();
+            quats.insert((v.timestamp_ms * 1000.0) as i64, Quat64::from_quaternion(Quaternion::from_parts(x.0, Vector3::new(x.1, x.2, x.3))));
 
-        quats
+            prev_time = v.timestamp_ms;
+        }
     }
+
+    quats
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -102,39 +117,49 @@ impl GyroIntegrator for ComplementaryIntegrator {
 
 impl GyroIntegrator for VQFIntegrator {
     fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
-        if imu_data.is_empty() { return BTreeMap::new(); }
-        let mut out_quats = BTreeMap::new();
-        let sample_time = duration_ms / (imu_data.len() * 1000) as f64;
-
-        let num_samples = imu_data.len();
+        integrate_vqf(imu_data, duration_ms, None)
+    }
+}
+impl GyroIntegrator for VQFMagIntegrator {
+    fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
+        let magn = magnetometer::prepare(imu_data);
+        integrate_vqf(imu_data, duration_ms, Some(&magn))
+    }
+}
 
-        let mut gyr = Vec::with_capacity(num_samples*3);
-        let mut acc = Vec::with_capacity(num_samples*3);
-        let mut mag = Vec::with_capacity(num_samples*3);
-        let mut quat = Vec::with_capacity(num_samples*4);
-        for v in imu_data {
-            let g = v.gyro.unwrap_or_default();
-            // zero mag or acc (default) is ignored by VQF
-            let a = v.accl.unwrap_or_default();
-            let m = [0.0, 0.0, 0.0]; // v.magn.unwrap_or_default();
-            gyr.extend([-g[1] * DEG2RAD, g[0] * DEG2RAD, g[2] * DEG2RAD]);
-            acc.extend([-a[1], a[0], a[2]]);
-            mag.extend([-m[1], m[0], m[2]]);
-            quat.extend([1.0, 0.0, 0.0, 0.0]);
-        }
+fn integrate_vqf(imu_data: &[TimeIMU], duration_ms: f64, magn: Option<&[Option<[f64; 3]>]>) -> TimeQuat {
+    if imu_data.is_empty() { return BTreeMap::new(); }
+    let mut out_quats = BTreeMap::new();
+    let sample_time = duration_ms / (imu_data.len() * 1000) as f64;
+
+    let num_samples = imu_data.len();
+
+    let mut gyr = Vec::with_capacity(num_samples*3);
+    let mut acc = Vec::with_capacity(num_samples*3);
+    let mut mag = Vec::with_capacity(num_samples*3);
+    let mut quat = Vec::with_capacity(num_samples*4);
+    for (i, v) in imu_data.iter().enumerate() {
+        let g = v.gyro.unwrap_or_default();
+        // zero mag or acc (default) is ignored by VQF
+        let a = v.accl.unwrap_or_default();
+        let m = magn.and_then(|x| x.get(i).copied().flatten()).unwrap_or_default();
+        gyr.extend([-g[1] * DEG2RAD, g[0] * DEG2RAD, g[2] * DEG2RAD]);
+        acc.extend([-a[1], a[0], a[2]]);
+        mag.extend([-m[1], m[0], m[2]]);
+        quat.extend([1.0, 0.0, 0.0, 0.0]);
+    }
 
-        // Tweak parameters here, see parameter descriptions: https://github.com/dlaidig/vqf/blob/main/vqf/cpp/vqf.hpp#L37
-        let params = vqf::VQFParams {
-            tau_acc: 40.0,
-            tau_mag: 40.0,
-            ..Default::default()
-        };
-        vqf::offline_vqf(gyr, acc, Some(mag), num_samples, sample_time, params, None, Some(&mut quat), None, None, None, None, None);
-        for (i, v) in imu_data.iter().enumerate() {
-            out_quats.insert((v.timestamp_ms * 1000.0) as i64, Quat64::from_quaternion(Quaternion::from_parts(quat[i*4], Vector3::new(quat[i*4+1], quat[i*4+2], quat[i*4+3]))));
-        }
-        out_quats
+    // Tweak parameters here, see parameter descriptions: https://github.com/dlaidig/vqf/blob/main/vqf/cpp/vqf.hpp#L37
+    let params = vqf::VQFParams {
+        tau_acc: 40.0,
+        tau_mag: 40.0,
+        ..Default::default()
+    };
+    vqf::offline_vqf(gyr, acc, magn.map(|_| mag), num_samples, sample_time, params, None, Some(&mut quat), None, None, None, None, None);
+    for (i, v) in imu_data.iter().enumerate() {
+        out_quats.insert((v.timestamp_ms * 1000.0) as i64, Quat64::from_quaternion(Quaternion::from_parts(quat[i*4], Vector3::new(quat[i*4+1], quat[i*4+2], quat[i*4+3]))));
     }
+    out_quats
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -306,3 +331,13 @@ impl GyroIntegrator for MadgwickIntegrator {
         quats
     }
 }
+
+///////////////////////////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////////////////////////
+///////////////////////////////////////////////////////////////////////////////
+
+impl GyroIntegrator for EskfIntegrator {
+    fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
+        eskf::integrate(imu_data, duration_ms)
+    }
+}
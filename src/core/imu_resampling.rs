@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// IMU data preprocessing: detection of duplicated timestamps, out of order samples and gaps,
+// and resampling to a uniform rate. Integrators and filters assume roughly constant sample interval,
+// so dropped samples cause orientation jumps.
+
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use crate::gyro_source::TimeIMU;
+
+// Interval larger than this times the median interval is reported as a gap
+const GAP_FACTOR: f64 = 1.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    Nearest,
+    #[default]
+    Linear,
+    /// Catmull-Rom spline
+    Cubic,
+}
+impl Interpolation {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nearest" => Some(Self::Nearest),
+            "linear"  => Some(Self::Linear),
+            "cubic"   => Some(Self::Cubic),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ResampleSettings {
+    /// Target sample rate in Hz. `None` uses the median rate of the data
+    pub sample_rate: Option<f64>,
+    pub interpolation: Interpolation,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ImuQualityReport {
+    pub sample_count: usize,
+    pub duplicated_timestamps: usize,
+    pub out_of_order: usize,
+    /// (start_ms, end_ms) of each gap
+    pub gaps: Vec<(f64, f64)>,
+    /// Estimated number of samples missing in the gaps
+    pub dropped_samples: usize,
+    pub median_interval_ms: f64,
+    /// Standard deviation of the sample interval, excluding the gaps
+    pub interval_jitter_ms: f64,
+    pub resampled_to: Option<f64>,
+}
+impl ImuQualityReport {
+    pub fn sample_rate(&self) -> f64 {
+        if self.median_interval_ms > 0.0 { 1000.0 / self.median_interval_ms } else { 0.0 }
+    }
+    pub fn has_issues(&self) -> bool {
+        self.duplicated_timestamps > 0 || self.out_of_order > 0 || !self.gaps.is_empty()
+    }
+    pub fn log(&self) {
+        ::log::info!("IMU data: {} samples, {:.2} Hz, interval jitter {:.4} ms", self.sample_count, self.sample_rate(), self.interval_jitter_ms);
+        if self.has_issues() {
+            ::log::warn!("IMU data issues: {} duplicated timestamps, {} out of order samples, {} gaps ({} dropped samples)", self.duplicated_timestamps, self.out_of_order, self.gaps.len(), self.dropped_samples);
+            for (start, end) in self.gaps.iter().take(20) {
+                ::log::warn!("IMU gap at {:.3} ms, {:.3} ms long", start, end - start);
+            }
+        }
+        if let Some(rate) = self.resampled_to {
+            ::log::info!("IMU data resampled to {:.2} Hz", rate);
+        }
+    }
+}
+
+fn median(mut v: Vec<f64>) -> f64 {
+    if v.is_empty() { return 0.0; }
+    v.sort_by(|a, b| a.total_cmp(b));
+    v[v.len() / 2]
+}
+
+pub fn analyze(imu: &[TimeIMU]) -> ImuQualityReport {
+    let mut report = ImuQualityReport { sample_count: imu.len(), ..Default::default() };
+    if imu.len() < 2 { return report; }
+
+    let mut intervals = Vec::with_capacity(imu.len());
+    for w in imu.windows(2) {
+        let d = w[1].timestamp_ms - w[0].timestamp_ms;
+        if d == 0.0 { report.duplicated_timestamps += 1; }
+        else if d < 0.0 { report.out_of_order += 1; }
+        else { intervals.push(d); }
+    }
+    report.median_interval_ms = median(intervals.clone());
+    if report.median_interval_ms <= 0.0 { return report; }
+
+    let gap_threshold = report.median_interval_ms * GAP_FACTOR;
+    for w in imu.windows(2) {
+        let d = w[1].timestamp_ms - w[0].timestamp_ms;
+        if d > gap_threshold {
+            report.gaps.push((w[0].timestamp_ms, w[1].timestamp_ms));
+            report.dropped_samples += (d / report.median_interval_ms).round() as usize - 1;
+        }
+    }
+    let regular: Vec<f64> = intervals.into_iter().filter(|d| *d <= gap_threshold).collect();
+    if regular.len() > 1 {
+        let mean = regular.iter().sum::<f64>() / regular.len() as f64;
+        report.interval_jitter_ms = (regular.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / (regular.len() - 1) as f64).sqrt();
+    }
+    report
+}
+
+/// Sorts the samples and merges the ones with duplicated timestamps by averaging them
+pub fn repair(imu: &[TimeIMU]) -> Vec<TimeIMU> {
+    let mut sorted = imu.to_vec();
+    sorted.sort_by(|a, b| a.timestamp_ms.total_cmp(&b.timestamp_ms));
+
+    let average = |a: Option<[f64; 3]>, b: Option<[f64; 3]>, n: f64| -> Option<[f64; 3]> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some([a[0] + (b[0] - a[0]) / n, a[1] + (b[1] - a[1]) / n, a[2] + (b[2] - a[2]) / n]),
+            (a, b) => a.or(b)
+        }
+    };
+
+    let mut ret: Vec<TimeIMU> = Vec::with_capacity(sorted.len());
+    let mut count = 1.0;
+    for x in sorted {
+        match ret.last_mut() {
+            Some(last) if last.timestamp_ms == x.timestamp_ms => {
+                // Running average
+                count += 1.0;
+                last.gyro = average(last.gyro, x.gyro, count);
+                last.accl = average(last.accl, x.accl, count);
+                last.magn = average(last.magn, x.magn, count);
+            },
+            _ => {
+                count = 1.0;
+                ret.push(x);
+            }
+        }
+    }
+    ret
+}
+
+fn interpolate(p: [Option<[f64; 3]>; 4], t: f64, interpolation: Interpolation) -> Option<[f64; 3]> {
+    let (p1, p2) = (p[1]?, p[2]?);
+    Some(match interpolation {
+        Interpolation::Nearest => if t < 0.5 { p1 } else { p2 },
+        Interpolation::Linear => [0, 1, 2].map(|i| p1[i] + (p2[i] - p1[i]) * t),
+        Interpolation::Cubic => {
+            // Use the linear slope at the ends of the data
+            let p0 = p[0].unwrap_or_else(|| [0, 1, 2].map(|i| 2.0 * p1[i] - p2[i]));
+            let p3 = p[3].unwrap_or_else(|| [0, 1, 2].map(|i| 2.0 * p2[i] - p1[i]));
+            let (t2, t3) = (t * t, t * t * t);
+            [0, 1, 2].map(|i| 0.5 * (
+                (2.0 * p1[i]) +
+                (-p0[i] + p2[i]) * t +
+                (2.0 * p0[i] - 5.0 * p1[i] + 4.0 * p2[i] - p3[i]) * t2 +
+                (-p0[i] + 3.0 * p1[i] - 3.0 * p2[i] + p3[i]) * t3
+            ))
+        }
+    })
+}
+
+/// Interpolates a single channel at the sorted `timestamps`, between the nearest samples which have that channel.
+/// Channels can have a lower rate than the gyro (eg. magnetometer), so the samples without it are skipped. `None` outside of the channel's data
+fn resample_channel(imu: &[TimeIMU], timestamps: &[f64], channel: fn(&TimeIMU) -> Option<[f64; 3]>, interpolation: Interpolation) -> Vec<Option<[f64; 3]>> {
+    let samples: Vec<(f64, [f64; 3])> = imu.iter().filter_map(|x| Some((x.timestamp_ms, channel(x)?))).collect();
+    let (first, last) = match (samples.first(), samples.last()) {
+        (Some(first), Some(last)) => (first.0, last.0),
+        _ => return vec![None; timestamps.len()]
+    };
+    if samples.len() < 2 {
+        return timestamps.iter().map(|ts| if *ts == first { Some(samples[0].1) } else { None }).collect();
+    }
+
+    let mut i = 0;
+    timestamps.iter().map(|&ts| {
+        if ts < first || ts > last { return None; }
+        while i + 2 < samples.len() && samples[i + 1].0 <= ts { i += 1; }
+
+        let (a, b) = (&samples[i], &samples[i + 1]);
+        let t = ((ts - a.0) / (b.0 - a.0)).clamp(0.0, 1.0);
+        let prev = i.checked_sub(1).map(|x| samples[x].1);
+        let next = samples.get(i + 2).map(|x| x.1);
+        interpolate([prev, Some(a.1), Some(b.1), next], t, interpolation)
+    }).collect()
+}
+
+/// Resamples the data to a uniform rate. The input has to be sorted and without duplicates (see `repair`)
+pub fn resample(imu: &[TimeIMU], sample_rate: f64, interpolation: Interpolation) -> Vec<TimeIMU> {
+    if imu.len() < 2 || sample_rate <= 0.0 { return imu.to_vec(); }
+
+    let interval = 1000.0 / sample_rate;
+    let first = imu[0].timestamp_ms;
+    let last = imu[imu.len() - 1].timestamp_ms;
+    let count = ((last - first) / interval).floor() as usize + 1;
+    let timestamps: Vec<f64> = (0..count).map(|n| first + n as f64 * interval).collect();
+
+    let gyro = resample_channel(imu, &timestamps, |x| x.gyro, interpolation);
+    let accl = resample_channel(imu, &timestamps, |x| x.accl, interpolation);
+    let magn = resample_channel(imu, &timestamps, |x| x.magn, interpolation);
+
+    timestamps.into_iter().zip(gyro).zip(accl).zip(magn).map(|(((timestamp_ms, gyro), accl), magn)| {
+        TimeIMU { timestamp_ms, gyro, accl, magn }
+    }).collect()
+}
+
+/// Analyzes the data and, if `settings` are provided, repairs and resamples it.
+/// Returns the processed samples and the quality report of the input
+pub fn process(imu: &[TimeIMU], settings: Option<&ResampleSettings>) -> (Option<Vec<TimeIMU>>, ImuQualityReport) {
+    let mut report = analyze(imu);
+    let settings = match settings {
+        Some(s) => s,
+        None => return (None, report)
+    };
+    let rate = settings.sample_rate.filter(|x| *x > 0.0).unwrap_or_else(|| report.sample_rate());
+    if rate <= 0.0 { return (None, report); }
+
+    let repaired = repair(imu);
+    report.resampled_to = Some(rate);
+    (Some(resample(&repaired, rate, settings.interpolation)), report)
+}
@@ -2,6 +2,10 @@
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
 pub mod gyro_source;
+pub mod bias_estimation;
+pub mod imu_resampling;
+pub mod saturation;
+pub mod csv_imu;
 pub mod imu_integration;
 pub mod lens_profile;
 pub mod lens_profile_database;
@@ -15,18 +19,21 @@ pub mod keyframes;
 pub mod zooming;
 pub mod smoothing;
 pub mod filtering;
+pub mod spectral_analysis;
 
 pub mod gpu;
 
 pub mod util;
 pub mod stabilization_params;
+pub mod export;
+pub mod project;
 
 use std::sync::{ Arc, atomic::{ AtomicU64, AtomicBool, Ordering::SeqCst } };
 use std::path::PathBuf;
 use keyframes::*;
 use parking_lot::{ RwLock, RwLockUpgradableReadGuard };
 use nalgebra::Vector4;
-use gyro_source::{ GyroSource, Quat64, TimeQuat, TimeVec };
+use gyro_source::GyroSource;
 use stabilization_params::StabilizationParams;
 use lens_profile::LensProfile;
 use lens_profile_database::LensProfileDatabase;
@@ -203,6 +210,27 @@ impl<T: PixelType> StabilizationManager<T> {
         }
     }
 
+    /// Sets the frame readout time, also in the lens profile. If `lens_profile_path` is given, the lens profile is saved there
+    pub fn apply_frame_readout_time(&self, readout_ms: f64, lens_profile_path: Option<&str>) -> std::io::Result<()> {
+        self.params.write().frame_readout_time = readout_ms;
+        let mut lens = self.lens.write();
+        lens.frame_readout_time = Some(readout_ms);
+        if let Some(path) = lens_profile_path {
+            lens.save_to_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Sync points placed by the motion in the trimmed part of the video, as a fraction of the duration. `sync_params` are in ms
+    pub fn get_optimal_sync_points(&self, target_sync_points: usize, sync_params: &synchronization::SyncParams) -> Vec<f64> {
+        let (duration_ms, trim) = {
+            let params = self.params.read();
+            (params.get_scaled_duration_ms(), (params.trim_start, params.trim_end))
+        };
+        // Ranges have `time_per_syncpoint` length in the scaled time, see `AutosyncProcess::from_manager`
+        synchronization::sync_points::optimal_sync_points(&self.gyro.read(), duration_ms, trim, target_sync_points, sync_params.time_per_syncpoint, sync_params.initial_offset, &sync_params.placement)
+    }
+
     fn init_size(&self) {
         let (w, h, ow, oh, bg) = {
             let params = self.params.read();
@@ -557,6 +585,11 @@ impl<T: PixelType> StabilizationManager<T> {
         self.keyframes.write().update_gyro(&self.gyro.read());
         self.invalidate_zooming();
     }
+    pub fn set_drift_model(&self, model: Option<synchronization::drift::DriftModelSettings>) {
+        self.gyro.write().set_drift_model(model);
+        self.keyframes.write().update_gyro(&self.gyro.read());
+        self.invalidate_zooming();
+    }
     pub fn clear_offsets(&self) {
         self.gyro.write().clear_offsets();
         self.keyframes.write().update_gyro(&self.gyro.read());
@@ -569,17 +602,46 @@ impl<T: PixelType> StabilizationManager<T> {
     pub fn set_imu_lpf(&self, lpf: f64) {
         self.gyro.write().imu_lpf = lpf;
     }
+    pub fn set_imu_notch(&self, settings: Option<filtering::NotchSettings>) {
+        self.gyro.write().imu_notch = settings;
+    }
     pub fn set_imu_rotation(&self, pitch_deg: f64, roll_deg: f64, yaw_deg: f64) {
         self.gyro.write().imu_rotation_angles = Some([pitch_deg, roll_deg, yaw_deg]);
     }
     pub fn set_acc_rotation(&self, pitch_deg: f64, roll_deg: f64, yaw_deg: f64) {
         self.gyro.write().acc_rotation_angles = Some([pitch_deg, roll_deg, yaw_deg]);
     }
+    /// Finds the 90° accelerometer rotation most consistent with the gyro, see `synchronization::orientation::guess_acc_rotation`
+    pub fn guess_acc_rotation(&self) -> Option<synchronization::orientation::AccRotationGuess> {
+        synchronization::orientation::guess_acc_rotation(&self.gyro.read())
+    }
     pub fn set_imu_orientation(&self, orientation: String) {
         self.gyro.write().imu_orientation = Some(orientation);
     }
+    pub fn set_imu_resampling(&self, settings: Option<imu_resampling::ResampleSettings>) {
+        self.gyro.write().imu_resampling = settings;
+    }
+    pub fn set_gyro_saturation(&self, settings: Option<saturation::SaturationSettings>) {
+        self.gyro.write().saturation = settings;
+    }
+    /// Uses the rotation estimated from the video for the saturation reconstruction. Call after the optical flow was processed
+    pub fn update_saturation_reference(&self) {
+        let estimated_gyro = self.pose_estimator.estimated_gyro.read();
+        self.gyro.write().set_saturation_reference(&estimated_gyro);
+    }
     pub fn set_imu_bias(&self, bx: f64, by: f64, bz: f64) {
-        self.gyro.write().gyro_bias = Some([bx, by, bz]);
+        let mut gyro = self.gyro.write();
+        gyro.gyro_bias = Some([bx, by, bz]);
+        gyro.gyro_bias_curve = None;
+    }
+    /// Detects the static segments and applies the estimated bias. Returns the constant bias, if any static segment was found
+    pub fn estimate_bias_auto(&self, time_varying: bool) -> Option<[f64; 3]> {
+        let segments = self.gyro.write().estimate_bias_auto(&Default::default(), time_varying);
+        if segments.is_empty() { return None; }
+
+        self.smoothing.write().update_quats_checksum(&self.gyro.read().quaternions);
+        self.invalidate_smoothing();
+        self.gyro.read().gyro_bias
     }
     pub fn recompute_gyro(&self) {
         self.gyro.write().apply_transforms();
@@ -717,6 +779,37 @@ impl<T: PixelType> StabilizationManager<T> {
         });
     }
 
+    pub fn export_frame_transforms<F: Fn(f64)>(&self, filepath: impl AsRef<std::path::Path>, format: export::ExportFormat, trim_range_only: bool, progress_cb: F) -> std::io::Result<()> {
+        let params = stabilization::ComputeParams::from_manager(self, false);
+        let file = std::fs::File::create(filepath)?;
+        export::frame_transforms::export(&params, format, trim_range_only, file, progress_cb)
+    }
+    pub fn export_stmaps<F: Fn(f64)>(&self, base_path: impl AsRef<std::path::Path>, trim_range_only: bool, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> std::io::Result<usize> {
+        let params = stabilization::ComputeParams::from_manager(self, false);
+        export::stmap::export(&params, base_path.as_ref(), trim_range_only, progress_cb, cancel_flag)
+    }
+    pub fn export_camera_motion<F: Fn(f64)>(&self, filepath: impl AsRef<std::path::Path>, format: export::camera_motion::CameraMotionFormat, rotation: export::camera_motion::CameraRotation, trim_range_only: bool, progress_cb: F) -> std::io::Result<()> {
+        let params = stabilization::ComputeParams::from_manager(self, false);
+        let file = std::fs::File::create(filepath)?;
+        export::camera_motion::export(&params, format, rotation, trim_range_only, file, progress_cb)
+    }
+    pub fn export_gyro_data<F: Fn(f64)>(&self, filepath: impl AsRef<std::path::Path>, format: export::gyro_data::GyroDataFormat, progress_cb: F) -> std::io::Result<()> {
+        let gyro = self.gyro.read();
+        let file = std::fs::File::create(filepath)?;
+        export::gyro_data::export(&gyro, format, file, progress_cb)
+    }
+
+    /// Spectral analysis of the original IMU data, or the processed one if `settings.processed` is set
+    pub fn spectral_analysis(&self, settings: &spectral_analysis::SpectralSettings) -> spectral_analysis::SpectralReport {
+        let gyro = self.gyro.read();
+        spectral_analysis::analyze(if settings.processed { &gyro.raw_imu } else { &gyro.org_raw_imu }, settings)
+    }
+    pub fn export_spectral_report(&self, filepath: impl AsRef<std::path::Path>, format: spectral_analysis::SpectralReportFormat, settings: &spectral_analysis::SpectralSettings) -> std::io::Result<spectral_analysis::SpectralReport> {
+        let report = self.spectral_analysis(settings);
+        report.write(format, std::io::BufWriter::new(std::fs::File::create(filepath)?))?;
+        Ok(report)
+    }
+
     pub fn export_gyroflow_file(&self, filepath: impl AsRef<std::path::Path>, thin: bool, extended: bool, additional_data: &str) -> std::io::Result<()> {
         let data = self.export_gyroflow_data(thin, extended, additional_data)?;
         let path_str = filepath.as_ref().to_string_lossy().to_string();
@@ -727,6 +820,7 @@ impl<T: PixelType> StabilizationManager<T> {
         Ok(())
     }
     pub fn export_gyroflow_data(&self, thin: bool, extended: bool, additional_data: &str) -> std::io::Result<String> {
+        use project::*;
         let gyro = self.gyro.read();
         let params = self.params.read();
 
@@ -734,15 +828,13 @@ impl<T: PixelType> StabilizationManager<T> {
             let smoothing_lock = self.smoothing.read();
             let smoothing = smoothing_lock.current();
 
-            let mut parameters = smoothing.get_parameters_json();
-            if let serde_json::Value::Array(ref mut arr) = parameters {
-                for v in arr.iter_mut() {
-                    if let serde_json::Value::Object(ref obj) = v {
-                        *v = serde_json::json!({
-                            "name": obj["name"],
-                            "value": obj["value"]
-                        });
-                    }
+            let mut parameters = Vec::new();
+            if let serde_json::Value::Array(ref arr) = smoothing.get_parameters_json() {
+                for v in arr {
+                    parameters.push(SmoothingParam {
+                        name:  v.get("name") .and_then(|x| x.as_str()).map(|x| x.to_string()),
+                        value: v.get("value").and_then(|x| x.as_f64()),
+                    });
                 }
             }
             let mut horizon_amount = smoothing_lock.horizon_lock.horizonlockpercent;
@@ -754,88 +846,90 @@ impl<T: PixelType> StabilizationManager<T> {
         };
 
         let input_file = self.input_file.read().clone();
+        let bg = params.background;
+
+        let mut project = ProjectFile {
+            title:            Some("Gyroflow data file".into()),
+            version:          Some(PROJECT_VERSION),
+            app_version:      Some(env!("CARGO_PKG_VERSION").to_string()),
+            videofile:        Some(input_file.path.clone()),
+            calibration_data: Some(self.lens.read().get_json_value().unwrap_or_else(|_| serde_json::json!({}))),
+            date:             Some(time::OffsetDateTime::now_local().map(|v| v.date().to_string()).unwrap_or_default()),
+
+            image_sequence_start:      Some(input_file.image_sequence_start),
+            image_sequence_fps:        Some(input_file.image_sequence_fps),
+            background_color:          Some([bg[0], bg[1], bg[2], bg[3]]),
+            background_mode:           Some(params.background_mode as i32),
+            background_margin:         Some(params.background_margin),
+            background_margin_feather: Some(params.background_margin_feather),
+
+            video_info: Some(VideoInfo {
+                width:           Some(params.video_size.0),
+                height:          Some(params.video_size.1),
+                rotation:        Some(params.video_rotation),
+                num_frames:      Some(params.frame_count),
+                fps:             Some(params.fps),
+                duration_ms:     Some(params.duration_ms),
+                fps_scale:       Some(params.fps_scale),
+                vfr_fps:         Some(params.get_scaled_fps()),
+                vfr_duration_ms: Some(params.get_scaled_duration_ms()),
+            }),
+            stabilization: Some(StabilizationSettings {
+                fov:                           Some(params.fov),
+                method:                        Some(smoothing_name.to_string()),
+                smoothing_params:              Some(smoothing_params),
+                frame_readout_time:            Some(params.frame_readout_time),
+                adaptive_zoom_window:          Some(params.adaptive_zoom_window),
+                adaptive_zoom_center_offset:   Some(params.adaptive_zoom_center_offset),
+                lens_correction_amount:        Some(params.lens_correction_amount),
+                horizon_lock_amount:           Some(horizon_amount),
+                horizon_lock_roll:             Some(horizon_roll),
+                use_gravity_vectors:           Some(gyro.use_gravity_vectors),
+                video_speed:                   Some(params.video_speed),
+                video_speed_affects_smoothing: Some(params.video_speed_affects_smoothing),
+                video_speed_affects_zooming:   Some(params.video_speed_affects_zooming),
+            }),
+            gyro_source: Some(GyroSourceSettings {
+                filepath:           Some(gyro.file_path.clone()),
+                lpf:                Some(gyro.imu_lpf),
+                notch:              Some(gyro.imu_notch.clone()),
+                rotation:           Some(gyro.imu_rotation_angles),
+                acc_rotation:       Some(gyro.acc_rotation_angles),
+                imu_orientation:    Some(gyro.imu_orientation.clone()),
+                gyro_bias:          Some(gyro.gyro_bias),
+                gyro_bias_curve:    Some(gyro.gyro_bias_curve.clone()),
+                resampling:         Some(gyro.imu_resampling),
+                saturation:         Some(gyro.saturation),
+                integration_method: Some(gyro.integration_method),
+                sample_index:       Some(gyro.file_load_options.sample_index),
+                csv_mapping:        Some(gyro.file_load_options.csv_mapping.clone()),
+                raw_imu:            Some(if !thin { util::compress_to_base91(&gyro.org_raw_imu).into() } else { serde_json::Value::Null }),
+                quaternions:        Some(if !thin && input_file.path != gyro.file_path { util::compress_to_base91(&gyro.org_quaternions).into() } else { serde_json::Value::Null }),
+                image_orientations: Some(if !thin && input_file.path != gyro.file_path { util::compress_to_base91(&gyro.image_orientations) } else { None }),
+                gravity_vectors:    Some(if !thin && input_file.path != gyro.file_path && gyro.gravity_vectors.is_some() { util::compress_to_base91(gyro.gravity_vectors.as_ref().unwrap()) } else { None }),
+                ..Default::default()
+            }),
+
+            offsets:     Some(gyro.get_offsets().clone()), // timestamp, offset value
+            drift_model: Some(gyro.drift_model),
+            keyframes: Some(self.keyframes.read().serialize()),
+
+            trim_start: Some(params.trim_start),
+            trim_end:   Some(params.trim_end),
 
-        let mut obj = serde_json::json!({
-            "title": "Gyroflow data file",
-            "version": 2,
-            "app_version": env!("CARGO_PKG_VERSION").to_string(),
-            "videofile": input_file.path,
-            "calibration_data": self.lens.read().get_json_value().unwrap_or_else(|_| serde_json::json!({})),
-            "date": time::OffsetDateTime::now_local().map(|v| v.date().to_string()).unwrap_or_default(),
-
-            "image_sequence_start": input_file.image_sequence_start,
-            "image_sequence_fps": input_file.image_sequence_fps,
-            "background_color": params.background.as_slice(),
-            "background_mode":  params.background_mode as i32,
-            "background_margin":          params.background_margin,
-            "background_margin_feather":  params.background_margin_feather,
-
-            "video_info": {
-                "width":       params.video_size.0,
-                "height":      params.video_size.1,
-                "rotation":    params.video_rotation,
-                "num_frames":  params.frame_count,
-                "fps":         params.fps,
-                "duration_ms": params.duration_ms,
-                "fps_scale":   params.fps_scale,
-                "vfr_fps":     params.get_scaled_fps(),
-                "vfr_duration_ms": params.get_scaled_duration_ms(),
-            },
-            "stabilization": {
-                "fov":                    params.fov,
-                "method":                 smoothing_name,
-                "smoothing_params":       smoothing_params,
-                "frame_readout_time":     params.frame_readout_time,
-                "adaptive_zoom_window":   params.adaptive_zoom_window,
-                "adaptive_zoom_center_offset": params.adaptive_zoom_center_offset,
-                // "adaptive_zoom_fovs":     if !thin { util::compress_to_base91(&params.fovs) } else { None },
-                "lens_correction_amount": params.lens_correction_amount,
-                "horizon_lock_amount":    horizon_amount,
-                "horizon_lock_roll":      horizon_roll,
-                "use_gravity_vectors":    gyro.use_gravity_vectors,
-                "video_speed":                   params.video_speed,
-                "video_speed_affects_smoothing": params.video_speed_affects_smoothing,
-                "video_speed_affects_zooming":   params.video_speed_affects_zooming,
-            },
-            "gyro_source": {
-                "filepath":           gyro.file_path,
-                "lpf":                gyro.imu_lpf,
-                "rotation":           gyro.imu_rotation_angles,
-                "acc_rotation":       gyro.acc_rotation_angles,
-                "imu_orientation":    gyro.imu_orientation,
-                "gyro_bias":          gyro.gyro_bias,
-                "integration_method": gyro.integration_method,
-                "sample_index":       gyro.file_load_options.sample_index,
-                "raw_imu":            if !thin { util::compress_to_base91(&gyro.org_raw_imu) } else { None },
-                "quaternions":        if !thin && input_file.path != gyro.file_path { util::compress_to_base91(&gyro.org_quaternions) } else { None },
-                "image_orientations": if !thin && input_file.path != gyro.file_path { util::compress_to_base91(&gyro.image_orientations) } else { None },
-                "gravity_vectors":    if !thin && input_file.path != gyro.file_path && gyro.gravity_vectors.is_some() { util::compress_to_base91(gyro.gravity_vectors.as_ref().unwrap()) } else { None },
-                // "smoothed_quaternions": smooth_quats
-            },
-
-            "offsets": gyro.get_offsets(), // timestamp, offset value
-            "keyframes": self.keyframes.read().serialize(),
-
-            "trim_start": params.trim_start,
-            "trim_end":   params.trim_end,
-
-            // "frame_orientation": {}, // timestamp, original frame quaternion
-            // "stab_transform":    {} // timestamp, final quaternion
-        });
-
-        util::merge_json(&mut obj, &serde_json::from_str(additional_data).unwrap_or_default());
+            ..Default::default()
+        };
 
         if extended {
-            if let Some(serde_json::Value::Object(ref mut obj)) = obj.get_mut("gyro_source") {
-                if let Some(q) = util::compress_to_base91(&gyro.quaternions) {
-                    obj.insert("integrated_quaternions".into(), serde_json::Value::String(q));
-                }
-                if let Some(q) = util::compress_to_base91(&gyro.smoothed_quaternions) {
-                    obj.insert("smoothed_quaternions".into(),   serde_json::Value::String(q));
-                }
+            if let Some(gyro_source) = project.gyro_source.as_mut() {
+                gyro_source.integrated_quaternions = util::compress_to_base91(&gyro.quaternions).map(Some);
+                gyro_source.smoothed_quaternions   = util::compress_to_base91(&gyro.smoothed_quaternions).map(Some);
             }
         }
 
+        let mut obj = serde_json::to_value(&project)?;
+        util::merge_json(&mut obj, &serde_json::from_str(additional_data).unwrap_or_default());
+
         Ok(serde_json::to_string_pretty(&obj)?)
     }
 
@@ -852,7 +946,7 @@ impl<T: PixelType> StabilizationManager<T> {
         file_path
     }
 
-    pub fn import_gyroflow_file<F: Fn(f64)>(&self, path: &str, blocking: bool, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> std::io::Result<serde_json::Value> {
+    pub fn import_gyroflow_file<F: Fn(f64)>(&self, path: &str, blocking: bool, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> std::io::Result<(serde_json::Value, project::ProjectReport)> {
         let data = std::fs::read(path)?;
 
         let mut is_preset = false;
@@ -862,11 +956,13 @@ impl<T: PixelType> StabilizationManager<T> {
         }
         result
     }
-    pub fn import_gyroflow_data<F: Fn(f64)>(&self, data: &[u8], blocking: bool, path: Option<std::path::PathBuf>, progress_cb: F, cancel_flag: Arc<AtomicBool>, is_preset: &mut bool) -> std::io::Result<serde_json::Value> {
-        let mut obj: serde_json::Value = serde_json::from_slice(&data)?;
+    pub fn import_gyroflow_data<F: Fn(f64)>(&self, data: &[u8], blocking: bool, path: Option<std::path::PathBuf>, progress_cb: F, cancel_flag: Arc<AtomicBool>, is_preset: &mut bool) -> std::io::Result<(serde_json::Value, project::ProjectReport)> {
+        let (project, mut obj, report) = project::parse(data)?;
+        report.log();
+
         if let serde_json::Value::Object(ref mut obj) = obj {
             let mut output_size = None;
-            let org_video_path = obj.get("videofile").and_then(|x| x.as_str()).unwrap_or(&"").to_string();
+            let org_video_path = project.videofile.clone().unwrap_or_default();
 
             let video_path = Self::get_new_videofile_path(&org_video_path, path.clone());
             if let Some(videofile) = obj.get_mut("videofile") {
@@ -874,95 +970,51 @@ impl<T: PixelType> StabilizationManager<T> {
             }
             *is_preset = org_video_path.is_empty();
 
-            if let Some(vid_info) = obj.get("video_info") {
+            if let Some(vid_info) = &project.video_info {
                 let mut params = self.params.write();
-                if let Some(w) = vid_info.get("width").and_then(|x| x.as_u64()) {
-                    if let Some(h) = vid_info.get("height").and_then(|x| x.as_u64()) {
-                        params.video_size = (w as usize, h as usize);
-                    }
+                if let (Some(w), Some(h)) = (vid_info.width, vid_info.height) {
+                    params.video_size = (w, h);
                 }
                 output_size = Some(params.video_size);
-                if let Some(v) = vid_info.get("rotation")   .and_then(|x| x.as_f64()) { params.video_rotation = v; }
-                if let Some(v) = vid_info.get("num_frames") .and_then(|x| x.as_u64()) { params.frame_count    = v as usize; }
-                if let Some(v) = vid_info.get("fps")        .and_then(|x| x.as_f64()) { params.fps            = v; }
-                if let Some(v) = vid_info.get("duration_ms").and_then(|x| x.as_f64()) { params.duration_ms    = v; }
-                if let Some(v) = vid_info.get("fps_scale") { params.fps_scale = v.as_f64(); }
+                if let Some(v) = vid_info.rotation    { params.video_rotation = v; }
+                if let Some(v) = vid_info.num_frames  { params.frame_count    = v; }
+                if let Some(v) = vid_info.fps         { params.fps            = v; }
+                if let Some(v) = vid_info.duration_ms { params.duration_ms    = v; }
+                if let Some(v) = vid_info.fps_scale   { params.fps_scale      = v; }
 
                 self.gyro.write().init_from_params(&params);
             }
-            if let Some(lens) = obj.get("calibration_data") {
+            if let Some(lens) = &project.calibration_data {
                 let mut l = self.lens.write();
-                l.load_from_json_value(&lens);
+                l.load_from_json_value(lens);
                 let db = self.lens_profile_db.read();
                 l.resolve_interpolations(&db);
             }
-            obj.remove("frame_orientation");
-            obj.remove("stab_transform");
-            if let Some(serde_json::Value::Object(ref mut obj)) = obj.get_mut("gyro_source") {
-                let org_gyro_path = obj.get("filepath").and_then(|x| x.as_str()).unwrap_or(&"").to_string();
+            if let Some(gyro_source) = &project.gyro_source {
+                let org_gyro_path = gyro_source.filepath.clone().unwrap_or_default();
                 let gyro_path = Self::get_new_videofile_path(&org_gyro_path, path.clone());
-                if let Some(fp) = obj.get_mut("filepath") {
-                    *fp = serde_json::Value::String(util::path_to_str(&gyro_path));
+                if let Some(serde_json::Value::Object(ref mut obj)) = obj.get_mut("gyro_source") {
+                    if let Some(fp) = obj.get_mut("filepath") {
+                        *fp = serde_json::Value::String(util::path_to_str(&gyro_path));
+                    }
+                    obj.remove("raw_imu");
+                    obj.remove("quaternions");
+                    obj.remove("smoothed_quaternions");
+                    obj.remove("image_orientations");
+                    obj.remove("gravity_vectors");
                 }
-                use crate::gyro_source::TimeIMU;
-
-                let is_compressed = obj.get("raw_imu").map(|x| x.is_string()).unwrap_or_default();
+                let load_options = crate::gyro_source::FileLoadOptions {
+                    sample_index: gyro_source.sample_index.flatten(),
+                    csv_mapping:  gyro_source.csv_mapping.clone().flatten(),
+                };
 
                 // Load IMU data only if it's from another file
                 if !org_gyro_path.is_empty() && org_gyro_path != org_video_path {
-                    let mut raw_imu = None;
-                    let mut quaternions = None;
-                    let mut image_orientations = None;
-                    let mut gravity_vectors = None;
-                    if is_compressed {
-                        if let Some(bytes) = util::decompress_from_base91(obj.get("raw_imu").and_then(|x| x.as_str()).unwrap_or_default()) {
-                            if let Ok(data) = bincode::deserialize(&bytes) as bincode::Result<Vec<TimeIMU>> {
-                                raw_imu = Some(data);
-                            }
-                        }
-                        if let Some(bytes) = util::decompress_from_base91(obj.get("quaternions").and_then(|x| x.as_str()).unwrap_or_default()) {
-                            if let Ok(data) = bincode::deserialize(&bytes) as bincode::Result<TimeQuat> {
-                                quaternions = Some(data);
-                            }
-                        }
-                        if let Some(bytes) = util::decompress_from_base91(obj.get("image_orientations").and_then(|x| x.as_str()).unwrap_or_default()) {
-                            if let Ok(data) = bincode::deserialize(&bytes) as bincode::Result<TimeQuat> {
-                                image_orientations = Some(data);
-                            }
-                        }
-                        if let Some(bytes) = util::decompress_from_base91(obj.get("gravity_vectors").and_then(|x| x.as_str()).unwrap_or_default()) {
-                            if let Ok(data) = bincode::deserialize(&bytes) as bincode::Result<TimeVec> {
-                                gravity_vectors = Some(data);
-                            }
-                        }
-                    } else {
-                        if let Some(ri) = obj.get("raw_imu") {
-                            if ri.is_array() {
-                                raw_imu = serde_json::from_value(ri.clone()).ok();
-                            }
-                        }
-                        quaternions = obj.get("quaternions")
-                            .and_then(|x| x.as_object())
-                            .and_then(|x| {
-                                let mut ret = TimeQuat::new();
-                                for (k, v) in x {
-                                    if let Ok(ts) = k.parse::<i64>() {
-                                        if let Some(v) = v.as_array() {
-                                            let v = v.into_iter().filter_map(|vv| vv.as_f64()).collect::<Vec<f64>>();
-                                            if v.len() == 4 {
-                                                let quat = Quat64::from_quaternion(nalgebra::Quaternion::from_vector(Vector4::new(v[0], v[1], v[2], v[3])));
-                                                ret.insert(ts, quat);
-                                            }
-                                        }
-                                    }
-                                }
-                                if !ret.is_empty() { Some(ret) } else { None }
-                            });
-                    }
+                    let (raw_imu, quaternions, image_orientations, gravity_vectors) = gyro_source.decode_imu_data();
 
                     if raw_imu.is_some() {
                         let md = crate::gyro_source::FileMetadata {
-                            imu_orientation: obj.get("imu_orientation").and_then(|x| x.as_str().map(|x| x.to_string())),
+                            imu_orientation: gyro_source.imu_orientation.clone().flatten(),
                             detected_source: Some("Gyroflow file".to_string()),
                             quaternions,
                             gravity_vectors,
@@ -973,18 +1025,19 @@ impl<T: PixelType> StabilizationManager<T> {
                             frame_rate: None,
                             camera_identifier: None,
                             lens_positions: None,
+                            start_time: None,
                             usable_logs: Vec::new()
                         };
 
                         let mut gyro = self.gyro.write();
                         gyro.load_from_telemetry(&md);
                     } else if gyro_path.exists() && blocking {
-                        if let Err(e) = self.load_gyro_data(&util::path_to_str(&gyro_path), &Default::default(), progress_cb, cancel_flag) {
+                        if let Err(e) = self.load_gyro_data(&util::path_to_str(&gyro_path), &load_options, progress_cb, cancel_flag) {
                             ::log::warn!("Failed to load gyro data from {:?}: {:?}", gyro_path, e);
                         }
                     }
                 } else if gyro_path.exists() && blocking {
-                    if let Err(e) = self.load_gyro_data(&util::path_to_str(&gyro_path), &Default::default(), progress_cb, cancel_flag) {
+                    if let Err(e) = self.load_gyro_data(&util::path_to_str(&gyro_path), &load_options, progress_cb, cancel_flag) {
                         ::log::warn!("Failed to load gyro data from {:?}: {:?}", gyro_path, e);
                     }
                 }
@@ -994,38 +1047,31 @@ impl<T: PixelType> StabilizationManager<T> {
                     gyro.file_path = util::path_to_str(&gyro_path);
                 }
 
-                if let Some(v) = obj.get("lpf").and_then(|x| x.as_f64()) { gyro.imu_lpf = v; }
-                if let Some(v) = obj.get("integration_method").and_then(|x| x.as_u64()) { gyro.integration_method = v as usize; }
-                if let Some(v) = obj.get("imu_orientation").and_then(|x| x.as_str()) { gyro.imu_orientation = Some(v.to_string()); }
-                if let Some(v) = obj.get("rotation")     { gyro.imu_rotation_angles = serde_json::from_value(v.clone()).ok(); }
-                if let Some(v) = obj.get("acc_rotation") { gyro.acc_rotation_angles = serde_json::from_value(v.clone()).ok(); }
-                if let Some(v) = obj.get("gyro_bias")    { gyro.gyro_bias           = serde_json::from_value(v.clone()).ok(); }
-
-                obj.remove("raw_imu");
-                obj.remove("quaternions");
-                obj.remove("smoothed_quaternions");
-                obj.remove("image_orientations");
-                obj.remove("gravity_vectors");
+                if let Some(v) = gyro_source.lpf                { gyro.imu_lpf = v; }
+                if let Some(v) = &gyro_source.notch             { gyro.imu_notch = v.clone(); }
+                if let Some(v) = gyro_source.integration_method { gyro.integration_method = v; }
+                if let Some(Some(v)) = &gyro_source.imu_orientation { gyro.imu_orientation = Some(v.clone()); }
+                if let Some(v) = gyro_source.rotation     { gyro.imu_rotation_angles = v; }
+                if let Some(v) = gyro_source.acc_rotation { gyro.acc_rotation_angles = v; }
+                if let Some(v) = gyro_source.gyro_bias    { gyro.gyro_bias           = v; }
+                if let Some(v) = &gyro_source.gyro_bias_curve { gyro.gyro_bias_curve = v.clone(); }
+                if let Some(v) = gyro_source.resampling   { gyro.imu_resampling      = v; }
+                if let Some(v) = gyro_source.saturation   { gyro.saturation          = v; }
             }
-            if let Some(serde_json::Value::Object(ref mut obj)) = obj.get_mut("stabilization") {
+            if let Some(stab) = &project.stabilization {
                 let mut params = self.params.write();
-                if let Some(v) = obj.get("fov")                   .and_then(|x| x.as_f64()) { params.fov                     = v; }
-                if let Some(v) = obj.get("frame_readout_time")    .and_then(|x| x.as_f64()) { params.frame_readout_time      = v; }
-                if let Some(v) = obj.get("adaptive_zoom_window")  .and_then(|x| x.as_f64()) { params.adaptive_zoom_window    = v; }
-                if let Some(v) = obj.get("lens_correction_amount").and_then(|x| x.as_f64()) { params.lens_correction_amount  = v; }
-
-                if let Some(v) = obj.get("video_speed").and_then(|x| x.as_f64()) { params.video_speed = v; }
-                if let Some(v) = obj.get("video_speed_affects_smoothing").and_then(|x| x.as_bool()) { params.video_speed_affects_smoothing = v; }
-                if let Some(v) = obj.get("video_speed_affects_zooming")  .and_then(|x| x.as_bool()) { params.video_speed_affects_zooming   = v; }
-
-                if let Some(center_offs) = obj.get("adaptive_zoom_center_offset").and_then(|x| x.as_array()) {
-                    params.adaptive_zoom_center_offset = (
-                        center_offs.get(0).and_then(|x| x.as_f64()).unwrap_or_default(),
-                        center_offs.get(1).and_then(|x| x.as_f64()).unwrap_or_default()
-                    );
-                }
+                if let Some(v) = stab.fov                    { params.fov                    = v; }
+                if let Some(v) = stab.frame_readout_time     { params.frame_readout_time     = v; }
+                if let Some(v) = stab.adaptive_zoom_window   { params.adaptive_zoom_window   = v; }
+                if let Some(v) = stab.lens_correction_amount { params.lens_correction_amount = v; }
+
+                if let Some(v) = stab.video_speed                   { params.video_speed = v; }
+                if let Some(v) = stab.video_speed_affects_smoothing { params.video_speed_affects_smoothing = v; }
+                if let Some(v) = stab.video_speed_affects_zooming   { params.video_speed_affects_zooming   = v; }
 
-                if let Some(method) = obj.get("method").and_then(|x| x.as_str()) {
+                if let Some(v) = stab.adaptive_zoom_center_offset { params.adaptive_zoom_center_offset = v; }
+
+                if let Some(method) = &stab.method {
                     let method_idx = self.get_smoothing_algs()
                         .iter().enumerate()
                         .find(|(_, m)| method == m.as_str())
@@ -1036,29 +1082,20 @@ impl<T: PixelType> StabilizationManager<T> {
                 }
 
                 let mut smoothing = self.smoothing.write();
-                let empty_vec = Vec::new();
-                let smoothing_params = obj.get("smoothing_params").and_then(|x| x.as_array()).unwrap_or(&empty_vec);
                 let smoothing_alg = smoothing.current_mut();
-                for param in smoothing_params {
-                    (|| -> Option<()> {
-                        let name = param.get("name").and_then(|x| x.as_str())?;
-                        let value = param.get("value").and_then(|x| x.as_f64())?;
+                for param in stab.smoothing_params.iter().flatten() {
+                    if let (Some(name), Some(value)) = (&param.name, param.value) {
                         smoothing_alg.set_parameter(name, value);
-                        Some(())
-                    })();
-                }
-                if let Some(horizon_amount) = obj.get("horizon_lock_amount").and_then(|x| x.as_f64()) {
-                    if let Some(horizon_roll) = obj.get("horizon_lock_roll").and_then(|x| x.as_f64()) {
-                        smoothing.horizon_lock.set_horizon(horizon_amount, horizon_roll);
                     }
                 }
-                if let Some(v) = obj.get("use_gravity_vectors").and_then(|x| x.as_bool()) {
+                if let (Some(horizon_amount), Some(horizon_roll)) = (stab.horizon_lock_amount, stab.horizon_lock_roll) {
+                    smoothing.horizon_lock.set_horizon(horizon_amount, horizon_roll);
+                }
+                if let Some(v) = stab.use_gravity_vectors {
                     self.gyro.write().set_use_gravity_vectors(v);
                 }
-
-                obj.remove("adaptive_zoom_fovs");
             }
-            if let Some(serde_json::Value::Object(ref obj)) = obj.get("output") {
+            if let Some(serde_json::Value::Object(ref obj)) = project.output {
                 if let Some(w) =  obj.get("output_width").and_then(|x| x.as_u64()) {
                     if let Some(h) =  obj.get("output_height").and_then(|x| x.as_u64()) {
                         output_size = Some((w as usize, h as usize));
@@ -1066,47 +1103,41 @@ impl<T: PixelType> StabilizationManager<T> {
                 }
             }
 
-            if let Some(serde_json::Value::Object(offsets)) = obj.get("offsets") {
+            if let Some(drift_model) = project.drift_model {
+                self.gyro.write().drift_model = drift_model;
+            }
+            if let Some(offsets) = &project.offsets {
                 let mut gyro = self.gyro.write();
-                gyro.set_offsets(offsets.iter().filter_map(|(k, v)| Some((k.parse().ok()?, v.as_f64()?))).collect());
+                gyro.set_offsets(offsets.clone());
                 self.keyframes.write().update_gyro(&gyro);
             }
 
-            if let Some(keyframes) = obj.get("keyframes") {
+            if let Some(keyframes) = &project.keyframes {
                 self.keyframes.write().deserialize(keyframes);
             }
 
-            if let Some(start) = obj.get("trim_start").and_then(|x| x.as_f64()) {
-                if let Some(end) = obj.get("trim_end").and_then(|x| x.as_f64()) {
-                    let mut params = self.params.write();
-                    params.trim_start = start;
-                    params.trim_end = end;
-                }
+            if let (Some(start), Some(end)) = (project.trim_start, project.trim_end) {
+                let mut params = self.params.write();
+                params.trim_start = start;
+                params.trim_end = end;
             }
 
             {
                 let mut params = self.params.write();
-                if let Some(v) = obj.get("background_color").and_then(|x| x.as_array()) {
-                    if v.len() == 4 {
-                        params.background = nalgebra::Vector4::new(
-                            v[0].as_f64().unwrap_or_default() as f32,
-                            v[1].as_f64().unwrap_or_default() as f32,
-                            v[2].as_f64().unwrap_or_default() as f32,
-                            v[3].as_f64().unwrap_or_default() as f32
-                        );
-                    }
+                if let Some(v) = project.background_color {
+                    params.background = Vector4::new(v[0], v[1], v[2], v[3]);
                 }
-                if let Some(v) = obj.get("background_mode").and_then(|x| x.as_i64()) { params.background_mode = stabilization_params::BackgroundMode::from(v as i32); }
-                if let Some(v) = obj.get("background_margin").and_then(|x| x.as_f64()) { params.background_margin = v; }
-                if let Some(v) = obj.get("background_margin_feather").and_then(|x| x.as_f64()) { params.background_margin_feather = v; }
+                if let Some(v) = project.background_mode           { params.background_mode = stabilization_params::BackgroundMode::from(v); }
+                if let Some(v) = project.background_margin         { params.background_margin = v; }
+                if let Some(v) = project.background_margin_feather { params.background_margin_feather = v; }
             }
 
             {
                 let mut input_file = self.input_file.write();
-                if let Some(seq_start) = obj.get("image_sequence_start").and_then(|x| x.as_i64()) {
-                    input_file.image_sequence_start = seq_start as i32;
+                if let Some(seq_start) = project.image_sequence_start {
+                    input_file.image_sequence_start = seq_start;
                 }
-                if let Some(seq_fps) = obj.get("image_sequence_fps").and_then(|x| x.as_f64()) {
+                if let Some(seq_fps) = project.image_sequence_fps {
                     input_file.image_sequence_fps = seq_fps;
                 }
                 if !org_video_path.is_empty() {
@@ -1126,7 +1157,7 @@ impl<T: PixelType> StabilizationManager<T> {
                 self.recompute_blocking();
             }
         }
-        Ok(obj)
+        Ok((obj, report))
     }
 
     pub fn set_keyframe(&self, typ: &KeyframeType, timestamp_us: i64, value: f64) {
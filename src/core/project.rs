@@ -0,0 +1,332 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Typed definition of the .gyroflow project file, with validation and migration from older versions.
+// All fields are optional, because presets contain only a subset of the values.
+// Fields are parsed one by one, so an invalid value is reported and skipped instead of discarding the whole file.
+
+use std::collections::BTreeMap;
+use serde::{ Serialize, Deserialize };
+use serde_json::Value;
+use schemars::JsonSchema;
+use crate::gyro_source::{ Quat64, TimeIMU, TimeQuat, TimeVec };
+use crate::synchronization::drift::DriftModelSettings;
+use crate::imu_resampling::ResampleSettings;
+use crate::saturation::SaturationSettings;
+use crate::filtering::NotchSettings;
+use crate::util;
+
+pub const PROJECT_VERSION: u64 = 3;
+
+/// Problems found while reading the project file. Paths are in the `section.field` form
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct ProjectReport {
+    pub version: Option<u64>,
+    pub migrated: Vec<String>,
+    pub invalid: Vec<String>,
+    pub ignored: Vec<String>,
+}
+impl ProjectReport {
+    pub fn is_valid(&self) -> bool { self.invalid.is_empty() }
+    pub fn is_empty(&self) -> bool { self.migrated.is_empty() && self.invalid.is_empty() && self.ignored.is_empty() }
+
+    fn invalid(&mut self, path: &str, err: impl std::fmt::Display) { self.invalid.push(format!("{}: {}", path, err)); }
+    fn ignored(&mut self, path: &str) { self.ignored.push(path.to_string()); }
+
+    pub fn log(&self) {
+        for x in &self.migrated { ::log::info!("Project migration: {}", x); }
+        for x in &self.invalid  { ::log::warn!("Invalid project field {}", x); }
+        for x in &self.ignored  { ::log::warn!("Unknown project field {} was ignored", x); }
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) }
+}
+
+pub trait ProjectValue: Sized {
+    fn parse(v: &Value, path: &str, report: &mut ProjectReport) -> Option<Self>;
+}
+macro_rules! serde_value {
+    ($($t:ty),*) => {
+        $(impl ProjectValue for $t {
+            fn parse(v: &Value, path: &str, report: &mut ProjectReport) -> Option<Self> {
+                match <$t>::deserialize(v) {
+                    Ok(x) => Some(x),
+                    Err(e) => { report.invalid(path, e); None }
+                }
+            }
+        })*
+    };
+}
+serde_value!(bool, i32, u64, usize, f64, String, Value, [f32; 4], [f64; 3], (f64, f64), (f64, [f64; 3]), BTreeMap<i64, f64>, DriftModelSettings, ResampleSettings, SaturationSettings, NotchSettings);
+
+// `null` is a valid value for nullable fields
+impl<T: ProjectValue> ProjectValue for Option<T> {
+    fn parse(v: &Value, path: &str, report: &mut ProjectReport) -> Option<Self> {
+        if v.is_null() { Some(None) } else { T::parse(v, path, report).map(Some) }
+    }
+}
+// Invalid items are skipped, the rest of the array is kept
+impl<T: ProjectValue> ProjectValue for Vec<T> {
+    fn parse(v: &Value, path: &str, report: &mut ProjectReport) -> Option<Self> {
+        match v.as_array() {
+            Some(arr) => Some(arr.iter().enumerate().filter_map(|(i, x)| T::parse(x, &format!("{}[{}]", path, i), report)).collect()),
+            None => { report.invalid(path, "expected an array"); None }
+        }
+    }
+}
+
+macro_rules! project_struct {
+    ($(#[$attr:meta])* pub struct $name:ident { $($(#[$fattr:meta])* $field:ident: $ty:ty,)* }) => {
+        $(#[$attr])*
+        #[derive(Serialize, JsonSchema, Default, Clone, Debug, PartialEq)]
+        pub struct $name {
+            $(
+                $(#[$fattr])*
+                #[serde(default, skip_serializing_if = "Option::is_none")]
+                pub $field: Option<$ty>,
+            )*
+        }
+        impl ProjectValue for $name {
+            fn parse(v: &Value, path: &str, report: &mut ProjectReport) -> Option<Self> {
+                let obj = match v.as_object() {
+                    Some(obj) => obj,
+                    None => { report.invalid(path, "expected an object"); return None; }
+                };
+                let mut ret = Self::default();
+                for (k, v) in obj {
+                    let path = join(path, k);
+                    match k.as_str() {
+                        $(stringify!($field) => { ret.$field = <$ty as ProjectValue>::parse(v, &path, report); },)*
+                        _ => report.ignored(&path)
+                    }
+                }
+                Some(ret)
+            }
+        }
+    };
+}
+
+project_struct! {
+    /// Root object of the .gyroflow file
+    pub struct ProjectFile {
+        title: String,
+        version: u64,
+        app_version: String,
+        /// Path to the video file. Empty for presets
+        videofile: String,
+        /// Lens profile
+        calibration_data: Value,
+        date: String,
+
+        image_sequence_start: i32,
+        image_sequence_fps: f64,
+        /// RGBA
+        background_color: [f32; 4],
+        /// 0 - solid color, 1 - repeat edge pixels, 2 - mirror edge pixels, 3 - margin with feather
+        background_mode: i32,
+        background_margin: f64,
+        background_margin_feather: f64,
+
+        video_info: VideoInfo,
+        stabilization: StabilizationSettings,
+        gyro_source: GyroSourceSettings,
+
+        /// Sync offsets in milliseconds, key is the timestamp in microseconds
+        offsets: BTreeMap<i64, f64>,
+        /// Clock drift model fitted to the offsets. `null` uses the offsets directly
+        drift_model: Option<DriftModelSettings>,
+        keyframes: Value,
+
+        trim_start: f64,
+        trim_end: f64,
+
+        /// Render settings, owned by the application
+        output: Value,
+        /// Synchronization settings, owned by the application
+        synchronization: Value,
+        muted: bool,
+        playback_speed: f64,
+    }
+}
+
+project_struct! {
+    pub struct VideoInfo {
+        width: usize,
+        height: usize,
+        rotation: f64,
+        num_frames: usize,
+        fps: f64,
+        duration_ms: f64,
+        fps_scale: Option<f64>,
+        vfr_fps: f64,
+        vfr_duration_ms: f64,
+    }
+}
+
+project_struct! {
+    pub struct StabilizationSettings {
+        fov: f64,
+        /// Smoothing algorithm name
+        method: String,
+        smoothing_params: Vec<SmoothingParam>,
+        frame_readout_time: f64,
+        adaptive_zoom_window: f64,
+        adaptive_zoom_center_offset: (f64, f64),
+        lens_correction_amount: f64,
+        horizon_lock_amount: f64,
+        horizon_lock_roll: f64,
+        use_gravity_vectors: bool,
+        video_speed: f64,
+        video_speed_affects_smoothing: bool,
+        video_speed_affects_zooming: bool,
+    }
+}
+
+project_struct! {
+    pub struct SmoothingParam {
+        name: String,
+        value: f64,
+    }
+}
+
+project_struct! {
+    pub struct GyroSourceSettings {
+        filepath: String,
+        lpf: f64,
+        /// Static and dynamic notch filters for each axis. `null` disables them
+        notch: Option<NotchSettings>,
+        rotation: Option<[f64; 3]>,
+        acc_rotation: Option<[f64; 3]>,
+        imu_orientation: Option<String>,
+        gyro_bias: Option<[f64; 3]>,
+        /// Time-varying bias as [timestamp_ms, [x, y, z]] pairs, used instead of gyro_bias if set
+        gyro_bias_curve: Option<Vec<(f64, [f64; 3])>>,
+        /// Resampling to a uniform rate before the integration. `null` uses the samples as they are
+        resampling: Option<ResampleSettings>,
+        /// Detection and reconstruction of clipped gyro samples. `null` disables it
+        saturation: Option<SaturationSettings>,
+        integration_method: usize,
+        sample_index: Option<usize>,
+        /// Column mapping descriptor for CSV logs
+        csv_mapping: Option<String>,
+        /// Compressed string or an array of samples
+        raw_imu: Value,
+        /// Compressed string or an object with timestamp keys and [w, x, y, z] values
+        quaternions: Value,
+        image_orientations: Option<String>,
+        gravity_vectors: Option<String>,
+        integrated_quaternions: Option<String>,
+        smoothed_quaternions: Option<String>,
+    }
+}
+
+impl GyroSourceSettings {
+    /// Decodes the embedded IMU data: (raw_imu, quaternions, image_orientations, gravity_vectors)
+    pub fn decode_imu_data(&self) -> (Option<Vec<TimeIMU>>, Option<TimeQuat>, Option<TimeQuat>, Option<TimeVec>) {
+        fn decompress<T: serde::de::DeserializeOwned>(s: Option<&str>) -> Option<T> {
+            let bytes = util::decompress_from_base91(s?)?;
+            bincode::deserialize(&bytes).ok()
+        }
+        let raw_imu = self.raw_imu.as_ref().unwrap_or(&Value::Null);
+        let quaternions = self.quaternions.as_ref().unwrap_or(&Value::Null);
+
+        if raw_imu.is_string() {
+            (
+                decompress(raw_imu.as_str()),
+                decompress(quaternions.as_str()),
+                decompress(self.image_orientations.as_ref().and_then(|x| x.as_deref())),
+                decompress(self.gravity_vectors.as_ref().and_then(|x| x.as_deref())),
+            )
+        } else {
+            let raw_imu = if raw_imu.is_array() { serde_json::from_value(raw_imu.clone()).ok() } else { None };
+            let quaternions = quaternions.as_object().and_then(|x| {
+                let mut ret = TimeQuat::new();
+                for (k, v) in x {
+                    if let Ok(ts) = k.parse::<i64>() {
+                        if let Some(v) = v.as_array() {
+                            let v = v.iter().filter_map(|vv| vv.as_f64()).collect::<Vec<f64>>();
+                            if v.len() == 4 {
+                                ret.insert(ts, Quat64::from_quaternion(nalgebra::Quaternion::from_vector(nalgebra::Vector4::new(v[0], v[1], v[2], v[3]))));
+                            }
+                        }
+                    }
+                }
+                if !ret.is_empty() { Some(ret) } else { None }
+            });
+            (raw_imu, quaternions, None, None)
+        }
+    }
+}
+
+/// Upgrades the project object to the current version in place
+pub fn migrate(obj: &mut Value, report: &mut ProjectReport) {
+    let obj = match obj.as_object_mut() {
+        Some(obj) => obj,
+        None => return
+    };
+    let version = obj.get("version").and_then(|x| x.as_u64());
+    report.version = version;
+
+    // Presets can skip the version
+    let version = match version {
+        Some(v) => v,
+        None => return
+    };
+    if version > PROJECT_VERSION {
+        report.migrated.push(format!("File version {} is newer than supported ({}), some fields may be ignored", version, PROJECT_VERSION));
+        return;
+    }
+    if version == PROJECT_VERSION { return; }
+
+    // v1, v2 -> v3
+    for key in ["frame_orientation", "stab_transform"] {
+        if obj.remove(key).is_some() {
+            report.migrated.push(format!("Removed deprecated field {}", key));
+        }
+    }
+    if let Some(Value::Object(stab)) = obj.get_mut("stabilization") {
+        if stab.remove("adaptive_zoom_fovs").is_some() {
+            report.migrated.push("Removed deprecated field stabilization.adaptive_zoom_fovs".into());
+        }
+        // Older versions stored the full parameter definition, only the name and value are used
+        if let Some(Value::Array(params)) = stab.get_mut("smoothing_params") {
+            let mut stripped = false;
+            for v in params.iter_mut() {
+                if let Value::Object(p) = v {
+                    if p.keys().any(|k| k != "name" && k != "value") {
+                        p.retain(|k, _| k == "name" || k == "value");
+                        stripped = true;
+                    }
+                }
+            }
+            if stripped {
+                report.migrated.push("Reduced stabilization.smoothing_params to name and value".into());
+            }
+        }
+    }
+    obj.insert("version".into(), Value::from(PROJECT_VERSION));
+    report.migrated.push(format!("Migrated from version {} to {}", version, PROJECT_VERSION));
+}
+
+/// Parses the project data and migrates it to the current version.
+/// Returns the typed project, the migrated JSON object and the list of problems
+pub fn parse(data: &[u8]) -> serde_json::Result<(ProjectFile, Value, ProjectReport)> {
+    let mut obj: Value = serde_json::from_slice(data)?;
+    let mut report = ProjectReport::default();
+    migrate(&mut obj, &mut report);
+
+    let project = ProjectFile::parse(&obj, "", &mut report).unwrap_or_default();
+    if let Some(lens) = &project.calibration_data {
+        if let Err(e) = <crate::lens_profile::LensProfile as Deserialize>::deserialize(lens) {
+            report.invalid("calibration_data", e);
+        }
+    }
+    Ok((project, obj, report))
+}
+
+/// JSON Schema of the current project version
+pub fn json_schema() -> Value {
+    serde_json::to_value(schemars::schema_for!(ProjectFile)).unwrap_or_default()
+}
@@ -0,0 +1,293 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Detection and reconstruction of gyro samples clipped at the full-scale range of the sensor.
+// Clipped samples lose part of the rotation, so the integrated orientation drifts permanently after the event.
+// Works on the raw sensor axes, before the orientation, rotation and low-pass filter are applied.
+
+use nalgebra::{ UnitQuaternion, Vector3 };
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use crate::gyro_source::TimeIMU;
+
+// Common gyro ranges are 250, 500, 1000, 2000 and 4000 deg/s, anything lower isn't a saturation
+const MIN_FULL_SCALE: f64 = 240.0;
+// Number of unclipped samples on each side of the segment used for the reconstruction
+const EDGE_SAMPLES: usize = 5;
+// Maximum difference between consecutive clipped samples, as a fraction of the full-scale range.
+// A clipped sensor outputs the same value, while a sharp peak changes from sample to sample
+const PLATEAU_TOLERANCE: f64 = 0.002;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconstructionMethod {
+    /// Optical flow if available for the segment, spline otherwise
+    #[default]
+    Auto,
+    /// Quadratic fit to the samples around the clipped segment
+    Spline,
+    /// Spline, scaled so the gravity direction after the segment matches the accelerometer
+    Accelerometer,
+    /// Rotation estimated from the video by the `PoseEstimator`
+    OpticalFlow,
+}
+impl ReconstructionMethod {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto"          => Some(Self::Auto),
+            "spline"        => Some(Self::Spline),
+            "accelerometer" => Some(Self::Accelerometer),
+            "optical_flow"  => Some(Self::OpticalFlow),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SaturationSettings {
+    /// Full-scale range in deg/s. `None` detects it from the data
+    pub full_scale: Option<f64>,
+    /// Samples above this fraction of the full-scale range are considered clipped
+    pub threshold: f64,
+    /// Minimum number of consecutive clipped samples with almost equal values
+    pub min_samples: usize,
+    pub method: ReconstructionMethod,
+}
+impl Default for SaturationSettings {
+    fn default() -> Self {
+        Self {
+            full_scale: None,
+            threshold: 0.99,
+            min_samples: 3,
+            method: ReconstructionMethod::Auto,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SaturatedSegment {
+    pub start_ms: f64,
+    pub end_ms: f64,
+    /// Clipped axes
+    pub axes: [bool; 3],
+    /// `None` if all reconstruction methods failed and the segment is left clipped
+    pub method: Option<ReconstructionMethod>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SaturationReport {
+    pub full_scale: Option<f64>,
+    pub segments: Vec<SaturatedSegment>,
+}
+impl SaturationReport {
+    pub fn log(&self) {
+        if self.segments.is_empty() { return; }
+        ::log::warn!("Gyro saturated at {:.0} deg/s in {} segments", self.full_scale.unwrap_or_default(), self.segments.len());
+        for s in &self.segments {
+            match s.method {
+                Some(method) => ::log::warn!("Gyro saturation at {:.3}..{:.3} ms, axes {:?}, reconstructed with {:?}", s.start_ms, s.end_ms, s.axes, method),
+                None         => ::log::warn!("Gyro saturation at {:.3}..{:.3} ms, axes {:?}, not reconstructed", s.start_ms, s.end_ms, s.axes),
+            }
+        }
+    }
+}
+
+/// Flat runs of samples above `level` on one axis (start index, end index exclusive), at least `min_samples` long.
+/// Consecutive samples in a run have the same sign and differ by at most `tolerance`
+fn plateaus(imu: &[TimeIMU], axis: usize, level: f64, tolerance: f64, min_samples: usize) -> Vec<(usize, usize)> {
+    let value = |i: usize| imu[i].gyro.map(|g| g[axis]).filter(|v| v.abs() >= level);
+    let continues = |i: usize| match (value(i - 1), value(i)) {
+        (Some(a), Some(b)) => a.signum() == b.signum() && (a - b).abs() <= tolerance,
+        _ => false
+    };
+
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < imu.len() {
+        if value(i).is_none() { i += 1; continue; }
+        let start = i;
+        i += 1;
+        while i < imu.len() && continues(i) { i += 1; }
+        if i - start >= min_samples.max(2) {
+            ret.push((start, i));
+        }
+    }
+    ret
+}
+
+/// Full-scale range from the maximum value, if it's clipped
+pub fn detect_full_scale(imu: &[TimeIMU], settings: &SaturationSettings) -> Option<f64> {
+    let max = imu.iter().filter_map(|x| x.gyro).flat_map(|g| g.map(f64::abs)).fold(0.0, f64::max);
+    if max < MIN_FULL_SCALE { return None; }
+
+    // The maximum has to be a flat plateau, not a sharp peak
+    let level = max * settings.threshold;
+    if (0..3).any(|axis| !plateaus(imu, axis, level, max * PLATEAU_TOLERANCE, settings.min_samples).is_empty()) {
+        Some(max)
+    } else {
+        None
+    }
+}
+
+/// Clipped sample ranges (start index, end index exclusive) with the clipped axes
+pub fn find_segments(imu: &[TimeIMU], full_scale: f64, settings: &SaturationSettings) -> Vec<(usize, usize, [bool; 3])> {
+    let level = full_scale * settings.threshold;
+
+    let mut ret: Vec<(usize, usize, [bool; 3])> = Vec::new();
+    for axis in 0..3 {
+        for (start, end) in plateaus(imu, axis, level, full_scale * PLATEAU_TOLERANCE, settings.min_samples) {
+            // Merge with overlapping segments of the other axes
+            match ret.iter_mut().find(|x| start < x.1 && end > x.0) {
+                Some(seg) => {
+                    seg.0 = seg.0.min(start);
+                    seg.1 = seg.1.max(end);
+                    seg.2[axis] = true;
+                },
+                None => {
+                    let mut axes = [false; 3];
+                    axes[axis] = true;
+                    ret.push((start, end, axes));
+                }
+            }
+        }
+    }
+    ret.sort_by_key(|x| x.0);
+    ret
+}
+
+/// Quadratic fit of the samples around the segment, the result is at least the clipped value
+fn reconstruct_spline(imu: &mut [TimeIMU], start: usize, end: usize, axes: [bool; 3]) -> bool {
+    let before = start.saturating_sub(EDGE_SAMPLES)..start;
+    let after = end..(end + EDGE_SAMPLES).min(imu.len());
+    let points: Vec<(f64, [f64; 3])> = before.chain(after).filter_map(|i| Some((imu[i].timestamp_ms, imu[i].gyro?))).collect();
+    if points.len() < 3 { return false; }
+
+    let t0 = imu[start].timestamp_ms;
+    for axis in (0..3).filter(|a| axes[*a]) {
+        let a = nalgebra::DMatrix::from_fn(points.len(), 3, |r, c| (points[r].0 - t0).powi(c as i32));
+        let b = nalgebra::DVector::from_fn(points.len(), |r, _| points[r].1[axis]);
+        let coeffs = match a.svd(true, true).solve(&b, 1e-12) {
+            Ok(x) => x,
+            Err(_) => return false
+        };
+        for x in &mut imu[start..end] {
+            if let Some(g) = x.gyro.as_mut() {
+                let t = x.timestamp_ms - t0;
+                let fitted = coeffs[0] + coeffs[1] * t + coeffs[2] * t * t;
+                if fitted.signum() == g[axis].signum() && fitted.abs() > g[axis].abs() {
+                    g[axis] = fitted;
+                }
+            }
+        }
+    }
+    true
+}
+
+fn mean_accl(imu: &[TimeIMU]) -> Option<Vector3<f64>> {
+    let v: Vec<Vector3<f64>> = imu.iter().filter_map(|x| x.accl.map(Vector3::from)).collect();
+    if v.is_empty() { return None; }
+    Some(v.iter().sum::<Vector3<f64>>() / v.len() as f64)
+}
+
+/// Scales the part above the clip level, so the rotation over the segment moves the gravity vector
+/// from the direction measured before the segment to the one measured after it.
+/// Assumes the linear acceleration around the segment is small compared to the gravity
+fn reconstruct_accelerometer(imu: &mut [TimeIMU], start: usize, end: usize, clip: &[Option<[f64; 3]>]) -> bool {
+    let g_before = match mean_accl(&imu[start.saturating_sub(EDGE_SAMPLES)..start]) { Some(x) => x, None => return false };
+    let g_after  = match mean_accl(&imu[end..(end + EDGE_SAMPLES).min(imu.len())]) { Some(x) => x, None => return false };
+    if g_before.norm() < 1e-6 || g_after.norm() < 1e-6 { return false; }
+
+    let spline: Vec<Option<[f64; 3]>> = imu[start..end].iter().map(|x| x.gyro).collect();
+    let scaled = |s: f64| -> Vec<Option<[f64; 3]>> {
+        spline.iter().zip(clip).map(|(f, c)| Some(match (f, c) {
+            (Some(f), Some(c)) => [0, 1, 2].map(|i| c[i] + (f[i] - c[i]) * s),
+            (f, _) => (*f)?
+        })).collect()
+    };
+    let cost = |s: f64| -> f64 {
+        let mut rot = UnitQuaternion::identity();
+        let values = scaled(s);
+        for (i, g) in values.iter().enumerate() {
+            let idx = start + i;
+            let dt = (if idx + 1 < imu.len() { imu[idx + 1].timestamp_ms - imu[idx].timestamp_ms } else { 0.0 }) / 1000.0;
+            if let Some(g) = g {
+                rot *= UnitQuaternion::from_scaled_axis(Vector3::from(*g) * (std::f64::consts::PI / 180.0) * dt);
+            }
+        }
+        let predicted = rot.inverse_transform_vector(&g_before);
+        predicted.angle(&g_after)
+    };
+
+    // Golden-section search
+    let (mut a, mut b) = (0.0, 4.0);
+    let ratio = (5.0f64.sqrt() - 1.0) / 2.0;
+    for _ in 0..40 {
+        let c = b - (b - a) * ratio;
+        let d = a + (b - a) * ratio;
+        if cost(c) < cost(d) { b = d; } else { a = c; }
+    }
+    let values = scaled((a + b) / 2.0);
+    for (x, v) in imu[start..end].iter_mut().zip(values) {
+        x.gyro = v;
+    }
+    true
+}
+
+/// `reference` is the sorted gyro estimated from the video, in the same axes and time base as `imu`
+fn reconstruct_optical_flow(imu: &mut [TimeIMU], start: usize, end: usize, axes: [bool; 3], reference: &[TimeIMU]) -> bool {
+    let at = |ts: f64| -> Option<[f64; 3]> {
+        let i = reference.partition_point(|x| x.timestamp_ms < ts);
+        let (a, b) = (reference.get(i.checked_sub(1)?)?, reference.get(i)?);
+        // Don't interpolate over more than a few frames
+        if b.timestamp_ms - a.timestamp_ms > 100.0 { return None; }
+        let t = (ts - a.timestamp_ms) / (b.timestamp_ms - a.timestamp_ms).max(1e-9);
+        let (ga, gb) = (a.gyro?, b.gyro?);
+        Some([0, 1, 2].map(|i| ga[i] + (gb[i] - ga[i]) * t))
+    };
+    let values: Option<Vec<[f64; 3]>> = imu[start..end].iter().map(|x| at(x.timestamp_ms)).collect();
+    let values = match values { Some(x) => x, None => return false };
+
+    for (x, v) in imu[start..end].iter_mut().zip(values) {
+        if let Some(g) = x.gyro.as_mut() {
+            for axis in (0..3).filter(|a| axes[*a]) {
+                if v[axis].signum() == g[axis].signum() && v[axis].abs() > g[axis].abs() {
+                    g[axis] = v[axis];
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Detects the clipped segments and reconstructs them in place
+pub fn reconstruct(imu: &mut [TimeIMU], settings: &SaturationSettings, reference: Option<&[TimeIMU]>) -> SaturationReport {
+    let full_scale = match settings.full_scale.or_else(|| detect_full_scale(imu, settings)) {
+        Some(x) => x,
+        None => return SaturationReport::default()
+    };
+    let mut report = SaturationReport { full_scale: Some(full_scale), segments: Vec::new() };
+
+    for (start, end, axes) in find_segments(imu, full_scale, settings) {
+        let clip: Vec<Option<[f64; 3]>> = imu[start..end].iter().map(|x| x.gyro).collect();
+
+        let use_optical_flow = matches!(settings.method, ReconstructionMethod::OpticalFlow | ReconstructionMethod::Auto);
+        // The accelerometer method scales the spline, so it needs the spline first
+        let method = if use_optical_flow && reference.map(|r| reconstruct_optical_flow(imu, start, end, axes, r)).unwrap_or_default() {
+            Some(ReconstructionMethod::OpticalFlow)
+        } else if !reconstruct_spline(imu, start, end, axes) {
+            None
+        } else if settings.method == ReconstructionMethod::Accelerometer && reconstruct_accelerometer(imu, start, end, &clip) {
+            Some(ReconstructionMethod::Accelerometer)
+        } else {
+            Some(ReconstructionMethod::Spline)
+        };
+
+        report.segments.push(SaturatedSegment {
+            start_ms: imu[start].timestamp_ms,
+            end_ms: imu[end - 1].timestamp_ms,
+            axes,
+            method
+        });
+    }
+    report
+}
@@ -0,0 +1,268 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Offline smoothing with a Kalman filter and a Rauch-Tung-Striebel smoother on the quaternion manifold.
+// The camera orientation is modeled as a constant angular velocity (or constant angular acceleration) process,
+// the raw orientations are the measurements. The forward filter is followed by the backward RTS pass, so there's no phase lag.
+// The state is the orientation + angular velocity (+ acceleration), with the error state in the body frame.
+// All of the model matrices are the same for each axis, so the covariance is a small per-axis matrix shared by the three axes.
+// The process noise is derived from the time constant, so the bandwidth of the smoother doesn't depend on the sample rate.
+
+use super::*;
+use nalgebra::{ DMatrix, DVector, Matrix3, Vector3 };
+use crate::gyro_source::{ TimeQuat, Quat64 };
+use crate::keyframes::*;
+use std::collections::BTreeMap;
+
+// Measurement noise variance, only the ratio to the process noise matters
+const MEASUREMENT_NOISE: f64 = 1.0;
+
+#[derive(Clone)]
+pub struct KalmanRts {
+    pub time_constant: f64,
+    pub constant_acceleration: bool,
+    pub trim_range_only: bool,
+}
+
+impl Default for KalmanRts {
+    fn default() -> Self { Self {
+        time_constant: 0.5,
+        constant_acceleration: false,
+        trim_range_only: false,
+    } }
+}
+
+// Mean of the state: orientation and its derivatives (angular velocity, acceleration) in the body frame
+#[derive(Clone, Copy)]
+struct State {
+    q: Quat64,
+    rates: [Vector3<f64>; 2],
+}
+
+// Per-axis transition matrix of the derivatives, truncated to the state size
+fn transition(n: usize, dt: f64) -> DMatrix<f64> {
+    let full = Matrix3::new(
+        1.0, dt,  dt * dt / 2.0,
+        0.0, 1.0, dt,
+        0.0, 0.0, 1.0
+    );
+    DMatrix::from_fn(n, n, |i, j| full[(i, j)])
+}
+
+// Per-axis process noise of white noise on the highest derivative, with spectral density `q`
+fn process_noise(n: usize, dt: f64, q: f64) -> DMatrix<f64> {
+    let (dt2, dt3) = (dt * dt, dt * dt * dt);
+    if n == 2 {
+        DMatrix::from_row_slice(2, 2, &[
+            dt3 / 3.0, dt2 / 2.0,
+            dt2 / 2.0, dt
+        ]) * q
+    } else {
+        let (dt4, dt5) = (dt3 * dt, dt3 * dt2);
+        DMatrix::from_row_slice(3, 3, &[
+            dt5 / 20.0, dt4 / 8.0, dt3 / 6.0,
+            dt4 / 8.0,  dt3 / 3.0, dt2 / 2.0,
+            dt3 / 6.0,  dt2 / 2.0, dt
+        ]) * q
+    }
+}
+
+fn predict(x: &State, f: &DMatrix<f64>) -> State {
+    let n = f.nrows();
+    let mut rotation = Vector3::zeros();
+    let mut rates = [Vector3::zeros(); 2];
+    for j in 1..n {
+        rotation += x.rates[j - 1] * f[(0, j)];
+        for i in 1..n {
+            rates[i - 1] += x.rates[j - 1] * f[(i, j)];
+        }
+    }
+    State { q: x.q * Quat64::from_scaled_axis(rotation), rates }
+}
+
+impl SmoothingAlgorithm for KalmanRts {
+    fn get_name(&self) -> String { "Kalman (RTS)".to_owned() }
+
+    fn set_parameter(&mut self, name: &str, val: f64) {
+        match name {
+            "time_constant"         => self.time_constant = val,
+            "constant_acceleration" => self.constant_acceleration = val > 0.1,
+            "trim_range_only"       => self.trim_range_only = val > 0.1,
+            _ => log::error!("Invalid parameter name: {}", name)
+        }
+    }
+    fn get_parameter(&self, name: &str) -> f64 {
+        match name {
+            "time_constant"         => self.time_constant,
+            "constant_acceleration" => if self.constant_acceleration { 1.0 } else { 0.0 },
+            "trim_range_only"       => if self.trim_range_only { 1.0 } else { 0.0 },
+            _ => 0.0
+        }
+    }
+
+    fn get_parameters_json(&self) -> serde_json::Value {
+        serde_json::json!([
+            {
+                "name": "time_constant",
+                "description": "Smoothness",
+                "type": "SliderWithField",
+                "from": 0.01,
+                "to": 10.0,
+                "value": self.time_constant,
+                "default": 0.5,
+                "unit": "s",
+                "keyframe": "SmoothingParamTimeConstant"
+            },
+            {
+                "name": "constant_acceleration",
+                "description": "Constant acceleration model",
+                "advanced": true,
+                "type": "CheckBox",
+                "default": self.constant_acceleration,
+                "value": if self.constant_acceleration { 1.0 } else { 0.0 },
+            },
+            {
+                "name": "trim_range_only",
+                "description": "Only within trim range",
+                "advanced": true,
+                "type": "CheckBox",
+                "default": self.trim_range_only,
+                "value": if self.trim_range_only { 1.0 } else { 0.0 },
+            },
+        ])
+    }
+    fn get_status_json(&self) -> serde_json::Value {
+        serde_json::json!([])
+    }
+
+    fn get_checksum(&self) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        hasher.write_u64(self.time_constant.to_bits());
+        hasher.write_u8(if self.constant_acceleration { 1 } else { 0 });
+        hasher.write_u8(if self.trim_range_only { 1 } else { 0 });
+        hasher.finish()
+    }
+
+    fn smooth(&self, quats: &TimeQuat, duration: f64, stabilization_params: &StabilizationParams, keyframes: &KeyframeManager) -> TimeQuat {
+        if quats.len() < 2 || duration <= 0.0 || self.time_constant <= 0.0 { return quats.clone(); }
+
+        let sample_rate: f64 = quats.len() as f64 / (duration / 1000.0);
+        let n = if self.constant_acceleration { 3 } else { 2 };
+
+        // Steady-state bandwidth of the smoother is (q * sample_rate / r) ^ (1 / 2n), so set it to 1 / time_constant
+        let get_noise = |time_constant: f64| {
+            MEASUREMENT_NOISE / sample_rate * (1.0 / time_constant.max(0.001)).powi(2 * n as i32)
+        };
+        let noise = get_noise(self.time_constant);
+
+        let mut _quats_copy = None;
+        let quats = if self.trim_range_only && (stabilization_params.trim_start != 0.0 || stabilization_params.trim_end != 1.0) {
+            let ts_start = ((duration * stabilization_params.trim_start) * 1000.0).round() as i64;
+            let ts_end   = ((duration * stabilization_params.trim_end) * 1000.0).round() as i64;
+            if quats.range(ts_start..ts_end).next().is_none() {
+                &quats
+            } else {
+                let first_q = quats.range(ts_start..ts_end).next().unwrap().1.clone();
+                let last_q = quats.range(ts_start..ts_end).next_back().unwrap().1.clone();
+                _quats_copy = Some(quats.clone());
+                for (ts, q) in _quats_copy.as_mut().unwrap().iter_mut() {
+                    if *ts < ts_start {
+                        *q = first_q.clone();
+                    } else if *ts > ts_end {
+                        *q = last_q.clone();
+                    }
+                }
+                _quats_copy.as_ref().unwrap()
+            }
+        } else {
+            &quats
+        };
+
+        let mut noise_per_timestamp = BTreeMap::<i64, f64>::new();
+        if keyframes.is_keyframed(&KeyframeType::SmoothingParamTimeConstant) || (stabilization_params.video_speed_affects_smoothing && (stabilization_params.video_speed != 1.0 || keyframes.is_keyframed(&KeyframeType::VideoSpeed))) {
+            noise_per_timestamp = quats.iter().map(|(ts, _)| {
+                let timestamp_ms = *ts as f64 / 1000.0;
+
+                let mut val = keyframes.value_at_gyro_timestamp(&KeyframeType::SmoothingParamTimeConstant, timestamp_ms).unwrap_or(self.time_constant);
+                if stabilization_params.video_speed_affects_smoothing {
+                    let vid_speed = keyframes.value_at_gyro_timestamp(&KeyframeType::VideoSpeed, timestamp_ms).unwrap_or(stabilization_params.video_speed);
+                    val *= vid_speed;
+                }
+
+                (*ts, get_noise(val))
+            }).collect();
+        }
+
+        let timestamps: Vec<i64> = quats.keys().copied().collect();
+        let measurements: Vec<Quat64> = quats.values().copied().collect();
+
+        // Forward Kalman filter. Keep the filtered and predicted states and covariances for the backward pass
+        let mut filtered = Vec::with_capacity(measurements.len());
+        let mut predicted = Vec::with_capacity(measurements.len());
+        let mut transitions = Vec::with_capacity(measurements.len());
+
+        let mut x = State { q: measurements[0], rates: [Vector3::zeros(); 2] };
+        // Unknown initial velocity and acceleration
+        let mut p = DMatrix::from_diagonal(&DVector::from_fn(n, |i, _| MEASUREMENT_NOISE * sample_rate.powi(2 * i as i32)));
+        filtered.push((x, p.clone()));
+        predicted.push((x, p.clone()));
+        transitions.push(DMatrix::identity(n, n));
+
+        for i in 1..measurements.len() {
+            let dt = (timestamps[i] - timestamps[i - 1]) as f64 / 1_000_000.0;
+            let f = transition(n, dt);
+            let q = noise_per_timestamp.get(&timestamps[i]).copied().unwrap_or(noise);
+
+            let x_pred = predict(&x, &f);
+            let p_pred = &f * &p * f.transpose() + process_noise(n, dt, q);
+
+            // Innovation is the rotation from the predicted to the measured orientation, in the body frame
+            let y = (x_pred.q.inverse() * measurements[i]).scaled_axis();
+            let s = p_pred[(0, 0)] + MEASUREMENT_NOISE;
+            let k = p_pred.column(0) / s;
+
+            x = State {
+                q: x_pred.q * Quat64::from_scaled_axis(y * k[0]),
+                rates: [
+                    x_pred.rates[0] + y * k[1],
+                    if n > 2 { x_pred.rates[1] + y * k[2] } else { Vector3::zeros() }
+                ]
+            };
+            p = &p_pred - &k * p_pred.row(0);
+
+            filtered.push((x, p.clone()));
+            predicted.push((x_pred, p_pred));
+            transitions.push(f);
+        }
+
+        // Backward RTS pass
+        let mut smoothed = vec![filtered.last().unwrap().0; measurements.len()];
+        for i in (0..measurements.len() - 1).rev() {
+            let (x_f, p_f) = &filtered[i];
+            let (x_pred, p_pred) = &predicted[i + 1];
+            let p_pred_inv = match p_pred.clone().try_inverse() {
+                Some(v) => v,
+                None => { smoothed[i] = *x_f; continue; }
+            };
+            let c = p_f * transitions[i + 1].transpose() * p_pred_inv;
+
+            let next = smoothed[i + 1];
+            let d = [
+                (x_pred.q.inverse() * next.q).scaled_axis(),
+                next.rates[0] - x_pred.rates[0],
+                next.rates[1] - x_pred.rates[1],
+            ];
+            let correction = |row: usize| (0..n).fold(Vector3::zeros(), |acc, j| acc + d[j] * c[(row, j)]);
+
+            smoothed[i] = State {
+                q: x_f.q * Quat64::from_scaled_axis(correction(0)),
+                rates: [
+                    x_f.rates[0] + correction(1),
+                    if n > 2 { x_f.rates[1] + correction(2) } else { Vector3::zeros() }
+                ]
+            };
+        }
+
+        timestamps.into_iter().zip(smoothed.into_iter()).map(|(ts, x)| (ts, x.q)).collect()
+    }
+}
@@ -6,6 +6,7 @@ pub mod none;
 pub mod plain;
 pub mod fixed;
 pub mod default_algo;
+pub mod kalman;
 
 pub use nalgebra::*;
 use super::gyro_source::TimeQuat;
@@ -48,7 +49,8 @@ impl Default for Smoothing {
                 Box::new(self::none::None::default()),
                 Box::new(self::default_algo::DefaultAlgo::default()),
                 Box::new(self::plain::Plain::default()),
-                Box::new(self::fixed::Fixed::default())
+                Box::new(self::fixed::Fixed::default()),
+                Box::new(self::kalman::KalmanRts::default())
             ],
 
             quats_checksum: 0,
@@ -0,0 +1,294 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Vibration and frequency analysis of the IMU data: spectrograms, dominant peaks and noise floor over time.
+// Used by the frequency graph in the UI and for the spectral report, which helps to find frame resonances
+// and choose the filter settings.
+
+use std::io::Write;
+use rustfft::{ num_complex::Complex, FftPlanner };
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use crate::gyro_source::TimeIMU;
+
+pub const CHANNEL_NAMES: [&str; 6] = ["gyro_x", "gyro_y", "gyro_z", "accl_x", "accl_y", "accl_z"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpectralReportFormat {
+    Json,
+    Csv,
+}
+impl SpectralReportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "csv"  => Some(Self::Csv),
+            _ => None
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Csv  => "csv",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct SpectralSettings {
+    /// Number of samples in each FFT window, rounded up to a power of two
+    pub fft_size: usize,
+    /// Overlap of the consecutive windows, 0 to 0.95
+    pub overlap: f64,
+    /// Frequencies below this are ignored for the peaks and noise floor, in Hz
+    pub min_frequency: f64,
+    /// Number of peaks reported for each window
+    pub max_peaks: usize,
+    /// Minimum ratio of the peak magnitude to the noise floor
+    pub min_prominence: f64,
+    /// Include the full spectrogram in the report
+    pub include_spectrogram: bool,
+    /// Indexes of the analyzed channels, see `CHANNEL_NAMES`
+    pub channels: Vec<usize>,
+    /// Analyze the processed IMU data (after the resampling, filters and bias) instead of the samples from the file
+    pub processed: bool,
+}
+impl Default for SpectralSettings {
+    fn default() -> Self {
+        Self {
+            fft_size: 512,
+            overlap: 0.5,
+            min_frequency: 5.0,
+            max_peaks: 3,
+            min_prominence: 4.0,
+            include_spectrogram: false,
+            channels: (0..6).collect(),
+            processed: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Peak {
+    /// Hz
+    pub frequency: f64,
+    pub magnitude: f64,
+    /// Ratio to the noise floor
+    pub prominence: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SpectralFrame {
+    /// Center of the window
+    pub timestamp_ms: f64,
+    pub noise_floor: f64,
+    pub rms: f64,
+    pub peaks: Vec<Peak>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ChannelAnalysis {
+    pub channel: String,
+    /// Spectrum averaged over all windows
+    pub average_spectrum: Vec<f64>,
+    /// Peaks of the average spectrum
+    pub peaks: Vec<Peak>,
+    pub noise_floor: f64,
+    pub frames: Vec<SpectralFrame>,
+    /// Magnitude spectrum of each frame, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spectrogram: Option<Vec<Vec<f64>>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SpectralReport {
+    pub sample_rate: f64,
+    pub fft_size: usize,
+    /// Frequency of each spectrum bin
+    pub frequencies: Vec<f64>,
+    pub channels: Vec<ChannelAnalysis>,
+}
+impl SpectralReport {
+    pub fn log(&self) {
+        ::log::info!("Spectral analysis: {:.2} Hz sample rate, {} point FFT", self.sample_rate, self.fft_size);
+        for ch in &self.channels {
+            let peaks = ch.peaks.iter().map(|x| format!("{:.1} Hz ({:.1}x)", x.frequency, x.prominence)).collect::<Vec<_>>().join(", ");
+            ::log::info!("{}: noise floor {:.5}, peaks: {}", ch.channel, ch.noise_floor, if peaks.is_empty() { "none" } else { &peaks });
+        }
+    }
+
+    pub fn write(&self, format: SpectralReportFormat, mut writer: impl Write) -> std::io::Result<()> {
+        match format {
+            SpectralReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, self)?;
+            },
+            SpectralReportFormat::Csv => {
+                // One row per window of each channel
+                let max_peaks = self.channels.iter().flat_map(|x| x.frames.iter().map(|f| f.peaks.len())).max().unwrap_or_default();
+                write!(writer, "channel,timestamp_ms,rms,noise_floor")?;
+                for i in 1..=max_peaks {
+                    write!(writer, ",peak{i}_hz,peak{i}_magnitude,peak{i}_prominence")?;
+                }
+                writeln!(writer)?;
+                for ch in &self.channels {
+                    for f in &ch.frames {
+                        write!(writer, "{},{:.3},{:.6},{:.6}", ch.channel, f.timestamp_ms, f.rms, f.noise_floor)?;
+                        for i in 0..max_peaks {
+                            match f.peaks.get(i) {
+                                Some(p) => write!(writer, ",{:.3},{:.6},{:.3}", p.frequency, p.magnitude, p.prominence)?,
+                                None => write!(writer, ",,,")?
+                            }
+                        }
+                        writeln!(writer)?;
+                    }
+                }
+            }
+        }
+        writer.flush()
+    }
+}
+
+fn channel_value(x: &TimeIMU, channel: usize) -> Option<f64> {
+    let v = if channel < 3 { x.gyro } else { x.accl };
+    v.map(|v| v[channel % 3])
+}
+
+/// Median sample rate of the data
+pub fn sample_rate(imu: &[TimeIMU]) -> f64 {
+    let mut intervals: Vec<f64> = imu.windows(2).map(|w| w[1].timestamp_ms - w[0].timestamp_ms).filter(|x| *x > 0.0).collect();
+    if intervals.is_empty() { return 0.0; }
+    intervals.sort_by(|a, b| a.total_cmp(b));
+    1000.0 / intervals[intervals.len() / 2]
+}
+
+/// Uniformly sampled values of one channel, linearly interpolated, starting at `start_ts` (ms).
+/// Missing values are treated as 0
+pub fn sample_channel(imu: &[TimeIMU], channel: usize, start_ts: f64, sample_rate: f64, count: usize) -> Vec<f64> {
+    let dt_ms = 1000.0 / sample_rate;
+    let mut sample_ts = start_ts;
+    let mut prev_ts = 0.0;
+    let mut prev_val = 0.0;
+
+    let mut samples: Vec<f64> = Vec::with_capacity(count);
+    for x in imu {
+        let val = channel_value(x, channel).unwrap_or_default();
+
+        while x.timestamp_ms > sample_ts && samples.len() < count {
+            let frac = (sample_ts - prev_ts) / (x.timestamp_ms - prev_ts);
+            samples.push(prev_val + (val - prev_val) * frac.clamp(0.0, 1.0));
+            sample_ts += dt_ms;
+        }
+        if samples.len() >= count {
+            break;
+        }
+        prev_ts = x.timestamp_ms;
+        prev_val = val;
+    }
+    samples
+}
+
+/// Blackman-Harris window
+pub fn window(size: usize) -> Vec<f64> {
+    let n = (size.max(2) - 1) as f64;
+    (0..size).map(|i| {
+        let r = i as f64 / n;
+        0.35875 -
+        0.48829 * (2.0 * std::f64::consts::PI * r).cos() +
+        0.14128 * (4.0 * std::f64::consts::PI * r).cos() -
+        0.01168 * (6.0 * std::f64::consts::PI * r).cos()
+    }).collect()
+}
+
+/// Magnitude spectrum of the samples (`samples.len() / 2` bins), windowed and scaled by the size
+pub fn spectrum(samples: &[f64]) -> Vec<f64> {
+    let size = samples.len();
+    if size == 0 { return Vec::new(); }
+    let mut buffer: Vec<Complex<f64>> = samples.iter().zip(window(size)).map(|(x, w)| Complex::new(x * w, 0.0)).collect();
+    FftPlanner::<f64>::new().plan_fft_forward(size).process(&mut buffer);
+
+    let scale = 1.0 / size as f64;
+    buffer.iter().take(size / 2).map(|x| x.norm() * scale).collect()
+}
+
+/// Median magnitude from `min_bin`, which is robust to the peaks
+pub fn noise_floor(spectrum: &[f64], min_bin: usize) -> f64 {
+    let mut v = spectrum.get(min_bin..).unwrap_or_default().to_vec();
+    if v.is_empty() { return 0.0; }
+    v.sort_by(|a, b| a.total_cmp(b));
+    v[v.len() / 2]
+}
+
+/// Local maxima at least `min_prominence` times above the noise floor, strongest first
+pub fn find_peaks(spectrum: &[f64], resolution: f64, min_bin: usize, floor: f64, min_prominence: f64, max_peaks: usize) -> Vec<Peak> {
+    let mut peaks = Vec::new();
+    if floor <= 0.0 || spectrum.len() < 3 { return peaks; }
+    for i in min_bin.max(1)..spectrum.len() - 1 {
+        let (a, b, c) = (spectrum[i - 1], spectrum[i], spectrum[i + 1]);
+        if b > a && b >= c && b / floor >= min_prominence {
+            // Parabolic interpolation of the peak position
+            let denom = a - 2.0 * b + c;
+            let delta = if denom.abs() > 1e-12 { (0.5 * (a - c) / denom).clamp(-0.5, 0.5) } else { 0.0 };
+            peaks.push(Peak { frequency: (i as f64 + delta) * resolution, magnitude: b, prominence: b / floor });
+        }
+    }
+    peaks.sort_by(|a, b| b.magnitude.total_cmp(&a.magnitude));
+    peaks.truncate(max_peaks);
+    peaks
+}
+
+pub fn analyze(imu: &[TimeIMU], settings: &SpectralSettings) -> SpectralReport {
+    let sample_rate = sample_rate(imu);
+    let fft_size = settings.fft_size.max(16).next_power_of_two();
+    let mut report = SpectralReport { sample_rate, fft_size, ..Default::default() };
+    if imu.len() < 2 || sample_rate <= 0.0 { return report; }
+
+    let resolution = sample_rate / fft_size as f64;
+    report.frequencies = (0..fft_size / 2).map(|i| i as f64 * resolution).collect();
+    let min_bin = (settings.min_frequency / resolution).ceil() as usize;
+    let hop = ((fft_size as f64 * (1.0 - settings.overlap.clamp(0.0, 0.95))).round() as usize).max(1);
+
+    let start_ts = imu[0].timestamp_ms;
+    let count = ((imu[imu.len() - 1].timestamp_ms - start_ts) * sample_rate / 1000.0).floor() as usize + 1;
+
+    for &channel in settings.channels.iter().filter(|x| **x < CHANNEL_NAMES.len()) {
+        if !imu.iter().any(|x| channel_value(x, channel).is_some()) { continue; }
+        let values = sample_channel(imu, channel, start_ts, sample_rate, count);
+
+        let mut analysis = ChannelAnalysis {
+            channel: CHANNEL_NAMES[channel].to_string(),
+            average_spectrum: vec![0.0; fft_size / 2],
+            ..Default::default()
+        };
+        let mut spectrogram = Vec::new();
+        let mut start = 0;
+        while start + fft_size <= values.len() {
+            let chunk = &values[start..start + fft_size];
+            let mean = chunk.iter().sum::<f64>() / fft_size as f64;
+            let centered: Vec<f64> = chunk.iter().map(|x| x - mean).collect();
+            let rms = (centered.iter().map(|x| x * x).sum::<f64>() / fft_size as f64).sqrt();
+            let spec = spectrum(&centered);
+
+            let floor = noise_floor(&spec, min_bin);
+            analysis.frames.push(SpectralFrame {
+                timestamp_ms: start_ts + (start + fft_size / 2) as f64 * 1000.0 / sample_rate,
+                noise_floor: floor,
+                rms,
+                peaks: find_peaks(&spec, resolution, min_bin, floor, settings.min_prominence, settings.max_peaks),
+            });
+            for (a, s) in analysis.average_spectrum.iter_mut().zip(&spec) { *a += s; }
+            if settings.include_spectrogram { spectrogram.push(spec); }
+            start += hop;
+        }
+        if analysis.frames.is_empty() { continue; }
+
+        let n = analysis.frames.len() as f64;
+        analysis.average_spectrum.iter_mut().for_each(|x| *x /= n);
+        analysis.noise_floor = noise_floor(&analysis.average_spectrum, min_bin);
+        analysis.peaks = find_peaks(&analysis.average_spectrum, resolution, min_bin, analysis.noise_floor, settings.min_prominence, settings.max_peaks);
+        if settings.include_spectrogram { analysis.spectrogram = Some(spectrogram); }
+
+        report.channels.push(analysis);
+    }
+    report
+}
@@ -86,6 +86,88 @@ pub const COEFFS: [f32; 64+128+256 + 9*4 + 4] = [
 // ];
 // const ALPHAS: [f32; 4] = [ 1.0, 0.75, 0.50, 0.25 ];
 
+fn map_coord(x: f32, in_min: f32, in_max: f32, out_min: f32, out_max: f32) -> f32 {
+    return (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min;
+}
+
+fn rotate_and_distort(pos: (f32, f32), idx: usize, params: &KernelParams, matrices: &[[f32; 9]], distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>, r_limit: f32) -> Option<(f32, f32)> {
+    let matrices = matrices[idx];
+    let _x = (pos.0 * matrices[0]) + (pos.1 * matrices[1]) + matrices[2] + params.translation3d[0];
+    let _y = (pos.0 * matrices[3]) + (pos.1 * matrices[4]) + matrices[5] + params.translation3d[1];
+    let _w = (pos.0 * matrices[6]) + (pos.1 * matrices[7]) + matrices[8] + params.translation3d[2];
+    if _w > 0.0 {
+        let pos = (_x / _w, _y / _w);
+        if params.r_limit > 0.0 && (pos.0 * pos.0 + pos.1 * pos.1) > r_limit {
+            return None;
+        }
+        let mut uv = distortion_model.distort_point(pos, &params);
+        uv = ((uv.0 * params.f[0]) + params.c[0], (uv.1 * params.f[1]) + params.c[1]);
+
+        if (params.flags & 2) == 2 { // Has digital lens
+            if let Some(digital) = digital_lens {
+                uv = digital.distort_point(uv, params);
+            }
+        }
+
+        if params.input_horizontal_stretch > 0.001 { uv.0 /= params.input_horizontal_stretch; }
+        if params.input_vertical_stretch   > 0.001 { uv.1 /= params.input_vertical_stretch; }
+
+        return Some(uv);
+    }
+    return None;
+}
+
+/// Maps the output pixel position to the source pixel position, including rolling shutter and lens correction amount.
+/// `row` is the output buffer row, used as a fallback for the rolling shutter matrix index
+pub fn undistort_coord(mut out_pos: (f32, f32), row: usize, params: &KernelParams, matrices: &[[f32; 9]], distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>) -> Option<(f32, f32)> {
+    let r_limit = params.r_limit * params.r_limit; // Square it so we don't have to do sqrt on the point length
+
+    let factor = (1.0 - params.lens_correction_amount).max(0.001); // FIXME: this is close but wrong
+    let out_c = (params.output_width as f32 / 2.0, params.output_height as f32 / 2.0);
+    let out_f = ((params.f[0] / params.fov / factor), (params.f[1] / params.fov / factor));
+
+    out_pos.0 += params.translation2d[0];
+    out_pos.1 += params.translation2d[1];
+
+    ///////////////////////////////////////////////////////////////////
+    // Calculate source `y` for rolling shutter
+    let mut sy = row;
+    if params.matrix_count > 1 {
+        let idx = params.matrix_count as usize / 2;
+        if let Some(pt) = rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens, r_limit) {
+            sy = (pt.1.round() as i32).min(params.height).max(0) as usize;
+        }
+    }
+    ///////////////////////////////////////////////////////////////////
+
+    ///////////////////////////////////////////////////////////////////
+    // Add lens distortion back
+    if params.lens_correction_amount < 1.0 {
+        let mut new_out_pos = out_pos;
+
+        if (params.flags & 2) == 2 { // Has digial lens
+            if let Some(digital) = digital_lens {
+                if let Some(pt) = digital.undistort_point(new_out_pos, params) {
+                    new_out_pos = pt;
+                }
+            }
+        }
+
+        new_out_pos = ((new_out_pos.0 - out_c.0) / out_f.0, (new_out_pos.1 - out_c.1) / out_f.1);
+        new_out_pos = distortion_model.undistort_point(new_out_pos, &params).unwrap_or_default();
+        new_out_pos = ((new_out_pos.0 * out_f.0) + out_c.0, (new_out_pos.1 * out_f.1) + out_c.1);
+
+        out_pos = (
+            new_out_pos.0 * (1.0 - params.lens_correction_amount) + (out_pos.0 * params.lens_correction_amount),
+            new_out_pos.1 * (1.0 - params.lens_correction_amount) + (out_pos.1 * params.lens_correction_amount),
+        );
+    }
+    ///////////////////////////////////////////////////////////////////
+
+    let idx = sy.min(params.matrix_count as usize - 1);
+    rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens, r_limit)
+}
+
 impl<T: PixelType> Stabilization<T> {
     // Adapted from OpenCV: initUndistortRectifyMap + remap
     // https://github.com/opencv/opencv/blob/2b60166e5c65f1caccac11964ad760d847c536e4/modules/calib3d/src/fisheye.cpp#L465-L567
@@ -118,37 +200,6 @@ impl<T: PixelType> Stabilization<T> {
             px[0] += 16.0;
             px[1] += 16.0;
         }
-        fn map_coord(x: f32, in_min: f32, in_max: f32, out_min: f32, out_max: f32) -> f32 {
-            return (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min;
-        }
-
-        fn rotate_and_distort(pos: (f32, f32), idx: usize, params: &KernelParams, matrices: &[[f32; 9]], distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>, r_limit: f32) -> Option<(f32, f32)> {
-            let matrices = matrices[idx];
-            let _x = (pos.0 * matrices[0]) + (pos.1 * matrices[1]) + matrices[2] + params.translation3d[0];
-            let _y = (pos.0 * matrices[3]) + (pos.1 * matrices[4]) + matrices[5] + params.translation3d[1];
-            let _w = (pos.0 * matrices[6]) + (pos.1 * matrices[7]) + matrices[8] + params.translation3d[2];
-            if _w > 0.0 {
-                let pos = (_x / _w, _y / _w);
-                if params.r_limit > 0.0 && (pos.0 * pos.0 + pos.1 * pos.1) > r_limit {
-                    return None;
-                }
-                let mut uv = distortion_model.distort_point(pos, &params);
-                uv = ((uv.0 * params.f[0]) + params.c[0], (uv.1 * params.f[1]) + params.c[1]);
-
-                if (params.flags & 2) == 2 { // Has digital lens
-                    if let Some(digital) = digital_lens {
-                        uv = digital.distort_point(uv, params);
-                    }
-                }
-
-                if params.input_horizontal_stretch > 0.001 { uv.0 /= params.input_horizontal_stretch; }
-                if params.input_vertical_stretch   > 0.001 { uv.1 /= params.input_vertical_stretch; }
-
-                return Some(uv);
-            }
-            return None;
-        }
-
         fn sample_input_at<const I: i32, T: PixelType>(uv: (f32, f32), input: &[u8], params: &KernelParams, bg: &Vector4<f32>, _drawing: &[u8]) -> Vector4<f32> {
             const INTER_BITS: usize = 5;
             const INTER_TAB_SIZE: usize = 1 << INTER_BITS;
@@ -201,15 +252,9 @@ impl<T: PixelType> Stabilization<T> {
         }
 
         if let BufferSource::Cpu { input, output } = &mut buffers.buffers {
-            let r_limit = params.r_limit * params.r_limit; // Square it so we don't have to do sqrt on the point length
-
             let bg = Vector4::<f32>::new(params.background[0], params.background[1], params.background[2], params.background[3]);
             let bg_t: T = PixelType::from_float(bg);
 
-            let factor = (1.0 - params.lens_correction_amount).max(0.001); // FIXME: this is close but wrong
-            let out_c = (params.output_width as f32 / 2.0, params.output_height as f32 / 2.0);
-            let out_f = ((params.f[0] / params.fov / factor), (params.f[1] / params.fov / factor));
-
             // let drawing_enabled = !drawing.is_empty() && (params.flags & 8) == 8;
             let fill_bg = (params.flags & 4) == 4;
             let fix_range = (params.flags & 1) == 1;
@@ -222,7 +267,7 @@ impl<T: PixelType> Stabilization<T> {
             output.par_chunks_mut(buffers.output_size.2).enumerate().for_each(|(y, row_bytes)| { // Parallel iterator over buffer rows
                 row_bytes.chunks_mut(params.bytes_per_pixel as usize).enumerate().for_each(|(x, pix_chunk)| { // iterator over row pixels
 
-                    let mut out_pos = (
+                    let out_pos = (
                         map_coord(x as f32, params.output_rect[0] as f32, (params.output_rect[0] + params.output_rect[2]) as f32, 0.0, params.output_width as f32 ),
                         map_coord(y as f32, params.output_rect[1] as f32, (params.output_rect[1] + params.output_rect[3]) as f32, 0.0, params.output_height as f32)
                     );
@@ -233,9 +278,6 @@ impl<T: PixelType> Stabilization<T> {
                         // let p = out_pos;
                         let mut pixel = bg;
 
-                        out_pos.0 += params.translation2d[0];
-                        out_pos.1 += params.translation2d[1];
-
                         let pix_out = bytemuck::from_bytes_mut(pix_chunk); // treat this byte chunk as `T`
 
                         if fill_bg {
@@ -243,43 +285,7 @@ impl<T: PixelType> Stabilization<T> {
                             return;
                         }
 
-                        ///////////////////////////////////////////////////////////////////
-                        // Calculate source `y` for rolling shutter
-                        let mut sy = y;
-                        if params.matrix_count > 1 {
-                            let idx = params.matrix_count as usize / 2;
-                            if let Some(pt) = rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens, r_limit) {
-                                sy = (pt.1.round() as i32).min(params.height).max(0) as usize;
-                            }
-                        }
-                        ///////////////////////////////////////////////////////////////////
-
-                        ///////////////////////////////////////////////////////////////////
-                        // Add lens distortion back
-                        if params.lens_correction_amount < 1.0 {
-                            let mut new_out_pos = out_pos;
-
-                            if (params.flags & 2) == 2 { // Has digial lens
-                                if let Some(digital) = digital_lens {
-                                    if let Some(pt) = digital.undistort_point(new_out_pos, params) {
-                                        new_out_pos = pt;
-                                    }
-                                }
-                            }
-
-                            new_out_pos = ((new_out_pos.0 - out_c.0) / out_f.0, (new_out_pos.1 - out_c.1) / out_f.1);
-                            new_out_pos = distortion_model.undistort_point(new_out_pos, &params).unwrap_or_default();
-                            new_out_pos = ((new_out_pos.0 * out_f.0) + out_c.0, (new_out_pos.1 * out_f.1) + out_c.1);
-
-                            out_pos = (
-                                new_out_pos.0 * (1.0 - params.lens_correction_amount) + (out_pos.0 * params.lens_correction_amount),
-                                new_out_pos.1 * (1.0 - params.lens_correction_amount) + (out_pos.1 * params.lens_correction_amount),
-                            );
-                        }
-                        ///////////////////////////////////////////////////////////////////
-
-                        let idx = sy.min(params.matrix_count as usize - 1);
-                        if let Some(mut uv) = rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens, r_limit) {
+                        if let Some(mut uv) = undistort_coord(out_pos, y, params, matrices, distortion_model, digital_lens) {
                             let width_f = params.width as f32;
                             let height_f = params.height as f32;
                             match params.background_mode {
@@ -16,10 +16,6 @@ use space::{Knn, LinearKnn};
 
 const LOWES_RATIO: f32 = 0.5;
 
-// lazy_static::lazy_static! {
-//     static ref THREAD_POOL: rayon::ThreadPool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
-// }
-
 pub type Descriptor = BitArray<64>;
 pub type Match = FeatureMatch;
 
@@ -30,8 +26,6 @@ pub struct ItemAkaze {
     img_size: (u32, u32)
 }
 
-// TODO: add caching checkbox to the UI
-
 impl EstimatorItemInterface for ItemAkaze {
     fn get_features(&self) -> &Vec<(f32, f32)> {
         &self.features
@@ -39,44 +33,11 @@ impl EstimatorItemInterface for ItemAkaze {
 
     fn estimate_pose(&self, next: &EstimatorItem, params: &ComputeParams, timestamp_us: i64, next_timestamp_us: i64) -> Option<Rotation3<f64>> {
         if let EstimatorItem::ItemAkaze(next) = next {
-            use cv_core::nalgebra::{ UnitVector3, Point2 };
-
-            let pts1 = &self.features;
-            let pts2 = &next.features;
+            let (pts1, pts2): (Vec<(f32, f32)>, Vec<(f32, f32)>) = Self::match_descriptors(&self.descriptors, &next.descriptors).into_iter()
+                .map(|(i1, i2)| (self.features[i1], next.features[i2]))
+                .unzip();
 
-            let pts1 = crate::stabilization::undistort_points_for_optical_flow(&pts1, timestamp_us, params, self.img_size);
-            let pts2 = crate::stabilization::undistort_points_for_optical_flow(&pts2, next_timestamp_us, params, self.img_size);
-
-            let matches: Vec<Match> = Self::match_descriptors(&self.descriptors, &next.descriptors).into_iter()
-                .map(|(i1, i2)| {
-                    FeatureMatch(
-                        UnitVector3::new_normalize(Point2::new(pts1[i1].0 as f64, pts1[i1].1 as f64).to_homogeneous()),
-                        UnitVector3::new_normalize(Point2::new(pts2[i2].0 as f64, pts2[i2].1 as f64).to_homogeneous())
-                    )
-                })
-                .collect();
-
-            // Try different thresholds for best results
-            let thresholds = [1e-10, 1e-8, 1e-6];
-
-            let mut arrsac = Arrsac::new(1e-10, Xoshiro256PlusPlus::seed_from_u64(0));
-                //.initialization_hypotheses(2048)
-                //.max_candidate_hypotheses(512);
-            for threshold in thresholds {
-                arrsac = arrsac.inlier_threshold(threshold);
-
-                let eight_point = eight_point::EightPoint::new();
-                if let Some(out) = arrsac.model(&eight_point, matches.iter().copied()) {
-                    let rot = out.isometry().rotation;
-                    return Some(nalgebra::Rotation3::from_matrix_unchecked(nalgebra::Matrix3::from_column_slice(rot.matrix().as_slice())));
-                    /*let rotations = cv_pinhole::EssentialMatrix::from(out).possible_rotations(1e-12, 1000).unwrap();
-                    if rotations[0].angle() < rotations[1].angle() {
-                        Some(rotations[0])
-                    } else {
-                        Some(rotations[1])
-                    }*/
-                }
-            }
+            return pose_from_matches(&pts1, &pts2, params, timestamp_us, next_timestamp_us, self.img_size);
         }
         ::log::warn!("couldn't find model");
         None
@@ -96,7 +57,49 @@ impl EstimatorItemInterface for ItemAkaze {
     fn cleanup(&mut self) { }
 }
 
+/// Relative rotation between two frames from the matched points, using the essential matrix
+pub fn pose_from_matches(pts1: &[(f32, f32)], pts2: &[(f32, f32)], params: &ComputeParams, timestamp_us: i64, next_timestamp_us: i64, img_size: (u32, u32)) -> Option<Rotation3<f64>> {
+    use cv_core::nalgebra::{ UnitVector3, Point2 };
+
+    let pts1 = crate::stabilization::undistort_points_for_optical_flow(pts1, timestamp_us, params, img_size);
+    let pts2 = crate::stabilization::undistort_points_for_optical_flow(pts2, next_timestamp_us, params, img_size);
+
+    let matches: Vec<Match> = pts1.iter().zip(pts2.iter())
+        .map(|(p1, p2)| {
+            FeatureMatch(
+                UnitVector3::new_normalize(Point2::new(p1.0 as f64, p1.1 as f64).to_homogeneous()),
+                UnitVector3::new_normalize(Point2::new(p2.0 as f64, p2.1 as f64).to_homogeneous())
+            )
+        })
+        .collect();
+
+    // Try different thresholds for best results
+    let thresholds = [1e-10, 1e-8, 1e-6];
+
+    let mut arrsac = Arrsac::new(1e-10, Xoshiro256PlusPlus::seed_from_u64(0));
+        //.initialization_hypotheses(2048)
+        //.max_candidate_hypotheses(512);
+    for threshold in thresholds {
+        arrsac = arrsac.inlier_threshold(threshold);
+
+        let eight_point = eight_point::EightPoint::new();
+        if let Some(out) = arrsac.model(&eight_point, matches.iter().copied()) {
+            let rot = out.isometry().rotation;
+            return Some(nalgebra::Rotation3::from_matrix_unchecked(nalgebra::Matrix3::from_column_slice(rot.matrix().as_slice())));
+        }
+    }
+    ::log::warn!("couldn't find model");
+    None
+}
+
 impl ItemAkaze {
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descriptors
+    }
+    pub fn img_size(&self) -> (u32, u32) {
+        self.img_size
+    }
+
     pub fn match_descriptors(ds1: &[Descriptor], ds2: &[Descriptor]) -> Vec<(usize, usize)> {
         if ds1.len() < 2 || ds2.len() < 2 { return Vec::new() }
         let two_neighbors = ds1.iter().map(|d1| LinearKnn { metric: Hamming, iter: ds2.iter() }.knn(d1, 2)).enumerate();
@@ -112,25 +115,6 @@ impl ItemAkaze {
         let img_size = (width, height);
         let (points, descriptors) = akz.extract(&image::DynamicImage::ImageLuma8(Arc::try_unwrap(img).unwrap()));
 
-        /*let mut hasher = crc32fast::Hasher::new();
-        hasher.update(img.as_bytes());
-        let frame_path = format!("cache/{}-{}.bin", frame, hasher.finalize());
-
-        let features = if let Ok(bytes) = std::fs::read(&frame_path) {
-            deserialize_features(&bytes)
-        } else {
-            let mut akz = Akaze::new(0.0007);
-            akz.maximum_features = 500;
-            let features = akz.extract(&image::DynamicImage::ImageLuma8(img));
-            let encoded: Vec<u8> = serialize_features(&features);
-            THREAD_POOL.spawn(move || {
-                let _ = std::fs::create_dir("cache");
-                let _ = std::fs::write(frame_path, encoded);
-            });
-
-            features
-        };*/
-
         Self {
             features: points.into_iter().map(|x| x.point).collect(),
             descriptors,
@@ -139,37 +123,3 @@ impl ItemAkaze {
     }
 }
 
-
-/*#[derive(serde::Serialize, serde::Deserialize)]
-struct SerializedKeypoint {
-    pub point: (f32, f32),
-    pub response: f32,
-    pub size: f32,
-    pub octave: usize,
-    pub class_id: usize,
-    pub angle: f32,
-}
-impl From<&akaze::KeyPoint> for SerializedKeypoint {
-    fn from(v: &akaze::KeyPoint) -> Self {
-        Self { point: v.point, response: v.response, size: v.size, octave: v.octave, class_id: v.class_id, angle: v.angle }
-    }
-}
-impl Into<akaze::KeyPoint> for SerializedKeypoint {
-    fn into(self) -> akaze::KeyPoint {
-        akaze::KeyPoint { point: self.point, response: self.response, size: self.size, octave: self.octave, class_id: self.class_id, angle: self.angle }
-    }
-}
-#[derive(serde::Serialize, serde::Deserialize)]
-struct SerializedFeatures(Vec<SerializedKeypoint>, Vec<Vec<u8>>);
-
-fn serialize_features(x: &DetectedFeatures) -> Vec<u8> {
-    let out = SerializedFeatures(x.0.iter().map(SerializedKeypoint::from).collect(), x.1.iter().map(|v| v.bytes().to_vec()).collect());
-    bincode::serialize(&out).unwrap()
-}
-fn deserialize_features(x: &[u8]) -> DetectedFeatures {
-    let val: SerializedFeatures = bincode::deserialize(x).unwrap();
-    (
-        val.0.into_iter().map(SerializedKeypoint::into).collect(),
-        val.1.into_iter().map(|x| { let mut a = [0u8; 64]; a.copy_from_slice(&x); BitArray::<64>::new(a) }).collect(),
-    )
-}*/
@@ -10,6 +10,11 @@ use crate::StabilizationManager;
 use crate::stabilization::ComputeParams;
 use super::PoseEstimator;
 use super::SyncParams;
+use super::quality::{ self, SyncQualityReport };
+use super::feature_cache::FeatureCache;
+use super::rolling_shutter::RollingShutterEstimate;
+use super::global_offset::{ find_global_offset, GlobalOffset };
+use super::timestamp_sync::{ find_offset as find_timestamp_offset, TimestampOffset };
 
 pub struct AutosyncProcess {
     frame_count: usize,
@@ -28,12 +33,18 @@ pub struct AutosyncProcess {
     finished_cb: Option<Arc<Box<dyn Fn(Either<Vec<(f64, f64, f64)>, Option<(String, f64)>>) + Send + Sync + 'static>>>,
 
     sync_params: SyncParams,
+    quality_report: RwLock<Option<SyncQualityReport>>,
+    rolling_shutter_estimate: RwLock<Option<RollingShutterEstimate>>,
+    global_offset: RwLock<Option<GlobalOffset>>,
+    timestamp_offset: Option<TimestampOffset>,
+    feature_cache: Option<FeatureCache>,
+    cached_ranges_us: Vec<(i64, i64)>, // Scaled ranges loaded from the feature cache
 
     thread_pool: rayon::ThreadPool,
 }
 
 impl AutosyncProcess {
-    pub fn from_manager<T: crate::stabilization::PixelType>(stab: &StabilizationManager<T>, timestamps_fract: &[f64], sync_params: SyncParams, mode: String, cancel_flag: Arc<AtomicBool>) -> Result<Self, ()> {
+    pub fn from_manager<T: crate::stabilization::PixelType>(stab: &StabilizationManager<T>, timestamps_fract: &[f64], mut sync_params: SyncParams, mode: String, cancel_flag: Arc<AtomicBool>) -> Result<Self, ()> {
         let params = stab.params.read();
         let org_fps = params.fps;
         let scaled_fps = params.get_scaled_fps();
@@ -57,6 +68,16 @@ impl AutosyncProcess {
 
         if duration_ms < 10.0 || frame_count < 2 || time_per_syncpoint < 10.0 || search_size < 10.0 { return Err(()); }
 
+        let mut timestamp_offset = None;
+        if let (Some(settings), "synchronize") = (sync_params.timestamp_sync, mode.as_str()) {
+            let video_path = stab.input_file.read().path.clone();
+            timestamp_offset = find_timestamp_offset(&video_path, &stab.gyro.read(), &settings);
+            if let Some(offset) = &timestamp_offset {
+                offset.log();
+                offset.apply(&mut sync_params);
+            }
+        }
+
         let ranges_us: Vec<(i64, i64)> = timestamps_fract.iter().map(|x| {
             let range = (
                 ((x * org_duration_ms) - (time_per_syncpoint / 2.0)).max(0.0),
@@ -101,6 +122,12 @@ impl AutosyncProcess {
             org_fps,
             scaled_fps,
             sync_params,
+            quality_report: RwLock::new(None),
+            rolling_shutter_estimate: RwLock::new(None),
+            global_offset: RwLock::new(None),
+            timestamp_offset,
+            feature_cache: None,
+            cached_ranges_us: Vec::new(),
             mode,
             ranges_us,
             scaled_ranges_us,
@@ -116,8 +143,29 @@ impl AutosyncProcess {
         })
     }
 
+    /// Loads the detected features and optical flow of the video from the disk cache, and saves the newly detected ones when finished.
+    /// `processing_height` is the height of the frames passed to `feed_frame`, or 0 for the original resolution
+    pub fn enable_feature_cache(&mut self, video_path: &str, processing_height: u32) {
+        self.feature_cache = FeatureCache::open(video_path, processing_height, self.sync_params.of_method, self.sync_params.every_nth_frame.max(1), self.fps_scale, self.of_frames());
+        if let Some(cache) = &self.feature_cache {
+            self.cached_ranges_us = self.scaled_ranges_us.iter().copied().filter(|r| cache.covers(*r)).collect();
+            if !self.cached_ranges_us.is_empty() {
+                let count = cache.load_into(&self.estimator, &self.cached_ranges_us);
+                log::info!("Using {} cached frames for {}/{} sync ranges", count, self.cached_ranges_us.len(), self.scaled_ranges_us.len());
+            }
+        }
+    }
+
+    /// Number of frames ahead the optical flow is needed for
+    fn of_frames(&self) -> usize {
+        if self.sync_params.offset_method == 1 || self.mode == "estimate_rolling_shutter" { 2 } else { 1 }
+    }
+
+    /// Ranges to decode, in ms. Ranges loaded from the feature cache are skipped, so this can be empty
     pub fn get_ranges(&self) -> Vec<(f64, f64)> {
-        self.ranges_us.iter().map(|&v| (v.0 as f64 / 1000.0, v.1 as f64 / 1000.0)).collect()
+        self.ranges_us.iter().zip(self.scaled_ranges_us.iter())
+            .filter(|(_, scaled)| !self.cached_ranges_us.contains(scaled))
+            .map(|(&v, _)| (v.0 as f64 / 1000.0, v.1 as f64 / 1000.0)).collect()
     }
 
     pub fn feed_frame(&self, mut timestamp_us: i64, frame_no: usize, mut width: u32, height: u32, stride: usize, pixels: &[u8]) {
@@ -140,7 +188,7 @@ impl AutosyncProcess {
             timestamp_us = (timestamp_us as f64 / scale) as i64;
         }
 
-        if let Some(_current_range) = self.scaled_ranges_us.iter().find(|(from, to)| (*from..*to).contains(&timestamp_us)).copied() {
+        if let Some(_current_range) = self.scaled_ranges_us.iter().find(|(from, to)| (*from..*to).contains(&timestamp_us) && !self.cached_ranges_us.contains(&(*from, *to))).copied() {
             self.total_read_frames.fetch_add(1, SeqCst);
 
             self.thread_pool.spawn(move || {
@@ -180,7 +228,12 @@ impl AutosyncProcess {
 
         self.estimator.process_detected_frames(self.org_fps, self.scaled_fps, &self.compute_params.read());
         self.estimator.recalculate_gyro_data(self.org_fps, true);
-        self.estimator.cache_optical_flow(if offset_method == 1 { 2 } else { 1 });
+        self.estimator.cache_optical_flow(self.of_frames());
+        if let Some(cache) = &self.feature_cache {
+            if !self.cancel_flag.load(SeqCst) {
+                cache.save(&self.estimator, &self.scaled_ranges_us, 1_000_000.0 / self.scaled_fps * self.sync_params.every_nth_frame.max(1) as f64);
+            }
+        }
         self.estimator.cleanup();
 
         if let Some(cb) = &progress_cb {
@@ -189,7 +242,22 @@ impl AutosyncProcess {
             cb(0.6, d, t);
         }
 
-        let check_negative = self.sync_params.initial_offset_inv && self.sync_params.initial_offset.abs() > 1.0;
+        let mut sync_params = self.sync_params.clone();
+        // The offset from timestamps is more reliable, the global search is used only without it
+        if sync_params.global_search && self.timestamp_offset.is_none() && self.mode == "synchronize" {
+            // Replace the initial offset with the best match in the entire log
+            let global = find_global_offset(&self.scaled_ranges_us, &self.estimator.estimated_gyro.read(), &self.compute_params.read().gyro);
+            if let Some(global) = &global {
+                global.log();
+                sync_params.initial_offset = global.offset_ms;
+                sync_params.initial_offset_inv = false;
+            } else {
+                log::warn!("Global offset search failed, using the initial offset");
+            }
+            *self.global_offset.write() = global;
+        }
+
+        let check_negative = sync_params.initial_offset_inv && sync_params.initial_offset.abs() > 1.0;
 
         let for_negative = AtomicBool::new(false);
 
@@ -207,43 +275,49 @@ impl AutosyncProcess {
 
         if let Some(cb) = &self.finished_cb {
             if self.mode == "estimate_rolling_shutter" {
-                cb(Either::Left(self.estimator.find_offsets_visually(&self.scaled_ranges_us, &self.sync_params, &self.compute_params.read(), true, progress_cb2, self.cancel_flag.clone())));
+                let estimate = self.estimator.estimate_rolling_shutter(&self.scaled_ranges_us, &self.compute_params.read(), &self.sync_params.rolling_shutter, progress_cb2, self.cancel_flag.clone());
+                if let Some(estimate) = &estimate {
+                    estimate.log();
+                    cb(Either::Left(vec![(0.0, estimate.readout_ms, estimate.std_dev)]));
+                }
+                *self.rolling_shutter_estimate.write() = estimate;
             } else if self.mode == "guess_imu_orientation" {
-                let guessed = self.estimator.guess_orientation_rssync(&self.scaled_ranges_us, &self.sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone());
+                let search = self.estimator.search_orientation_rssync(&self.scaled_ranges_us, &self.sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone());
                 if !self.cancel_flag.load(SeqCst) {
-                    cb(Either::Right(guessed));
+                    search.log();
+                    cb(Either::Right(search.best()));
                 }
             } else {
                 let offsets = match offset_method {
-                    0 => self.estimator.find_offsets(&self.scaled_ranges_us, &self.sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()),
-                    1 => self.estimator.find_offsets_visually(&self.scaled_ranges_us, &self.sync_params, &self.compute_params.read(), false, progress_cb2, self.cancel_flag.clone()),
-                    2 => self.estimator.find_offsets_rssync(&self.scaled_ranges_us, &self.sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()),
+                    0 => self.estimator.find_offsets(&self.scaled_ranges_us, &sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()),
+                    1 => self.estimator.find_offsets_visually(&self.scaled_ranges_us, &sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()),
+                    2 => self.estimator.find_offsets_rssync(&self.scaled_ranges_us, &sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()),
                     _ => { log::error!("Unsupported offset method: {}", offset_method); Vec::new() }
                 };
                 if check_negative {
                     for_negative.store(true, SeqCst);
                     // Try also negative rough offset
-                    let mut sync_params = self.sync_params.clone();
-                    sync_params.initial_offset = -sync_params.initial_offset;
+                    let mut negative_params = sync_params.clone();
+                    negative_params.initial_offset = -negative_params.initial_offset;
                     let offsets2 = match offset_method {
-                        0 => self.estimator.find_offsets(&self.scaled_ranges_us, &sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()),
-                        1 => self.estimator.find_offsets_visually(&self.scaled_ranges_us, &sync_params, &self.compute_params.read(), false, progress_cb2, self.cancel_flag.clone()),
-                        2 => self.estimator.find_offsets_rssync(&self.scaled_ranges_us, &sync_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()),
+                        0 => self.estimator.find_offsets(&self.scaled_ranges_us, &negative_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()),
+                        1 => self.estimator.find_offsets_visually(&self.scaled_ranges_us, &negative_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()),
+                        2 => self.estimator.find_offsets_rssync(&self.scaled_ranges_us, &negative_params, &self.compute_params.read(), progress_cb2, self.cancel_flag.clone()),
                         _ => { log::error!("Unsupported offset method: {}", offset_method); Vec::new() }
                     };
                     if offsets2.len() > offsets.len() {
-                        cb(Either::Left(offsets2));
+                        cb(Either::Left(self.check_quality(offsets2)));
                     } else if offsets2.len() == offsets.len() {
                         let sum1: f64 = offsets.iter().map(|(_, _, cost)| *cost).sum();
                         let sum2: f64 = offsets2.iter().map(|(_, _, cost)| *cost).sum();
                         if sum1 < sum2 {
-                            cb(Either::Left(offsets));
+                            cb(Either::Left(self.check_quality(offsets)));
                         } else {
-                            cb(Either::Left(offsets2));
+                            cb(Either::Left(self.check_quality(offsets2)));
                         }
                     }
                 } else {
-                    cb(Either::Left(offsets));
+                    cb(Either::Left(self.check_quality(offsets)));
                 }
             }
         }
@@ -253,6 +327,36 @@ impl AutosyncProcess {
         }
     }
 
+    /// Evaluates the sync points if enabled in `SyncParams::quality` and returns only the accepted ones
+    fn check_quality(&self, offsets: Vec<(f64, f64, f64)>) -> Vec<(f64, f64, f64)> {
+        match &self.sync_params.quality {
+            Some(settings) => {
+                let report = quality::evaluate(&offsets, &self.scaled_ranges_us, &self.estimator, settings);
+                report.log();
+                let accepted = report.accepted_offsets();
+                *self.quality_report.write() = Some(report);
+                accepted
+            },
+            None => offsets
+        }
+    }
+    /// Quality report of the last synchronization, if enabled in `SyncParams::quality`
+    pub fn quality_report(&self) -> Option<SyncQualityReport> {
+        self.quality_report.read().clone()
+    }
+    /// Result of the global offset search, if enabled in `SyncParams::global_search`
+    pub fn global_offset(&self) -> Option<GlobalOffset> {
+        *self.global_offset.read()
+    }
+    /// Initial offset from the absolute time of the video and the log, if enabled in `SyncParams::timestamp_sync`
+    pub fn timestamp_offset(&self) -> Option<TimestampOffset> {
+        self.timestamp_offset
+    }
+    /// Result of the `estimate_rolling_shutter` mode
+    pub fn rolling_shutter_estimate(&self) -> Option<RollingShutterEstimate> {
+        self.rolling_shutter_estimate.read().clone()
+    }
+
     pub fn on_progress<F>(&mut self, cb: F) where F: Fn(f64, usize, usize) + Send + Sync + 'static {
         self.progress_cb = Some(Arc::new(Box::new(cb)));
     }
@@ -0,0 +1,249 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Clock drift model between the gyro and the video, fitted to the sync points.
+// External loggers have their own oscillator, so the offset changes slowly over the recording.
+// The model is a polynomial or a piecewise-linear curve of the offset over the gyro timestamp,
+// fitted robustly, so wrong sync points don't pull the curve and are flagged as outliers instead.
+
+use std::collections::BTreeMap;
+use nalgebra::{ DMatrix, DVector };
+use rand::seq::index::sample;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rand_xoshiro::rand_core::SeedableRng;
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+
+// Weight of the smoothness term of the piecewise-linear model, so segments without sync points follow the neighbors
+const REGULARIZATION_WEIGHT: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DriftModelKind {
+    /// Polynomial of the given degree, 1 is a constant drift rate
+    Polynomial { degree: usize },
+    /// Linear segments between knots spaced `segment_ms` apart
+    PiecewiseLinear { segment_ms: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RobustFit {
+    LeastSquares,
+    /// Consensus of random minimal subsets, then least squares on the inliers
+    Ransac { iterations: usize },
+    /// Iteratively reweighted least squares with the Huber loss
+    Huber { delta_ms: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DriftModelSettings {
+    pub kind: DriftModelKind,
+    pub fit: RobustFit,
+    /// Sync points with a larger residual are flagged as outliers
+    pub outlier_threshold_ms: f64,
+}
+impl Default for DriftModelSettings {
+    fn default() -> Self {
+        Self {
+            kind: DriftModelKind::Polynomial { degree: 1 },
+            fit: RobustFit::Ransac { iterations: 200 },
+            outlier_threshold_ms: 5.0,
+        }
+    }
+}
+impl DriftModelSettings {
+    /// Parses `linear`, `poly:<degree>` or `piecewise:<segment_ms>` and `lsq`, `ransac` or `huber`
+    pub fn from_names(kind: &str, fit: &str) -> Option<Self> {
+        let kind = match kind.split_once(':').unwrap_or((kind, "")) {
+            ("linear", _)           => DriftModelKind::Polynomial { degree: 1 },
+            ("poly", d)             => DriftModelKind::Polynomial { degree: d.parse().ok().filter(|x| *x <= 5)? },
+            ("piecewise", "")       => DriftModelKind::PiecewiseLinear { segment_ms: 60000.0 },
+            ("piecewise", s)        => DriftModelKind::PiecewiseLinear { segment_ms: s.parse().ok().filter(|x: &f64| *x > 0.0)? },
+            _ => return None
+        };
+        let fit = match fit {
+            "lsq"    => RobustFit::LeastSquares,
+            "ransac" => RobustFit::Ransac { iterations: 200 },
+            "huber"  => RobustFit::Huber { delta_ms: 1.0 },
+            _ => return None
+        };
+        Some(Self { kind, fit, ..Default::default() })
+    }
+}
+
+/// Fitted model, in gyro timestamps (ms)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DriftModel {
+    kind: DriftModelKind,
+    range_ms: (f64, f64),
+    coeffs: Vec<f64>,
+}
+impl DriftModel {
+    fn num_params(kind: DriftModelKind, range_ms: (f64, f64)) -> usize {
+        match kind {
+            DriftModelKind::Polynomial { degree } => degree + 1,
+            DriftModelKind::PiecewiseLinear { segment_ms } => ((range_ms.1 - range_ms.0) / segment_ms).ceil().max(1.0) as usize + 1,
+        }
+    }
+    fn basis(kind: DriftModelKind, range_ms: (f64, f64), timestamp_ms: f64) -> Vec<f64> {
+        let n = Self::num_params(kind, range_ms);
+        match kind {
+            DriftModelKind::Polynomial { .. } => {
+                // Normalize to -1..1 to keep the matrix well conditioned
+                let half = ((range_ms.1 - range_ms.0) / 2.0).max(1.0);
+                let t = (timestamp_ms - (range_ms.0 + range_ms.1) / 2.0) / half;
+                (0..n).map(|i| t.powi(i as i32)).collect()
+            },
+            DriftModelKind::PiecewiseLinear { segment_ms } => {
+                // Hat functions centered at the knots, extrapolated linearly from the first and last segment
+                let pos = (timestamp_ms - range_ms.0) / segment_ms;
+                let i = (pos.floor().max(0.0) as usize).min(n - 2);
+                let fract = pos - i as f64;
+                let mut ret = vec![0.0; n];
+                ret[i] = 1.0 - fract;
+                ret[i + 1] = fract;
+                ret
+            }
+        }
+    }
+
+    /// Offset in ms at the gyro timestamp
+    pub fn evaluate(&self, timestamp_ms: f64) -> f64 {
+        Self::basis(self.kind, self.range_ms, timestamp_ms).iter().zip(&self.coeffs).map(|(a, b)| a * b).sum()
+    }
+    /// Drift rate at the gyro timestamp, in ms per second
+    pub fn drift_rate(&self, timestamp_ms: f64) -> f64 {
+        self.evaluate(timestamp_ms + 500.0) - self.evaluate(timestamp_ms - 500.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DriftFit {
+    pub model: DriftModel,
+    /// Measured minus fitted offset, in ms. Key is the sync point timestamp in microseconds
+    pub residuals: BTreeMap<i64, f64>,
+    pub outliers: Vec<i64>,
+    /// RMS of the inlier residuals
+    pub rms_ms: f64,
+    pub max_abs_ms: f64,
+}
+impl DriftFit {
+    pub fn log(&self) {
+        ::log::info!("Clock drift fit: {} sync points, {} outliers, RMS {:.3} ms, max {:.3} ms", self.residuals.len(), self.outliers.len(), self.rms_ms, self.max_abs_ms);
+        for ts in &self.outliers {
+            ::log::warn!("Sync point at {:.3} s is an outlier, residual {:.3} ms", *ts as f64 / 1000000.0, self.residuals.get(ts).copied().unwrap_or_default());
+        }
+    }
+}
+
+/// Weighted least squares. `regularization` rows are added with zero target value
+fn solve_weighted(rows: &[Vec<f64>], values: &[f64], weights: &[f64], regularization: &[Vec<f64>]) -> Option<Vec<f64>> {
+    let n = rows.first()?.len();
+    let row = |r: usize| if r < rows.len() { &rows[r] } else { &regularization[r - rows.len()] };
+    let weight = |r: usize| if r < rows.len() { weights[r] } else { REGULARIZATION_WEIGHT };
+    let value = |r: usize| if r < rows.len() { values[r] } else { 0.0 };
+
+    let count = rows.len() + regularization.len();
+    let a = DMatrix::from_fn(count, n, |r, c| row(r)[c] * weight(r).sqrt());
+    let b = DVector::from_fn(count, |r, _| value(r) * weight(r).sqrt());
+    let svd = a.svd(true, true);
+    svd.solve(&b, 1e-12).ok().map(|x| x.iter().copied().collect())
+}
+
+/// Fits the model to the sync points. `offsets` are keyed by the timestamp in microseconds, values are offsets in ms
+pub fn fit(offsets: &BTreeMap<i64, f64>, settings: &DriftModelSettings) -> Option<DriftFit> {
+    if offsets.is_empty() { return None; }
+
+    let timestamps: Vec<f64> = offsets.keys().map(|k| *k as f64 / 1000.0).collect();
+    let values: Vec<f64> = offsets.values().copied().collect();
+    let range_ms = (*timestamps.first()?, *timestamps.last()?);
+
+    // Reduce the model if there's not enough points
+    let kind = match settings.kind {
+        DriftModelKind::Polynomial { degree } => DriftModelKind::Polynomial { degree: degree.min(offsets.len() - 1) },
+        DriftModelKind::PiecewiseLinear { .. } if offsets.len() < 3 => DriftModelKind::Polynomial { degree: offsets.len().min(2) - 1 },
+        x => x
+    };
+    let num_params = DriftModel::num_params(kind, range_ms);
+    let rows: Vec<Vec<f64>> = timestamps.iter().map(|t| DriftModel::basis(kind, range_ms, *t)).collect();
+    let residuals_of = |coeffs: &[f64]| -> Vec<f64> {
+        rows.iter().zip(&values).map(|(row, v)| v - row.iter().zip(coeffs).map(|(a, b)| a * b).sum::<f64>()).collect()
+    };
+    let threshold = settings.outlier_threshold_ms;
+
+    // Second differences of the knot values
+    let regularization: Vec<Vec<f64>> = match kind {
+        DriftModelKind::PiecewiseLinear { .. } => (1..num_params.saturating_sub(1)).map(|i| {
+            let mut row = vec![0.0; num_params];
+            row[i - 1] = 1.0;
+            row[i] = -2.0;
+            row[i + 1] = 1.0;
+            row
+        }).collect(),
+        _ => Vec::new()
+    };
+    let solve = |weights: &[f64]| solve_weighted(&rows, &values, weights, &regularization);
+
+    let coeffs = match settings.fit {
+        RobustFit::LeastSquares => solve(&vec![1.0; rows.len()])?,
+        RobustFit::Huber { delta_ms } => {
+            let mut weights = vec![1.0; rows.len()];
+            let mut coeffs = solve(&weights)?;
+            for _ in 0..20 {
+                for (w, r) in weights.iter_mut().zip(residuals_of(&coeffs)) {
+                    *w = if r.abs() <= delta_ms { 1.0 } else { delta_ms / r.abs() };
+                }
+                let new_coeffs = solve(&weights)?;
+                let change = new_coeffs.iter().zip(&coeffs).map(|(a, b)| (a - b).abs()).fold(0.0, f64::max);
+                coeffs = new_coeffs;
+                if change < 1e-6 { break; }
+            }
+            coeffs
+        },
+        RobustFit::Ransac { iterations } => {
+            let subset_size = num_params.min(rows.len());
+            let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+            let mut best_inliers: Vec<bool> = Vec::new();
+            let mut best_count = 0;
+            let mut best_error = f64::MAX;
+            for _ in 0..iterations.max(1) {
+                let mut weights = vec![0.0; rows.len()];
+                for i in sample(&mut rng, rows.len(), subset_size).into_iter() { weights[i] = 1.0; }
+
+                if let Some(coeffs) = solve(&weights) {
+                    let residuals = residuals_of(&coeffs);
+                    let inliers: Vec<bool> = residuals.iter().map(|r| r.abs() < threshold).collect();
+                    let count = inliers.iter().filter(|x| **x).count();
+                    let error: f64 = residuals.iter().zip(&inliers).filter(|(_, i)| **i).map(|(r, _)| r * r).sum();
+                    if count > best_count || (count == best_count && error < best_error) {
+                        best_inliers = inliers;
+                        best_count = count;
+                        best_error = error;
+                    }
+                }
+                // All points agree
+                if best_count == rows.len() { break; }
+            }
+            if best_count < subset_size {
+                // No consensus, fall back to all points
+                best_inliers = vec![true; rows.len()];
+            }
+            let weights: Vec<f64> = best_inliers.iter().map(|x| if *x { 1.0 } else { 0.0 }).collect();
+            solve(&weights)?
+        }
+    };
+
+    let residuals = residuals_of(&coeffs);
+    let keys: Vec<i64> = offsets.keys().copied().collect();
+    let outliers: Vec<i64> = keys.iter().zip(&residuals).filter(|(_, r)| r.abs() >= threshold).map(|(k, _)| *k).collect();
+    let inlier_residuals: Vec<f64> = residuals.iter().copied().filter(|r| r.abs() < threshold).collect();
+
+    Some(DriftFit {
+        model: DriftModel { kind, range_ms, coeffs },
+        rms_ms: (inlier_residuals.iter().map(|r| r * r).sum::<f64>() / inlier_residuals.len().max(1) as f64).sqrt(),
+        max_abs_ms: residuals.iter().map(|r| r.abs()).fold(0.0, f64::max),
+        residuals: keys.into_iter().zip(residuals).collect(),
+        outliers,
+    })
+}
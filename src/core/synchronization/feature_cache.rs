@@ -0,0 +1,260 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Disk cache of the detected features and optical flow. The cache file is keyed by the video file and processing resolution,
+// so running the sync again with different parameters or offset method doesn't need to decode the video and detect the features again.
+
+use std::collections::BTreeMap;
+use std::io::{ Read, Write };
+use std::path::PathBuf;
+use nalgebra::Rotation3;
+use serde::{ Serialize, Deserialize };
+use crate::stabilization::ComputeParams;
+use super::akaze::{ self, Descriptor, ItemAkaze };
+use super::{ EstimatorItem, EstimatorItemInterface, FrameResult, OpticalFlowPair, OpticalFlowPairWithTs, PoseEstimator };
+
+const CACHE_VERSION: u32 = 2;
+// Cached frames are numbered from here, so they are never adjacent to the newly decoded frames
+const CACHED_FRAME_NO_BASE: usize = 1_000_000_000;
+// Amount of data hashed at the beginning and end of the file
+const HASH_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Frame loaded from the cache. Only AKAZE has descriptors, for other methods only the stored optical flow is available
+#[derive(Default, Clone)]
+pub struct ItemCached {
+    features: Vec<(f32, f32)>,
+    descriptors: Option<Vec<Descriptor>>,
+    flow_to_next: OpticalFlowPair,
+    img_size: (u32, u32),
+    of_method: usize
+}
+
+impl EstimatorItemInterface for ItemCached {
+    fn get_features(&self) -> &Vec<(f32, f32)> {
+        &self.features
+    }
+
+    fn estimate_pose(&self, next: &EstimatorItem, params: &ComputeParams, timestamp_us: i64, next_timestamp_us: i64) -> Option<Rotation3<f64>> {
+        // Only called with the next frame
+        let (pts1, pts2) = self.optical_flow_to(next)?;
+        // Same estimator as the method which detected the features, so the cached results match the uncached ones
+        match self.of_method {
+            #[cfg(feature = "use-opencv")]
+            1 | 2 => super::opencv::pose_from_matches(&pts1, &pts2, params, timestamp_us, next_timestamp_us, self.img_size),
+            _ => akaze::pose_from_matches(&pts1, &pts2, params, timestamp_us, next_timestamp_us, self.img_size)
+        }
+    }
+
+    fn optical_flow_to(&self, to: &EstimatorItem) -> OpticalFlowPair {
+        #[allow(unreachable_patterns)]
+        let to = match to {
+            EstimatorItem::ItemAkaze(to) => Some((to.descriptors(), to.get_features())),
+            EstimatorItem::ItemCached(to) => to.descriptors.as_deref().map(|d| (d, &to.features)),
+            _ => None
+        };
+        match (&self.descriptors, to) {
+            (Some(ds1), Some((ds2, features2))) => {
+                Some(ItemAkaze::match_descriptors(ds1, ds2).into_iter()
+                    .map(|(i1, i2)| (self.features[i1], features2[i2]))
+                    .unzip())
+            },
+            _ => self.flow_to_next.clone()
+        }
+    }
+
+    fn cleanup(&mut self) { }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedFrame {
+    frame_no: usize,
+    timestamp_us: i64,
+    frame_size: (u32, u32),
+    features: Vec<(f32, f32)>,
+    descriptors: Option<Vec<Vec<u8>>>,
+    optical_flow: BTreeMap<usize, OpticalFlowPairWithTs>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    of_method: usize,
+    /// Number of frames the optical flow was calculated for
+    of_frames: usize,
+    /// Video timestamps with fps scale applied, in microseconds
+    ranges_us: Vec<(i64, i64)>,
+    frames: Vec<CachedFrame>,
+}
+
+pub struct FeatureCache {
+    path: PathBuf,
+    of_frames: usize,
+    file: CacheFile,
+}
+
+impl FeatureCache {
+    pub fn cache_dir() -> PathBuf {
+        std::env::var_os("GYROFLOW_CACHE_DIR").map(PathBuf::from).unwrap_or_else(|| std::env::temp_dir().join("gyroflow_cache"))
+    }
+
+    fn file_hash(video_path: &str) -> Option<u32> {
+        use std::io::{ Seek, SeekFrom };
+        let mut file = std::fs::File::open(video_path).ok()?;
+        let size = file.metadata().ok()?.len();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&size.to_le_bytes());
+
+        let mut buf = Vec::with_capacity(HASH_CHUNK_SIZE as usize);
+        (&mut file).take(HASH_CHUNK_SIZE).read_to_end(&mut buf).ok()?;
+        hasher.update(&buf);
+        if size > HASH_CHUNK_SIZE {
+            buf.clear();
+            file.seek(SeekFrom::Start(size.saturating_sub(HASH_CHUNK_SIZE).max(HASH_CHUNK_SIZE))).ok()?;
+            file.take(HASH_CHUNK_SIZE).read_to_end(&mut buf).ok()?;
+            hasher.update(&buf);
+        }
+        Some(hasher.finalize())
+    }
+
+    /// Opens the cache of the video. The key contains everything which changes the detected features
+    pub fn open(video_path: &str, processing_height: u32, of_method: usize, every_nth_frame: usize, fps_scale: Option<f64>, of_frames: usize) -> Option<Self> {
+        let hash = Self::file_hash(video_path)?;
+        let name = format!("{:08x}-{}-{}-{}-{}.bin", hash, processing_height, of_method, every_nth_frame, (fps_scale.unwrap_or(1.0) * 1000.0).round() as i64);
+        let path = Self::cache_dir().join(name);
+
+        let file = std::fs::read(&path).ok().and_then(|compressed| {
+            let mut data = Vec::new();
+            flate2::read::ZlibDecoder::new(&compressed[..]).read_to_end(&mut data).ok()?;
+            bincode::deserialize::<CacheFile>(&data).ok()
+        }).filter(|x| x.version == CACHE_VERSION && x.of_frames >= of_frames);
+
+        if let Some(file) = &file {
+            ::log::info!("Loaded feature cache {}: {} frames in {} ranges", path.display(), file.frames.len(), file.ranges_us.len());
+        }
+
+        Some(Self {
+            path,
+            of_frames,
+            file: file.unwrap_or_else(|| CacheFile { version: CACHE_VERSION, of_method, of_frames, ..Default::default() })
+        })
+    }
+
+    /// Whether all frames of the range are in the cache
+    pub fn covers(&self, range: (i64, i64)) -> bool {
+        self.file.ranges_us.iter().any(|(from, to)| *from <= range.0 && *to >= range.1)
+    }
+
+    /// Inserts the cached frames of the covered ranges to the estimator. Returns the number of inserted frames
+    pub fn load_into(&self, estimator: &PoseEstimator, ranges_us: &[(i64, i64)]) -> usize {
+        let mut next_frame_no = CACHED_FRAME_NO_BASE;
+        let mut count = 0;
+        let mut l = estimator.sync_results.write();
+        for range in ranges_us.iter().filter(|r| self.covers(**r)) {
+            let frames: Vec<&CachedFrame> = self.file.frames.iter().filter(|x| (range.0..range.1).contains(&x.timestamp_us)).collect();
+            let first_frame_no = frames.iter().map(|x| x.frame_no).min().unwrap_or_default();
+            let mut last_frame_no = next_frame_no;
+            for x in frames {
+                let frame_no = next_frame_no + x.frame_no - first_frame_no;
+                last_frame_no = last_frame_no.max(frame_no);
+                let item = ItemCached {
+                    features: x.features.clone(),
+                    descriptors: x.descriptors.as_ref().map(|v| v.iter().filter_map(|d| Some(Descriptor::new(d.as_slice().try_into().ok()?))).collect()),
+                    flow_to_next: x.optical_flow.get(&1).cloned().flatten().map(|((_, a), (_, b))| (a, b)),
+                    img_size: x.frame_size,
+                    of_method: self.file.of_method
+                };
+                l.entry(x.timestamp_us).or_insert_with(|| {
+                    count += 1;
+                    FrameResult {
+                        item: item.into(),
+                        frame_no,
+                        frame_size: x.frame_size,
+                        timestamp_us: x.timestamp_us,
+                        gyro_timestamp_us: 0,
+                        rotation: None,
+                        quat: None,
+                        euler: None,
+                        optical_flow: std::cell::RefCell::new(x.optical_flow.clone())
+                    }
+                });
+            }
+            // Leave a gap between the ranges
+            next_frame_no = last_frame_no + 2;
+        }
+        count
+    }
+
+    /// Whether the frames cover the whole range without any missing frame
+    fn is_complete(timestamps: &[i64], range: (i64, i64), max_gap_us: f64) -> bool {
+        match (timestamps.first(), timestamps.last()) {
+            (Some(first), Some(last)) => {
+                (first - range.0) as f64 <= max_gap_us &&
+                (range.1 - last) as f64 <= max_gap_us &&
+                timestamps.windows(2).all(|w| (w[1] - w[0]) as f64 <= max_gap_us)
+            },
+            _ => false
+        }
+    }
+
+    /// Stores the frames of the ranges in the cache, replacing the previously cached frames in these ranges.
+    /// Ranges with missing frames (failed decoding or detection) are not stored. `frame_duration_us` is the interval of the processed frames
+    pub fn save(&self, estimator: &PoseEstimator, ranges_us: &[(i64, i64)], frame_duration_us: f64) {
+        let ranges_us: Vec<(i64, i64)> = ranges_us.iter().copied().filter(|r| !self.covers(*r)).collect();
+        if ranges_us.is_empty() { return; }
+        let in_ranges = |ranges_us: &[(i64, i64)], ts: &i64| ranges_us.iter().any(|(from, to)| (*from..*to).contains(ts));
+
+        let mut frames = Vec::new();
+        {
+            let l = estimator.sync_results.read();
+            for (ts, x) in l.iter().filter(|(ts, _)| in_ranges(&ranges_us, ts)) {
+                let optical_flow = match x.optical_flow.try_borrow() {
+                    Ok(of) => of.clone(),
+                    Err(_) => continue
+                };
+                #[allow(unreachable_patterns)]
+                let descriptors = match &x.item {
+                    EstimatorItem::ItemAkaze(item) => Some(item.descriptors()),
+                    EstimatorItem::ItemCached(item) => item.descriptors.as_deref(),
+                    _ => None
+                };
+                frames.push(CachedFrame {
+                    frame_no: x.frame_no,
+                    timestamp_us: *ts,
+                    frame_size: x.frame_size,
+                    features: x.item.get_features().clone(),
+                    descriptors: descriptors.map(|v| v.iter().map(|d| d.bytes().to_vec()).collect()),
+                    optical_flow
+                });
+            }
+        }
+
+        let complete_ranges: Vec<(i64, i64)> = ranges_us.iter().copied().filter(|r| {
+            let timestamps: Vec<i64> = frames.iter().map(|x| x.timestamp_us).filter(|ts| (r.0..r.1).contains(ts)).collect();
+            let complete = Self::is_complete(&timestamps, *r, frame_duration_us * 1.5);
+            if !complete {
+                ::log::debug!("Not caching the range {:?}, some frames are missing", r);
+            }
+            complete
+        }).collect();
+        if complete_ranges.is_empty() { return; }
+
+        let mut file = self.file.clone();
+        file.of_frames = self.of_frames;
+        file.frames.retain(|x| !in_ranges(&complete_ranges, &x.timestamp_us));
+        file.frames.extend(frames.into_iter().filter(|x| in_ranges(&complete_ranges, &x.timestamp_us)));
+        file.frames.sort_by_key(|x| x.timestamp_us);
+        file.ranges_us.extend(complete_ranges.iter().copied());
+
+        let result = (|| -> std::io::Result<()> {
+            let data = bincode::serialize(&file).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            let mut e = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+            e.write_all(&data)?;
+            std::fs::create_dir_all(Self::cache_dir())?;
+            std::fs::write(&self.path, e.finish()?)
+        })();
+        match result {
+            Ok(_) => ::log::info!("Saved feature cache {}: {} frames in {} ranges", self.path.display(), file.frames.len(), file.ranges_us.len()),
+            Err(e) => ::log::warn!("Failed to save feature cache {}: {}", self.path.display(), e)
+        }
+    }
+}
@@ -4,6 +4,7 @@
 use super::OpticalFlowPoints;
 use super::FrameResult;
 use super::SyncParams;
+use super::orientation::{ self, OrientationSearch };
 use crate::gyro_source::{ Quat64, TimeQuat, GyroSource };
 use crate::stabilization::{ undistort_points_for_optical_flow, ComputeParams };
 use nalgebra::Vector3;
@@ -22,7 +23,7 @@ pub struct FindOffsetsRssync<'a> {
     frame_readout_time: f64,
     sync_points: Vec::<(i64, i64)>,
     sync_params: &'a SyncParams,
-    is_guess_orient: Arc<AtomicBool>,
+    num_orientations: Arc<AtomicUsize>,
 
     current_sync_point: Arc<AtomicUsize>,
     current_orientation: Arc<AtomicUsize>
@@ -51,7 +52,7 @@ impl FindOffsetsRssync<'_> {
             frame_readout_time: frame_readout_time,
             sync_points: Vec::new(),
             sync_params,
-            is_guess_orient: Arc::new(AtomicBool::new(false)),
+            num_orientations: Arc::new(AtomicUsize::new(1)),
             current_sync_point: Arc::new(AtomicUsize::new(0)),
             current_orientation: Arc::new(AtomicUsize::new(0))
         };
@@ -59,11 +60,11 @@ impl FindOffsetsRssync<'_> {
 
         {
             let num_sync_points = matched_points.len() as f64;
-            let is_guess_orient = ret.is_guess_orient.clone();
+            let num_orientations = ret.num_orientations.clone();
             let cur_sync_point = ret.current_sync_point.clone();
             let cur_orientation = ret.current_orientation.clone();
             ret.sync.on_progress( move |progress| -> bool {
-                let num_orientations  = if is_guess_orient.load(SeqCst) { 48.0 } else { 1.0 };
+                let num_orientations = num_orientations.load(SeqCst).max(1) as f64;
                 progress_cb((cur_orientation.load(SeqCst) as f64 + ((cur_sync_point.load(SeqCst) as f64 + progress) / num_sync_points)) / num_orientations);
                 !cancel_flag.load(Relaxed)
             });
@@ -116,7 +117,7 @@ impl FindOffsetsRssync<'_> {
     }
 
     pub fn full_sync(&mut self) -> Vec<(f64, f64, f64)> { // Vec<(timestamp, offset, cost)>
-        self.is_guess_orient.store(false, SeqCst);
+        self.num_orientations.store(1, SeqCst);
 
         let mut offsets = Vec::new();
         set_quats(&mut self.sync, &self.gyro_source.quaternions);
@@ -150,37 +151,44 @@ impl FindOffsetsRssync<'_> {
     }
 
     pub fn guess_orient(&mut self) -> Option<(String, f64)> {
-        self.is_guess_orient.store(true, SeqCst);
+        self.search_orientations(true).best()
+    }
 
-        let mut clone_source = self.gyro_source.clone();
+    /// Evaluates every orientation in each sync range and ranks them
+    pub fn search_orientations(&mut self, include_mirrored: bool) -> OrientationSearch {
+        let possible_orientations = orientation::all_orientations(include_mirrored);
+        self.num_orientations.store(possible_orientations.len(), SeqCst);
+        self.current_orientation.store(0, SeqCst);
 
-        let possible_orientations = [
-            "YxZ", "Xyz", "XZy", "Zxy", "zyX", "yxZ", "ZXY", "zYx", "ZYX", "yXz", "YZX", "XyZ",
-            "Yzx", "zXy", "YXz", "xyz", "yZx", "XYZ", "zxy", "xYz", "XYz", "zxY", "zXY", "xZy",
-            "zyx", "xyZ", "Yxz", "xzy", "yZX", "yzX", "ZYx", "xYZ", "zYX", "ZxY", "yzx", "xZY",
-            "Xzy", "XzY", "YzX", "Zyx", "XZY", "yxz", "xzY", "ZyX", "YXZ", "yXZ", "YZx", "ZXy"
-        ];
+        let mut clone_source = self.gyro_source.clone();
 
-        possible_orientations.iter().map(|orient| {
-            clone_source.imu_orientation = Some(orient.to_string());
+        let costs = possible_orientations.into_iter().map(|orient| {
+            clone_source.imu_orientation = Some(orient.clone());
             clone_source.apply_transforms();
 
             set_quats(&mut self.sync, &clone_source.quaternions);
 
-            let total_cost: f64 = self.sync_points.iter().map(|(from_ts, to_ts)| {
+            let range_costs: Vec<Option<f64>> = self.sync_points.iter().map(|(from_ts, to_ts)| {
                 self.sync.pre_sync(
                     -self.sync_params.initial_offset / 1000.0,
                     *from_ts,
                     *to_ts,
                     3.0 / 1000.0,
                     self.sync_params.search_size / 1000.0
-                ).unwrap_or((0.0,0.0))
-            }).map(|v| {v.0}).sum();
+                ).map(|v| v.0)
+            }).collect();
 
             self.current_orientation.fetch_add(1, SeqCst);
 
-            (orient.to_string(), total_cost)
-        }).reduce(|a: (String, f64), b: (String, f64)| -> (String, f64) { if a.1 < b.1 { a } else { b } })
+            (orient, range_costs)
+        }).collect();
+
+        let mut search = OrientationSearch::from_costs(costs);
+        if let Some((best, _)) = search.best() {
+            clone_source.imu_orientation = Some(best);
+            search.acc_rotation = orientation::guess_acc_rotation(&clone_source);
+        }
+        search
     }
 
     fn collect_points(sync_results: Arc<RwLock<BTreeMap<i64, FrameResult>>>, ranges: &[(i64, i64)]) -> Vec<Vec<(((i64, OpticalFlowPoints), (i64, OpticalFlowPoints)), (u32, u32))>> {
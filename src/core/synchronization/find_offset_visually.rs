@@ -7,13 +7,65 @@ use std::sync::{ Arc, atomic::{ AtomicBool, Ordering::Relaxed } };
 use super::PoseEstimator;
 use super::SyncParams;
 
-pub fn find_offsets<F: Fn(f64) + Sync>(ranges: &[(i64, i64)], estimator: &PoseEstimator, sync_params: &SyncParams, params: &ComputeParams, for_rs: bool, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Vec<(f64, f64, f64)> { // Vec<(timestamp, offset, cost)>
+pub type MatchedPoints = Vec<((i64, Vec<(f32, f32)>), (i64, Vec<(f32, f32)>))>;
+
+/// Optical flow lines of the frames in the range, to `next_frame_no` frames ahead
+pub fn matched_points(estimator: &PoseEstimator, keys: &[i64], from_ts: i64, to_ts: i64, next_frame_no: usize) -> MatchedPoints {
+    let mut matched_points = Vec::new();
+    for ts in keys {
+        if (from_ts..to_ts).contains(&ts) {
+            match estimator.get_of_lines_for_timestamp(&ts, 0, 1.0, next_frame_no, true) {
+                (Some(lines), Some(_frame_size)) => {
+                    if !lines.0.1.is_empty() && lines.0.1.len() == lines.1.1.len() {
+                        matched_points.push(lines);
+                    } else {
+                        log::warn!("Invalid point pairs {} {}", lines.0.1.len(), lines.1.1.len());
+                    }
+                },
+                _ => {
+                    log::warn!("No detected features for ts {}", ts);
+                }
+            }
+        }
+    }
+    matched_points
+}
+
+/// Sum of squared distances between the undistorted matched points, with the gyro offset `offs`
+pub fn calculate_distance(matched_points: &MatchedPoints, offs: f64, params: &ComputeParams) -> f64 {
     let (w, h) = (params.width as i32, params.height as i32);
+    let mut total_dist = 0.0;
+
+    for ((ts, pts1), (next_ts, pts2)) in matched_points {
+        let timestamp_ms  = *ts as f64 / 1000.0;
+        let timestamp_ms2 = *next_ts as f64 / 1000.0;
+
+        let undistorted_points1 = stabilization::undistort_points_with_rolling_shutter(&pts1, timestamp_ms - offs, params);
+        let undistorted_points2 = stabilization::undistort_points_with_rolling_shutter(&pts2, timestamp_ms2 - offs, params);
+
+        let mut distances = Vec::with_capacity(undistorted_points1.len());
+        for (p1, p2) in undistorted_points1.iter().zip(undistorted_points2.iter()) {
+            if p1.0 > 0.0 && p1.0 < w as f32 && p1.1 > 0.0 && p1.1 < h as f32 &&
+               p2.0 > 0.0 && p2.0 < w as f32 && p2.1 > 0.0 && p2.1 < h as f32 {
+                let dist = ((p2.0 - p1.0) * (p2.0 - p1.0))
+                              + ((p2.1 - p1.1) * (p2.1 - p1.1));
+                distances.push(dist as u64);
+            }
+        }
+        distances.sort_unstable();
 
+        // Use only 90% of lines, discard the longest ones as they are often wrongly computed point matches
+        for dist in &distances[0..(distances.len() as f64 * 0.9) as usize] {
+            total_dist += *dist as f64;
+        }
+    }
+    total_dist
+}
+
+pub fn find_offsets<F: Fn(f64) + Sync>(ranges: &[(i64, i64)], estimator: &PoseEstimator, sync_params: &SyncParams, params: &ComputeParams, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Vec<(f64, f64, f64)> { // Vec<(timestamp, offset, cost)>
     let mut final_offsets = Vec::new();
 
     let next_frame_no = 2;
-    let fps = params.gyro.fps;
     let ranges_len = ranges.len() as f64;
 
     let keys: Vec<i64> = estimator.sync_results.read().keys().copied().collect();
@@ -22,117 +74,39 @@ pub fn find_offsets<F: Fn(f64) + Sync>(ranges: &[(i64, i64)], estimator: &PoseEs
         if cancel_flag.load(Relaxed) { break; }
         progress_cb(i as f64 / ranges_len);
 
-        let mut matched_points = Vec::new();
-        for ts in &keys {
-            if (*from_ts..*to_ts).contains(&ts) {
-                match estimator.get_of_lines_for_timestamp(&ts, 0, 1.0, next_frame_no, true) {
-                    (Some(lines), Some(_frame_size)) => {
-                        if !lines.0.1.is_empty() && lines.0.1.len() == lines.1.1.len() {
-                            matched_points.push(lines);
-                        } else {
-                            log::warn!("Invalid point pairs {} {}", lines.0.1.len(), lines.1.1.len());
-                        }
-                    },
-                    _ => {
-                        log::warn!("No detected features for ts {}", ts);
-                    }
-                }
-            }
-        }
-
-        let calculate_distance = |offs, rs: Option<f64>| -> f64 {
-            let mut total_dist = 0.0;
-            let mut params_ref = params;
-            let mut _params2 = None;
-            if let Some(rs) = rs {
-                _params2 = Some(params.clone());
-                _params2.as_mut().unwrap().frame_readout_time = rs;
-                params_ref = _params2.as_ref().unwrap();
-            }
-
-            for ((ts, pts1), (next_ts, pts2)) in &matched_points {
-                let timestamp_ms  = *ts as f64 / 1000.0;
-                let timestamp_ms2 = *next_ts as f64 / 1000.0;
-
-                let undistorted_points1 = stabilization::undistort_points_with_rolling_shutter(&pts1, timestamp_ms - offs, params_ref);
-                let undistorted_points2 = stabilization::undistort_points_with_rolling_shutter(&pts2, timestamp_ms2 - offs, params_ref);
-
-                let mut distances = Vec::with_capacity(undistorted_points1.len());
-                for (p1, p2) in undistorted_points1.iter().zip(undistorted_points2.iter()) {
-                    if p1.0 > 0.0 && p1.0 < w as f32 && p1.1 > 0.0 && p1.1 < h as f32 &&
-                       p2.0 > 0.0 && p2.0 < w as f32 && p2.1 > 0.0 && p2.1 < h as f32 {
-                        let dist = ((p2.0 - p1.0) * (p2.0 - p1.0))
-                                      + ((p2.1 - p1.1) * (p2.1 - p1.1));
-                        distances.push(dist as u64);
-                    }
-                }
-                distances.sort_unstable();
-
-                // Use only 90% of lines, discard the longest ones as they are often wrongly computed point matches
-                for dist in &distances[0..(distances.len() as f64 * 0.9) as usize] {
-                    total_dist += *dist as f64;
-                }
-            }
-            total_dist
-        };
+        let matched_points = matched_points(estimator, &keys, *from_ts, *to_ts, next_frame_no);
 
         let find_min = |a: (f64, f64), b: (f64, f64)| -> (f64, f64) { if a.1 < b.1 { a } else { b } };
 
-        if for_rs { // Estimate rolling shutter
-            // First search every 1 ms
-            let max_rs = 1000.0 / fps;
-            let steps = max_rs as isize;
-            let lowest = (-steps..steps)
-                .into_par_iter()
-                .map(|i| {
-                    (i as f64, calculate_distance(0.0, Some(i as f64)))
-                })
-                .reduce_with(find_min)
-                .and_then(|lowest| {
-                    // Then refine to 0.01 ms
-                    (0..200)
-                        .into_par_iter()
-                        .map(|i| {
-                            let rs = lowest.0 - 1.0 + (i as f64 * 0.01);
-                            (rs, calculate_distance(0.0, Some(rs)))
-                        })
-                        .reduce_with(find_min)
-                });
-            log::debug!("lowest: {:?}", &lowest);
-            if let Some(lowest) = lowest {
-                final_offsets.push((0.0, lowest.0, lowest.1));
-            }
-        } else {
-            // First search every 1 ms
-            let steps = sync_params.search_size as usize;
-            let lowest = (0..steps)
-                .into_par_iter()
-                .map(|i| {
-                    let offs = sync_params.initial_offset + (-(sync_params.search_size / 2.0) + (i as f64));
-                    (offs, calculate_distance(offs, None))
-                })
-                .reduce_with(find_min)
-                .and_then(|lowest| {
-                    // Then refine to 0.01 ms
-                    (0..200)
-                        .into_par_iter()
-                        .map(|i| {
-                            let offs = lowest.0 - 1.0 + (i as f64 * 0.01);
-                            (offs, calculate_distance(offs, None))
-                        })
-                        .reduce_with(find_min)
-                });
-
-            log::debug!("lowest: {:?}", &lowest);
-            if let Some(lowest) = lowest {
-                let middle_timestamp = (*from_ts as f64 + (to_ts - from_ts) as f64 / 2.0) / 1000.0;
-
-                // Only accept offsets that are within 90% of search size range
-                if (lowest.0 - sync_params.initial_offset).abs() < sync_params.search_size * 0.9 {
-                    final_offsets.push((middle_timestamp, lowest.0, lowest.1));
-                } else {
-                    log::warn!("Sync point out of acceptable range {} < {}", (lowest.0 - sync_params.initial_offset).abs(), sync_params.search_size * 0.9);
-                }
+        // First search every 1 ms
+        let steps = sync_params.search_size as usize;
+        let lowest = (0..steps)
+            .into_par_iter()
+            .map(|i| {
+                let offs = sync_params.initial_offset + (-(sync_params.search_size / 2.0) + (i as f64));
+                (offs, calculate_distance(&matched_points, offs, params))
+            })
+            .reduce_with(find_min)
+            .and_then(|lowest| {
+                // Then refine to 0.01 ms
+                (0..200)
+                    .into_par_iter()
+                    .map(|i| {
+                        let offs = lowest.0 - 1.0 + (i as f64 * 0.01);
+                        (offs, calculate_distance(&matched_points, offs, params))
+                    })
+                    .reduce_with(find_min)
+            });
+
+        log::debug!("lowest: {:?}", &lowest);
+        if let Some(lowest) = lowest {
+            let middle_timestamp = (*from_ts as f64 + (to_ts - from_ts) as f64 / 2.0) / 1000.0;
+
+            // Only accept offsets that are within 90% of search size range
+            if (lowest.0 - sync_params.initial_offset).abs() < sync_params.search_size * 0.9 {
+                final_offsets.push((middle_timestamp, lowest.0, lowest.1));
+            } else {
+                log::warn!("Sync point out of acceptable range {} < {}", (lowest.0 - sync_params.initial_offset).abs(), sync_params.search_size * 0.9);
             }
         }
     }
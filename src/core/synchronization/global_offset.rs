@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Coarse offset search over the entire motion log, for logs much longer than the video (e.g. one blackbox log for many clips).
+// Angular speed estimated from the optical flow in each sync range is cross-correlated with the whole log. The correlations
+// of all ranges are summed, so the offset has to match in all of them. The result is refined with the regular offset methods.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use crate::gyro_source::{ GyroSource, TimeIMU };
+use super::optimsync::normalized_cross_correlation;
+
+// Sample rate of the compared signals
+const SAMPLE_RATE: f64 = 100.0;
+// Peaks closer than this to the best one are not considered as the second best peak
+const PEAK_EXCLUSION_MS: f64 = 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct GlobalOffset {
+    /// Offset in ms, same as the offsets in `GyroSource`
+    pub offset_ms: f64,
+    /// Average normalized correlation of the ranges at the offset, -1 to 1
+    pub score: f64,
+    /// Score divided by the score of the second best peak. Close to 1 means the result is ambiguous
+    pub peak_ratio: f64,
+    pub ranges: usize,
+}
+impl GlobalOffset {
+    pub fn log(&self) {
+        ::log::info!("Global offset search: {:.1} ms, correlation {:.3}, peak ratio {:.2}, {} ranges", self.offset_ms, self.score, self.peak_ratio, self.ranges);
+        if self.peak_ratio < 1.2 {
+            ::log::warn!("Global offset is ambiguous, there are other offsets with similar correlation");
+        }
+    }
+}
+
+fn angular_speed(v: &[f64; 3]) -> f64 {
+    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+
+/// Angular speed of the log averaged to `SAMPLE_RATE`. Returns (timestamp of the first sample, samples)
+fn log_signal(gyro: &GyroSource) -> Option<(f64, Vec<f64>)> {
+    let imu: Vec<(f64, f64)> = gyro.raw_imu.iter().filter_map(|x| Some((x.timestamp_ms, angular_speed(x.gyro.as_ref()?)))).collect();
+    let first = imu.first()?.0;
+    let len = ((imu.last()?.0 - first) * SAMPLE_RATE / 1000.0) as usize + 1;
+    let mut sum = vec![0.0; len];
+    let mut count = vec![0usize; len];
+    for (ts, v) in &imu {
+        let i = (((ts - first) * SAMPLE_RATE / 1000.0) as usize).min(len - 1);
+        sum[i] += v;
+        count[i] += 1;
+    }
+    // IMU with lower sample rate leaves empty bins, use the previous value
+    let mut prev = 0.0;
+    Some((first, sum.iter().zip(count.iter()).map(|(s, c)| {
+        if *c > 0 { prev = s / *c as f64; }
+        prev
+    }).collect()))
+}
+
+/// Angular speed estimated from the video in the range, interpolated at `SAMPLE_RATE`. Returns (timestamp of the first sample, samples)
+fn video_signal(estimated_gyro: &BTreeMap<i64, TimeIMU>, from_us: i64, to_us: i64) -> Option<(f64, Vec<f64>)> {
+    let samples: Vec<(f64, f64)> = estimated_gyro.range(from_us..to_us).filter_map(|(_, x)| Some((x.timestamp_ms, angular_speed(x.gyro.as_ref()?)))).collect();
+    if samples.len() < 2 { return None; }
+    let first = samples[0].0;
+    let len = ((samples[samples.len() - 1].0 - first) * SAMPLE_RATE / 1000.0) as usize + 1;
+    let mut j = 0;
+    Some((first, (0..len).map(|i| {
+        let ts = first + i as f64 * 1000.0 / SAMPLE_RATE;
+        while j + 2 < samples.len() && samples[j + 1].0 < ts { j += 1; }
+        let (a, b) = (samples[j], samples[j + 1]);
+        let t = if b.0 > a.0 { ((ts - a.0) / (b.0 - a.0)).clamp(0.0, 1.0) } else { 0.0 };
+        a.1 + (b.1 - a.1) * t
+    }).collect()))
+}
+
+/// Finds the offset at which the video motion in the ranges (video timestamps in microseconds) best matches the entire log
+pub fn find_global_offset(ranges: &[(i64, i64)], estimated_gyro: &BTreeMap<i64, TimeIMU>, gyro: &GyroSource) -> Option<GlobalOffset> {
+    let (log_start, log) = log_signal(gyro)?;
+
+    // (index of the offset in samples, correlation for each offset starting from that index)
+    let mut correlations = Vec::new();
+    for (from_us, to_us) in ranges {
+        if let Some((start, signal)) = video_signal(estimated_gyro, *from_us, *to_us) {
+            let ncc = normalized_cross_correlation(&log, &signal);
+            if ncc.is_empty() { continue; }
+            // `signal[0]` at video time `start` corresponds to `log[i]` at `log_start + i / SAMPLE_RATE`,
+            // so the offset is `start - log_start - i / SAMPLE_RATE`
+            let base = ((start - log_start) * SAMPLE_RATE / 1000.0).round() as i64;
+            correlations.push((base - ncc.len() as i64 + 1, ncc));
+        }
+    }
+    if correlations.is_empty() { return None; }
+
+    let min_index = correlations.iter().map(|x| x.0).min()?;
+    let max_index = correlations.iter().map(|x| x.0 + x.1.len() as i64).max()?;
+    let mut total = vec![0.0; (max_index - min_index) as usize];
+    for (first_index, ncc) in &correlations {
+        // `ncc` is ordered by increasing `i`, so by decreasing offset
+        for (i, c) in ncc.iter().rev().enumerate() {
+            total[(first_index - min_index) as usize + i] += c;
+        }
+    }
+    let ranges = correlations.len();
+    let (best, best_score) = total.iter().copied().enumerate().max_by(|a, b| a.1.total_cmp(&b.1))?;
+
+    let exclusion = (PEAK_EXCLUSION_MS * SAMPLE_RATE / 1000.0) as usize;
+    let second_score = total.iter().enumerate()
+        .filter(|(i, _)| (*i as i64 - best as i64).unsigned_abs() as usize > exclusion)
+        .map(|(_, x)| *x)
+        .fold(0.0, f64::max);
+
+    Some(GlobalOffset {
+        offset_ms: (min_index + best as i64) as f64 * 1000.0 / SAMPLE_RATE,
+        score: best_score / ranges as f64,
+        peak_ratio: if second_score > 1e-9 { (best_score / second_score).min(100.0) } else { 100.0 },
+        ranges
+    })
+}
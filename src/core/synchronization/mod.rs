@@ -20,6 +20,8 @@ use self::opencv::ItemOpenCV;
 #[cfg(feature = "use-opencv")]
 use self::opencv_dis::ItemOpenCVDis;
 use self::akaze::ItemAkaze;
+use self::feature_cache::ItemCached;
+use self::pyrlk::ItemPyrLK;
 
 use super::gyro_source::TimeIMU;
 
@@ -28,9 +30,18 @@ mod opencv;
 #[cfg(feature = "use-opencv")]
 mod opencv_dis;
 mod akaze;
+mod feature_cache;
+mod pyrlk;
 mod find_offset;
 mod find_offset_rssync;
 pub mod optimsync;
+pub mod drift;
+pub mod orientation;
+pub mod quality;
+pub mod rolling_shutter;
+pub mod global_offset;
+pub mod timestamp_sync;
+pub mod sync_points;
 // mod cpp_wrapper;
 mod find_offset_visually;
 mod autosync;
@@ -43,7 +54,7 @@ pub type OpticalFlowPoints = Vec<(f32, f32)>; // timestamp_us, points
 pub type OpticalFlowPair = Option<(OpticalFlowPoints, OpticalFlowPoints)>;
 pub type OpticalFlowPairWithTs = Option<((i64, OpticalFlowPoints), (i64, OpticalFlowPoints))>;
 
-#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 #[serde(default)]
 pub struct SyncParams {
     pub initial_offset: f64,
@@ -54,13 +65,44 @@ pub struct SyncParams {
     pub every_nth_frame: usize,
     pub time_per_syncpoint: f64,
     pub of_method: usize,
-    pub offset_method: usize
+    pub offset_method: usize,
+    pub orientation_include_mirrored: bool, // Also try the orientations which flip the handedness in the orientation search
+    pub quality: Option<quality::SyncQualitySettings>, // Evaluate the found sync points and reject the bad ones
+    pub rolling_shutter: rolling_shutter::RollingShutterSettings, // Used in the `estimate_rolling_shutter` mode
+    pub global_search: bool, // Find the initial offset by matching the video motion with the entire log
+    pub timestamp_sync: Option<timestamp_sync::TimestampSyncSettings>, // Initial offset from the absolute time of the video and the log, if both have it
+    pub auto_sync_points: bool, // Place the sync points by the motion instead of evenly
+    pub placement: sync_points::PlacementSettings, // Used with `auto_sync_points`
+}
+impl Default for SyncParams {
+    fn default() -> Self {
+        Self {
+            initial_offset: 0.0,
+            initial_offset_inv: false,
+            search_size: 0.0,
+            calc_initial_fast: false,
+            max_sync_points: 0,
+            every_nth_frame: 0,
+            time_per_syncpoint: 0.0,
+            of_method: 0,
+            offset_method: 0,
+            orientation_include_mirrored: true,
+            quality: None,
+            rolling_shutter: Default::default(),
+            global_search: false,
+            timestamp_sync: None,
+            auto_sync_points: false,
+            placement: Default::default(),
+        }
+    }
 }
 
 #[enum_dispatch]
 #[derive(Clone)]
 pub enum EstimatorItem {
     ItemAkaze,
+    ItemCached,
+    ItemPyrLK,
     #[cfg(feature = "use-opencv")]
     ItemOpenCV,
     #[cfg(feature = "use-opencv")]
@@ -119,6 +161,10 @@ impl PoseEstimator {
             1 => ItemOpenCV::detect_features(timestamp_us, img, width, height).into(),
             #[cfg(feature = "use-opencv")]
             2 => ItemOpenCVDis::detect_features(timestamp_us, img, width, height).into(),
+            // Built-in tracker is used instead of the OpenCV methods if OpenCV is not available
+            #[cfg(not(feature = "use-opencv"))]
+            1 | 2 => ItemPyrLK::detect_features(timestamp_us, img, width, height).into(),
+            3 => ItemPyrLK::detect_features(timestamp_us, img, width, height).into(),
             _ => panic!("Invalid method {}", method) // TODO change to Result<>
         };
         {
@@ -219,11 +265,11 @@ impl PoseEstimator {
         let keys: Vec<i64> = l.keys().copied().collect();
         for (i, k) in keys.iter().enumerate() {
             if let Some(from_fr) = l.get(k) {
-                if from_fr.optical_flow.try_borrow().map(|of| !of.is_empty()).unwrap_or_default() {
-                    // We already have OF for this frame
-                    continue;
-                }
                 for d in 1..=num_frames {
+                    if from_fr.optical_flow.try_borrow().map(|of| of.contains_key(&d)).unwrap_or_default() {
+                        // We already have OF for this frame
+                        continue;
+                    }
                     if let Some(to_key) = keys.get(i + d) {
                         if let Some(to_item) = l.get(to_key) {
                             if from_fr.frame_no + d == to_item.frame_no {
@@ -240,6 +286,21 @@ impl PoseEstimator {
             }
         }
     }
+    /// Average number of points tracked to the next frame, for frames in the range of video timestamps
+    pub fn average_tracked_points(&self, from_us: i64, to_us: i64) -> f64 {
+        let l = self.sync_results.read();
+        let counts: Vec<usize> = l.range(from_us..=to_us).filter_map(|(_, x)| {
+            let of = x.optical_flow.try_borrow().ok()?;
+            let count = match of.get(&1) {
+                Some(Some(((_, a), _))) => Some(a.len()),
+                _ => None
+            };
+            count
+        }).collect();
+        if counts.is_empty() { return 0.0; }
+        counts.iter().sum::<usize>() as f64 / counts.len() as f64
+    }
+
     pub fn cleanup(&self) {
         let mut l = self.sync_results.write();
         for (_, i) in l.iter_mut(){
@@ -405,8 +466,11 @@ impl PoseEstimator {
         let gyro = self.estimated_gyro.read().clone();
         find_offset::find_offsets(ranges, &gyro, sync_params, params, progress_cb, cancel_flag)
     }
-    pub fn find_offsets_visually<F: Fn(f64) + Sync>(&self, ranges: &[(i64, i64)], sync_params: &SyncParams, params: &ComputeParams, for_rs: bool, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Vec<(f64, f64, f64)> { // Vec<(timestamp, offset, cost)>
-        find_offset_visually::find_offsets(ranges, self, sync_params, params, for_rs, progress_cb, cancel_flag)
+    pub fn find_offsets_visually<F: Fn(f64) + Sync>(&self, ranges: &[(i64, i64)], sync_params: &SyncParams, params: &ComputeParams, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Vec<(f64, f64, f64)> { // Vec<(timestamp, offset, cost)>
+        find_offset_visually::find_offsets(ranges, self, sync_params, params, progress_cb, cancel_flag)
+    }
+    pub fn estimate_rolling_shutter<F: Fn(f64) + Sync>(&self, ranges: &[(i64, i64)], params: &ComputeParams, settings: &rolling_shutter::RollingShutterSettings, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Option<rolling_shutter::RollingShutterEstimate> {
+        rolling_shutter::estimate(ranges, self, params, settings, progress_cb, cancel_flag)
     }
     pub fn find_offsets_rssync<F: Fn(f64) + Sync>(&self, ranges: &[(i64, i64)], sync_params: &SyncParams, params: &ComputeParams, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Vec<(f64, f64, f64)> { // Vec<(timestamp, offset, cost)>
         // Try essential matrix first, because it's much faster
@@ -440,4 +504,7 @@ impl PoseEstimator {
     pub fn guess_orientation_rssync<F: Fn(f64) + Sync>(&self, ranges: &[(i64, i64)], sync_params: &SyncParams, params: &ComputeParams, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Option<(String, f64)> {
         FindOffsetsRssync::new(ranges, self.sync_results.clone(), sync_params, params, progress_cb, cancel_flag).guess_orient()
     }
+    pub fn search_orientation_rssync<F: Fn(f64) + Sync>(&self, ranges: &[(i64, i64)], sync_params: &SyncParams, params: &ComputeParams, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> orientation::OrientationSearch {
+        FindOffsetsRssync::new(ranges, self.sync_results.clone(), sync_params, params, progress_cb, cancel_flag).search_orientations(sync_params.orientation_include_mirrored)
+    }
 }
@@ -27,40 +27,7 @@ impl EstimatorItemInterface for ItemOpenCV {
 
     fn estimate_pose(&self, next: &EstimatorItem, params: &ComputeParams, timestamp_us: i64, next_timestamp_us: i64) -> Option<Rotation3<f64>> {
         let (pts1, pts2) = self.get_matched_features(next)?;
-
-        let result = || -> Result<Rotation3<f64>, opencv::Error> {
-            let pts11 = crate::stabilization::undistort_points_for_optical_flow(&pts1, timestamp_us, params, (self.img.width(), self.img.height()));
-            let pts22 = crate::stabilization::undistort_points_for_optical_flow(&pts2, next_timestamp_us, params, (self.img.width(), self.img.height()));
-
-            let pts1 = pts11.into_iter().map(|(x, y)| Point2f::new(x, y)).collect::<Vec<Point2f>>();
-            let pts2 = pts22.into_iter().map(|(x, y)| Point2f::new(x, y)).collect::<Vec<Point2f>>();
-
-            let a1_pts = Mat::from_slice(&pts1)?;
-            let a2_pts = Mat::from_slice(&pts2)?;
-
-            let identity = Mat::eye(3, 3, opencv::core::CV_64F)?;
-
-            let mut mask = Mat::default();
-            let e = opencv::calib3d::find_essential_mat(&a1_pts, &a2_pts, &identity, opencv::calib3d::RANSAC, 0.999, 0.0005, 1000, &mut mask)?;
-
-            let mut r1 = Mat::default();
-            let mut t = Mat::default();
-
-            let inliers = opencv::calib3d::recover_pose_triangulated(&e, &a1_pts, &a2_pts, &identity, &mut r1, &mut t, 100000.0, &mut mask, &mut Mat::default())?;
-            if inliers < 20 {
-                return Err(opencv::Error::new(0, "Model not found".to_string()));
-            }
-
-            cv_to_rot2(r1)
-        }();
-
-        match result {
-            Ok(res) => Some(res),
-            Err(e) => {
-                log::error!("OpenCV error: {:?}", e);
-                None
-            }
-        }
+        pose_from_matches(&pts1, &pts2, params, timestamp_us, next_timestamp_us, (self.img.width(), self.img.height()))
     }
 
     fn optical_flow_to(&self, to: &EstimatorItem) -> OpticalFlowPair {
@@ -174,6 +141,42 @@ pub fn init() -> Result<(), opencv::Error> {
     Ok(())
 }
 
+pub fn pose_from_matches(pts1: &[(f32, f32)], pts2: &[(f32, f32)], params: &ComputeParams, timestamp_us: i64, next_timestamp_us: i64, img_size: (u32, u32)) -> Option<Rotation3<f64>> {
+    let result = || -> Result<Rotation3<f64>, opencv::Error> {
+        let pts11 = crate::stabilization::undistort_points_for_optical_flow(pts1, timestamp_us, params, img_size);
+        let pts22 = crate::stabilization::undistort_points_for_optical_flow(pts2, next_timestamp_us, params, img_size);
+
+        let pts1 = pts11.into_iter().map(|(x, y)| Point2f::new(x, y)).collect::<Vec<Point2f>>();
+        let pts2 = pts22.into_iter().map(|(x, y)| Point2f::new(x, y)).collect::<Vec<Point2f>>();
+
+        let a1_pts = Mat::from_slice(&pts1)?;
+        let a2_pts = Mat::from_slice(&pts2)?;
+
+        let identity = Mat::eye(3, 3, opencv::core::CV_64F)?;
+
+        let mut mask = Mat::default();
+        let e = opencv::calib3d::find_essential_mat(&a1_pts, &a2_pts, &identity, opencv::calib3d::RANSAC, 0.999, 0.0005, 1000, &mut mask)?;
+
+        let mut r1 = Mat::default();
+        let mut t = Mat::default();
+
+        let inliers = opencv::calib3d::recover_pose_triangulated(&e, &a1_pts, &a2_pts, &identity, &mut r1, &mut t, 100000.0, &mut mask, &mut Mat::default())?;
+        if inliers < 20 {
+            return Err(opencv::Error::new(0, "Model not found".to_string()));
+        }
+
+        cv_to_rot2(r1)
+    }();
+
+    match result {
+        Ok(res) => Some(res),
+        Err(e) => {
+            log::error!("OpenCV error: {:?}", e);
+            None
+        }
+    }
+}
+
 fn cv_to_rot2(r1: Mat) -> Result<Rotation3<f64>, opencv::Error> {
     if r1.typ() != opencv::core::CV_64FC1 {
         return Err(opencv::Error::new(0, "Invalid matrix type".to_string()));
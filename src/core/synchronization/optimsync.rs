@@ -212,3 +212,58 @@ pub fn nlfunc(arg: f32, trip_point: f32) -> f32 {
         arg - trip_point
     }
 }
+
+/// Normalized cross-correlation of `signal` with every window of `reference` of the same length, computed with FFT.
+/// Element `i` is the correlation of `signal` with `reference[i..i + signal.len()]`, from -1 to 1
+pub fn normalized_cross_correlation(reference: &[f64], signal: &[f64]) -> Vec<f64> {
+    let n = signal.len();
+    if n < 2 || reference.len() < n {
+        return Vec::new();
+    }
+    let mean = signal.iter().sum::<f64>() / n as f64;
+    let centered: Vec<f64> = signal.iter().map(|x| x - mean).collect();
+    let signal_norm = centered.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if signal_norm < 1e-12 {
+        return vec![0.0; reference.len() - n + 1];
+    }
+
+    let fft_size = (reference.len() + n).next_power_of_two();
+    let mut planner = FftPlanner::<f64>::new();
+    let fft = planner.plan_fft_forward(fft_size);
+    let ifft = planner.plan_fft_inverse(fft_size);
+
+    let padded = |v: &[f64]| -> Vec<Complex<f64>> {
+        let mut ret: Vec<_> = v.iter().map(|&x| Complex::new(x, 0.0)).collect();
+        ret.resize(fft_size, Complex::new(0.0, 0.0));
+        ret
+    };
+    let mut corr = padded(reference);
+    let mut sig = padded(&centered);
+    fft.process(&mut corr);
+    fft.process(&mut sig);
+    for (a, b) in zip(corr.iter_mut(), &sig) {
+        *a *= b.conj();
+    }
+    ifft.process(&mut corr);
+
+    // Window sums of the reference for the normalization
+    let mut sum = vec![0.0; reference.len() + 1];
+    let mut sum2 = vec![0.0; reference.len() + 1];
+    for (i, x) in reference.iter().enumerate() {
+        sum[i + 1] = sum[i] + x;
+        sum2[i + 1] = sum2[i] + x * x;
+    }
+
+    (0..=reference.len() - n)
+        .map(|i| {
+            let s = sum[i + n] - sum[i];
+            let s2 = sum2[i + n] - sum2[i];
+            let var = (s2 - s * s / n as f64).max(0.0);
+            if var < 1e-12 {
+                0.0
+            } else {
+                corr[i].re / fft_size as f64 / (var.sqrt() * signal_norm)
+            }
+        })
+        .collect()
+}
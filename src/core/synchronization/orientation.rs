@@ -0,0 +1,203 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// IMU orientation search. Every candidate orientation is evaluated on each sync range separately,
+// so the result contains not only the best orientation, but also how much better it is than the next one
+// and whether the ranges agree on it. A low margin or agreement usually means there's not enough motion in the ranges.
+
+use nalgebra::{ Matrix3, Rotation3, UnitQuaternion, Vector3 };
+use serde::Serialize;
+use crate::gyro_source::GyroSource;
+
+// Length of the window used to compare the accelerometer with the gyro rotation
+const ACC_WINDOW_MS: f64 = 150.0;
+// Number of accelerometer samples averaged at each end of the window
+const ACC_AVERAGE_SAMPLES: usize = 5;
+// Windows with less rotation than this don't say anything about the accelerometer orientation
+const ACC_MIN_ROTATION_DEG: f64 = 5.0;
+
+fn axis_matrix(orientation: &str) -> Matrix3<f64> {
+    let mut m = Matrix3::zeros();
+    for (row, o) in orientation.bytes().enumerate().take(3) {
+        let col = (o.to_ascii_uppercase().saturating_sub(b'X') as usize).min(2);
+        m[(row, col)] = if o.is_ascii_uppercase() { 1.0 } else { -1.0 };
+    }
+    m
+}
+
+/// Orientation which flips the handedness of the coordinate system
+pub fn is_mirrored(orientation: &str) -> bool {
+    axis_matrix(orientation).determinant() < 0.0
+}
+
+/// All 24 axis permutations with signs which are proper rotations, or all 48 including the mirrored ones
+pub fn all_orientations(include_mirrored: bool) -> Vec<String> {
+    const PERMUTATIONS: [[u8; 3]; 6] = [*b"XYZ", *b"XZY", *b"YXZ", *b"YZX", *b"ZXY", *b"ZYX"];
+    let mut ret = Vec::with_capacity(48);
+    for perm in PERMUTATIONS {
+        for signs in 0..8 {
+            let s: String = perm.iter().enumerate().map(|(i, c)| if signs & (1 << i) != 0 { c.to_ascii_lowercase() as char } else { *c as char }).collect();
+            if include_mirrored || !is_mirrored(&s) {
+                ret.push(s);
+            }
+        }
+    }
+    ret
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OrientationCandidate {
+    pub orientation: String,
+    pub mirrored: bool,
+    pub total_cost: f64,
+    /// Cost for each sync range, `None` if the sync failed in that range
+    pub range_costs: Vec<Option<f64>>,
+    /// Number of ranges where this orientation has the lowest cost
+    pub range_wins: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct OrientationSearch {
+    /// Sorted from the best
+    pub candidates: Vec<OrientationCandidate>,
+    pub ranges: usize,
+    /// Relative cost difference between the best and the second candidate, 0 to 1
+    pub margin: f64,
+    /// Fraction of the ranges where the best candidate is also the best in that range
+    pub agreement: f64,
+    /// Accelerometer rotation relative to the best gyro orientation
+    pub acc_rotation: Option<AccRotationGuess>,
+}
+impl OrientationSearch {
+    /// Ranks the orientations by their costs in each range. Failed ranges count as the worst cost in that range
+    pub fn from_costs(costs: Vec<(String, Vec<Option<f64>>)>) -> Self {
+        let ranges = costs.iter().map(|x| x.1.len()).max().unwrap_or_default();
+        let worst: Vec<f64> = (0..ranges).map(|i| costs.iter().filter_map(|x| x.1.get(i).copied().flatten()).fold(0.0, f64::max)).collect();
+        let best: Vec<f64> = (0..ranges).map(|i| costs.iter().filter_map(|x| x.1.get(i).copied().flatten()).fold(f64::MAX, f64::min)).collect();
+
+        let mut candidates: Vec<OrientationCandidate> = costs.into_iter().map(|(orientation, range_costs)| {
+            let total_cost = (0..ranges).map(|i| range_costs.get(i).copied().flatten().unwrap_or(worst[i])).sum();
+            let range_wins = range_costs.iter().enumerate().filter(|(i, c)| matches!(c, Some(c) if *c <= best[*i])).count();
+            OrientationCandidate { mirrored: is_mirrored(&orientation), orientation, total_cost, range_costs, range_wins }
+        }).collect();
+        candidates.sort_by(|a, b| a.total_cost.total_cmp(&b.total_cost));
+
+        let margin = match (candidates.get(0), candidates.get(1)) {
+            (Some(a), Some(b)) if b.total_cost.abs() > 1e-12 => ((b.total_cost - a.total_cost) / b.total_cost.abs()).clamp(0.0, 1.0),
+            (Some(_), None) => 1.0,
+            _ => 0.0
+        };
+        let agreement = match candidates.get(0) {
+            Some(c) if ranges > 0 => c.range_wins as f64 / ranges as f64,
+            _ => 0.0
+        };
+        Self { candidates, ranges, margin, agreement, acc_rotation: None }
+    }
+
+    pub fn best(&self) -> Option<(String, f64)> {
+        self.candidates.first().map(|x| (x.orientation.clone(), x.total_cost))
+    }
+
+    pub fn log(&self) {
+        ::log::info!("IMU orientation search over {} ranges: margin {:.1}%, agreement {:.0}%", self.ranges, self.margin * 100.0, self.agreement * 100.0);
+        for (i, c) in self.candidates.iter().take(5).enumerate() {
+            ::log::info!("{}. {}{}: cost {:.6}, best in {}/{} ranges", i + 1, c.orientation, if c.mirrored { " (mirrored)" } else { "" }, c.total_cost, c.range_wins, self.ranges);
+        }
+        if let Some(acc) = &self.acc_rotation {
+            acc.log();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AccRotationGuess {
+    /// [pitch, roll, yaw] in degrees, as used by `GyroSource::acc_rotation_angles`. Includes the IMU rotation
+    pub angles: [f64; 3],
+    /// Rotation on top of the IMU rotation, [pitch, roll, yaw] in degrees
+    pub relative_angles: [f64; 3],
+    /// Mean angle between the measured and predicted gravity direction, in degrees
+    pub cost: f64,
+    pub identity_cost: f64,
+    /// Relative cost difference to the second best rotation, 0 to 1
+    pub margin: f64,
+    pub windows: usize,
+}
+impl AccRotationGuess {
+    /// The accelerometer is aligned with the gyro, so it doesn't need its own rotation
+    pub fn is_identity(&self) -> bool {
+        self.relative_angles.iter().all(|x| x.abs() < 0.5)
+    }
+    pub fn log(&self) {
+        ::log::info!("Accelerometer rotation guess: {:?} deg ({:?} deg relative to the gyro), error {:.2} deg (without rotation {:.2} deg), margin {:.1}%, {} windows",
+            self.angles, self.relative_angles, self.cost, self.identity_cost, self.margin * 100.0, self.windows);
+    }
+}
+
+/// Finds the 90° rotation of the accelerometer which is most consistent with the gyro.
+/// Gravity is fixed in the world, so in the camera frame it has to rotate opposite to the gyro rotation
+pub fn guess_acc_rotation(gyro: &GyroSource) -> Option<AccRotationGuess> {
+    let mut source = gyro.clone();
+    source.acc_rotation_angles = None;
+    source.acc_rotation = None;
+    source.apply_transforms();
+    let imu = &source.raw_imu;
+    if imu.len() < ACC_AVERAGE_SAMPLES * 2 { return None; }
+    let one_g = crate::bias_estimation::median_accl_magnitude(imu)?;
+
+    let average_acc = |from: usize, to: usize| -> Option<Vector3<f64>> {
+        let v: Vec<Vector3<f64>> = imu[from..to].iter().filter_map(|x| x.accl.map(Vector3::from)).collect();
+        if v.is_empty() { return None; }
+        let avg = v.iter().sum::<Vector3<f64>>() / v.len() as f64;
+        // Skip windows with large linear acceleration
+        if ((avg.norm() - one_g).abs() / one_g) > 0.2 { return None; }
+        Some(avg.normalize())
+    };
+
+    // (gravity at the start, gravity at the end, rotation from the start to the end of the window)
+    let mut windows = Vec::new();
+    let mut start = 0;
+    while start < imu.len() {
+        let mut end = start;
+        let mut rotation = UnitQuaternion::identity();
+        while end + 1 < imu.len() && imu[end + 1].timestamp_ms - imu[start].timestamp_ms <= ACC_WINDOW_MS {
+            if let Some(g) = imu[end + 1].gyro {
+                let dt = (imu[end + 1].timestamp_ms - imu[end].timestamp_ms) / 1000.0;
+                rotation *= UnitQuaternion::from_scaled_axis(Vector3::from(g) * (std::f64::consts::PI / 180.0) * dt);
+            }
+            end += 1;
+        }
+        if end - start >= ACC_AVERAGE_SAMPLES * 2 && rotation.angle().to_degrees() >= ACC_MIN_ROTATION_DEG {
+            if let (Some(a), Some(b)) = (average_acc(start, start + ACC_AVERAGE_SAMPLES), average_acc(end + 1 - ACC_AVERAGE_SAMPLES, end + 1)) {
+                windows.push((a, b, rotation));
+            }
+        }
+        start = end + 1;
+    }
+    if windows.is_empty() { return None; }
+
+    let mut costs: Vec<(Rotation3<f64>, f64)> = all_orientations(false).iter().map(|o| {
+        let rot = Rotation3::from_matrix_unchecked(axis_matrix(o));
+        let cost = windows.iter().map(|(a, b, q)| {
+            let predicted = q.inverse_transform_vector(&(rot * a));
+            predicted.angle(&(rot * b)).to_degrees()
+        }).sum::<f64>() / windows.len() as f64;
+        (rot, cost)
+    }).collect();
+    let identity_cost = costs.iter().find(|x| x.0 == Rotation3::identity()).map(|x| x.1).unwrap_or_default();
+    costs.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    let (rot, cost) = costs[0];
+    let margin = if costs[1].1 > 1e-12 { ((costs[1].1 - cost) / costs[1].1).clamp(0.0, 1.0) } else { 0.0 };
+    // Inverse of `Rotation3::from_euler_angles(yaw, pitch, roll)` in `GyroSource::apply_transforms`
+    let to_angles = |rot: Rotation3<f64>| {
+        let (r, p, y) = rot.euler_angles();
+        [p, y, r].map(|x| {
+            let deg = x.to_degrees().round();
+            if deg == -0.0 { 0.0 } else { deg }
+        })
+    };
+    // The windows are measured after the IMU rotation, but `acc_rotation` replaces it for the accelerometer
+    let absolute = rot * source.imu_rotation.unwrap_or_else(Rotation3::identity);
+
+    Some(AccRotationGuess { angles: to_angles(absolute), relative_angles: to_angles(rot), cost, identity_cost, margin, windows: windows.len() })
+}
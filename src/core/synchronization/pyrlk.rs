@@ -0,0 +1,262 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Sparse optical flow without OpenCV: Shi-Tomasi corners ("good features to track") tracked with the pyramidal Lucas-Kanade method.
+// Parameters match the OpenCV PyrLK method. Points which don't track back to the starting point are rejected.
+
+use nalgebra::Rotation3;
+use rayon::iter::{ ParallelIterator, IntoParallelRefIterator };
+use std::sync::Arc;
+use image::GrayImage;
+use super::{ EstimatorItem, EstimatorItemInterface, OpticalFlowPair };
+use crate::stabilization::ComputeParams;
+
+const MAX_FEATURES: usize = 200;
+const QUALITY_LEVEL: f32 = 0.01;
+const MIN_DISTANCE: f32 = 10.0;
+const PYRAMID_LEVELS: usize = 4; // Original and 3 downscaled levels
+const WINDOW_RADIUS: i32 = 10; // 21x21 window
+const MAX_ITERATIONS: usize = 30;
+const EPSILON: f32 = 0.01;
+const MIN_EIGENVALUE: f32 = 1e-4;
+// Maximum distance in pixels between the point and the point tracked forward and back
+const MAX_FB_ERROR: f32 = 1.0;
+
+#[derive(Default, Clone)]
+pub struct ItemPyrLK {
+    features: Vec<(f32, f32)>,
+    pyramid: Arc<Vec<GrayImage>>,
+    size: (u32, u32)
+}
+
+impl EstimatorItemInterface for ItemPyrLK {
+    fn get_features(&self) -> &Vec<(f32, f32)> {
+        &self.features
+    }
+
+    fn estimate_pose(&self, next: &EstimatorItem, params: &ComputeParams, timestamp_us: i64, next_timestamp_us: i64) -> Option<Rotation3<f64>> {
+        let (pts1, pts2) = self.get_matched_features(next)?;
+        super::akaze::pose_from_matches(&pts1, &pts2, params, timestamp_us, next_timestamp_us, self.size)
+    }
+
+    fn optical_flow_to(&self, to: &EstimatorItem) -> OpticalFlowPair {
+        self.get_matched_features(to)
+    }
+    fn cleanup(&mut self) {
+        self.pyramid = Arc::new(Vec::new());
+    }
+}
+
+impl ItemPyrLK {
+    pub fn detect_features(_timestamp_us: i64, img: Arc<GrayImage>, width: u32, height: u32) -> Self {
+        // The image can be wider than `width` because of the stride
+        let img = if img.width() != width || img.height() != height {
+            image::imageops::crop_imm(&*img, 0, 0, width.min(img.width()), height.min(img.height())).to_image()
+        } else {
+            Arc::try_unwrap(img).unwrap_or_else(|img| (*img).clone())
+        };
+
+        let features = good_features_to_track(&img);
+
+        let mut pyramid = Vec::with_capacity(PYRAMID_LEVELS);
+        pyramid.push(img);
+        while pyramid.len() < PYRAMID_LEVELS {
+            let last = pyramid.last().unwrap();
+            if last.width() < (WINDOW_RADIUS * 4) as u32 || last.height() < (WINDOW_RADIUS * 4) as u32 { break; }
+            let next = downscale(last);
+            pyramid.push(next);
+        }
+
+        Self {
+            features,
+            size: (width, height),
+            pyramid: Arc::new(pyramid)
+        }
+    }
+
+    fn get_matched_features(&self, next: &EstimatorItem) -> OpticalFlowPair {
+        if let EstimatorItem::ItemPyrLK(next) = next {
+            if self.pyramid.is_empty() || next.pyramid.is_empty() { return None; }
+            let (w, h) = (self.size.0 as f32, self.size.1 as f32);
+
+            return Some(self.features.par_iter().filter_map(|pt| {
+                let pt2 = track_point(&self.pyramid, &next.pyramid, *pt)?;
+                if !(0.0..w).contains(&pt2.0) || !(0.0..h).contains(&pt2.1) { return None; }
+
+                let back = track_point(&next.pyramid, &self.pyramid, pt2)?;
+                if (back.0 - pt.0).powi(2) + (back.1 - pt.1).powi(2) > MAX_FB_ERROR * MAX_FB_ERROR { return None; }
+
+                Some((*pt, pt2))
+            }).unzip());
+        }
+        None
+    }
+}
+
+/// Half resolution image with 2x2 box filter
+fn downscale(img: &GrayImage) -> GrayImage {
+    let (sw, sh) = (img.width() as usize, img.height() as usize);
+    let src = img.as_raw();
+    GrayImage::from_fn((img.width() + 1) / 2, (img.height() + 1) / 2, |x, y| {
+        let (x0, y0) = (x as usize * 2, y as usize * 2);
+        let (x1, y1) = ((x0 + 1).min(sw - 1), (y0 + 1).min(sh - 1));
+        let sum = src[y0 * sw + x0] as u32 + src[y0 * sw + x1] as u32 + src[y1 * sw + x0] as u32 + src[y1 * sw + x1] as u32;
+        image::Luma([((sum + 2) / 4) as u8])
+    })
+}
+
+/// Bilinear interpolation, clamped to the image borders
+#[inline]
+fn sample(img: &GrayImage, x: f32, y: f32) -> f32 {
+    let (w, h) = (img.width() as i32, img.height() as i32);
+    let x = x.clamp(0.0, (w - 1) as f32);
+    let y = y.clamp(0.0, (h - 1) as f32);
+    let (x0, y0) = (x as i32, y as i32);
+    let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let data = img.as_raw();
+    let px = |x: i32, y: i32| data[(y * w + x) as usize] as f32;
+    (px(x0, y0) * (1.0 - fx) + px(x1, y0) * fx) * (1.0 - fy) + (px(x0, y1) * (1.0 - fx) + px(x1, y1) * fx) * fy
+}
+
+/// Corners with the largest minimal eigenvalue of the structure tensor, at least `MIN_DISTANCE` apart
+fn good_features_to_track(img: &GrayImage) -> Vec<(f32, f32)> {
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    if w < 8 || h < 8 { return Vec::new(); }
+    let data = img.as_raw();
+    let px = |x: usize, y: usize| data[y * w + x] as f32;
+
+    // Sobel gradients
+    let mut ixx = vec![0.0f32; w * h];
+    let mut ixy = vec![0.0f32; w * h];
+    let mut iyy = vec![0.0f32; w * h];
+    for y in 1..h - 1 {
+        for x in 1..w - 1 {
+            let gx = (px(x + 1, y - 1) + 2.0 * px(x + 1, y) + px(x + 1, y + 1)) - (px(x - 1, y - 1) + 2.0 * px(x - 1, y) + px(x - 1, y + 1));
+            let gy = (px(x - 1, y + 1) + 2.0 * px(x, y + 1) + px(x + 1, y + 1)) - (px(x - 1, y - 1) + 2.0 * px(x, y - 1) + px(x + 1, y - 1));
+            let i = y * w + x;
+            ixx[i] = gx * gx;
+            ixy[i] = gx * gy;
+            iyy[i] = gy * gy;
+        }
+    }
+
+    // Minimal eigenvalue of the 3x3 block
+    let mut eig = vec![0.0f32; w * h];
+    let mut max_eig = 0.0f32;
+    for y in 2..h - 2 {
+        for x in 2..w - 2 {
+            let (mut a, mut b, mut c) = (0.0, 0.0, 0.0);
+            for yy in y - 1..=y + 1 {
+                for xx in x - 1..=x + 1 {
+                    let i = yy * w + xx;
+                    a += ixx[i];
+                    b += ixy[i];
+                    c += iyy[i];
+                }
+            }
+            let e = (a + c) / 2.0 - (((a - c) / 2.0).powi(2) + b * b).sqrt();
+            eig[y * w + x] = e;
+            max_eig = max_eig.max(e);
+        }
+    }
+    if max_eig <= 0.0 { return Vec::new(); }
+
+    // Local maxima above the quality threshold
+    let threshold = max_eig * QUALITY_LEVEL;
+    let mut corners = Vec::new();
+    for y in 3..h - 3 {
+        for x in 3..w - 3 {
+            let e = eig[y * w + x];
+            if e > threshold && (y - 1..=y + 1).all(|yy| (x - 1..=x + 1).all(|xx| eig[yy * w + xx] <= e)) {
+                corners.push((e, x, y));
+            }
+        }
+    }
+    corners.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    // Strongest corners first, the grid is used to check the distance to the already selected ones
+    let cell = MIN_DISTANCE as usize;
+    let (gw, gh) = (w / cell + 1, h / cell + 1);
+    let mut grid: Vec<Vec<(f32, f32)>> = vec![Vec::new(); gw * gh];
+    let mut ret = Vec::with_capacity(MAX_FEATURES);
+    for (_, x, y) in corners {
+        let (cx, cy) = (x / cell, y / cell);
+        let (fx, fy) = (x as f32, y as f32);
+        let too_close = (cy.saturating_sub(1)..=(cy + 1).min(gh - 1)).any(|gy| {
+            (cx.saturating_sub(1)..=(cx + 1).min(gw - 1)).any(|gx| {
+                grid[gy * gw + gx].iter().any(|(px, py)| (px - fx).powi(2) + (py - fy).powi(2) < MIN_DISTANCE * MIN_DISTANCE)
+            })
+        });
+        if !too_close {
+            grid[cy * gw + cx].push((fx, fy));
+            ret.push((fx, fy));
+            if ret.len() >= MAX_FEATURES { break; }
+        }
+    }
+    ret
+}
+
+/// Position of the point `pt` from the `prev` pyramid in the `next` pyramid, `None` if the point was lost
+fn track_point(prev: &[GrayImage], next: &[GrayImage], pt: (f32, f32)) -> Option<(f32, f32)> {
+    let levels = prev.len().min(next.len());
+    let win = (2 * WINDOW_RADIUS + 1) as usize;
+    let area = (win * win) as f32;
+    let mut template = vec![0.0f32; win * win];
+    let mut grad_x = vec![0.0f32; win * win];
+    let mut grad_y = vec![0.0f32; win * win];
+
+    // Displacement at the current level
+    let mut g = (0.0f32, 0.0f32);
+    for level in (0..levels).rev() {
+        let scale = (1u32 << level) as f32;
+        let (px, py) = (pt.0 / scale, pt.1 / scale);
+        let (i, j) = (&prev[level], &next[level]);
+        let (w, h) = (j.width() as f32, j.height() as f32);
+
+        let (mut gxx, mut gxy, mut gyy) = (0.0f32, 0.0f32, 0.0f32);
+        let mut k = 0;
+        for dy in -WINDOW_RADIUS..=WINDOW_RADIUS {
+            for dx in -WINDOW_RADIUS..=WINDOW_RADIUS {
+                let (x, y) = (px + dx as f32, py + dy as f32);
+                let ix = (sample(i, x + 1.0, y) - sample(i, x - 1.0, y)) * 0.5;
+                let iy = (sample(i, x, y + 1.0) - sample(i, x, y - 1.0)) * 0.5;
+                template[k] = sample(i, x, y);
+                grad_x[k] = ix;
+                grad_y[k] = iy;
+                gxx += ix * ix;
+                gxy += ix * iy;
+                gyy += iy * iy;
+                k += 1;
+            }
+        }
+        let det = gxx * gyy - gxy * gxy;
+        let min_eig = ((gxx + gyy) - ((gxx - gyy).powi(2) + 4.0 * gxy * gxy).sqrt()) / (2.0 * area);
+        if min_eig < MIN_EIGENVALUE || det.abs() < f32::EPSILON { return None; }
+
+        let mut v = (0.0f32, 0.0f32);
+        for _ in 0..MAX_ITERATIONS {
+            let (nx, ny) = (px + g.0 + v.0, py + g.1 + v.1);
+            if nx < -(WINDOW_RADIUS as f32) || ny < -(WINDOW_RADIUS as f32) || nx >= w + WINDOW_RADIUS as f32 || ny >= h + WINDOW_RADIUS as f32 { return None; }
+
+            let (mut bx, mut by) = (0.0f32, 0.0f32);
+            let mut k = 0;
+            for dy in -WINDOW_RADIUS..=WINDOW_RADIUS {
+                for dx in -WINDOW_RADIUS..=WINDOW_RADIUS {
+                    let diff = template[k] - sample(j, nx + dx as f32, ny + dy as f32);
+                    bx += diff * grad_x[k];
+                    by += diff * grad_y[k];
+                    k += 1;
+                }
+            }
+            let eta = ((gyy * bx - gxy * by) / det, (gxx * by - gxy * bx) / det);
+            v = (v.0 + eta.0, v.1 + eta.1);
+            if eta.0 * eta.0 + eta.1 * eta.1 < EPSILON * EPSILON { break; }
+        }
+        g = (g.0 + v.0, g.1 + v.1);
+        if level > 0 {
+            g = (g.0 * 2.0, g.1 * 2.0);
+        }
+    }
+    Some((pt.0 + g.0, pt.1 + g.1))
+}
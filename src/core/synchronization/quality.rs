@@ -0,0 +1,171 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Quality of the sync points found by the autosync. Costs of the offset methods have different scales,
+// so the cost is normalized by the median cost of the run and combined with the amount of texture
+// (tracked points) and motion in each range. Sync points which disagree with the clock drift model are rejected.
+
+use std::collections::BTreeMap;
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use super::PoseEstimator;
+use super::drift::{ self, DriftFit, DriftModelSettings };
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct SyncQualitySettings {
+    /// Average number of tracked points per frame below which the range is considered low-texture
+    pub min_tracked_points: f64,
+    /// Average rotation speed in deg/s below which the range is considered low-motion
+    pub min_motion_deg_s: f64,
+    /// Cost above this multiple of the median cost is flagged
+    pub max_cost_ratio: f64,
+    /// Sync points with lower confidence are rejected
+    pub min_confidence: f64,
+    /// Reject the sync points which are outliers of the drift model
+    pub reject_outliers: bool,
+    pub drift: DriftModelSettings,
+}
+impl Default for SyncQualitySettings {
+    fn default() -> Self {
+        Self {
+            min_tracked_points: 20.0,
+            min_motion_deg_s: 10.0,
+            max_cost_ratio: 3.0,
+            min_confidence: 0.1,
+            reject_outliers: true,
+            drift: DriftModelSettings::default(),
+        }
+    }
+}
+impl SyncQualitySettings {
+    /// Only evaluates the sync points, without rejecting any
+    pub fn report_only() -> Self {
+        Self { min_confidence: 0.0, reject_outliers: false, ..Default::default() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncIssue {
+    LowTexture,
+    LowMotion,
+    HighCost,
+    DriftOutlier,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncPointQuality {
+    /// Video timestamp of the sync point
+    pub timestamp_ms: f64,
+    pub offset_ms: f64,
+    /// Cost as returned by the offset method
+    pub cost: f64,
+    /// Cost divided by the median cost of all sync points
+    pub cost_ratio: f64,
+    /// Average number of tracked points per frame
+    pub tracked_points: f64,
+    /// Average rotation speed estimated from the video, in deg/s
+    pub motion_deg_s: f64,
+    /// 0 to 1
+    pub confidence: f64,
+    /// Offset minus the drift model, in ms
+    pub drift_residual_ms: Option<f64>,
+    pub issues: Vec<SyncIssue>,
+    pub rejected: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SyncQualityReport {
+    pub points: Vec<SyncPointQuality>,
+    pub drift: Option<DriftFit>,
+}
+impl SyncQualityReport {
+    pub fn accepted(&self) -> impl Iterator<Item = &SyncPointQuality> {
+        self.points.iter().filter(|x| !x.rejected)
+    }
+    pub fn rejected_count(&self) -> usize {
+        self.points.iter().filter(|x| x.rejected).count()
+    }
+    /// Accepted sync points as (timestamp, offset, cost), like the `find_offsets*` functions return
+    pub fn accepted_offsets(&self) -> Vec<(f64, f64, f64)> {
+        self.accepted().map(|x| (x.timestamp_ms, x.offset_ms, x.cost)).collect()
+    }
+    pub fn log(&self) {
+        ::log::info!("Sync quality: {} sync points, {} rejected", self.points.len(), self.rejected_count());
+        for x in &self.points {
+            let msg = format!("Sync point at {:.3} s: offset {:.3} ms, confidence {:.2}, cost ratio {:.2}, {:.0} tracked points, motion {:.1} deg/s{}{}",
+                x.timestamp_ms / 1000.0, x.offset_ms, x.confidence, x.cost_ratio, x.tracked_points, x.motion_deg_s,
+                if x.issues.is_empty() { String::new() } else { format!(", issues: {:?}", x.issues) },
+                if x.rejected { " - rejected" } else { "" }
+            );
+            if x.rejected { ::log::warn!("{}", msg); } else { ::log::info!("{}", msg); }
+        }
+    }
+}
+
+fn median(mut v: Vec<f64>) -> f64 {
+    if v.is_empty() { return 0.0; }
+    v.sort_by(|a, b| a.total_cmp(b));
+    v[v.len() / 2]
+}
+
+/// Evaluates the sync points found in `ranges_us` (video timestamps in microseconds).
+/// `offsets` are (video timestamp in ms, offset in ms, cost)
+pub fn evaluate(offsets: &[(f64, f64, f64)], ranges_us: &[(i64, i64)], estimator: &PoseEstimator, settings: &SyncQualitySettings) -> SyncQualityReport {
+    let median_cost = median(offsets.iter().map(|x| x.2).filter(|x| *x > 0.0).collect());
+
+    let mut points: Vec<SyncPointQuality> = offsets.iter().map(|&(timestamp_ms, offset_ms, cost)| {
+        let ts_us = (timestamp_ms * 1000.0).round() as i64;
+        let range = ranges_us.iter().find(|(from, to)| (*from..=*to).contains(&ts_us)).copied()
+            .or_else(|| ranges_us.iter().min_by_key(|(from, to)| ((from + to) / 2 - ts_us).abs()).copied())
+            .unwrap_or((ts_us, ts_us));
+
+        let tracked_points = estimator.average_tracked_points(range.0, range.1);
+        let motion_deg_s = {
+            let gyro = estimator.estimated_gyro.read();
+            let speeds: Vec<f64> = gyro.range(range.0..=range.1).filter_map(|(_, x)| x.gyro).map(|g| (g[0] * g[0] + g[1] * g[1] + g[2] * g[2]).sqrt()).collect();
+            if speeds.is_empty() { 0.0 } else { speeds.iter().sum::<f64>() / speeds.len() as f64 }
+        };
+        let cost_ratio = if median_cost > 0.0 { cost / median_cost } else { 1.0 };
+
+        let mut issues = Vec::new();
+        if tracked_points < settings.min_tracked_points { issues.push(SyncIssue::LowTexture); }
+        if motion_deg_s < settings.min_motion_deg_s { issues.push(SyncIssue::LowMotion); }
+        if cost_ratio > settings.max_cost_ratio { issues.push(SyncIssue::HighCost); }
+
+        let score = |value: f64, min: f64| if min > 0.0 { (value / (2.0 * min)).clamp(0.0, 1.0) } else { 1.0 };
+        let cost_score = if cost_ratio <= 1.0 { 1.0 } else { 1.0 / cost_ratio };
+        let confidence = cost_score * score(tracked_points, settings.min_tracked_points) * score(motion_deg_s, settings.min_motion_deg_s);
+
+        SyncPointQuality { timestamp_ms, offset_ms, cost, cost_ratio, tracked_points, motion_deg_s, confidence, drift_residual_ms: None, issues, rejected: false }
+    }).collect();
+
+    // Same keys as the offsets stored in `GyroSource`
+    let key = |x: &SyncPointQuality| ((x.timestamp_ms - x.offset_ms) * 1000.0).round() as i64;
+    let drift = if points.len() >= 3 {
+        let map: BTreeMap<i64, f64> = points.iter().map(|x| (key(x), x.offset_ms)).collect();
+        drift::fit(&map, &settings.drift)
+    } else {
+        None
+    };
+    if let Some(fit) = &drift {
+        for x in points.iter_mut() {
+            let k = key(x);
+            x.drift_residual_ms = fit.residuals.get(&k).copied();
+            if fit.outliers.contains(&k) { x.issues.push(SyncIssue::DriftOutlier); }
+        }
+    }
+
+    for x in points.iter_mut() {
+        x.rejected = x.confidence < settings.min_confidence || (settings.reject_outliers && x.issues.contains(&SyncIssue::DriftOutlier));
+    }
+    // Keep at least the best sync point
+    if !points.is_empty() && points.iter().all(|x| x.rejected) {
+        if let Some(best) = points.iter_mut().max_by(|a, b| a.confidence.total_cmp(&b.confidence)) {
+            best.rejected = false;
+        }
+    }
+
+    SyncQualityReport { points, drift }
+}
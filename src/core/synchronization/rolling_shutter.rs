@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Frame readout time estimation. The readout time is searched in each range separately by minimizing the distance
+// between the optical flow points corrected with the (already synced) gyro. The results of the ranges are combined
+// with outlier rejection, which also gives the confidence interval of the estimate.
+// Points are in the image coordinates, so negative values mean bottom-to-top readout regardless of `framebuffer_inverted`.
+
+use rayon::iter::{ ParallelIterator, IntoParallelIterator };
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use std::sync::{ Arc, atomic::{ AtomicBool, Ordering::Relaxed } };
+use crate::stabilization::ComputeParams;
+use super::PoseEstimator;
+use super::find_offset_visually::{ matched_points, calculate_distance, MatchedPoints };
+
+// z-score of the 95% confidence interval
+const CONFIDENCE_Z: f64 = 1.96;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct RollingShutterSettings {
+    /// Maximum readout time to search, in ms. 0 means one frame duration
+    pub max_readout_ms: f64,
+    /// Also search the bottom-to-top readout (negative values)
+    pub both_directions: bool,
+    /// Ranges where the best readout time improves the cost less than this fraction don't carry information and are skipped
+    pub min_improvement: f64,
+    /// Ranges further from the median than this many robust standard deviations are rejected
+    pub outlier_threshold: f64,
+}
+impl Default for RollingShutterSettings {
+    fn default() -> Self {
+        Self {
+            max_readout_ms: 0.0,
+            both_directions: true,
+            min_improvement: 0.02,
+            outlier_threshold: 3.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RangeReadoutTime {
+    /// Middle of the range
+    pub timestamp_ms: f64,
+    /// Signed, negative is bottom-to-top
+    pub readout_ms: f64,
+    pub cost: f64,
+    /// Cost without the rolling shutter correction
+    pub cost_without_rs: f64,
+    /// 1 - cost / cost_without_rs
+    pub improvement: f64,
+    pub frames: usize,
+    pub used: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RollingShutterEstimate {
+    /// Signed frame readout time in ms, as used in `frame_readout_time`
+    pub readout_ms: f64,
+    pub bottom_to_top: bool,
+    /// 95% confidence interval of the readout time, if there's more than one used range
+    pub confidence_interval: Option<(f64, f64)>,
+    pub std_dev: f64,
+    /// Fraction of the used ranges with the same readout direction
+    pub direction_agreement: f64,
+    pub ranges: Vec<RangeReadoutTime>,
+}
+impl RollingShutterEstimate {
+    pub fn used_ranges(&self) -> usize {
+        self.ranges.iter().filter(|x| x.used).count()
+    }
+    pub fn log(&self) {
+        ::log::info!("Estimated frame readout time: {:.2} ms ({}), {} of {} ranges used{}",
+            self.readout_ms.abs(), if self.bottom_to_top { "bottom to top" } else { "top to bottom" },
+            self.used_ranges(), self.ranges.len(),
+            self.confidence_interval.map(|(a, b)| format!(", 95% CI: {:.2} .. {:.2} ms", a, b)).unwrap_or_default()
+        );
+        if self.direction_agreement < 1.0 {
+            ::log::warn!("Only {:.0}% of the ranges agree on the readout direction", self.direction_agreement * 100.0);
+        }
+        for x in &self.ranges {
+            ::log::debug!("Range at {:.3} s: {:.2} ms, improvement {:.1}%, {} frames{}", x.timestamp_ms / 1000.0, x.readout_ms, x.improvement * 100.0, x.frames, if x.used { "" } else { " - not used" });
+        }
+    }
+}
+
+fn search_range(matched_points: &MatchedPoints, params: &ComputeParams, max_readout_ms: f64, both_directions: bool) -> Option<(f64, f64)> {
+    let find_min = |a: (f64, f64), b: (f64, f64)| -> (f64, f64) { if a.1 < b.1 { a } else { b } };
+    let cost = |params: &mut ComputeParams, rs: f64| {
+        params.frame_readout_time = rs;
+        calculate_distance(matched_points, 0.0, params)
+    };
+
+    // First search every 1 ms
+    let steps = max_readout_ms.ceil() as isize;
+    let from = if both_directions { -steps } else { 0 };
+    (from..=steps)
+        .into_par_iter()
+        .map_with(params.clone(), |params, i| (i as f64, cost(params, i as f64)))
+        .reduce_with(find_min)
+        .and_then(|lowest| {
+            // Then refine to 0.01 ms
+            (0..=200)
+                .into_par_iter()
+                .map_with(params.clone(), |params, i| {
+                    let rs = lowest.0 - 1.0 + (i as f64 * 0.01);
+                    (rs, cost(params, rs))
+                })
+                .reduce_with(find_min)
+        })
+}
+
+fn weighted_median(mut v: Vec<(f64, f64)>) -> f64 {
+    v.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let total: f64 = v.iter().map(|x| x.1).sum();
+    let mut sum = 0.0;
+    for (x, w) in &v {
+        sum += w;
+        if sum >= total / 2.0 { return *x; }
+    }
+    v.last().map(|x| x.0).unwrap_or_default()
+}
+
+/// Estimates the frame readout time in the ranges of video timestamps (in microseconds).
+/// Requires the optical flow to 2 frames ahead and synchronized gyro in `params`
+pub fn estimate<F: Fn(f64) + Sync>(ranges: &[(i64, i64)], estimator: &PoseEstimator, params: &ComputeParams, settings: &RollingShutterSettings, progress_cb: F, cancel_flag: Arc<AtomicBool>) -> Option<RollingShutterEstimate> {
+    let max_readout_ms = if settings.max_readout_ms > 0.0 { settings.max_readout_ms } else { 1000.0 / params.gyro.fps };
+    let keys: Vec<i64> = estimator.sync_results.read().keys().copied().collect();
+
+    let mut per_range = Vec::with_capacity(ranges.len());
+    for (i, (from_ts, to_ts)) in ranges.iter().enumerate() {
+        if cancel_flag.load(Relaxed) { return None; }
+        progress_cb(i as f64 / ranges.len() as f64);
+
+        let matched_points = matched_points(estimator, &keys, *from_ts, *to_ts, 2);
+        if matched_points.is_empty() { continue; }
+
+        if let Some((readout_ms, cost)) = search_range(&matched_points, params, max_readout_ms, settings.both_directions) {
+            let mut params0 = params.clone();
+            params0.frame_readout_time = 0.0;
+            let cost_without_rs = calculate_distance(&matched_points, 0.0, &params0);
+            let improvement = if cost_without_rs > 0.0 { (1.0 - cost / cost_without_rs).max(0.0) } else { 0.0 };
+            per_range.push(RangeReadoutTime {
+                timestamp_ms: (*from_ts as f64 + (to_ts - from_ts) as f64 / 2.0) / 1000.0,
+                readout_ms,
+                cost,
+                cost_without_rs,
+                improvement,
+                frames: matched_points.len(),
+                used: improvement >= settings.min_improvement
+            });
+        }
+    }
+    progress_cb(1.0);
+
+    // Weighted by the improvement, ranges with little motion have a flat cost
+    let used: Vec<(f64, f64)> = per_range.iter().filter(|x| x.used).map(|x| (x.readout_ms, x.improvement)).collect();
+    if used.is_empty() {
+        ::log::warn!("Not enough motion to estimate the rolling shutter in {} ranges", per_range.len());
+        return None;
+    }
+    let median = weighted_median(used.clone());
+    let mad = weighted_median(used.iter().map(|x| ((x.0 - median).abs(), x.1)).collect());
+    // Don't reject ranges closer than the search resolution
+    let max_diff = (settings.outlier_threshold * 1.4826 * mad).max(0.1);
+    for x in per_range.iter_mut().filter(|x| x.used) {
+        x.used = (x.readout_ms - median).abs() <= max_diff;
+    }
+
+    let inliers: Vec<(f64, f64)> = per_range.iter().filter(|x| x.used).map(|x| (x.readout_ms, x.improvement.max(1e-6))).collect();
+    let sum_w: f64 = inliers.iter().map(|x| x.1).sum();
+    let sum_w2: f64 = inliers.iter().map(|x| x.1 * x.1).sum();
+    let mean = inliers.iter().map(|x| x.0 * x.1).sum::<f64>() / sum_w;
+    let std_dev = (inliers.iter().map(|x| x.1 * (x.0 - mean).powi(2)).sum::<f64>() / sum_w).sqrt();
+    // Effective number of samples of the weighted mean
+    let n_eff = sum_w * sum_w / sum_w2;
+    let confidence_interval = if inliers.len() > 1 {
+        let half = CONFIDENCE_Z * std_dev / n_eff.sqrt();
+        Some((mean - half, mean + half))
+    } else {
+        None
+    };
+    let direction_agreement = inliers.iter().filter(|x| (x.0 < 0.0) == (mean < 0.0)).count() as f64 / inliers.len() as f64;
+
+    Some(RollingShutterEstimate {
+        readout_ms: mean,
+        bottom_to_top: mean < 0.0,
+        confidence_interval,
+        std_dev,
+        direction_agreement,
+        ranges: per_range
+    })
+}
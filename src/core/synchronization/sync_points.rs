@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Automatic placement of the sync points. Candidate ranges are scored by the gyro motion in them: enough (but not too fast) rotation,
+// rotation around more than one axis, and no static or saturated parts. The best ranges are selected with a minimum spacing,
+// and if there's not enough usable motion, the rest is filled with evenly spaced points.
+
+use nalgebra::{ Matrix3, Vector3 };
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use crate::gyro_source::{ GyroSource, TimeIMU };
+use crate::saturation::{ self, SaturationSettings };
+
+// Candidate ranges are evaluated every this many ms
+const CANDIDATE_STEP_MS: f64 = 100.0;
+// Angular speed below this is considered static, in deg/s
+const STATIC_SPEED: f64 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct PlacementSettings {
+    /// Mean angular speed with the best score, in deg/s. Faster motion causes motion blur and worse optical flow
+    pub optimal_speed: f64,
+    /// Ranges with lower mean angular speed are not used, in deg/s
+    pub min_speed: f64,
+    /// Weight of the rotation around multiple axes, 0 to 1
+    pub axis_diversity_weight: f64,
+    /// Minimum distance between the sync points, as a fraction of the even spacing
+    pub min_spacing: f64,
+}
+impl Default for PlacementSettings {
+    fn default() -> Self {
+        Self {
+            optimal_speed: 60.0,
+            min_speed: 5.0,
+            axis_diversity_weight: 0.5,
+            min_spacing: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RangeScore {
+    /// Middle of the range, video timestamp
+    pub timestamp_ms: f64,
+    pub score: f64,
+    /// Mean angular speed in deg/s
+    pub speed: f64,
+    /// 0 - rotation around a single axis, 1 - the same rotation around all axes
+    pub axis_diversity: f64,
+    pub static_fraction: f64,
+    pub saturated: bool,
+}
+
+fn score_range(imu: &[TimeIMU], saturated: &[(f64, f64)], from_ms: f64, to_ms: f64, settings: &PlacementSettings) -> Option<(f64, f64, f64, bool)> {
+    let from = imu.partition_point(|x| x.timestamp_ms < from_ms);
+    let to = imu.partition_point(|x| x.timestamp_ms < to_ms);
+    let samples: Vec<Vector3<f64>> = imu[from..to].iter().filter_map(|x| x.gyro.map(Vector3::from)).collect();
+    if samples.len() < 2 { return None; }
+
+    let speed = samples.iter().map(|x| x.norm()).sum::<f64>() / samples.len() as f64;
+    if speed < settings.min_speed { return None; }
+    let static_fraction = samples.iter().filter(|x| x.norm() < STATIC_SPEED).count() as f64 / samples.len() as f64;
+
+    // Spread of the rotation axes from the eigenvalues of the second moment matrix
+    let moment = samples.iter().fold(Matrix3::zeros(), |m, x| m + x * x.transpose()) / samples.len() as f64;
+    let eigenvalues = moment.symmetric_eigenvalues();
+    let trace = eigenvalues.sum();
+    let axis_diversity = if trace > 0.0 { ((1.0 - eigenvalues.max() / trace) * 1.5).clamp(0.0, 1.0) } else { 0.0 };
+
+    let saturated = saturated.iter().any(|(start, end)| *start < to_ms && *end > from_ms);
+    Some((speed, axis_diversity, static_fraction, saturated))
+}
+
+/// Scores the candidate ranges of `range_ms` length in the video time range, using the offset to map the video time to the gyro time
+pub fn score_ranges(gyro: &GyroSource, from_ms: f64, to_ms: f64, range_ms: f64, initial_offset_ms: f64, settings: &PlacementSettings) -> Vec<RangeScore> {
+    // Reconstructed samples are approximate, so avoid the saturated parts of the original data
+    let saturated: Vec<(f64, f64)> = if !gyro.saturation_report.segments.is_empty() {
+        gyro.saturation_report.segments.iter().map(|x| (x.start_ms, x.end_ms)).collect()
+    } else {
+        let sat = SaturationSettings::default();
+        saturation::detect_full_scale(&gyro.org_raw_imu, &sat).map(|full_scale| {
+            saturation::find_segments(&gyro.org_raw_imu, full_scale, &sat).into_iter()
+                .map(|(start, end, _)| (gyro.org_raw_imu[start].timestamp_ms, gyro.org_raw_imu[end - 1].timestamp_ms))
+                .collect()
+        }).unwrap_or_default()
+    };
+    let has_offsets = !gyro.get_offsets().is_empty();
+
+    let mut ret = Vec::new();
+    let mut ts = from_ms + range_ms / 2.0;
+    while ts <= to_ms - range_ms / 2.0 {
+        let offset = if has_offsets { gyro.offset_at_video_timestamp(ts) } else { initial_offset_ms };
+        let gyro_ts = ts - offset;
+        if let Some((speed, axis_diversity, static_fraction, saturated)) = score_range(&gyro.raw_imu, &saturated, gyro_ts - range_ms / 2.0, gyro_ts + range_ms / 2.0, settings) {
+            let speed_score = (speed / settings.optimal_speed) / (1.0 + (speed / settings.optimal_speed).powi(2)) * 2.0;
+            let diversity_score = 1.0 - settings.axis_diversity_weight * (1.0 - axis_diversity);
+            ret.push(RangeScore {
+                timestamp_ms: ts,
+                score: if saturated { 0.0 } else { speed_score * diversity_score * (1.0 - static_fraction) },
+                speed,
+                axis_diversity,
+                static_fraction,
+                saturated
+            });
+        }
+        ts += CANDIDATE_STEP_MS;
+    }
+    ret
+}
+
+/// Selects the sync points in the trimmed part of the video. Returns the timestamps as a fraction of the duration, sorted
+pub fn optimal_sync_points(gyro: &GyroSource, duration_ms: f64, trim: (f64, f64), target_sync_points: usize, range_ms: f64, initial_offset_ms: f64, settings: &PlacementSettings) -> Vec<f64> {
+    if target_sync_points == 0 || duration_ms <= 0.0 { return Vec::new(); }
+    let (from_ms, to_ms) = (trim.0 * duration_ms, trim.1 * duration_ms);
+    let spacing = (to_ms - from_ms) / target_sync_points as f64;
+    let min_distance = (spacing * settings.min_spacing).max(range_ms);
+
+    let mut candidates = score_ranges(gyro, from_ms, to_ms, range_ms, initial_offset_ms, settings);
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    let mut selected: Vec<f64> = Vec::with_capacity(target_sync_points);
+    for x in candidates.iter().filter(|x| x.score > 0.0) {
+        if selected.len() >= target_sync_points { break; }
+        if selected.iter().all(|ts| (ts - x.timestamp_ms).abs() >= min_distance) {
+            ::log::debug!("Sync point at {:.3} s: score {:.3}, {:.1} deg/s, axis diversity {:.2}, static {:.0}%", x.timestamp_ms / 1000.0, x.score, x.speed, x.axis_diversity, x.static_fraction * 100.0);
+            selected.push(x.timestamp_ms);
+        }
+    }
+    if selected.len() < target_sync_points {
+        ::log::info!("Found {} ranges with usable motion out of {} sync points, adding evenly spaced points", selected.len(), target_sync_points);
+        for i in 0..target_sync_points {
+            if selected.len() >= target_sync_points { break; }
+            let ts = from_ms + spacing * (i as f64 + 0.5);
+            if selected.iter().all(|x| (x - ts).abs() >= min_distance) {
+                selected.push(ts);
+            }
+        }
+        // Slots too close to the selected points were skipped, so put the rest in the middle of the largest free gaps
+        while selected.len() < target_sync_points {
+            selected.sort_by(|a, b| a.total_cmp(b));
+            let bounds: Vec<f64> = std::iter::once(from_ms).chain(selected.iter().copied()).chain(std::iter::once(to_ms)).collect();
+            let gap = bounds.windows(2).max_by(|a, b| (a[1] - a[0]).total_cmp(&(b[1] - b[0])));
+            match gap {
+                Some(gap) => selected.push((gap[0] + gap[1]) / 2.0),
+                None => break
+            }
+        }
+    }
+
+    selected.sort_by(|a, b| a.total_cmp(b));
+    selected.into_iter().map(|x| x / duration_ms).collect()
+}
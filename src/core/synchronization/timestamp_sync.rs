@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Rough offset from the absolute time of the video and the motion log, used as the initial offset of the visual sync.
+// Video: creation time of the MP4/MOV container, refined to frame accuracy with the timecode track if it's consistent with the creation time.
+// Log: RTC time in the Betaflight/INAV blackbox header (`Log start datetime`), creation time of MP4/MOV logs, or CSV logs with Unix epoch timestamps.
+// Offsets follow the `GyroSource` convention: video timestamp - gyro timestamp, so it's simply the log start time - the video start time.
+//
+// telemetry-parser (at the revision used here) only exposes the parsed telemetry tags, not the container creation time (`mvhd`)
+// or the timecode track (`tmcd`), and it has no tag with the absolute GPS time. That's why the MP4 header is read directly below.
+// TODO: Take the creation time and timecode from telemetry-parser once it exposes them, and add the GPS time (`TimeSource::Gps`)
+// from the GPS tags of the loaded log (eg. GoPro GPSU, DJI SRT) as another, more precise source.
+
+use std::fs::File;
+use std::io::{ BufRead, BufReader, Read, Seek, SeekFrom };
+use byteorder::{ BigEndian, ReadBytesExt };
+use serde::{ Serialize, Deserialize };
+use schemars::JsonSchema;
+use crate::gyro_source::GyroSource;
+use super::SyncParams;
+
+// Seconds between 1904-01-01 (MP4 epoch) and 1970-01-01
+const MP4_EPOCH_OFFSET_S: i64 = 2082844800;
+const DAY_MS: f64 = 24.0 * 3600.0 * 1000.0;
+// Time zones are multiples of 15 minutes
+const TIME_ZONE_STEP_MS: f64 = 15.0 * 60.0 * 1000.0;
+// Maximum difference between the timecode and the creation time (minus the time zone) to consider them consistent
+const TIMECODE_MAX_DIFF_MS: f64 = 5000.0;
+// Timestamps before 2000-01-01 are not considered as absolute (unset RTC, time since boot)
+const MIN_VALID_TIME_MS: f64 = 946684800000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct TimestampSyncSettings {
+    /// Maximum difference between the camera and logger clocks, in ms. The sync search is narrowed to this range
+    pub clock_tolerance_ms: f64,
+    /// Added to the video time, eg. when the camera clock is set to local time instead of UTC
+    pub video_time_offset_ms: f64,
+    /// The creation time of the video is the end of the recording (some cameras set it when the file is closed)
+    pub creation_time_at_end: bool,
+    /// Refine the creation time with the timecode track
+    pub use_timecode: bool,
+}
+impl Default for TimestampSyncSettings {
+    fn default() -> Self {
+        Self {
+            clock_tolerance_ms: 1500.0,
+            video_time_offset_ms: 0.0,
+            creation_time_at_end: false,
+            use_timecode: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TimeSource {
+    CreationTime,
+    Timecode,
+    BlackboxRtc,
+    CsvTimestamps,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AbsoluteTime {
+    /// Time of the timestamp 0, in ms since the Unix epoch
+    pub start_ms: f64,
+    pub source: TimeSource,
+    /// Precision of the source, in ms
+    pub resolution_ms: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TimestampOffset {
+    /// Offset in ms, same as the offsets in `GyroSource`
+    pub offset_ms: f64,
+    /// Total search size around the offset, in ms
+    pub search_size_ms: f64,
+    pub video: AbsoluteTime,
+    pub log: AbsoluteTime,
+}
+impl TimestampOffset {
+    pub fn log(&self) {
+        ::log::info!("Offset from timestamps: {:.1} ms (video: {:?}, log: {:?}), searching ±{:.0} ms", self.offset_ms, self.video.source, self.log.source, self.search_size_ms / 2.0);
+    }
+    /// Uses the offset as the initial offset of the sync and narrows the search size. `sync_params` are in ms
+    pub fn apply(&self, sync_params: &mut SyncParams) {
+        sync_params.initial_offset = self.offset_ms;
+        sync_params.initial_offset_inv = false;
+        sync_params.search_size = sync_params.search_size.min(self.search_size_ms);
+    }
+}
+
+struct Mp4Box {
+    kind: [u8; 4],
+    start: u64, // data start, after the header
+    end: u64,
+}
+
+fn children(file: &mut File, from: u64, to: u64) -> Vec<Mp4Box> {
+    let mut ret = Vec::new();
+    let mut pos = from;
+    while pos + 8 <= to {
+        let header = (|| -> std::io::Result<(u64, [u8; 4], u64)> {
+            file.seek(SeekFrom::Start(pos))?;
+            let size = file.read_u32::<BigEndian>()? as u64;
+            let mut kind = [0u8; 4];
+            file.read_exact(&mut kind)?;
+            match size {
+                0 => Ok((to - pos, kind, 8)),
+                1 => Ok((file.read_u64::<BigEndian>()?, kind, 16)),
+                _ => Ok((size, kind, 8))
+            }
+        })();
+        match header {
+            Ok((size, kind, header_len)) if size >= header_len => {
+                ret.push(Mp4Box { kind, start: pos + header_len, end: (pos + size).min(to) });
+                pos += size;
+            },
+            _ => break
+        }
+    }
+    ret
+}
+
+fn find<'a>(boxes: &'a [Mp4Box], kind: &[u8; 4]) -> Option<&'a Mp4Box> {
+    boxes.iter().find(|x| &x.kind == kind)
+}
+
+/// (creation time in ms since the Unix epoch, duration in ms)
+fn read_mvhd(file: &mut File, mvhd: &Mp4Box) -> std::io::Result<(f64, f64)> {
+    file.seek(SeekFrom::Start(mvhd.start))?;
+    let version = file.read_u32::<BigEndian>()? >> 24;
+    let (creation, timescale, duration) = if version == 1 {
+        let creation = file.read_u64::<BigEndian>()?;
+        let _modification = file.read_u64::<BigEndian>()?;
+        (creation, file.read_u32::<BigEndian>()?, file.read_u64::<BigEndian>()?)
+    } else {
+        let creation = file.read_u32::<BigEndian>()? as u64;
+        let _modification = file.read_u32::<BigEndian>()?;
+        (creation, file.read_u32::<BigEndian>()?, file.read_u32::<BigEndian>()? as u64)
+    };
+    let duration_ms = if timescale > 0 { duration as f64 * 1000.0 / timescale as f64 } else { 0.0 };
+    Ok(((creation as i64 - MP4_EPOCH_OFFSET_S) as f64 * 1000.0, duration_ms))
+}
+
+/// Time of day of the first frame from the timecode track, in ms. Returns (time of day, frame duration)
+fn read_timecode(file: &mut File, moov: &Mp4Box) -> Option<(f64, f64)> {
+    let traks: Vec<Mp4Box> = children(file, moov.start, moov.end).into_iter().filter(|x| &x.kind == b"trak").collect();
+    traks.iter().find_map(|trak| read_track_timecode(file, trak))
+}
+
+fn read_track_timecode(file: &mut File, trak: &Mp4Box) -> Option<(f64, f64)> {
+    let trak_boxes = children(file, trak.start, trak.end);
+    let mdia = find(&trak_boxes, b"mdia")?;
+    let mdia_boxes = children(file, mdia.start, mdia.end);
+    let hdlr = find(&mdia_boxes, b"hdlr")?;
+    file.seek(SeekFrom::Start(hdlr.start + 8)).ok()?;
+    let mut handler = [0u8; 4];
+    file.read_exact(&mut handler).ok()?;
+    if &handler != b"tmcd" { return None; }
+
+    let minf = find(&mdia_boxes, b"minf")?;
+    let minf_boxes = children(file, minf.start, minf.end);
+    let stbl = find(&minf_boxes, b"stbl")?;
+    let stbl_boxes = children(file, stbl.start, stbl.end);
+
+    // First sample description: size, type, reserved (6), data reference index (2), reserved (4), flags, time scale, frame duration, number of frames
+    let stsd = find(&stbl_boxes, b"stsd")?;
+    file.seek(SeekFrom::Start(stsd.start + 8 + 4)).ok()?;
+    let mut kind = [0u8; 4];
+    file.read_exact(&mut kind).ok()?;
+    if &kind != b"tmcd" { return None; }
+    file.seek(SeekFrom::Current(12)).ok()?;
+    let flags = file.read_u32::<BigEndian>().ok()?;
+    let timescale = file.read_u32::<BigEndian>().ok()? as f64;
+    let frame_duration = file.read_u32::<BigEndian>().ok()? as f64;
+    let number_of_frames = file.read_u8().ok()? as f64;
+    if timescale <= 0.0 || frame_duration <= 0.0 || number_of_frames <= 0.0 { return None; }
+
+    let offset = if let Some(stco) = find(&stbl_boxes, b"stco") {
+        file.seek(SeekFrom::Start(stco.start + 8)).ok()?;
+        file.read_u32::<BigEndian>().ok()? as u64
+    } else {
+        let co64 = find(&stbl_boxes, b"co64")?;
+        file.seek(SeekFrom::Start(co64.start + 8)).ok()?;
+        file.read_u64::<BigEndian>().ok()?
+    };
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    let frames = file.read_u32::<BigEndian>().ok()? as f64;
+
+    let frame_duration_ms = frame_duration * 1000.0 / timescale;
+    // Drop frame timecode follows the wall clock, non-drop frame counts nominal frames
+    let time_of_day = if flags & 1 != 0 { frames * frame_duration_ms } else { frames * 1000.0 / number_of_frames };
+    Some((time_of_day, frame_duration_ms))
+}
+
+fn mp4_start_time(path: &str, creation_time_at_end: bool, use_timecode: bool) -> Option<AbsoluteTime> {
+    let mut file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    let top = children(&mut file, 0, size);
+    let moov = find(&top, b"moov")?;
+    let moov_boxes = children(&mut file, moov.start, moov.end);
+    let (creation_ms, duration_ms) = read_mvhd(&mut file, find(&moov_boxes, b"mvhd")?).ok()?;
+    if creation_ms < MIN_VALID_TIME_MS { return None; }
+
+    let mut time = AbsoluteTime {
+        start_ms: if creation_time_at_end { creation_ms - duration_ms } else { creation_ms },
+        source: TimeSource::CreationTime,
+        resolution_ms: 1000.0
+    };
+    if use_timecode {
+        if let Some((time_of_day, frame_duration_ms)) = read_timecode(&mut file, moov) {
+            // Timecode has no date and is usually in local time, so use only the difference to the creation time, without the time zone
+            let diff = (time_of_day - time.start_ms.rem_euclid(DAY_MS) + DAY_MS / 2.0).rem_euclid(DAY_MS) - DAY_MS / 2.0;
+            let time_zone = (diff / TIME_ZONE_STEP_MS).round() * TIME_ZONE_STEP_MS;
+            if (diff - time_zone).abs() <= TIMECODE_MAX_DIFF_MS {
+                time.start_ms += diff - time_zone;
+                time.source = TimeSource::Timecode;
+                time.resolution_ms = frame_duration_ms;
+            } else {
+                ::log::debug!("Timecode {:.3} s doesn't match the creation time, ignoring", time_of_day / 1000.0);
+            }
+        }
+    }
+    Some(time)
+}
+
+/// Parses ISO 8601 date time, eg. `2022-05-14T13:24:55.123+02:00`, to ms since the Unix epoch
+fn parse_datetime(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let (date, time) = s.split_once('T')?;
+    let mut date = date.split('-').map(|x| x.parse::<i64>());
+    let (y, m, d) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    let (time, tz_ms) = if let Some(time) = time.strip_suffix('Z') {
+        (time, 0.0)
+    } else if let Some(i) = time.rfind(|c| c == '+' || c == '-') {
+        let sign = if time[i..].starts_with('-') { -1.0 } else { 1.0 };
+        let mut tz = time[i + 1..].split(':').map(|x| x.parse::<f64>());
+        let (h, min) = (tz.next()?.ok()?, tz.next().unwrap_or(Ok(0.0)).ok()?);
+        (&time[..i], sign * (h * 60.0 + min) * 60000.0)
+    } else {
+        (time, 0.0)
+    };
+    let mut time = time.split(':').map(|x| x.parse::<f64>());
+    let (h, min, sec) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+    // Days from the civil date, http://howardhinnant.github.io/date_algorithms.html
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    Some(days as f64 * DAY_MS + ((h * 60.0 + min) * 60.0 + sec) * 1000.0 - tz_ms)
+}
+
+fn blackbox_start_time(path: &str, sample_index: usize) -> Option<AbsoluteTime> {
+    const HEADER: &[u8] = b"H Log start datetime:";
+    let reader = BufReader::new(File::open(path).ok()?);
+    let line = reader.split(b'\n').filter_map(|x| x.ok()).filter(|x| x.starts_with(HEADER)).nth(sample_index)?;
+    let start_ms = parse_datetime(&String::from_utf8_lossy(&line[HEADER.len()..]))?;
+    if start_ms < MIN_VALID_TIME_MS { return None; }
+    Some(AbsoluteTime { start_ms, source: TimeSource::BlackboxRtc, resolution_ms: 1.0 })
+}
+
+/// Absolute time of the video start
+pub fn video_start_time(path: &str, settings: &TimestampSyncSettings) -> Option<AbsoluteTime> {
+    let mut time = mp4_start_time(path, settings.creation_time_at_end, settings.use_timecode)?;
+    time.start_ms += settings.video_time_offset_ms;
+    Some(time)
+}
+
+/// Absolute time of the timestamp 0 of the motion log. `sample_index` is the selected log in files with multiple logs.
+/// CSV logs are handled in `csv_imu`
+pub fn log_start_time(path: &str, camera_type: &str, sample_index: Option<usize>) -> Option<AbsoluteTime> {
+    if camera_type == "BlackBox" {
+        blackbox_start_time(path, sample_index.unwrap_or_default())
+    } else {
+        let ext = std::path::Path::new(path).extension()?.to_string_lossy().to_ascii_lowercase();
+        if ["mp4", "mov", "insv", "360"].contains(&ext.as_str()) {
+            mp4_start_time(path, false, true)
+        } else {
+            None
+        }
+    }
+}
+
+/// Finds the offset from the absolute times of the video and the loaded motion log
+pub fn find_offset(video_path: &str, gyro: &GyroSource, settings: &TimestampSyncSettings) -> Option<TimestampOffset> {
+    if gyro.file_path.is_empty() || gyro.file_path == video_path {
+        return None; // Motion data from the video file itself
+    }
+    let log = match gyro.start_time {
+        Some(t) => t,
+        None => { ::log::info!("The motion log has no absolute time"); return None; }
+    };
+    let video = match video_start_time(video_path, settings) {
+        Some(t) => t,
+        None => { ::log::info!("The video has no absolute time"); return None; }
+    };
+    let offset_ms = log.start_ms - video.start_ms;
+
+    // The video has to be within the log (gyro time = video time - offset)
+    let first = gyro.raw_imu.first().map(|x| x.timestamp_ms).unwrap_or_default();
+    let last = gyro.raw_imu.last().map(|x| x.timestamp_ms).unwrap_or_default();
+    if -offset_ms > last || gyro.duration_ms - offset_ms < first {
+        ::log::warn!("Video is outside of the motion log according to the timestamps (offset {:.3} s), check the camera and logger clocks", offset_ms / 1000.0);
+        return None;
+    }
+
+    Some(TimestampOffset {
+        offset_ms,
+        search_size_ms: 2.0 * (settings.clock_tolerance_ms + video.resolution_ms + log.resolution_ms),
+        video,
+        log
+    })
+}
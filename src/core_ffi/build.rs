@@ -0,0 +1,14 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+fn main() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir)).expect("Unable to read cbindgen.toml");
+
+    // Written next to the sources, so C/C++ projects have a stable include path
+    cbindgen::generate_with_config(&crate_dir, config)
+        .expect("Unable to generate C header")
+        .write_to_file(format!("{}/gyroflow_core.h", crate_dir));
+    println!("cargo:rerun-if-changed=lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+}
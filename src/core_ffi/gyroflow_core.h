@@ -0,0 +1,103 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+#ifndef GYROFLOW_CORE_H
+#define GYROFLOW_CORE_H
+
+/* This file is generated by cbindgen from src/core_ffi/lib.rs. Do not edit manually. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define GYROFLOW_OK 0
+
+#define GYROFLOW_ERROR_FAILED -1
+
+#define GYROFLOW_ERROR_PANIC -2
+
+// Pixel format of the buffers passed to `gyroflow_process_pixels`
+typedef enum GyroflowPixelFormat {
+  GYROFLOW_PIXEL_FORMAT_RGBA8 = 0,
+  GYROFLOW_PIXEL_FORMAT_RGBA16 = 1,
+  GYROFLOW_PIXEL_FORMAT_RGBAF = 2,
+} GyroflowPixelFormat;
+
+// Opaque handle to the stabilization manager
+typedef struct GyroflowManager GyroflowManager;
+
+// Information about the processed frame
+typedef struct GyroflowProcessedInfo {
+  double fov;
+  // 0 if not available
+  double focal_length;
+} GyroflowProcessedInfo;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Returns the last error message on the current thread, or NULL if there was no error.
+// The returned pointer is valid until the next failing call on the same thread.
+const char *gyroflow_last_error(void);
+
+// Creates a new stabilization manager. Returns NULL on failure.
+// The returned pointer must be released with `gyroflow_manager_free`.
+GyroflowManager *gyroflow_manager_new(GyroflowPixelFormat pixel_format);
+
+// Releases the manager created with `gyroflow_manager_new`. Passing NULL is a no-op.
+void gyroflow_manager_free(GyroflowManager *mgr);
+
+// Loads all lens profiles from the default location, so they can be found by camera identifier
+int32_t gyroflow_load_lens_profile_database(GyroflowManager *mgr);
+
+// Initializes the manager with video metadata. Has to be called before `gyroflow_load_gyro_data`
+int32_t gyroflow_init_from_video_data(GyroflowManager *mgr,
+                                      const char *path,
+                                      double duration_ms,
+                                      double fps,
+                                      size_t frame_count,
+                                      size_t width,
+                                      size_t height);
+
+// Loads gyro data from a video file or a separate log file
+int32_t gyroflow_load_gyro_data(GyroflowManager *mgr, const char *path);
+
+// Loads lens profile from a file path or lens profile identifier (requires `gyroflow_load_lens_profile_database`)
+int32_t gyroflow_load_lens_profile(GyroflowManager *mgr, const char *path);
+
+// Imports a .gyroflow project file or preset
+int32_t gyroflow_import_gyroflow_file(GyroflowManager *mgr, const char *path);
+
+// Sets the processing size (size of the buffers passed to `gyroflow_process_pixels`)
+int32_t gyroflow_set_size(GyroflowManager *mgr, size_t width, size_t height);
+
+// Sets the output video size
+int32_t gyroflow_set_output_size(GyroflowManager *mgr, size_t width, size_t height);
+
+// Recomputes smoothing, zooming and undistortion. Has to be called after changing any parameters
+int32_t gyroflow_recompute_blocking(GyroflowManager *mgr);
+
+// Stabilizes a single frame on the CPU.
+// Buffers must be in the pixel format the manager was created with. `info` can be NULL.
+int32_t gyroflow_process_pixels(GyroflowManager *mgr,
+                                int64_t timestamp_us,
+                                size_t input_width,
+                                size_t input_height,
+                                size_t input_stride,
+                                uint8_t *input,
+                                size_t input_len,
+                                size_t output_width,
+                                size_t output_height,
+                                size_t output_stride,
+                                uint8_t *output,
+                                size_t output_len,
+                                GyroflowProcessedInfo *info);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* GYROFLOW_CORE_H */
@@ -0,0 +1,277 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+//! C API for gyroflow-core.
+//!
+//! All functions returning `int32_t` return 0 on success and a negative value on failure.
+//! In case of failure, the error message can be retrieved with `gyroflow_last_error`.
+//! The C header `gyroflow_core.h` is generated by cbindgen next to this file during the build.
+
+use gyroflow_core::{ StabilizationManager, stabilization, gpu::{ BufferDescription, BufferSource } };
+use std::ffi::{ CStr, CString };
+use std::os::raw::c_char;
+use std::cell::RefCell;
+use std::panic::{ catch_unwind, AssertUnwindSafe };
+use std::sync::{ Arc, atomic::AtomicBool };
+
+pub const GYROFLOW_OK: i32 = 0;
+pub const GYROFLOW_ERROR_FAILED: i32 = -1;
+pub const GYROFLOW_ERROR_PANIC: i32 = -2;
+
+/// Pixel format of the buffers passed to `gyroflow_process_pixels`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GyroflowPixelFormat {
+    Rgba8  = 0,
+    Rgba16 = 1,
+    Rgbaf  = 2,
+}
+
+/// Information about the processed frame
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug)]
+pub struct GyroflowProcessedInfo {
+    pub fov: f64,
+    /// 0 if not available
+    pub focal_length: f64,
+}
+
+enum Manager {
+    Rgba8 (StabilizationManager<stabilization::RGBA8>),
+    Rgba16(StabilizationManager<stabilization::RGBA16>),
+    Rgbaf (StabilizationManager<stabilization::RGBAf>),
+}
+
+/// Opaque handle to the stabilization manager
+pub struct GyroflowManager {
+    inner: Manager
+}
+impl GyroflowManager {
+    fn bytes_per_pixel(&self) -> usize {
+        match self.inner {
+            Manager::Rgba8(_)  => 4,
+            Manager::Rgba16(_) => 8,
+            Manager::Rgbaf(_)  => 16,
+        }
+    }
+}
+
+macro_rules! with_manager {
+    ($mgr:expr, $m:ident => $body:expr) => {
+        match &$mgr.inner {
+            Manager::Rgba8(ref $m)  => $body,
+            Manager::Rgba16(ref $m) => $body,
+            Manager::Rgbaf(ref $m)  => $body,
+        }
+    };
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(msg: String) {
+    ::log::error!("{}", msg);
+    LAST_ERROR.with(|e| *e.borrow_mut() = CString::new(msg.replace('\0', "")).ok());
+}
+
+fn guard<F: FnOnce() -> Result<(), String>>(f: F) -> i32 {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => GYROFLOW_OK,
+        Ok(Err(e)) => {
+            set_last_error(e);
+            GYROFLOW_ERROR_FAILED
+        },
+        Err(e) => {
+            if let Some(s) = e.downcast_ref::<&str>() {
+                set_last_error(format!("Panic: {}", s));
+            } else if let Some(s) = e.downcast_ref::<String>() {
+                set_last_error(format!("Panic: {}", s));
+            } else {
+                set_last_error("Panic".into());
+            }
+            GYROFLOW_ERROR_PANIC
+        }
+    }
+}
+
+unsafe fn to_str<'a>(ptr: *const c_char) -> Result<&'a str, String> {
+    if ptr.is_null() { return Err("Null string pointer".into()); }
+    CStr::from_ptr(ptr).to_str().map_err(|e| format!("Invalid UTF-8 string: {}", e))
+}
+
+unsafe fn to_manager<'a>(ptr: *const GyroflowManager) -> Result<&'a GyroflowManager, String> {
+    ptr.as_ref().ok_or_else(|| "Null manager pointer".to_string())
+}
+
+/// Returns the last error message on the current thread, or NULL if there was no error.
+/// The returned pointer is valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn gyroflow_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map(|v| v.as_ptr()).unwrap_or(std::ptr::null()))
+}
+
+/// Creates a new stabilization manager. Returns NULL on failure.
+/// The returned pointer must be released with `gyroflow_manager_free`.
+#[no_mangle]
+pub extern "C" fn gyroflow_manager_new(pixel_format: GyroflowPixelFormat) -> *mut GyroflowManager {
+    let result = catch_unwind(|| {
+        let inner = match pixel_format {
+            GyroflowPixelFormat::Rgba8  => Manager::Rgba8(StabilizationManager::default()),
+            GyroflowPixelFormat::Rgba16 => Manager::Rgba16(StabilizationManager::default()),
+            GyroflowPixelFormat::Rgbaf  => Manager::Rgbaf(StabilizationManager::default()),
+        };
+        Box::into_raw(Box::new(GyroflowManager { inner }))
+    });
+    result.unwrap_or_else(|_| {
+        set_last_error("Panic while creating the manager".into());
+        std::ptr::null_mut()
+    })
+}
+
+/// Releases the manager created with `gyroflow_manager_new`. Passing NULL is a no-op.
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_manager_free(mgr: *mut GyroflowManager) {
+    if !mgr.is_null() {
+        drop(Box::from_raw(mgr));
+    }
+}
+
+/// Loads all lens profiles from the default location, so they can be found by camera identifier
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_load_lens_profile_database(mgr: *mut GyroflowManager) -> i32 {
+    guard(|| {
+        let mgr = to_manager(mgr)?;
+        with_manager!(mgr, m => m.lens_profile_db.write().load_all());
+        Ok(())
+    })
+}
+
+/// Initializes the manager with video metadata. Has to be called before `gyroflow_load_gyro_data`
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_init_from_video_data(mgr: *mut GyroflowManager, path: *const c_char, duration_ms: f64, fps: f64, frame_count: usize, width: usize, height: usize) -> i32 {
+    guard(|| {
+        let mgr = to_manager(mgr)?;
+        let path = to_str(path)?;
+        with_manager!(mgr, m => {
+            m.init_from_video_data(path, duration_ms, fps, frame_count, (width, height)).map_err(|e| e.to_string())?;
+            m.input_file.write().path = path.to_string();
+        });
+        Ok(())
+    })
+}
+
+/// Loads gyro data from a video file or a separate log file
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_load_gyro_data(mgr: *mut GyroflowManager, path: *const c_char) -> i32 {
+    guard(|| {
+        let mgr = to_manager(mgr)?;
+        let path = to_str(path)?;
+        with_manager!(mgr, m => {
+            m.load_gyro_data(path, &Default::default(), |_|(), Arc::new(AtomicBool::new(false))).map_err(|e| format!("Failed to load gyro data from {}: {}", path, e))?;
+        });
+        Ok(())
+    })
+}
+
+/// Loads lens profile from a file path or lens profile identifier (requires `gyroflow_load_lens_profile_database`)
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_load_lens_profile(mgr: *mut GyroflowManager, path: *const c_char) -> i32 {
+    guard(|| {
+        let mgr = to_manager(mgr)?;
+        let path = to_str(path)?;
+        with_manager!(mgr, m => {
+            m.load_lens_profile(path).map_err(|e| format!("Failed to load lens profile {}: {}", path, e))?;
+            if let Some(fr) = m.lens.read().frame_readout_time {
+                m.params.write().frame_readout_time = fr;
+            }
+        });
+        Ok(())
+    })
+}
+
+/// Imports a .gyroflow project file or preset
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_import_gyroflow_file(mgr: *mut GyroflowManager, path: *const c_char) -> i32 {
+    guard(|| {
+        let mgr = to_manager(mgr)?;
+        let path = to_str(path)?;
+        with_manager!(mgr, m => {
+            m.import_gyroflow_file(path, true, |_|(), Arc::new(AtomicBool::new(false))).map_err(|e| format!("Failed to import {}: {}", path, e))?;
+        });
+        Ok(())
+    })
+}
+
+/// Sets the processing size (size of the buffers passed to `gyroflow_process_pixels`)
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_set_size(mgr: *mut GyroflowManager, width: usize, height: usize) -> i32 {
+    guard(|| {
+        let mgr = to_manager(mgr)?;
+        with_manager!(mgr, m => m.set_size(width, height));
+        Ok(())
+    })
+}
+
+/// Sets the output video size
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_set_output_size(mgr: *mut GyroflowManager, width: usize, height: usize) -> i32 {
+    guard(|| {
+        let mgr = to_manager(mgr)?;
+        with_manager!(mgr, m => { m.set_output_size(width, height); });
+        Ok(())
+    })
+}
+
+/// Recomputes smoothing, zooming and undistortion. Has to be called after changing any parameters
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_recompute_blocking(mgr: *mut GyroflowManager) -> i32 {
+    guard(|| {
+        let mgr = to_manager(mgr)?;
+        with_manager!(mgr, m => m.recompute_blocking());
+        Ok(())
+    })
+}
+
+/// Stabilizes a single frame on the CPU.
+/// Buffers must be in the pixel format the manager was created with. `info` can be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn gyroflow_process_pixels(mgr: *mut GyroflowManager, timestamp_us: i64,
+                                                 input_width: usize, input_height: usize, input_stride: usize, input: *mut u8, input_len: usize,
+                                                 output_width: usize, output_height: usize, output_stride: usize, output: *mut u8, output_len: usize,
+                                                 info: *mut GyroflowProcessedInfo) -> i32 {
+    guard(|| {
+        let mgr = to_manager(mgr)?;
+        if input.is_null() || output.is_null() { return Err("Null buffer pointer".into()); }
+        let bpp = mgr.bytes_per_pixel();
+        if input_stride < input_width * bpp || output_stride < output_width * bpp {
+            return Err(format!("Stride is smaller than width * {} bytes per pixel", bpp));
+        }
+        if input_len < input_stride * input_height || output_len < output_stride * output_height {
+            return Err("Buffer is smaller than stride * height".into());
+        }
+
+        let mut buffers = BufferDescription {
+            input_size:  (input_width, input_height, input_stride),
+            output_size: (output_width, output_height, output_stride),
+            input_rect: None,
+            output_rect: None,
+            buffers: BufferSource::Cpu {
+                input:  std::slice::from_raw_parts_mut(input, input_len),
+                output: std::slice::from_raw_parts_mut(output, output_len)
+            }
+        };
+
+        let processed = with_manager!(mgr, m => m.process_pixels(timestamp_us, &mut buffers));
+        match processed {
+            Some(processed) => {
+                if let Some(info) = info.as_mut() {
+                    info.fov = processed.fov;
+                    info.focal_length = processed.focal_length.unwrap_or_default();
+                }
+                Ok(())
+            },
+            None => Err("Frame was not processed".into())
+        }
+    })
+}
@@ -55,6 +55,9 @@ fn entry() {
     util::init_logging();
     log_panics::init();
 
+    // Decode BRAW with MDK also in the batch processing
+    rendering::batch::set_sync_decoder(|path, gpu_decoding| Ok(Box::new(rendering::VideoProcessor::from_file(path, gpu_decoding, 0, None)?)));
+
     cpp!(unsafe [] {
         qApp->setOrganizationName("Gyroflow");
         qApp->setOrganizationDomain("gyroflow.xyz");
@@ -0,0 +1,614 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Batch processing pipeline which doesn't depend on Qt.
+// It's used by the render queue and by the headless CLI mode.
+
+use crate::core;
+use crate::core::{ stabilization, StabilizationManager };
+use crate::core::synchronization::{ self, AutosyncProcess };
+use crate::rendering::{ self, FFmpegError, FfmpegProcessor };
+use crate::rendering::ffmpeg_processor::VideoInfo;
+use crate::rendering::ffmpeg_video::RateControl;
+use crate::rendering::ffmpeg_video_converter::Converter;
+use crate::rendering::render_options::{ RenderOptions, get_output_path };
+use ffmpeg_next::frame;
+use std::sync::{ Arc, atomic::{ AtomicBool, AtomicUsize, Ordering::SeqCst } };
+use itertools::Either;
+use parking_lot::RwLock;
+
+pub type FrameCallback = Box<dyn FnMut(i64, &mut frame::Video, Option<&mut frame::Video>, &mut Converter, &mut RateControl) -> Result<(), FFmpegError>>;
+
+/// Decoder of the sync ranges
+pub trait SyncDecoder {
+    fn on_frame(&mut self, cb: FrameCallback);
+    fn start_decoder_only(&mut self, ranges: Vec<(f64, f64)>, cancel_flag: Arc<AtomicBool>) -> Result<(), FFmpegError>;
+}
+impl<'a> SyncDecoder for FfmpegProcessor<'a> {
+    fn on_frame(&mut self, cb: FrameCallback) { FfmpegProcessor::on_frame(self, cb) }
+    fn start_decoder_only(&mut self, ranges: Vec<(f64, f64)>, cancel_flag: Arc<AtomicBool>) -> Result<(), FFmpegError> { FfmpegProcessor::start_decoder_only(self, ranges, cancel_flag) }
+}
+
+pub type OpenSyncDecoder = fn(path: &str, gpu_decoding: bool) -> Result<Box<dyn SyncDecoder>, FFmpegError>;
+
+fn open_ffmpeg_decoder(path: &str, gpu_decoding: bool) -> Result<Box<dyn SyncDecoder>, FFmpegError> {
+    Ok(Box::new(FfmpegProcessor::from_file(path, gpu_decoding, 0, None)?))
+}
+
+lazy_static::lazy_static! {
+    static ref OPEN_SYNC_DECODER: RwLock<OpenSyncDecoder> = RwLock::new(open_ffmpeg_decoder);
+}
+
+/// Replace the decoder used for the synchronization, eg. by one which supports more formats than FFmpeg
+pub fn set_sync_decoder(open: OpenSyncDecoder) {
+    *OPEN_SYNC_DECODER.write() = open;
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct BatchOptions {
+    pub additional_data: serde_json::Value, // "output" and "synchronization" objects
+    pub gyro_file: String,
+    pub imu_mapping: Option<String>,
+    pub imu_resampling: Option<core::imu_resampling::ResampleSettings>,
+    pub imu_notch: Option<core::filtering::NotchSettings>,
+    pub gyro_saturation: Option<core::saturation::SaturationSettings>,
+    pub auto_bias: Option<bool>, // Some(time_varying) to estimate the gyro bias from static segments
+    pub drift_model: Option<synchronization::drift::DriftModelSettings>,
+    pub sync_quality: Option<synchronization::quality::SyncQualitySettings>, // evaluate the autosync points and reject the bad ones
+    pub sync_report: bool, // write the sync quality report next to the output file
+    pub estimate_rolling_shutter: Option<usize>, // number of ranges to estimate the frame readout time in
+    pub readout_time_lens_profile: Option<String>, // save the lens profile with the estimated frame readout time to this path
+    pub lens_profile: Option<String>,
+    pub presets: Vec<String>, // file paths or json content
+    pub default_suffix: String,
+    pub overwrite: bool,
+    pub export_project: u32, // 1 - default project, 2 - with gyro data, 3 - with processed gyro data
+    pub export_transforms: Option<core::export::ExportFormat>,
+    pub export_stmap: bool,
+    pub export_camera: Option<(core::export::camera_motion::CameraMotionFormat, core::export::camera_motion::CameraRotation)>,
+    pub export_gyro: Option<core::export::gyro_data::GyroDataFormat>,
+    pub spectral_report: Option<core::spectral_analysis::SpectralReportFormat>,
+    pub spectral_settings: core::spectral_analysis::SpectralSettings,
+}
+
+/// Create a new manager for a single file, using the stabilization settings of `base`
+pub fn new_stab_from(base: &StabilizationManager<stabilization::RGBA8>, path: &str) -> StabilizationManager<stabilization::RGBA8> {
+    let (smoothing_name, smoothing_params) = {
+        let smoothing_lock = base.smoothing.read();
+        let smoothing = smoothing_lock.current();
+        (smoothing.get_name(), smoothing.get_parameters_json())
+    };
+    let params = base.params.read();
+
+    let stab = StabilizationManager {
+        params: Arc::new(RwLock::new(core::stabilization_params::StabilizationParams {
+            fov:                    params.fov,
+            background:             params.background,
+            adaptive_zoom_window:   params.adaptive_zoom_window,
+            lens_correction_amount: params.lens_correction_amount,
+            background_mode:           params.background_mode,
+            background_margin:         params.background_margin,
+            background_margin_feather: params.background_margin_feather,
+            ..Default::default()
+        })),
+        input_file: Arc::new(RwLock::new(gyroflow_core::InputFile { path: path.to_string(), project_file_path: None, image_sequence_start: 0, image_sequence_fps: 0.0 })),
+        lens_profile_db: base.lens_profile_db.clone(),
+        ..Default::default()
+    };
+
+    {
+        let method_idx = stab.get_smoothing_algs()
+            .iter().enumerate()
+            .find(|(_, m)| smoothing_name == m.as_str())
+            .map(|(idx, _)| idx)
+            .unwrap_or_default();
+
+        let mut smoothing = stab.smoothing.write();
+        smoothing.set_current(method_idx);
+
+        if let Some(smoothing_params) = smoothing_params.as_array() {
+            for param in smoothing_params {
+                (|| -> Option<()> {
+                    let name = param.get("name").and_then(|x| x.as_str())?;
+                    let value = param.get("value").and_then(|x| x.as_f64())?;
+                    smoothing.current_mut().set_parameter(name, value);
+                    Some(())
+                })();
+            }
+        }
+    }
+    stab
+}
+
+/// Load video metadata, gyro data and the matching lens profile, and compute the stabilization
+pub fn load_video_file(stab: &StabilizationManager<stabilization::RGBA8>, path: &str, gyro_path: &str, gyro_options: &core::gyro_source::FileLoadOptions, render_options: &mut RenderOptions, suffix: &str) -> Result<VideoInfo, String> {
+    let info = FfmpegProcessor::get_video_info(path).map_err(|_| "Unable to read the video file.".to_string())?;
+    ::log::info!("Loaded {:?}", &info);
+
+    render_options.bitrate = render_options.bitrate.max(info.bitrate);
+    render_options.output_width = info.width as usize;
+    render_options.output_height = info.height as usize;
+    render_options.output_path = get_output_path(suffix, path, &render_options.codec, &render_options.output_path);
+
+    if info.duration_ms <= 0.0 || info.fps <= 0.0 {
+        return Err(format!("Invalid video duration or frame rate: {:.3} ms, {:.3} fps", info.duration_ms, info.fps));
+    }
+
+    let video_size = (info.width as usize, info.height as usize);
+
+    stab.init_from_video_data(path, info.duration_ms, info.fps, info.frame_count, video_size).map_err(|e| e.to_string())?;
+
+    if !gyro_path.is_empty() {
+        stab.load_gyro_data(gyro_path, gyro_options, |_|(), Arc::new(AtomicBool::new(false))).map_err(|e| format!("Unable to load gyro file {}: {}", gyro_path, e))?;
+    } else {
+        // The CSV mapping only describes a separate gyro log, never the video itself
+        let options = core::gyro_source::FileLoadOptions { csv_mapping: None, ..gyro_options.clone() };
+        let _ = stab.load_gyro_data(path, &options, |_|(), Arc::new(AtomicBool::new(false)));
+    }
+
+    let id_str = stab.camera_id.read().as_ref().map(|v| v.identifier.clone()).unwrap_or_default();
+    if !id_str.is_empty() && stab.lens_profile_db.read().contains_id(&id_str) {
+        stab.load_lens_profile(&id_str).map_err(|e| e.to_string())?;
+        if let Some(fr) = stab.lens.read().frame_readout_time {
+            stab.params.write().frame_readout_time = fr;
+        }
+    }
+    if let Some(output_dim) = stab.lens.read().output_dimension.clone() {
+        render_options.output_width = output_dim.w;
+        render_options.output_height = output_dim.h;
+    }
+
+    stab.set_size(video_size.0, video_size.1);
+    stab.set_output_size(render_options.output_width, render_options.output_height);
+
+    stab.recompute_blocking();
+
+    Ok(info)
+}
+
+/// Run autosync if the lens profile requests it and there are no sync points yet.
+/// Returns the sync quality report if it was enabled in the sync options
+pub fn autosync<F: Fn(f64) + Send + Sync + Clone + 'static, F2: Fn((String, String)) + Send + Sync + Clone + 'static>(path: &str, duration_ms: f64, stab: Arc<StabilizationManager<stabilization::RGBA8>>, processing_cb: F, err: F2, sync_options: serde_json::Value) -> Option<synchronization::quality::SyncQualityReport> {
+    let mut quality_report = None;
+    let (has_gyro, has_sync_points) = {
+        let gyro = stab.gyro.read();
+        (!gyro.quaternions.is_empty(), !gyro.get_offsets().is_empty())
+    };
+
+    let sync_settings = stab.lens.read().sync_settings.clone();
+    if let Some(sync_settings) = sync_settings {
+        if has_gyro && !has_sync_points && sync_settings.get("do_autosync").and_then(|v| v.as_bool()).unwrap_or_default() {
+            // ----------------------------------------------------------------------------
+            // --------------------------------- Autosync ---------------------------------
+            processing_cb(0.01);
+
+            if let serde_json::Value::Object(mut sync_options) = sync_options {
+                for (k, v) in sync_settings.as_object().unwrap() {
+                    sync_options.insert(k.clone(), v.clone());
+                }
+
+                if let Some(points) = sync_options.get("max_sync_points").and_then(|v| v.as_i64()) {
+                    let custom_timestamps: Option<Vec<f64>> = sync_options.get("custom_sync_timestamps").and_then(|v| v.as_array()).map(|v| {
+                        v.iter().filter_map(|v| v.as_f64()).filter(|v| *v <= duration_ms).map(|v| v / duration_ms).collect()
+                    });
+
+                    if let Ok(mut sync_params) = serde_json::from_value(serde_json::Value::Object(sync_options)) as serde_json::Result<synchronization::SyncParams> {
+
+                        let cancel_flag = Arc::new(AtomicBool::new(false));
+                        sync_params.initial_offset     *= 1000.0; // s to ms
+                        sync_params.time_per_syncpoint *= 1000.0; // s to ms
+                        sync_params.search_size        *= 1000.0; // s to ms
+
+                        let timestamps_fract = match custom_timestamps {
+                            Some(v) => v,
+                            None if sync_params.auto_sync_points => stab.get_optimal_sync_points(points.max(1) as usize, &sync_params),
+                            None => {
+                                let chunks = 1.0 / points as f64;
+                                let start = chunks / 2.0;
+                                (0..points).map(|i| start + (i as f64 * chunks)).collect()
+                            }
+                        };
+
+                        let every_nth_frame = sync_params.every_nth_frame.max(1);
+
+                        let size = stab.params.read().video_size;
+
+                        if let Ok(mut sync) = AutosyncProcess::from_manager(&stab, &timestamps_fract, sync_params, "synchronize".into(), cancel_flag.clone()) {
+                            let processing_cb2 = processing_cb.clone();
+                            sync.on_progress(move |percent, _ready, _total| {
+                                processing_cb2(percent);
+                            });
+                            let stab2 = stab.clone();
+                            sync.on_finished(move |arg| {
+                                if let Either::Left(offsets) = arg {
+                                    let mut gyro = stab2.gyro.write();
+                                    gyro.prevent_recompute = true;
+                                    for x in offsets {
+                                        ::log::info!("Setting offset at {:.4}: {:.4} (cost {:.4})", x.0, x.1, x.2);
+                                        let new_ts = ((x.0 - x.1) * 1000.0) as i64;
+                                        // Remove existing offsets within 100ms range
+                                        gyro.remove_offsets_near(new_ts, 100.0);
+                                        gyro.set_offset(new_ts, x.1);
+                                    }
+                                    gyro.prevent_recompute = false;
+                                    gyro.adjust_offsets();
+                                    stab2.keyframes.write().update_gyro(&gyro);
+                                }
+                            });
+
+                            if let Some(sync) = run_sync_process(path, sync, size, every_nth_frame, cancel_flag, err.clone()) {
+                                quality_report = sync.quality_report();
+                            }
+                        } else {
+                            err(("An error occured: %1".to_string(), "Invalid parameters".to_string()));
+                        }
+
+                        stab.recompute_blocking();
+                    }
+                }
+            }
+            processing_cb(1.0);
+            // --------------------------------- Autosync ---------------------------------
+            // ----------------------------------------------------------------------------
+        }
+    }
+    quality_report
+}
+
+/// Decode the sync ranges at 720p, feed the frames to the sync process and run it.
+/// Returns `None` if the video couldn't be opened
+fn run_sync_process<F2: Fn((String, String)) + Send + Sync + Clone + 'static>(path: &str, mut sync: AutosyncProcess, size: (usize, usize), every_nth_frame: usize, cancel_flag: Arc<AtomicBool>, err: F2) -> Option<std::rc::Rc<AutosyncProcess>> {
+    let (sw, sh) = ((720.0 * (size.0 as f64 / size.1 as f64)).round() as u32, 720);
+    sync.enable_feature_cache(path, sh);
+
+    let gpu_decoding = *rendering::GPU_DECODING.read();
+
+    let mut frame_no = 0;
+    let mut abs_frame_no = 0;
+    let sync = std::rc::Rc::new(sync);
+
+    let open_decoder = *OPEN_SYNC_DECODER.read();
+    match open_decoder(path, gpu_decoding) {
+        Ok(mut proc) => {
+            let err2 = err.clone();
+            let sync2 = sync.clone();
+            proc.on_frame(Box::new(move |timestamp_us: i64, input_frame: &mut frame::Video, _output_frame: Option<&mut frame::Video>, converter: &mut Converter, _rate_control: &mut RateControl| {
+                if abs_frame_no % every_nth_frame == 0 {
+                    match converter.scale(input_frame, ffmpeg_next::format::Pixel::GRAY8, sw, sh) {
+                        Ok(small_frame) => {
+                            let (width, height, stride, pixels) = (small_frame.plane_width(0), small_frame.plane_height(0), small_frame.stride(0), small_frame.data(0));
+
+                            sync2.feed_frame(timestamp_us, frame_no, width, height, stride, pixels);
+                        },
+                        Err(e) => {
+                            err2(("An error occured: %1".to_string(), e.to_string()))
+                        }
+                    }
+                    frame_no += 1;
+                }
+                abs_frame_no += 1;
+                Ok(())
+            }));
+            // All ranges can be already in the feature cache
+            let ranges = sync.get_ranges();
+            if !ranges.is_empty() {
+                if let Err(e) = proc.start_decoder_only(ranges, cancel_flag) {
+                    err(("An error occured: %1".to_string(), e.to_string()));
+                }
+            }
+            sync.finished_feeding_frames();
+            Some(sync)
+        }
+        Err(error) => {
+            err(("An error occured: %1".to_string(), error.to_string()));
+            None
+        }
+    }
+}
+
+/// Estimate the frame readout time in `ranges` evenly spaced ranges. The video has to be already synchronized
+pub fn estimate_rolling_shutter<F: Fn(f64) + Send + Sync + Clone + 'static, F2: Fn((String, String)) + Send + Sync + Clone + 'static>(path: &str, stab: Arc<StabilizationManager<stabilization::RGBA8>>, processing_cb: F, err: F2, sync_options: serde_json::Value, settings: synchronization::rolling_shutter::RollingShutterSettings, ranges: usize) -> Option<synchronization::rolling_shutter::RollingShutterEstimate> {
+    if stab.gyro.read().get_offsets().is_empty() {
+        ::log::warn!("The video is not synchronized, the rolling shutter can't be estimated");
+        return None;
+    }
+    let mut sync_params: synchronization::SyncParams = serde_json::from_value(sync_options).unwrap_or_default();
+    sync_params.time_per_syncpoint *= 1000.0; // s to ms
+    sync_params.search_size        *= 1000.0; // s to ms
+    sync_params.rolling_shutter = settings;
+    if sync_params.time_per_syncpoint <= 0.0 { sync_params.time_per_syncpoint = 1500.0; }
+    if sync_params.search_size <= 0.0 { sync_params.search_size = 100.0; }
+    let every_nth_frame = sync_params.every_nth_frame.max(1);
+
+    let ranges = ranges.max(1);
+    let (size, trim_start, trim_end, duration_ms) = {
+        let params = stab.params.read();
+        (params.video_size, params.trim_start, params.trim_end, params.duration_ms)
+    };
+    // Spread the ranges evenly in the trimmed part, and keep them inside it if it's long enough
+    let half_range = if duration_ms > 0.0 { sync_params.time_per_syncpoint / 2.0 / duration_ms } else { 0.0 };
+    let timestamps_fract: Vec<f64> = (0..ranges).map(|i| {
+        let fract = trim_start + (trim_end - trim_start) * (i as f64 + 0.5) / ranges as f64;
+        if trim_end - trim_start > half_range * 2.0 { fract.clamp(trim_start + half_range, trim_end - half_range) } else { fract }
+    }).collect();
+
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    match AutosyncProcess::from_manager(&stab, &timestamps_fract, sync_params, "estimate_rolling_shutter".into(), cancel_flag.clone()) {
+        Ok(mut sync) => {
+            sync.on_progress(move |percent, _ready, _total| {
+                processing_cb(percent);
+            });
+            let sync = run_sync_process(path, sync, size, every_nth_frame, cancel_flag, err)?;
+            sync.rolling_shutter_estimate()
+        },
+        Err(_) => {
+            err(("An error occured: %1".to_string(), "Invalid parameters".to_string()));
+            None
+        }
+    }
+}
+
+/// Render the video, retrying with other GPU decoders (and without GPU decoding) if nothing was rendered yet
+pub fn render_with_fallback<F, F2>(stab: Arc<StabilizationManager<stabilization::RGBA8>>, progress: F, input_file: &gyroflow_core::InputFile, render_options: &RenderOptions, cancel_flag: Arc<AtomicBool>, pause_flag: Arc<AtomicBool>, encoder_initialized: F2) -> Result<(), FFmpegError>
+    where F: Fn((f64, usize, usize, bool)) + Send + Sync + Clone,
+          F2: Fn(String) + Send + Sync + Clone
+{
+    let rendered_frames = Arc::new(AtomicUsize::new(0));
+    let rendered_frames2 = rendered_frames.clone();
+    let progress = move |params: (f64, usize, usize, bool)| {
+        rendered_frames2.store(params.1, SeqCst);
+        progress(params);
+    };
+
+    let mut i = 0;
+    loop {
+        let result = rendering::render(stab.clone(), progress.clone(), input_file, render_options, i, cancel_flag.clone(), pause_flag.clone(), encoder_initialized.clone());
+        if let Err(e) = result {
+            if let FFmpegError::PixelFormatNotSupported(_) = e {
+                return Err(e);
+            }
+            if rendered_frames.load(SeqCst) == 0 {
+                if (0..4).contains(&i) {
+                    // Try 4 times with different GPU decoders
+                    i += 1;
+                    continue;
+                }
+                if (0..5).contains(&i) {
+                    // Try without GPU decoder
+                    i = -1;
+                    continue;
+                }
+            }
+            return Err(e);
+        }
+        // Render ok
+        return Ok(());
+    }
+}
+
+fn apply_preset(stab: &StabilizationManager<stabilization::RGBA8>, preset: &str, render_options: &mut RenderOptions, suffix: &str, video_path: &str) -> Result<(), String> {
+    let data = if preset.starts_with('{') {
+        preset.to_string()
+    } else {
+        std::fs::read_to_string(preset).map_err(|e| format!("Unable to read preset {}: {}", preset, e))?
+    };
+    ::log::info!("Applying preset {}", preset);
+
+    let mut is_preset = false;
+    stab.import_gyroflow_data(data.as_bytes(), true, None, |_|(), Arc::new(AtomicBool::new(false)), &mut is_preset).map_err(|e| format!("Failed to apply preset: {:?}", e))?;
+
+    if let Ok(obj) = serde_json::from_str(&data) as serde_json::Result<serde_json::Value> {
+        if let Some(output) = obj.get("output") {
+            render_options.update_from_json(output);
+            render_options.output_path = get_output_path(suffix, video_path, &render_options.codec, &render_options.output_path);
+        }
+    }
+    Ok(())
+}
+
+// Exports are computed at the full video resolution
+fn prepare_for_export(stab: &StabilizationManager<stabilization::RGBA8>, render_options: &RenderOptions) {
+    let size = stab.params.read().video_size;
+    stab.set_render_params(size, (render_options.output_width, render_options.output_height));
+    stab.recompute_blocking();
+}
+
+/// Process a single video or project file: load, apply lens profile and presets, autosync and render (or export a project file).
+/// Returns the output path
+pub fn process_file<F: Fn(f64) + Send + Sync + Clone + 'static, F2: Fn((f64, usize, usize, bool)) + Send + Sync + Clone>(base: &StabilizationManager<stabilization::RGBA8>, path: &str, opts: &BatchOptions, processing_cb: F, render_progress: F2, cancel_flag: Arc<AtomicBool>) -> Result<String, String> {
+    let stab = Arc::new(new_stab_from(base, path));
+
+    let mut render_options: RenderOptions = opts.additional_data.get("output")
+        .and_then(|x| serde_json::from_value(x.clone()).ok())
+        .unwrap_or_default();
+    let mut sync_options = opts.additional_data.get("synchronization").cloned().unwrap_or_default();
+    if let (Some(quality), serde_json::Value::Object(sync_options)) = (&opts.sync_quality, &mut sync_options) {
+        sync_options.insert("quality".into(), serde_json::to_value(quality).unwrap_or_default());
+    }
+
+    if path.ends_with(".gyroflow") {
+        let (obj, _report) = stab.import_gyroflow_file(path, true, |_|(), cancel_flag.clone()).map_err(|e| format!("Error loading {}: {:?}", path, e))?;
+        let video_path = stab.input_file.read().path.clone();
+        match obj.get("output").and_then(|x| serde_json::from_value(x.clone()).ok()) {
+            Some(project_options) => { render_options = project_options; },
+            None => {
+                let size = stab.params.read().video_size;
+                render_options.output_width = size.0;
+                render_options.output_height = size.1;
+                render_options.output_path = get_output_path(&opts.default_suffix, &video_path, &render_options.codec, &render_options.output_path);
+            }
+        }
+    } else {
+        let gyro_options = core::gyro_source::FileLoadOptions { csv_mapping: opts.imu_mapping.clone(), ..Default::default() };
+        load_video_file(&stab, path, &opts.gyro_file, &gyro_options, &mut render_options, &opts.default_suffix)?;
+    }
+
+    if let Some(lens) = &opts.lens_profile {
+        ::log::info!("Loading lens profile {}", lens);
+        stab.load_lens_profile(lens).map_err(|e| format!("Failed to load lens profile {}: {}", lens, e))?;
+        stab.recompute_blocking();
+    }
+
+    let video_path = stab.input_file.read().path.clone();
+    for preset in &opts.presets {
+        apply_preset(&stab, preset, &mut render_options, &opts.default_suffix, &video_path)?;
+    }
+
+    if opts.imu_resampling.is_some() {
+        stab.set_imu_resampling(opts.imu_resampling);
+        stab.recompute_gyro();
+        stab.gyro.read().imu_quality.log();
+    }
+
+    if opts.gyro_saturation.is_some() {
+        stab.set_gyro_saturation(opts.gyro_saturation);
+        stab.recompute_gyro();
+    }
+
+    if opts.imu_notch.is_some() {
+        stab.set_imu_notch(opts.imu_notch.clone());
+        stab.recompute_gyro();
+        stab.gyro.read().notch_report.log();
+    }
+
+    if let Some(time_varying) = opts.auto_bias {
+        match stab.estimate_bias_auto(time_varying) {
+            Some(bias) => ::log::info!("Estimated gyro bias: {:?}", bias),
+            None => ::log::warn!("No static segments found, gyro bias was not estimated")
+        }
+    }
+
+    if opts.drift_model.is_some() {
+        stab.set_drift_model(opts.drift_model);
+    }
+
+    let duration_ms = stab.params.read().duration_ms;
+    let err = |(msg, arg): (String, String)| { ::log::error!("{}", msg.replace("%1", &arg)); };
+    let sync_quality = autosync(&video_path, duration_ms, stab.clone(), processing_cb.clone(), err, sync_options);
+    if let Some(report) = &sync_quality {
+        if opts.sync_report {
+            let path = std::path::Path::new(&render_options.output_path).with_extension("sync.json");
+            let json = serde_json::to_string_pretty(report).map_err(|e| e.to_string())?;
+            std::fs::write(&path, json).map_err(|e| format!("Failed to write the sync report {}: {}", path.display(), e))?;
+        }
+    }
+
+    if let Some(fit) = stab.gyro.read().get_drift_fit() {
+        fit.log();
+    }
+
+    if let Some(ranges) = opts.estimate_rolling_shutter {
+        let sync_options = opts.additional_data.get("synchronization").cloned().unwrap_or_default();
+        match estimate_rolling_shutter(&video_path, stab.clone(), processing_cb.clone(), err, sync_options, Default::default(), ranges) {
+            Some(estimate) => {
+                stab.apply_frame_readout_time(estimate.readout_ms, opts.readout_time_lens_profile.as_deref())
+                    .map_err(|e| format!("Failed to save the lens profile: {}", e))?;
+                stab.recompute_blocking();
+            },
+            None => ::log::warn!("Frame readout time was not estimated")
+        }
+    }
+
+    if let Some(saturation) = &opts.gyro_saturation {
+        use core::saturation::ReconstructionMethod;
+        if matches!(saturation.method, ReconstructionMethod::Auto | ReconstructionMethod::OpticalFlow) {
+            // Optical flow is available after the sync
+            stab.update_saturation_reference();
+            stab.recompute_gyro();
+        }
+        let gyro = stab.gyro.read();
+        gyro.saturation_report.log();
+        let frames = gyro.saturated_frames();
+        if !frames.is_empty() {
+            ::log::warn!("Frames affected by the gyro saturation: {:?}", frames);
+        }
+    }
+
+    if opts.export_project > 0 {
+        let mut additional_data = opts.additional_data.clone();
+        if let (Some(obj), Ok(output)) = (additional_data.as_object_mut(), serde_json::to_value(&render_options)) {
+            obj.insert("output".into(), output);
+        }
+        let path = std::path::Path::new(&render_options.output_path.replace(&opts.default_suffix, "")).with_extension("gyroflow");
+        let additional_data = additional_data.to_string();
+        let result = match opts.export_project {
+            1 => stab.export_gyroflow_file(&path, true, false, &additional_data),
+            2 => stab.export_gyroflow_file(&path, false, false, &additional_data),
+            3 => stab.export_gyroflow_file(&path, false, true, &additional_data),
+            _ => { Err(std::io::Error::new(std::io::ErrorKind::Other, "Unknown option")) }
+        };
+        result.map_err(|e| e.to_string())?;
+        return Ok(path.to_string_lossy().replace('\\', "/"));
+    }
+
+    if let Some(format) = opts.export_transforms {
+        prepare_for_export(&stab, &render_options);
+
+        let path = std::path::Path::new(&render_options.output_path).with_extension(format!("transforms.{}", format.extension()));
+        if !opts.overwrite && path.exists() {
+            return Err(format!("file_exists:{}", path.to_string_lossy()));
+        }
+        stab.export_frame_transforms(&path, format, false, |_| ()).map_err(|e| format!("Failed to export transforms: {}", e))?;
+        return Ok(path.to_string_lossy().replace('\\', "/"));
+    }
+
+    if opts.export_stmap {
+        prepare_for_export(&stab, &render_options);
+
+        let base_path = std::path::Path::new(&render_options.output_path).with_extension("stmap");
+        if !opts.overwrite && core::export::stmap::frame_path(&base_path, 0).exists() {
+            return Err(format!("file_exists:{}", base_path.to_string_lossy()));
+        }
+        let frames = stab.export_stmaps(&base_path, false, |_| (), cancel_flag).map_err(|e| format!("Failed to export ST-maps: {}", e))?;
+        ::log::info!("Exported {} ST-map frames", frames);
+        return Ok(base_path.to_string_lossy().replace('\\', "/"));
+    }
+
+    if let Some((format, rotation)) = opts.export_camera {
+        prepare_for_export(&stab, &render_options);
+
+        let path = std::path::Path::new(&render_options.output_path).with_extension(format!("camera.{}", format.extension()));
+        if !opts.overwrite && path.exists() {
+            return Err(format!("file_exists:{}", path.to_string_lossy()));
+        }
+        stab.export_camera_motion(&path, format, rotation, false, |_| ()).map_err(|e| format!("Failed to export camera motion: {}", e))?;
+        return Ok(path.to_string_lossy().replace('\\', "/"));
+    }
+
+    if let Some(format) = opts.export_gyro {
+        prepare_for_export(&stab, &render_options);
+
+        let path = std::path::Path::new(&render_options.output_path).with_extension(format!("gyro.{}", format.extension()));
+        if !opts.overwrite && path.exists() {
+            return Err(format!("file_exists:{}", path.to_string_lossy()));
+        }
+        stab.export_gyro_data(&path, format, |_| ()).map_err(|e| format!("Failed to export gyro data: {}", e))?;
+        return Ok(path.to_string_lossy().replace('\\', "/"));
+    }
+
+    if let Some(format) = opts.spectral_report {
+        let path = std::path::Path::new(&render_options.output_path).with_extension(format!("spectrum.{}", format.extension()));
+        if !opts.overwrite && path.exists() {
+            return Err(format!("file_exists:{}", path.to_string_lossy()));
+        }
+        let report = stab.export_spectral_report(&path, format, &opts.spectral_settings).map_err(|e| format!("Failed to export spectral report: {}", e))?;
+        report.log();
+        return Ok(path.to_string_lossy().replace('\\', "/"));
+    }
+
+    if !opts.overwrite && std::path::Path::new(&render_options.output_path).exists() {
+        return Err(format!("file_exists:{}", render_options.output_path));
+    }
+
+    let size = stab.params.read().video_size;
+    stab.set_render_params(size, (render_options.output_width, render_options.output_height));
+
+    rendering::clear_log();
+
+    let input_file = stab.input_file.read().clone();
+    render_with_fallback(stab, render_progress, &input_file, &render_options, cancel_flag, Arc::new(AtomicBool::new(false)), |encoder_name| {
+        ::log::debug!("Encoder initialized: {}", encoder_name);
+    }).map_err(|e| format!("{}\n\n{}", e, rendering::get_log()))?;
+
+    Ok(render_options.output_path)
+}
@@ -0,0 +1,575 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Command line options shared by the GUI and the headless binary, and the headless processing
+
+use argh::FromArgs;
+use gyroflow_core::*;
+use std::sync::{ Arc, atomic::AtomicBool };
+use std::time::Instant;
+use std::collections::HashMap;
+use crate::rendering;
+use crate::batch;
+use indicatif::{ ProgressBar, ProgressState, ProgressStyle };
+
+/** Gyroflow v1.3.0
+Video stabilization using gyroscope data
+*/
+#[derive(FromArgs)]
+pub struct Opts {
+    /// input files: videos, project files, lens profiles, presets
+    #[argh(positional)]
+    pub input: Vec<String>,
+
+    /// overwrite if output file exists, default: false
+    #[argh(switch, short = 'f')]
+    pub overwrite: bool,
+
+    /// number of parallel renders, default: 1
+    #[argh(option, short = 'j', default = "1")]
+    pub parallel_renders: i32,
+
+    /// when done: 1 - shut down; 2 - reboot; 3 - sleep; 4 - hibernate; 5 - logout
+    #[argh(option, short = 'd', default = "0")]
+    pub when_done: i32,
+
+    /// output parameters, eg. "{{ 'codec': 'H.265/HEVC', 'bitrate': 150, 'use_gpu': true, 'audio': true }}"
+    #[argh(option, short = 'p')]
+    pub out_params: Option<String>,
+
+    /// export project file instead of rendering: 1 - default project, 2 - with gyro data, 3 - with processed gyro data
+    #[argh(option, default = "0")]
+    pub export_project: u32,
+
+    /// preset (file or content directly), eg. "{{ 'version': 2, 'stabilization': {{ 'fov': 1.5 }} }}"
+    #[argh(option)]
+    pub preset: Option<String>,
+
+    /// open file in the GUI (video or project)
+    #[argh(option)]
+    pub open: Option<String>,
+
+    /// watch folder for automated processing
+    #[argh(option)]
+    pub watch: Option<String>,
+
+    /// gyro file path
+    #[argh(option, short = 'g')]
+    pub gyro_file: Option<String>,
+
+    /// column mapping descriptor for CSV/TSV gyro logs (JSON file)
+    #[argh(option)]
+    pub imu_mapping: Option<String>,
+
+    /// resample IMU data to a uniform rate before the integration, repairing gaps and duplicated samples: auto (median rate of the data) or rate in Hz
+    #[argh(option)]
+    pub imu_resample: Option<String>,
+
+    /// interpolation used for the IMU resampling: nearest, linear or cubic
+    #[argh(option, default = "String::from(\"linear\")")]
+    pub imu_interpolation: String,
+
+    /// static notch filters applied to all gyro axes: comma-separated frequencies in Hz, optionally with the Q factor, eg. "120:5,240"
+    #[argh(option)]
+    pub notch: Option<String>,
+
+    /// dynamic notch tracking the dominant vibration frequency of each gyro axis: auto (60-400 Hz) or frequency range in Hz, eg. "80-300"
+    #[argh(option)]
+    pub dynamic_notch: Option<String>,
+
+    /// detect gyro samples clipped at the full-scale range and reconstruct them: auto, spline, accelerometer or optical_flow
+    #[argh(option)]
+    pub gyro_saturation: Option<String>,
+
+    /// estimate gyro bias from the static parts of the log: constant or varying (interpolated between the static parts)
+    #[argh(option)]
+    pub auto_bias: Option<String>,
+
+    /// clock drift model fitted to the sync points: linear, poly:<degree> or piecewise:<segment length in ms>
+    #[argh(option)]
+    pub drift_model: Option<String>,
+
+    /// robust fitting method of the clock drift model: ransac, huber or lsq
+    #[argh(option, default = "String::from(\"ransac\")")]
+    pub drift_fit: String,
+
+    /// evaluate the quality of the autosync points: reject (drop low-confidence points and drift model outliers), report (only log) or off
+    #[argh(option, default = "String::from(\"reject\")")]
+    pub sync_quality: String,
+
+    /// write the sync quality report next to the output file (<output>.sync.json)
+    #[argh(switch)]
+    pub sync_report: bool,
+
+    /// find the rough offset by matching the video motion with the entire motion log, for logs much longer than the video
+    #[argh(switch)]
+    pub global_sync: bool,
+
+    /// use the creation time/timecode of the video and the absolute time of the motion log (blackbox RTC, epoch timestamps) as the rough offset
+    #[argh(switch)]
+    pub timestamp_sync: bool,
+
+    /// estimate the frame readout time in this many ranges of the already synchronized video. Implies --headless
+    #[argh(option)]
+    pub estimate_rolling_shutter: Option<usize>,
+
+    /// save the lens profile with the estimated frame readout time to this path
+    #[argh(option)]
+    pub readout_lens_profile: Option<String>,
+
+    /// process the files sequentially without the Qt event loop and without reading the GUI settings
+    #[argh(switch)]
+    pub headless: bool,
+
+    /// export per-frame transformation data instead of rendering: json, csv or bin. Implies --headless
+    #[argh(option)]
+    pub export_transforms: Option<String>,
+
+    /// export ST-map EXR sequence (<output>.stmap.000000.exr) instead of rendering. Implies --headless
+    #[argh(switch)]
+    pub export_stmap: bool,
+
+    /// export camera motion instead of rendering: chan (Nuke), ae (After Effects), blender (Python script), json or csv. Implies --headless
+    #[argh(option)]
+    pub export_camera: Option<String>,
+
+    /// export processed gyro data instead of rendering: gcsv, csv or bin. Implies --headless
+    #[argh(option)]
+    pub export_gyro: Option<String>,
+
+    /// write the vibration analysis of the IMU data (per-axis peaks and noise floor over time) instead of rendering: json or csv. Implies --headless
+    #[argh(option)]
+    pub spectral_report: Option<String>,
+
+    /// settings of the spectral report, JSON file path or content, eg. {"fft_size":1024,"processed":true}
+    #[argh(option)]
+    pub spectral_settings: Option<String>,
+
+    /// rotation used for the camera motion export: original, smoothed or correction
+    #[argh(option, default = "String::from(\"original\")")]
+    pub camera_rotation: String,
+
+    /// validate project file and print the list of invalid and ignored fields. Exits with code 1 if the file is invalid
+    #[argh(option)]
+    pub validate_project: Option<String>,
+
+    /// print the JSON Schema of the project file
+    #[argh(switch)]
+    pub project_schema: bool,
+}
+
+/// Handle the options which don't need the render queue: project schema, project validation and the headless processing.
+/// Returns `None` if everything was done (or failed), otherwise the files for the render queue
+pub fn process(opts: &Opts, force_headless: bool) -> Option<(Vec<String>, Vec<String>, Vec<String>)> { // -> Videos/projects, lens profiles, presets
+    if opts.project_schema {
+        println!("{}", serde_json::to_string_pretty(&gyroflow_core::project::json_schema()).unwrap_or_default());
+        return None;
+    }
+    if let Some(path) = &opts.validate_project {
+        validate_project(path);
+        return None;
+    }
+
+    let (videos, lens_profiles, mut presets) = detect_types(&opts.input);
+    if let Some(mut preset) = opts.preset.clone() {
+        if !preset.is_empty() {
+            if preset.starts_with('{') { preset = preset.replace('\'', "\""); }
+            presets.push(preset);
+        }
+    }
+
+    for file in videos.iter().chain(lens_profiles.iter()) {
+        if !std::path::Path::new(&file).exists() {
+            log::error!("File {} doesn't exist.", file);
+            return None;
+        }
+    }
+    let watching = opts.watch.as_ref().map(|x| !x.is_empty()).unwrap_or_default();
+
+    let batch_opts = match batch_options(opts, &lens_profiles, &presets) {
+        Ok(batch_opts) => batch_opts,
+        Err(e) => {
+            log::error!("{}", e);
+            return None;
+        }
+    };
+
+    if force_headless || opts.headless || batch_opts.export_transforms.is_some() || batch_opts.export_stmap || batch_opts.export_camera.is_some() || batch_opts.export_gyro.is_some() || batch_opts.spectral_report.is_some() || batch_opts.estimate_rolling_shutter.is_some() {
+        if watching {
+            log::error!("Watching a folder is not supported in the headless mode!");
+            return None;
+        }
+        if lens_profiles.len() > 1 {
+            log::error!("More than one lens profile!");
+            return None;
+        }
+        if videos.is_empty() {
+            log::error!("No videos provided!");
+            return None;
+        }
+        run_headless(&videos, batch_opts, opts.out_params.clone(), opts.global_sync, opts.timestamp_sync);
+        return None;
+    }
+
+    Some((videos, lens_profiles, presets))
+}
+
+/// Parse the processing and export options
+pub fn batch_options(opts: &Opts, lens_profiles: &[String], presets: &[String]) -> Result<batch::BatchOptions, String> {
+    let export_transforms = match opts.export_transforms.as_deref().filter(|x| !x.is_empty()) {
+        Some(name) => match gyroflow_core::export::ExportFormat::from_name(name) {
+            Some(format) => Some(format),
+            None => {
+                return Err(format!("Unknown transform export format: {}", name));
+            }
+        },
+        None => None
+    };
+
+    let export_camera = match opts.export_camera.as_deref().filter(|x| !x.is_empty()) {
+        Some(name) => {
+            use gyroflow_core::export::camera_motion::{ CameraMotionFormat, CameraRotation };
+            match (CameraMotionFormat::from_name(name), CameraRotation::from_name(&opts.camera_rotation)) {
+                (Some(format), Some(rotation)) => Some((format, rotation)),
+                (None, _) => { return Err(format!("Unknown camera motion format: {}", name)); },
+                (_, None) => { return Err(format!("Unknown camera rotation: {}", opts.camera_rotation)); }
+            }
+        },
+        None => None
+    };
+
+    let export_gyro = match opts.export_gyro.as_deref().filter(|x| !x.is_empty()) {
+        Some(name) => match gyroflow_core::export::gyro_data::GyroDataFormat::from_name(name) {
+            Some(format) => Some(format),
+            None => {
+                return Err(format!("Unknown gyro data export format: {}", name));
+            }
+        },
+        None => None
+    };
+
+    let spectral_report = match opts.spectral_report.as_deref().filter(|x| !x.is_empty()) {
+        Some(name) => match gyroflow_core::spectral_analysis::SpectralReportFormat::from_name(name) {
+            Some(format) => Some(format),
+            None => {
+                return Err(format!("Unknown spectral report format: {}", name));
+            }
+        },
+        None => None
+    };
+
+    let spectral_settings = match opts.spectral_settings.as_deref().filter(|x| !x.is_empty()) {
+        Some(settings) => {
+            let data = if settings.starts_with('{') {
+                settings.to_string()
+            } else {
+                std::fs::read_to_string(settings).map_err(|e| format!("Unable to read spectral settings {}: {}", settings, e))?
+            };
+            serde_json::from_str(&data).map_err(|e| format!("Invalid spectral settings: {}", e))?
+        },
+        None => Default::default()
+    };
+
+    let imu_resampling = match opts.imu_resample.as_deref().filter(|x| !x.is_empty()) {
+        Some(rate) => {
+            use gyroflow_core::imu_resampling::{ Interpolation, ResampleSettings };
+            let sample_rate = if rate == "auto" { Some(None) } else { rate.parse::<f64>().ok().filter(|x| *x > 0.0).map(Some) };
+            match (sample_rate, Interpolation::from_name(&opts.imu_interpolation)) {
+                (Some(sample_rate), Some(interpolation)) => Some(ResampleSettings { sample_rate, interpolation }),
+                (None, _) => { return Err(format!("Invalid IMU sample rate: {}", rate)); },
+                (_, None) => { return Err(format!("Unknown interpolation: {}", opts.imu_interpolation)); }
+            }
+        },
+        None => None
+    };
+
+    let gyro_saturation = match opts.gyro_saturation.as_deref().filter(|x| !x.is_empty()) {
+        Some(name) => match gyroflow_core::saturation::ReconstructionMethod::from_name(name) {
+            Some(method) => Some(gyroflow_core::saturation::SaturationSettings { method, ..Default::default() }),
+            None => {
+                return Err(format!("Unknown saturation reconstruction method: {}", name));
+            }
+        },
+        None => None
+    };
+
+    let imu_notch = if opts.notch.as_deref().unwrap_or_default().is_empty() && opts.dynamic_notch.as_deref().unwrap_or_default().is_empty() {
+        None
+    } else {
+        use gyroflow_core::filtering::{ AxisNotchSettings, DynamicNotchSettings, NotchFilter, NotchSettings };
+        let mut axis = AxisNotchSettings::default();
+        for x in opts.notch.as_deref().unwrap_or_default().split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let (freq, q) = x.split_once(':').unwrap_or((x, "5"));
+            match (freq.parse::<f64>(), q.parse::<f64>()) {
+                (Ok(frequency), Ok(q)) if frequency > 0.0 && q > 0.0 => axis.notches.push(NotchFilter { frequency, q }),
+                _ => { return Err(format!("Invalid notch filter: {}", x)); }
+            }
+        }
+        match opts.dynamic_notch.as_deref().filter(|x| !x.is_empty()) {
+            Some("auto") => axis.dynamic = Some(DynamicNotchSettings::default()),
+            Some(range) => {
+                match range.split_once('-').map(|(a, b)| (a.trim().parse::<f64>(), b.trim().parse::<f64>())) {
+                    Some((Ok(min_frequency), Ok(max_frequency))) if min_frequency > 0.0 && max_frequency > min_frequency => {
+                        axis.dynamic = Some(DynamicNotchSettings { min_frequency, max_frequency, ..Default::default() });
+                    },
+                    _ => { return Err(format!("Invalid dynamic notch frequency range: {}", range)); }
+                }
+            },
+            None => { }
+        }
+        Some(NotchSettings::uniform(axis, false))
+    };
+
+    let auto_bias = match opts.auto_bias.as_deref().filter(|x| !x.is_empty()) {
+        Some("constant") => Some(false),
+        Some("varying")  => Some(true),
+        Some(name) => {
+            return Err(format!("Unknown bias estimation mode: {}", name));
+        },
+        None => None
+    };
+
+    let drift_model = match opts.drift_model.as_deref().filter(|x| !x.is_empty()) {
+        Some(name) => match gyroflow_core::synchronization::drift::DriftModelSettings::from_names(name, &opts.drift_fit) {
+            Some(model) => Some(model),
+            None => {
+                return Err(format!("Unknown clock drift model: {} ({})", name, opts.drift_fit));
+            }
+        },
+        None => None
+    };
+
+    let sync_quality = {
+        use gyroflow_core::synchronization::quality::SyncQualitySettings;
+        let settings = match opts.sync_quality.as_str() {
+            "reject" => Some(SyncQualitySettings::default()),
+            "report" => Some(SyncQualitySettings::report_only()),
+            "off" => None,
+            name => {
+                return Err(format!("Unknown sync quality mode: {}", name));
+            }
+        };
+        settings.map(|x| SyncQualitySettings { drift: drift_model.unwrap_or(x.drift), ..x })
+    };
+
+    if opts.imu_mapping.as_deref().map_or(false, |x| !x.is_empty()) && opts.gyro_file.as_deref().map_or(true, |x| x.is_empty()) {
+        return Err("--imu-mapping requires a separate gyro file (-g)".into());
+    }
+
+    if opts.readout_lens_profile.is_some() && opts.estimate_rolling_shutter.is_none() {
+        return Err("--readout-lens-profile requires --estimate-rolling-shutter".into());
+    }
+
+    Ok(batch::BatchOptions {
+        gyro_file: opts.gyro_file.clone().unwrap_or_default(),
+        imu_mapping: opts.imu_mapping.clone().filter(|x| !x.is_empty()),
+        imu_resampling,
+        imu_notch,
+        gyro_saturation,
+        auto_bias,
+        drift_model,
+        sync_quality,
+        sync_report: opts.sync_report,
+        estimate_rolling_shutter: opts.estimate_rolling_shutter,
+        readout_time_lens_profile: opts.readout_lens_profile.clone(),
+        lens_profile: lens_profiles.first().cloned(),
+        presets: presets.to_vec(),
+        overwrite: opts.overwrite,
+        export_project: opts.export_project,
+        export_transforms,
+        export_stmap: opts.export_stmap,
+        export_camera,
+        export_gyro,
+        spectral_report,
+        spectral_settings,
+        ..Default::default()
+    })
+}
+
+pub fn run_headless(videos: &[String], mut opts: batch::BatchOptions, out_params: Option<String>, global_sync: bool, timestamp_sync: bool) {
+    log::set_max_level(log::LevelFilter::Info);
+
+    let time = Instant::now();
+
+    let stab = StabilizationManager::<stabilization::RGBA8>::default();
+    stab.lens_profile_db.write().load_all();
+
+    rendering::init().unwrap();
+    if let Some((name, _list_name)) = gyroflow_core::gpu::initialize_contexts() {
+        rendering::set_gpu_type_from_name(&name);
+    }
+
+    // Saved GUI settings are not used in the headless mode, everything is controlled by the arguments
+    opts.additional_data = setup_defaults(&stab, &HashMap::new(), global_sync, timestamp_sync);
+    opts.default_suffix = "_stabilized".into();
+
+    if let Some(mut outp) = out_params {
+        outp = outp.replace('\'', "\"");
+        gyroflow_core::util::merge_json(opts.additional_data.get_mut("output").unwrap(), &serde_json::from_str(&outp).expect("Invalid json"));
+    }
+
+    let sty = ProgressStyle::with_template("[{bar:50.cyan/blue}] {pos:>5}/{len:5} {eta:11} {prefix:.magenta}\x1B[37;1m{msg}\x1B[0m")
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "ETA {:.1}s", state.eta().as_secs_f64()).unwrap())
+        .progress_chars("#>-");
+
+    for file in videos {
+        let fname = std::path::Path::new(file).file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+        let pb = ProgressBar::new(1);
+        pb.set_style(sty.clone());
+        pb.set_message(fname.clone());
+
+        let pb_sync = pb.clone();
+        let pb_render = pb.clone();
+        let result = batch::process_file(&stab, file, &opts, move |progress| {
+            if progress < 0.999 {
+                pb_sync.set_prefix("Synchronizing ");
+                pb_sync.set_length(100);
+                pb_sync.set_position((progress * 100.0).round() as u64);
+            }
+        }, move |(_progress, current_frame, total_frames, _finished)| {
+            pb_render.set_prefix("");
+            pb_render.set_length(total_frames as u64);
+            pb_render.set_position(current_frame as u64);
+        }, Arc::new(AtomicBool::new(false)));
+
+        match result {
+            Ok(output_path) => {
+                pb.finish_with_message(format!("\x1B[1;32m{}\x1B[0m", fname)); // Green
+                log::info!("{} -> {}", file, output_path);
+            }
+            Err(e) => {
+                pb.abandon_with_message(format!("\x1B[1;31m{}\x1B[0m", fname)); // Red
+                if let Some(path) = e.strip_prefix("file_exists:") {
+                    log::error!("File exists, use -f to overwrite: {}", path);
+                } else {
+                    log::error!("Error processing {}: {}", file, e);
+                }
+            }
+        }
+    }
+
+    log::info!("Done in {:.3}s", time.elapsed().as_millis() as f64 / 1000.0);
+}
+
+pub fn validate_project(path: &str) {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) => { log::error!("Failed to read {}: {}", path, e); std::process::exit(1); }
+    };
+    match gyroflow_core::project::parse(&data) {
+        Ok((_, _, report)) => {
+            for x in &report.migrated { println!("migrated: {}", x); }
+            for x in &report.ignored  { println!("ignored: {}", x); }
+            for x in &report.invalid  { println!("invalid: {}", x); }
+            if !report.is_valid() {
+                std::process::exit(1);
+            }
+            println!("{} is valid", path);
+        },
+        Err(e) => {
+            println!("invalid: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn detect_types(all_files: &[String]) -> (Vec<String>, Vec<String>, Vec<String>) { // -> Videos/projects, lens profiles, presets
+    let mut videos = Vec::new();
+    let mut lens_profiles = Vec::new();
+    let mut presets = Vec::new();
+    for file in all_files {
+        if file.ends_with(".json") { // Lens profile
+            lens_profiles.push(file.clone());
+        } else if file.ends_with(".gyroflow") {
+            let video_path = || -> Option<String> {
+                let data = std::fs::read(&file).ok()?;
+                let obj: serde_json::Value = serde_json::from_slice(&data).ok()?;
+                Some(obj.get("videofile")?.as_str()?.to_string())
+            }().unwrap_or_default();
+
+            if video_path.is_empty() { // It's a preset
+                presets.push(file.clone());
+            } else {
+                videos.push(file.clone());
+            }
+        } else {
+            videos.push(file.clone());
+        }
+    }
+    (videos, lens_profiles, presets)
+}
+
+pub fn setup_defaults(stab: &StabilizationManager<stabilization::RGBA8>, settings: &HashMap<String, String>, global_sync: bool, timestamp_sync: bool) -> serde_json::Value {
+    ::log::debug!("Settings: {:?}", settings);
+
+    let codecs = [
+        "H.264/AVC",
+        "H.265/HEVC",
+        "ProRes",
+        "DNxHD",
+        "EXR Sequence",
+        "PNG Sequence",
+    ];
+
+    // Default settings - project file will override this
+
+    match settings.get("croppingMode").unwrap_or(&"1".into()).parse::<u32>() {
+        Ok(0) => stab.set_adaptive_zoom(0.0), // No zooming
+        Ok(1) => stab.set_adaptive_zoom(settings.get("adaptiveZoom").unwrap_or(&"4".into()).parse::<f64>().unwrap()),
+        Ok(2) => stab.set_adaptive_zoom(-1.0), // Static zoom
+        _ => { }
+    }
+    stab.set_lens_correction_amount(settings.get("correctionAmount").unwrap_or(&"1".into()).parse::<f64>().unwrap());
+    let smoothing_method = settings.get("smoothingMethod").unwrap_or(&"1".into()).parse::<usize>().unwrap();
+    let smoothing_method_prefix = format!("smoothing-{}-", smoothing_method);
+    stab.set_smoothing_method(smoothing_method);
+    for (k, v) in settings {
+        if k.starts_with(&smoothing_method_prefix) {
+            stab.set_smoothing_param(k.strip_prefix(&smoothing_method_prefix).unwrap(), v.parse::<f64>().unwrap());
+        }
+    }
+
+    // TODO: set more params from `settings`
+
+    if let Some(gdec) = settings.get("gpudecode").and_then(|x| x.parse::<bool>().ok()) {
+        *rendering::GPU_DECODING.write() = gdec;
+    }
+    let codec = settings.get("defaultCodec").unwrap_or(&"0".into()).parse::<usize>().unwrap().min(codecs.len() - 1);
+    let codec_name = codecs[codec];
+
+    // Sync and export settings
+    serde_json::json!({
+        "output": {
+            "codec":          codec_name,
+            "codec_options":  "",
+            // "output_path":    "C:/test.mp4",
+            // "output_width":   3840,
+            // "output_height":  2160,
+            // "bitrate":        150,
+            "use_gpu":        settings.get(&format!("exportGpu-{}", codec)).unwrap_or(&"1".into()).parse::<u32>().unwrap() > 0,
+            "audio":          settings.get("exportAudio").unwrap_or(&"true".into()).parse::<bool>().unwrap(),
+            "pixel_format":   "",
+
+            // Advanced
+            "encoder_options":       settings.get(&format!("encoderOptions-{}", codec)).unwrap_or(&"".into()),
+            "keyframe_distance":     settings.get("keyframeDistance").unwrap_or(&"1".into()).parse::<u32>().unwrap(),
+            "preserve_other_tracks": settings.get("preserveOtherTracks").unwrap_or(&"false".into()).parse::<bool>().unwrap(),
+            "pad_with_black":        settings.get("padWithBlack").unwrap_or(&"false".into()).parse::<bool>().unwrap(),
+        },
+        "synchronization": {
+            "initial_offset":     0,
+            "initial_offset_inv": false,
+            "global_search":      global_sync,
+            "timestamp_sync":     if timestamp_sync { serde_json::json!({ }) } else { serde_json::Value::Null },
+            "search_size":        5,
+            "calc_initial_fast":  false,
+            "max_sync_points":    5,
+            "every_nth_frame":    1,
+            "time_per_syncpoint": 1,
+            "of_method":          2,
+            "offset_method":      2,
+            "auto_sync_points":   true,
+        }
+    })
+}
@@ -0,0 +1,10 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// FFmpeg rendering, batch processing and the command line options, without the Qt dependency.
+// Used by the GUI (render queue, CLI) and by the `gyroflow-headless` binary.
+
+pub use gyroflow_core as core;
+pub mod rendering;
+pub mod batch;
+pub mod cli;
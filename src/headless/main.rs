@@ -0,0 +1,24 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Command line batch processing which doesn't link Qt. Accepts the same options as `gyroflow`, but always runs headless
+
+use gyroflow_headless::cli::{ self, Opts };
+
+fn main() {
+    use simplelog::*;
+    let log_config = ConfigBuilder::new()
+        .add_filter_ignore_str("mp4parse")
+        .add_filter_ignore_str("wgpu")
+        .add_filter_ignore_str("naga")
+        .add_filter_ignore_str("akaze")
+        .build();
+    let _ = TermLogger::init(LevelFilter::Debug, log_config, TerminalMode::Mixed, ColorChoice::Auto);
+
+    let opts: Opts = argh::from_env();
+    if opts.open.is_some() || opts.watch.is_some() {
+        log::error!("--open and --watch require the GUI, use `gyroflow` instead");
+        std::process::exit(1);
+    }
+    cli::process(&opts, true);
+}
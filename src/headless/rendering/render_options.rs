@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use regex::Regex;
+
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RenderOptions {
+    pub codec: String,
+    pub codec_options: String,
+    pub output_path: String,
+    pub output_width: usize,
+    pub output_height: usize,
+    pub bitrate: f64,
+    pub use_gpu: bool,
+    pub audio: bool,
+    pub pixel_format: String,
+
+    // Advanced
+    pub encoder_options: String,
+    pub keyframe_distance: f64,
+    pub preserve_other_tracks: bool,
+    pub pad_with_black: bool,
+}
+impl RenderOptions {
+    pub fn settings_string(&self, fps: f64) -> String {
+        let codec_info = match self.codec.as_ref() {
+            "H.264/AVC" | "H.265/HEVC" => format!("{} {:.0} Mbps", self.codec, self.bitrate),
+            "DNxHD" => self.codec_options.clone(),
+            "ProRes" => format!("{} {}", self.codec, self.codec_options),
+            _ => self.codec.clone()
+        };
+
+        format!("{}x{} {:.3}fps | {}", self.output_width, self.output_height, fps, codec_info)
+    }
+
+    pub fn get_encoder_options_dict(&self) -> ffmpeg_next::Dictionary {
+        let re = Regex::new(r#"-([^\s"]+)\s+("[^"]+"|[^\s"]+)"#).unwrap();
+
+        let mut options = ffmpeg_next::Dictionary::new();
+        for x in re.captures_iter(&self.encoder_options) {
+            if let Some(k) = x.get(1) {
+                if let Some(v) = x.get(2) {
+                    let k = k.as_str();
+                    let v = v.as_str().trim_matches('"');
+                    options.set(k, v);
+                }
+            }
+        }
+        options
+    }
+    pub fn update_from_json(&mut self, obj: &serde_json::Value) {
+        if let serde_json::Value::Object(obj) = obj {
+            if let Some(v) = obj.get("codec")          .and_then(|x| x.as_str())  { self.codec = v.to_string(); }
+            if let Some(v) = obj.get("codec_options")  .and_then(|x| x.as_str())  { self.codec_options = v.to_string(); }
+            if let Some(v)  = obj.get("output_width")   .and_then(|x| x.as_u64())  { self.output_width = v as usize; }
+            if let Some(v)  = obj.get("output_height")  .and_then(|x| x.as_u64())  { self.output_height = v as usize; }
+            if let Some(v)  = obj.get("bitrate")        .and_then(|x| x.as_f64())  { self.bitrate = v; }
+            if let Some(v) = obj.get("use_gpu")        .and_then(|x| x.as_bool()) { self.use_gpu = v; }
+            if let Some(v) = obj.get("audio")          .and_then(|x| x.as_bool()) { self.audio = v; }
+            if let Some(v) = obj.get("pixel_format")   .and_then(|x| x.as_str())  { self.pixel_format = v.to_string(); }
+
+            // Advanced
+            if let Some(v) = obj.get("encoder_options")      .and_then(|x| x.as_str())  { self.encoder_options = v.to_string(); }
+            if let Some(v)  = obj.get("keyframe_distance")    .and_then(|x| x.as_f64())  { self.keyframe_distance = v; }
+            if let Some(v) = obj.get("preserve_other_tracks").and_then(|x| x.as_bool()) { self.preserve_other_tracks = v; }
+            if let Some(v) = obj.get("pad_with_black")       .and_then(|x| x.as_bool()) { self.pad_with_black = v; }
+
+            if let Some(v) = obj.get("output_path").and_then(|x| x.as_str()) {
+                let cur_path = std::path::Path::new(&self.output_path);
+                let mut new_path = std::path::Path::new(v).to_path_buf();
+                if let Some(fname) = cur_path.file_name() {
+                    new_path.push(fname.to_string_lossy().to_string());
+                    self.output_path = new_path.to_string_lossy().replace('\\', "/");
+                }
+            }
+        }
+    }
+}
+
+pub fn get_output_path(suffix: &str, path: &str, codec: &str, ui_output_path: &str) -> String {
+    use std::path::Path;
+
+    let mut path = Path::new(path).with_extension("");
+
+    if !ui_output_path.is_empty() {
+        // Prefer output path of the currently opened file
+        let org_filename = path.file_name().map(|x| x.to_owned()).unwrap_or_default();
+        path = Path::new(ui_output_path).to_path_buf();
+        if path.is_dir() || ui_output_path.ends_with('/') || ui_output_path.ends_with('\\') {
+            path.push(&org_filename);
+        } else {
+            path = path.with_file_name(&org_filename);
+        }
+    }
+
+    let ext = match codec {
+        "ProRes"        => ".mov",
+        "DNxHD"         => ".mov",
+        "EXR Sequence"  => "_%05d.exr",
+        "PNG Sequence"  => "_%05d.png",
+        _ => ".mp4"
+    };
+
+    path.set_file_name(format!("{}{}{}", path.file_name().map(|v| v.to_string_lossy()).unwrap_or_default(), suffix, ext));
+
+    path.to_string_lossy().replace('\\', "/")
+}
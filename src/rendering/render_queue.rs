@@ -5,11 +5,11 @@ use qmetaobject::*;
 
 use crate::{ core, rendering, util };
 use crate::core::{ stabilization, StabilizationManager };
-use std::sync::{ Arc, atomic::{ AtomicBool, AtomicUsize, Ordering::SeqCst } };
+use std::sync::{ Arc, atomic::{ AtomicBool, Ordering::SeqCst } };
 use std::cell::RefCell;
 use std::collections::{ HashMap, HashSet };
-use parking_lot::RwLock;
-use regex::Regex;
+pub use super::render_options::{ RenderOptions, get_output_path };
+use super::batch;
 
 #[derive(Default, Clone, SimpleListItem, Debug)]
 pub struct RenderQueueItem {
@@ -51,81 +51,6 @@ struct Job {
     stab: Arc<StabilizationManager<stabilization::RGBA8>>
 }
 
-#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
-#[serde(default)]
-pub struct RenderOptions {
-    pub codec: String,
-    pub codec_options: String,
-    pub output_path: String,
-    pub output_width: usize,
-    pub output_height: usize,
-    pub bitrate: f64,
-    pub use_gpu: bool,
-    pub audio: bool,
-    pub pixel_format: String,
-
-    // Advanced
-    pub encoder_options: String,
-    pub keyframe_distance: f64,
-    pub preserve_other_tracks: bool,
-    pub pad_with_black: bool,
-}
-impl RenderOptions {
-    pub fn settings_string(&self, fps: f64) -> String {
-        let codec_info = match self.codec.as_ref() {
-            "H.264/AVC" | "H.265/HEVC" => format!("{} {:.0} Mbps", self.codec, self.bitrate),
-            "DNxHD" => self.codec_options.clone(),
-            "ProRes" => format!("{} {}", self.codec, self.codec_options),
-            _ => self.codec.clone()
-        };
-
-        format!("{}x{} {:.3}fps | {}", self.output_width, self.output_height, fps, codec_info)
-    }
-
-    pub fn get_encoder_options_dict(&self) -> ffmpeg_next::Dictionary {
-        let re = Regex::new(r#"-([^\s"]+)\s+("[^"]+"|[^\s"]+)"#).unwrap();
-
-        let mut options = ffmpeg_next::Dictionary::new();
-        for x in re.captures_iter(&self.encoder_options) {
-            if let Some(k) = x.get(1) {
-                if let Some(v) = x.get(2) {
-                    let k = k.as_str();
-                    let v = v.as_str().trim_matches('"');
-                    options.set(k, v);
-                }
-            }
-        }
-        options
-    }
-    pub fn update_from_json(&mut self, obj: &serde_json::Value) {
-        if let serde_json::Value::Object(obj) = obj {
-            if let Some(v) = obj.get("codec")          .and_then(|x| x.as_str())  { self.codec = v.to_string(); }
-            if let Some(v) = obj.get("codec_options")  .and_then(|x| x.as_str())  { self.codec_options = v.to_string(); }
-            if let Some(v)  = obj.get("output_width")   .and_then(|x| x.as_u64())  { self.output_width = v as usize; }
-            if let Some(v)  = obj.get("output_height")  .and_then(|x| x.as_u64())  { self.output_height = v as usize; }
-            if let Some(v)  = obj.get("bitrate")        .and_then(|x| x.as_f64())  { self.bitrate = v; }
-            if let Some(v) = obj.get("use_gpu")        .and_then(|x| x.as_bool()) { self.use_gpu = v; }
-            if let Some(v) = obj.get("audio")          .and_then(|x| x.as_bool()) { self.audio = v; }
-            if let Some(v) = obj.get("pixel_format")   .and_then(|x| x.as_str())  { self.pixel_format = v.to_string(); }
-
-            // Advanced
-            if let Some(v) = obj.get("encoder_options")      .and_then(|x| x.as_str())  { self.encoder_options = v.to_string(); }
-            if let Some(v)  = obj.get("keyframe_distance")    .and_then(|x| x.as_f64())  { self.keyframe_distance = v; }
-            if let Some(v) = obj.get("preserve_other_tracks").and_then(|x| x.as_bool()) { self.preserve_other_tracks = v; }
-            if let Some(v) = obj.get("pad_with_black")       .and_then(|x| x.as_bool()) { self.pad_with_black = v; }
-
-            if let Some(v) = obj.get("output_path").and_then(|x| x.as_str()) {
-                let cur_path = std::path::Path::new(&self.output_path);
-                let mut new_path = std::path::Path::new(v).to_path_buf();
-                if let Some(fname) = cur_path.file_name() {
-                    new_path.push(fname.to_string_lossy().to_string());
-                    self.output_path = new_path.to_string_lossy().replace('\\', "/");
-                }
-            }
-        }
-    }
-}
-
 #[derive(Default, QObject)]
 pub struct RenderQueue {
     base: qt_base_class!(trait QObject),
@@ -650,11 +575,7 @@ impl RenderQueue {
 
             rendering::clear_log();
 
-            let rendered_frames = Arc::new(AtomicUsize::new(0));
-            let rendered_frames2 = rendered_frames.clone();
             let progress = util::qt_queued_callback_mut(self, move |this, (progress, current_frame, total_frames, finished): (f64, usize, usize, bool)| {
-                rendered_frames2.store(current_frame, SeqCst);
-
                 let mut start_time = 0;
 
                 update_model!(this, job_id, itm {
@@ -779,66 +700,17 @@ impl RenderQueue {
             }
 
             core::run_threaded(move || {
-                let mut i = 0;
-                loop {
-                    let result = rendering::render(stab.clone(), progress.clone(), &input_file, &render_options, i, cancel_flag.clone(), pause_flag.clone(), encoder_initialized.clone());
-                    if let Err(e) = result {
-                        if let rendering::FFmpegError::PixelFormatNotSupported((fmt, supported)) = e {
-                            convert_format((format!("{:?}", fmt), supported.into_iter().map(|v| format!("{:?}", v)).collect::<Vec<String>>().join(",")));
-                            break;
-                        }
-                        if rendered_frames.load(SeqCst) == 0 {
-                            if (0..4).contains(&i) {
-                                // Try 4 times with different GPU decoders
-                                i += 1;
-                                continue;
-                            }
-                            if (0..5).contains(&i) {
-                                // Try without GPU decoder
-                                i = -1;
-                                continue;
-                            }
-                        }
-                        err(("An error occured: %1".to_string(), e.to_string()));
-                        break;
+                if let Err(e) = batch::render_with_fallback(stab, progress, &input_file, &render_options, cancel_flag, pause_flag, encoder_initialized) {
+                    if let rendering::FFmpegError::PixelFormatNotSupported((fmt, supported)) = e {
+                        convert_format((format!("{:?}", fmt), supported.into_iter().map(|v| format!("{:?}", v)).collect::<Vec<String>>().join(",")));
                     } else {
-                        // Render ok
-                        break;
+                        err(("An error occured: %1".to_string(), e.to_string()));
                     }
                 }
             });
         }
     }
 
-    fn get_output_path(suffix: &str, path: &str, codec: &str, ui_output_path: &str) -> String {
-        use std::path::Path;
-
-        let mut path = Path::new(path).with_extension("");
-
-        if !ui_output_path.is_empty() {
-            // Prefer output path of the currently opened file
-            let org_filename = path.file_name().map(|x| x.to_owned()).unwrap_or_default();
-            path = Path::new(ui_output_path).to_path_buf();
-            if path.is_dir() || ui_output_path.ends_with('/') || ui_output_path.ends_with('\\') {
-                path.push(&org_filename);
-            } else {
-                path = path.with_file_name(&org_filename);
-            }
-        }
-
-        let ext = match codec {
-            "ProRes"        => ".mov",
-            "DNxHD"         => ".mov",
-            "EXR Sequence"  => "_%05d.exr",
-            "PNG Sequence"  => "_%05d.png",
-            _ => ".mp4"
-        };
-
-        path.set_file_name(format!("{}{}{}", path.file_name().map(|v| v.to_string_lossy()).unwrap_or_default(), suffix, ext));
-
-        path.to_string_lossy().replace('\\', "/")
-    }
-
     pub fn add_file(&mut self, path: String, gyro_path: String, additional_data: String) -> u32 {
         let job_id = fastrand::u32(1..);
 
@@ -875,50 +747,7 @@ impl RenderQueue {
             }
             if let Some(out) = additional_data.get("output") {
                 if let Ok(mut render_options) = serde_json::from_value(out.clone()) as serde_json::Result<RenderOptions> {
-                    let (smoothing_name, smoothing_params) = {
-                        let smoothing_lock = stabilizer.smoothing.read();
-                        let smoothing = smoothing_lock.current();
-                        (smoothing.get_name(), smoothing.get_parameters_json())
-                    };
-                    let params = stabilizer.params.read();
-
-                    let stab = StabilizationManager {
-                        params: Arc::new(RwLock::new(core::stabilization_params::StabilizationParams {
-                            fov:                    params.fov,
-                            background:             params.background,
-                            adaptive_zoom_window:   params.adaptive_zoom_window,
-                            lens_correction_amount: params.lens_correction_amount,
-                            background_mode:           params.background_mode,
-                            background_margin:         params.background_margin,
-                            background_margin_feather: params.background_margin_feather,
-                            ..Default::default()
-                        })),
-                        input_file: Arc::new(RwLock::new(gyroflow_core::InputFile { path: path.clone(), project_file_path: None, image_sequence_start: 0, image_sequence_fps: 0.0 })),
-                        lens_profile_db: stabilizer.lens_profile_db.clone(),
-                        ..Default::default()
-                    };
-
-                    {
-                        let method_idx = stab.get_smoothing_algs()
-                            .iter().enumerate()
-                            .find(|(_, m)| smoothing_name == m.as_str())
-                            .map(|(idx, _)| idx)
-                            .unwrap_or_default();
-
-                        let mut smoothing = stab.smoothing.write();
-                        smoothing.set_current(method_idx);
-
-                        for param in smoothing_params.as_array().unwrap() {
-                            (|| -> Option<()> {
-                                let name = param.get("name").and_then(|x| x.as_str())?;
-                                let value = param.get("value").and_then(|x| x.as_f64())?;
-                                smoothing.current_mut().set_parameter(name, value);
-                                Some(())
-                            })();
-                        }
-                    }
-
-                    let stab = Arc::new(stab);
+                    let stab = Arc::new(batch::new_stab_from(&stabilizer, &path));
 
                     let stab2 = stab.clone();
                     let loaded = util::qt_queued_callback_mut(self, move |this, (render_options, ask_path): (RenderOptions, bool)| {
@@ -987,7 +816,7 @@ impl RenderQueue {
                             };
 
                             match result {
-                                Ok(obj) => {
+                                Ok((obj, _report)) => {
                                     if let Some(out) = obj.get("output") {
                                         if let Ok(render_options2) = serde_json::from_value(out.clone()) as serde_json::Result<RenderOptions> {
                                             loaded((render_options2, true));
@@ -1009,70 +838,25 @@ impl RenderQueue {
                                     err(("An error occured: %1".to_string(), format!("Error loading {}: {:?}", path, e)));
                                 }
                             }
-                        } else if let Ok(info) = rendering::FfmpegProcessor::get_video_info(&path) {
-                            ::log::info!("Loaded {:?}", &info);
-
-                            render_options.bitrate = render_options.bitrate.max(info.bitrate);
-                            render_options.output_width = info.width as usize;
-                            render_options.output_height = info.height as usize;
-                            render_options.output_path = Self::get_output_path(&suffix, &path, &render_options.codec, &render_options.output_path);
-
-                            let ratio = info.width as f64 / info.height as f64;
-
-                            if info.duration_ms > 0.0 && info.fps > 0.0 {
+                        } else {
+                            match batch::load_video_file(&stab, &path, &gyro_path, &Default::default(), &mut render_options, &suffix) {
+                                Ok(info) => {
+                                    // println!("{}", stab.export_gyroflow_data(true, serde_json::to_string(&render_options).unwrap_or_default()));
 
-                                let video_size = (info.width as usize, info.height as usize);
+                                    loaded((render_options, true));
 
-                                if let Err(e) = stab.init_from_video_data(&path, info.duration_ms, info.fps, info.frame_count, video_size) {
-                                    err(("An error occured: %1".to_string(), e.to_string()));
-                                    return;
-                                }
-                                let gyro_path = if !gyro_path.is_empty() { &gyro_path } else { &path };
-                                let _ = stab.load_gyro_data(&gyro_path, &Default::default(), |_|(), Arc::new(AtomicBool::new(false)));
-
-                                let camera_id = stab.camera_id.read();
-
-                                let id_str = camera_id.as_ref().map(|v| v.identifier.clone()).unwrap_or_default();
-                                if !id_str.is_empty() {
-                                    let db = stab.lens_profile_db.read();
-                                    if db.contains_id(&id_str) {
-                                        match stab.load_lens_profile(&id_str) {
-                                            Ok(_) => {
-                                                if let Some(fr) = stab.lens.read().frame_readout_time {
-                                                    stab.params.write().frame_readout_time = fr;
-                                                }
-                                            }
-                                            Err(e) => {
-                                                err(("An error occured: %1".to_string(), e.to_string()));
-                                                return;
-                                            }
-                                        }
+                                    if let Err(e) = fetch_thumb(&path, info.width as f64 / info.height as f64) {
+                                        err(("An error occured: %1".to_string(), e.to_string()));
                                     }
-                                }
-                                if let Some(output_dim) = stab.lens.read().output_dimension.clone() {
-                                    render_options.output_width = output_dim.w;
-                                    render_options.output_height = output_dim.h;
-                                }
-
-                                stab.set_size(video_size.0, video_size.1);
-                                stab.set_output_size(render_options.output_width, render_options.output_height);
 
-                                stab.recompute_blocking();
+                                    batch::autosync(&path, info.duration_ms, stab.clone(), processing, err.clone(), sync_options);
 
-                                // println!("{}", stab.export_gyroflow_data(true, serde_json::to_string(&render_options).unwrap_or_default()));
-
-                                loaded((render_options, true));
-
-                                if let Err(e) = fetch_thumb(&path, ratio) {
-                                    err(("An error occured: %1".to_string(), e.to_string()));
+                                    processing_done(());
+                                }
+                                Err(e) => {
+                                    err(("An error occured: %1".to_string(), e));
                                 }
-
-                                Self::do_autosync(&path, info.duration_ms, stab.clone(), processing, err.clone(), sync_options);
-
-                                processing_done(());
                             }
-                        } else {
-                            err(("An error occured: %1".to_string(), "Unable to read the video file.".to_string()));
                         }
                     });
                 }
@@ -1083,123 +867,6 @@ impl RenderQueue {
         job_id
     }
 
-    fn do_autosync<F: Fn(f64) + Send + Sync + Clone + 'static, F2: Fn((String, String)) + Send + Sync + Clone + 'static>(path: &str, duration_ms: f64, stab: Arc<StabilizationManager<stabilization::RGBA8>>, processing_cb: F, err: F2, sync_options: serde_json::Value) {
-        let (has_gyro, has_sync_points) = {
-            let gyro = stab.gyro.read();
-            (!gyro.quaternions.is_empty(), !gyro.get_offsets().is_empty())
-        };
-
-        let sync_settings = stab.lens.read().sync_settings.clone();
-        if let Some(sync_settings) = sync_settings {
-            if has_gyro && !has_sync_points && sync_settings.get("do_autosync").and_then(|v| v.as_bool()).unwrap_or_default() {
-                // ----------------------------------------------------------------------------
-                // --------------------------------- Autosync ---------------------------------
-                processing_cb(0.01);
-                use gyroflow_core::synchronization::AutosyncProcess;
-                use gyroflow_core::synchronization;
-                use crate::rendering::VideoProcessor;
-                use itertools::Either;
-
-                if let serde_json::Value::Object(mut sync_options) = sync_options {
-                    for (k, v) in sync_settings.as_object().unwrap() {
-                        sync_options.insert(k.clone(), v.clone());
-                    }
-
-                    if let Some(points) = sync_options.get("max_sync_points").and_then(|v| v.as_i64()) {
-                        let chunks = 1.0 / points as f64;
-                        let start = chunks / 2.0;
-                        let mut timestamps_fract: Vec<f64> = (0..points).map(|i| start + (i as f64 * chunks)).collect();
-                        if let Some(v) = sync_options.get("custom_sync_timestamps").and_then(|v| v.as_array()) {
-                            timestamps_fract = v.iter().filter_map(|v| v.as_f64()).filter(|v| *v <= duration_ms).map(|v| v / duration_ms).collect();
-                        }
-
-                        if let Ok(mut sync_params) = serde_json::from_value(serde_json::Value::Object(sync_options)) as serde_json::Result<synchronization::SyncParams> {
-
-                            let cancel_flag = Arc::new(AtomicBool::new(false));
-                            sync_params.initial_offset     *= 1000.0; // s to ms
-                            sync_params.time_per_syncpoint *= 1000.0; // s to ms
-                            sync_params.search_size        *= 1000.0; // s to ms
-
-                            let every_nth_frame = sync_params.every_nth_frame.max(1);
-
-                            let size = stab.params.read().video_size;
-
-                            if let Ok(mut sync) = AutosyncProcess::from_manager(&stab, &timestamps_fract, sync_params, "synchronize".into(), cancel_flag.clone()) {
-                                let processing_cb2 = processing_cb.clone();
-                                sync.on_progress(move |percent, _ready, _total| {
-                                    processing_cb2(percent);
-                                });
-                                let stab2 = stab.clone();
-                                sync.on_finished(move |arg| {
-                                    if let Either::Left(offsets) = arg {
-                                        let mut gyro = stab2.gyro.write();
-                                        gyro.prevent_recompute = true;
-                                        for x in offsets {
-                                            ::log::info!("Setting offset at {:.4}: {:.4} (cost {:.4})", x.0, x.1, x.2);
-                                            let new_ts = ((x.0 - x.1) * 1000.0) as i64;
-                                            // Remove existing offsets within 100ms range
-                                            gyro.remove_offsets_near(new_ts, 100.0);
-                                            gyro.set_offset(new_ts, x.1);
-                                        }
-                                        gyro.prevent_recompute = false;
-                                        gyro.adjust_offsets();
-                                        stab2.keyframes.write().update_gyro(&gyro);
-                                    }
-                                });
-
-                                let (sw, sh) = ((720.0 * (size.0 as f64 / size.1 as f64)).round() as u32, 720);
-
-                                let gpu_decoding = *rendering::GPU_DECODING.read();
-
-                                let mut frame_no = 0;
-                                let mut abs_frame_no = 0;
-                                let sync = std::rc::Rc::new(sync);
-
-                                match VideoProcessor::from_file(path, gpu_decoding, 0, None) {
-                                    Ok(mut proc) => {
-                                        let err2 = err.clone();
-                                        let sync2 = sync.clone();
-                                        proc.on_frame(move |timestamp_us, input_frame, _output_frame, converter, _rate_control| {
-                                            if abs_frame_no % every_nth_frame == 0 {
-                                                match converter.scale(input_frame, ffmpeg_next::format::Pixel::GRAY8, sw, sh) {
-                                                    Ok(small_frame) => {
-                                                        let (width, height, stride, pixels) = (small_frame.plane_width(0), small_frame.plane_height(0), small_frame.stride(0), small_frame.data(0));
-
-                                                        sync2.feed_frame(timestamp_us, frame_no, width, height, stride, pixels);
-                                                    },
-                                                    Err(e) => {
-                                                        err2(("An error occured: %1".to_string(), e.to_string()))
-                                                    }
-                                                }
-                                                frame_no += 1;
-                                            }
-                                            abs_frame_no += 1;
-                                            Ok(())
-                                        });
-                                        if let Err(e) = proc.start_decoder_only(sync.get_ranges(), cancel_flag) {
-                                            err(("An error occured: %1".to_string(), e.to_string()));
-                                        }
-                                        sync.finished_feeding_frames();
-                                    }
-                                    Err(error) => {
-                                        err(("An error occured: %1".to_string(), error.to_string()));
-                                    }
-                                }
-                            } else {
-                                err(("An error occured: %1".to_string(), "Invalid parameters".to_string()));
-                            }
-
-                            stab.recompute_blocking();
-                        }
-                    }
-                }
-                processing_cb(1.0);
-                // --------------------------------- Autosync ---------------------------------
-                // ----------------------------------------------------------------------------
-            }
-        }
-    }
-
     pub fn apply_to_all(&mut self, data: String, additional_data: String) {
         ::log::debug!("Applying preset {}", &data);
         let mut new_output_options = None;
@@ -1239,7 +906,7 @@ impl RenderQueue {
                     let job_id = *job_id;
                     if let Some(ref new_output_options) = new_output_options {
                         job.render_options.update_from_json(new_output_options);
-                        job.render_options.output_path = Self::get_output_path(&self.default_suffix.to_string(), &itm.input_file.to_string(), &job.render_options.codec, &job.render_options.output_path);
+                        job.render_options.output_path = get_output_path(&self.default_suffix.to_string(), &itm.input_file.to_string(), &job.render_options.codec, &job.render_options.output_path);
                         itm.export_settings = QString::from(job.render_options.settings_string(job.stab.params.read().fps));
                         itm.output_path = QString::from(job.render_options.output_path.as_str());
                         if std::path::Path::new(&job.render_options.output_path).exists() {
@@ -1259,7 +926,7 @@ impl RenderQueue {
                             let params = stab.params.read();
                             (stab.input_file.read().path.clone(), params.duration_ms)
                         };
-                        Self::do_autosync(&path, duration_ms, stab, move |progress| processing2((progress, job_id)) , |_|{}, sync_options);
+                        batch::autosync(&path, duration_ms, stab, move |progress| processing2((progress, job_id)) , |_|{}, sync_options);
                         processing_done(job_id);
                     });
 
@@ -37,3 +37,8 @@ impl<'a> VideoProcessor<'a> {
         }
     }
 }
+
+impl<'a> batch::SyncDecoder for VideoProcessor<'a> {
+    fn on_frame(&mut self, cb: batch::FrameCallback) { VideoProcessor::on_frame(self, cb) }
+    fn start_decoder_only(&mut self, ranges: Vec<(f64, f64)>, cancel_flag: Arc<AtomicBool>) -> Result<(), FFmpegError> { VideoProcessor::start_decoder_only(self, ranges, cancel_flag) }
+}
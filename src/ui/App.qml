@@ -431,6 +431,13 @@ Rectangle {
         function onMessage(text: string, arg: string, callback: string, id: string) {
             messageBox(Modal.Info, qsTr(text).arg(arg), [ { text: qsTr("Ok"), clicked: window[callback] } ], null, undefined, id);
         }
+        function onProject_report_loaded(report: var) {
+            let text = "";
+            if (report.migrated.length) text += qsTr("Project file was migrated to the current version:") + "\n- " + report.migrated.join("\n- ") + "\n\n";
+            if (report.invalid.length)  text += qsTr("Invalid fields were skipped:") + "\n- " + report.invalid.join("\n- ") + "\n\n";
+            if (report.ignored.length)  text += qsTr("Unknown fields were ignored:") + "\n- " + report.ignored.join("\n- ");
+            messageBox(report.invalid.length ? Modal.Warning : Modal.Info, text.trim(), [ { text: qsTr("Ok") } ], undefined, Text.PlainText);
+        }
         function onRequest_recompute() {
             Qt.callLater(controller.recompute_threaded);
         }
@@ -37,7 +37,7 @@ Modal {
     },
     { // Right column
         "Synchronization|synchronization": {
-            "Rough gyro offset":          ["initial_offset", "initial_offset_inv"],
+            "Rough gyro offset":          ["initial_offset", "initial_offset_inv", "global_search", "timestamp_sync"],
             "Sync search size":           ["search_size", "calc_initial_fast"],
             "Max sync points":            ["max_sync_points"],
             "Do autosync":                ["do_autosync"],
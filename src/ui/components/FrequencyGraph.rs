@@ -3,11 +3,6 @@
 
 #![allow(non_snake_case)]
 
-use rustfft::algorithm::Radix4;
-use nalgebra::ComplexField;
-use rustfft::{num_complex::Complex, Fft, FftDirection};
-use std::{f32::consts::PI};
-
 use qmetaobject::*;
 use crate::util;
 
@@ -16,7 +11,6 @@ struct Series {
     line: Vec<QPointF>,
     data: Vec<f64>,
     spectrum: Vec<f64>,
-    window: Vec<f32>,
 }
 
 
@@ -61,44 +55,7 @@ impl FrequencyGraph {
     }
 
     fn analyze_spectrum(&mut self) {
-        self.series.spectrum.clear();
-        
-        let fft_size = self.series.data.len();
-        if fft_size == 0 { return; }
-
-        if self.series.window.len() != fft_size {
-            // using blackmann-harris
-            self.series.window = {
-                let mut samples = vec![0.0; fft_size];
-                let size = (fft_size - 1) as f32;
-                for i in 0..fft_size {
-                    let r = i as f32 / size;
-                    samples[i] = 0.35875 -
-						0.48829 * (2.0 * PI * r).cos() +
-						0.14128 * (4.0 * PI * r).cos() -
-						0.01168 * (6.0 * PI * r).cos();
-                }
-                samples
-            }
-        }
-
-        let mut samples = self.series.data
-            .iter()
-            .map(|x| Complex::from_real(*x as f32))
-            .zip(&self.series.window)
-            .map(|(x,y)| x * y)
-            .collect::<Vec<Complex<f32>>>();
-
-        let fft = Radix4::new(fft_size, FftDirection::Forward);
-        fft.process(&mut samples);
-
-        let scale = 1.0 / (fft_size as f64);
-
-        self.series.spectrum = samples
-            .iter()
-            .take(fft_size / 2)
-            .map(|&complex| complex.norm() as f64 * scale)
-            .collect();
+        self.series.spectrum = gyroflow_core::spectral_analysis::spectrum(&self.series.data);
     }
 
     fn calculate_lines(&mut self) {
@@ -552,6 +552,26 @@ Item {
                         ]);
                     }
                 }
+                Action {
+                    iconName: "readout_time";
+                    text: qsTr("Estimate rolling shutter in the whole video");
+                    onTriggered: {
+                        const maxPoints = Math.max(3, +window.sync.getSettings().max_sync_points);
+                        const chunks = (root.trimEnd - root.trimStart) / maxPoints;
+                        let ranges = [];
+                        for (let i = 0; i < maxPoints; ++i) {
+                            ranges.push(root.trimStart + (chunks / 2) + (i * chunks));
+                        }
+                        const text = qsTr("Your video needs to be already synced properly.\nThe readout time is estimated in %1 parts of the video and the parts without enough motion are skipped.\n\n").arg(maxPoints) +
+                                     qsTr("Are you sure you want to continue?");
+                        messageBox(Modal.Warning, text, [
+                            { text: qsTr("Yes"), clicked: function() {
+                                controller.start_autosync(ranges.join(";"), window.sync.getSettingsJson(), "estimate_rolling_shutter");
+                            }},
+                            { text: qsTr("No"), accent: true },
+                        ]);
+                    }
+                }
                 Action {
                     iconName: "bias";
                     text: qsTr("Estimate gyro bias here");
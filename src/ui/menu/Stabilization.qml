@@ -175,6 +175,7 @@ MenuItem {
         QT_TRANSLATE_NOOP("Popup", "Default"),
         QT_TRANSLATE_NOOP("Popup", "Plain 3D");
         QT_TRANSLATE_NOOP("Popup", "Fixed camera");
+        QT_TRANSLATE_NOOP("Popup", "Kalman (RTS)");
 
         QT_TRANSLATE_NOOP("Stabilization", "Pitch smoothness");
         QT_TRANSLATE_NOOP("Stabilization", "Yaw smoothness");
@@ -185,6 +186,7 @@ MenuItem {
         QT_TRANSLATE_NOOP("Stabilization", "Max smoothness at high velocity");
         QT_TRANSLATE_NOOP("Stabilization", "Second smoothing pass");
         QT_TRANSLATE_NOOP("Stabilization", "Only within trim range");
+        QT_TRANSLATE_NOOP("Stabilization", "Constant acceleration model");
         QT_TRANSLATE_NOOP("Stabilization", "Yaw angle correction");
         QT_TRANSLATE_NOOP("Stabilization", "Pitch angle correction");
         QT_TRANSLATE_NOOP("Stabilization", "Roll angle correction");
@@ -22,6 +22,8 @@ MenuItem {
         property alias timePerSyncpoint: timePerSyncpoint.value;
         property alias sync_lpf: lpf.value;
         property alias checkNegativeInitialOffset: checkNegativeInitialOffset.checked;
+        property alias globalSearch: globalSearch.checked;
+        property alias timestampSync: timestampSync.checked;
         property alias experimentalAutoSyncPoints: experimentalAutoSyncPoints.checked;
         // property alias syncMethod: syncMethod.currentIndex;
         // property alias offsetMethod: offsetMethod.currentIndex;
@@ -40,6 +42,8 @@ MenuItem {
         if (o && Object.keys(o).length > 0) {
             if (o.hasOwnProperty("initial_offset"))     initialOffset.value                 = +o.initial_offset;
             if (o.hasOwnProperty("initial_offset_inv")) checkNegativeInitialOffset.checked  = !!o.initial_offset_inv;
+            if (o.hasOwnProperty("global_search"))      globalSearch.checked                = !!o.global_search;
+            if (o.hasOwnProperty("timestamp_sync"))     timestampSync.checked               = !!o.timestamp_sync;
             if (o.hasOwnProperty("search_size"))        syncSearchSize.value                = +o.search_size;
             if (o.hasOwnProperty("calc_initial_fast"))  calculateInitialOffsetFirst.checked = !!o.calc_initial_fast;
             if (o.hasOwnProperty("max_sync_points"))    maxSyncPoints.value                 = +o.max_sync_points;
@@ -48,7 +52,7 @@ MenuItem {
             if (o.hasOwnProperty("of_method"))          syncMethod.currentIndex             = +o.of_method;
             if (o.hasOwnProperty("offset_method"))      offsetMethod.currentIndex           = +o.offset_method;
             if (o.hasOwnProperty("custom_sync_timestamps")) sync.customSyncTimestamps       = o.custom_sync_timestamps;
-            if (o.hasOwnProperty("auto_sync_points")) experimentalAutoSyncPoints.checked    = !!o.experimental_auto_sync_points;
+            if (o.hasOwnProperty("auto_sync_points")) experimentalAutoSyncPoints.checked    = !!o.auto_sync_points;
             if (o.hasOwnProperty("do_autosync") && o.do_autosync) autosyncTimer.doRun = true;
         }
     }
@@ -67,6 +71,8 @@ MenuItem {
         return {
             "initial_offset":     initialOffset.value,
             "initial_offset_inv": checkNegativeInitialOffset.checked,
+            "global_search":      globalSearch.checked,
+            "timestamp_sync":     timestampSync.checked? { } : null,
             "search_size":        syncSearchSize.value,
             "calc_initial_fast":  calculateInitialOffsetFirst.checked,
             "max_sync_points":    maxSyncPoints.value,
@@ -91,7 +97,7 @@ MenuItem {
             let sync_points = null;
 
             if (experimentalAutoSyncPoints.checked) {
-                sync_points = controller.get_optimal_sync_points(maxPoints);
+                sync_points = controller.get_optimal_sync_points(maxPoints, sync.getSettingsJson());
             }
             if (!sync_points) {
                 const trimmed = videoArea.trimEnd - videoArea.trimStart;
@@ -131,7 +137,8 @@ MenuItem {
             anchors.verticalCenter: parent.verticalCenter;
             contentItem.visible: false;
             scale: 0.7;
-            tooltip: qsTr("Experimental automatic sync point selection.");
+            checked: true;
+            tooltip: qsTr("Place the sync points in the parts with the best motion, avoiding static and saturated parts.");
         }
     }
 
@@ -164,6 +171,18 @@ MenuItem {
             tooltip: qsTr("Analyze both positive and negative offset.\nThis doubles the calculation time, so check this only for the initial point and uncheck once you know the offset.");
         }
     }
+    CheckBox {
+        id: globalSearch;
+        text: qsTr("Search the whole motion log");
+        checked: false;
+        tooltip: qsTr("Find the rough offset by matching the video motion with the entire motion log.\nUseful when the log is much longer than the video, for example one log for many clips.");
+    }
+    CheckBox {
+        id: timestampSync;
+        text: qsTr("Rough offset from timestamps");
+        checked: false;
+        tooltip: qsTr("Calculate the rough offset from the creation time or timecode of the video and the absolute time of the motion log (blackbox RTC, CSV with Unix timestamps).\nThe camera and logger clocks have to be set correctly.");
+    }
 
     Label {
         position: Label.LeftPosition;
@@ -266,7 +285,7 @@ MenuItem {
 
             ComboBox {
                 id: syncMethod;
-                model: ["AKAZE", "OpenCV (PyrLK)", "OpenCV (DIS)"];
+                model: ["AKAZE", "OpenCV (PyrLK)", "OpenCV (DIS)", "Built-in (PyrLK)"];
                 font.pixelSize: 12 * dpiScale;
                 width: parent.width;
                 currentIndex: 2;